@@ -0,0 +1,274 @@
+//! Denylists of keys and IP ranges rejected before rate limiting, optionally reloaded from a file
+//! or fetched periodically, e.g. from a URL with the `blocklist-url` feature.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use jsonrpsee_tower_governor::{blocklist::Blocklist, governor::GovernorConfigBuilder};
+//! use std::{net::IpAddr, time::Duration};
+//!
+//! // blocked.txt, one IP address, CIDR range or key per line:
+//! //   203.0.113.7
+//! //   198.51.100.0/24
+//! let blocklist = Blocklist::<IpAddr>::watch_file("blocked.txt", Duration::from_secs(5)).unwrap();
+//! let config = GovernorConfigBuilder::default()
+//!     .blocklist(blocklist)
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::BoxError;
+#[cfg(feature = "blocklist-url")]
+use bytes::Bytes;
+use http::StatusCode;
+#[cfg(feature = "blocklist-url")]
+use http::Uri;
+#[cfg(feature = "blocklist-url")]
+use http_body_util::{BodyExt, Empty};
+#[cfg(feature = "blocklist-url")]
+use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+use ipnet::IpNet;
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    future::Future,
+    hash::Hash,
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+/// The keys and IP ranges of a [`Blocklist`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlocklistEntries<Key: Hash + Eq> {
+    /// Blocked keys.
+    pub keys: HashSet<Key>,
+    /// Blocked IP ranges, matched against the client IP address of the IP key extractors,
+    /// see [`KeyExtractor::client_ip`](crate::key_extractor::KeyExtractor::client_ip).
+    pub networks: Vec<IpNet>,
+}
+
+impl<Key: Hash + Eq> Default for BlocklistEntries<Key> {
+    fn default() -> Self {
+        Self {
+            keys: HashSet::new(),
+            networks: Vec::new(),
+        }
+    }
+}
+
+impl<Key: Hash + Eq + FromStr> BlocklistEntries<Key> {
+    /// Parse one entry per line or comma separated, e.g. a CSV column: an IP address or CIDR range,
+    /// or else a key. Empty entries and lines starting with `#` are ignored, as are entries that are neither.
+    pub fn parse(text: &str) -> Self {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .flat_map(|line| line.split(','))
+            .collect()
+    }
+
+    /// Parse a JSON array of entries, see [`parse`](Self::parse).
+    pub fn parse_json(text: &str) -> Result<Self, serde_json::Error> {
+        let entries: Vec<String> = serde_json::from_str(text)?;
+        Ok(entries.iter().map(String::as_str).collect())
+    }
+}
+
+impl<'a, Key: Hash + Eq + FromStr> FromIterator<&'a str> for BlocklistEntries<Key> {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut entries = Self::default();
+        for entry in iter.into_iter().map(str::trim) {
+            if entry.is_empty() {
+                continue;
+            }
+            if let Ok(network) = entry.parse::<IpNet>() {
+                entries.networks.push(network);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                entries.networks.push(IpNet::from(ip));
+            } else if let Ok(key) = entry.parse::<Key>() {
+                entries.keys.insert(key);
+            }
+        }
+        entries
+    }
+}
+
+/// A denylist rejecting the requests of its keys and IP ranges before rate limiting, see
+/// [`GovernorConfigBuilder::blocklist`](crate::governor::GovernorConfigBuilder::blocklist).
+///
+/// Clones share the same entries, which can be replaced at runtime.
+pub struct Blocklist<Key: Hash + Eq> {
+    entries: Arc<RwLock<Arc<BlocklistEntries<Key>>>>,
+    status: StatusCode,
+}
+
+impl<Key: Hash + Eq> Blocklist<Key> {
+    /// Block `entries`, rejecting their requests with `403 Forbidden`.
+    pub fn new(entries: BlocklistEntries<Key>) -> Self {
+        Self {
+            entries: Arc::new(RwLock::new(Arc::new(entries))),
+            status: StatusCode::FORBIDDEN,
+        }
+    }
+
+    /// Set the status of the rejections, `403 Forbidden` by default.
+    pub fn status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// The status of the rejections.
+    pub fn rejection_status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// The blocked entries.
+    pub fn entries(&self) -> Arc<BlocklistEntries<Key>> {
+        self.entries.read().unwrap().clone()
+    }
+
+    /// Replace the blocked entries, at once for all requests.
+    pub fn set(&self, entries: BlocklistEntries<Key>) {
+        *self.entries.write().unwrap() = Arc::new(entries);
+    }
+
+    /// Whether requests with `key` or from `ip` are blocked.
+    pub fn blocks(&self, key: Option<&Key>, ip: Option<IpAddr>) -> bool {
+        let entries = self.entries.read().unwrap();
+        key.is_some_and(|key| entries.keys.contains(key))
+            || ip.is_some_and(|ip| entries.networks.iter().any(|network| network.contains(&ip)))
+    }
+}
+
+impl<Key: Hash + Eq + FromStr + Send + Sync + 'static> Blocklist<Key> {
+    /// Read a blocklist from a file with one entry per line, see [`BlocklistEntries::parse`],
+    /// and reload it whenever the file is modified, checking its modification time every `interval`.
+    ///
+    /// A modified file that can't be read keeps the previous entries.
+    /// The watching thread stops once every clone of the returned blocklist is dropped.
+    pub fn watch_file(path: impl Into<PathBuf>, interval: Duration) -> Result<Self, io::Error> {
+        let path = path.into();
+        let mut modified = fs::metadata(&path)?.modified()?;
+        let blocklist = Self::new(BlocklistEntries::parse(&fs::read_to_string(&path)?));
+        let entries = Arc::downgrade(&blocklist.entries);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let Some(entries) = entries.upgrade() else {
+                return;
+            };
+            let Ok(latest) = fs::metadata(&path).and_then(|m| m.modified()) else {
+                continue;
+            };
+            if latest == modified {
+                continue;
+            }
+            modified = latest;
+            match fs::read_to_string(&path) {
+                Ok(text) => *entries.write().unwrap() = Arc::new(BlocklistEntries::parse(&text)),
+                Err(_e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("Keeping the previous blocklist: {}", _e);
+                }
+            }
+        });
+        Ok(blocklist)
+    }
+
+    /// Replace the entries with the ones returned by `fetch` every `interval`, starting right away,
+    /// e.g. to pull the blocks of a central abuse system. The text is parsed with
+    /// [`BlocklistEntries::parse_json`] if it is a JSON array, [`BlocklistEntries::parse`] otherwise.
+    ///
+    /// Failed fetches keep the previous entries. The task runs on the current tokio runtime and
+    /// stops once every clone of the blocklist is dropped.
+    pub fn refresh<F, Fut>(&self, interval: Duration, fetch: F) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<String, BoxError>> + Send,
+    {
+        let entries = Arc::downgrade(&self.entries);
+        tokio::spawn(async move {
+            loop {
+                let fetched = fetch().await.and_then(|text| match text.trim_start() {
+                    json if json.starts_with('[') => Ok(BlocklistEntries::parse_json(json)?),
+                    text => Ok(BlocklistEntries::parse(text)),
+                });
+                let Some(entries) = entries.upgrade() else {
+                    return;
+                };
+                match fetched {
+                    Ok(fetched) => *entries.write().unwrap() = Arc::new(fetched),
+                    Err(_e) => {
+                        #[cfg(feature = "tracing")]
+                        tracing::warn!("Keeping the previous blocklist: {}", _e);
+                    }
+                }
+                drop(entries);
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    /// Fetch the entries from `url` every `interval` over plain HTTP, see [`refresh`](Self::refresh).
+    /// Use [`refresh`](Self::refresh) with your own HTTP client for HTTPS.
+    ///
+    /// Requires the `blocklist-url` feature.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use jsonrpsee_tower_governor::blocklist::{Blocklist, BlocklistEntries};
+    /// # use std::{net::IpAddr, time::Duration};
+    /// # async fn run() {
+    /// let blocklist = Blocklist::<IpAddr>::new(BlocklistEntries::default());
+    /// blocklist.refresh_from_url(
+    ///     "http://abuse.internal/blocked.json".parse().unwrap(),
+    ///     Duration::from_secs(60),
+    /// );
+    /// # }
+    /// ```
+    #[cfg(feature = "blocklist-url")]
+    pub fn refresh_from_url(&self, url: Uri, interval: Duration) -> JoinHandle<()> {
+        let client = Client::builder(TokioExecutor::new()).build_http::<Empty<Bytes>>();
+        self.refresh(interval, move || {
+            let response = client.get(url.clone());
+            async move {
+                let response = response.await?;
+                if !response.status().is_success() {
+                    return Err(format!("Unexpected status {}", response.status()).into());
+                }
+                let body = response.into_body().collect().await?.to_bytes();
+                Ok(String::from_utf8(body.to_vec())?)
+            }
+        })
+    }
+}
+
+impl<Key: Hash + Eq> Clone for Blocklist<Key> {
+    fn clone(&self) -> Self {
+        Self {
+            entries: self.entries.clone(),
+            status: self.status,
+        }
+    }
+}
+
+impl<Key: Hash + Eq> fmt::Debug for Blocklist<Key> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Blocklist")
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+impl<Key: Hash + Eq> PartialEq for Blocklist<Key> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.entries, &other.entries) && self.status == other.status
+    }
+}
+
+impl<Key: Hash + Eq> Eq for Blocklist<Key> {}
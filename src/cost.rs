@@ -0,0 +1,113 @@
+//! The number of tokens requests consume from the quota, see
+//! [`GovernorConfigBuilder::cost_extractor`](crate::governor::GovernorConfigBuilder::cost_extractor).
+//!
+//! # Example
+//!
+//! ```rust
+//! use http::Request;
+//! use hyper::body::Incoming;
+//! use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+//! use std::num::NonZeroU32;
+//!
+//! // Exports cost 10 tokens, everything else 1.
+//! let config = GovernorConfigBuilder::default()
+//!     .burst_size(20)
+//!     .cost_extractor(|req: &Request<Incoming>| {
+//!         let cost = if req.uri().path().starts_with("/export") { 10 } else { 1 };
+//!         NonZeroU32::new(cost).unwrap()
+//!     })
+//!     .finish()
+//!     .unwrap();
+//! ```
+use http::{HeaderName, Request};
+use hyper::body::Incoming;
+use std::num::NonZeroU32;
+
+/// Default header carrying the cost of a request, see [`HeaderCost`].
+pub const DEFAULT_COST_HEADER: &str = "x-request-cost";
+
+/// Computes the number of tokens a request consumes from the quota, e.g. more for expensive
+/// endpoints than for cheap ones, see the [module documentation](self).
+///
+/// Implemented for closures taking the request and returning its cost.
+pub trait CostExtractor: Send + Sync {
+    /// The number of tokens `req` consumes.
+    fn cost(&self, req: &Request<Incoming>) -> NonZeroU32;
+}
+
+impl<F> CostExtractor for F
+where
+    F: Fn(&Request<Incoming>) -> NonZeroU32 + Send + Sync,
+{
+    fn cost(&self, req: &Request<Incoming>) -> NonZeroU32 {
+        self(req)
+    }
+}
+
+/// A [`CostExtractor`] reading the cost of requests from a header, e.g. set by an internal gateway
+/// that already computed the complexity of the query.
+///
+/// Requests without the header, or with a value that isn't a positive integer, cost the default
+/// cost, 1 unless set, and costs above the maximum cost are capped.
+///
+/// **Only use it if the header is set by a trusted gateway**, clients could otherwise pick their cost.
+///
+/// # Example
+///
+/// ```rust
+/// use jsonrpsee_tower_governor::{cost::HeaderCost, governor::GovernorConfigBuilder};
+/// use std::num::NonZeroU32;
+///
+/// let config = GovernorConfigBuilder::default()
+///     .burst_size(100)
+///     .cost_extractor(HeaderCost::default().max(NonZeroU32::new(50).unwrap()))
+///     .finish()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderCost {
+    header: HeaderName,
+    default: NonZeroU32,
+    max: NonZeroU32,
+}
+
+impl HeaderCost {
+    /// Read the cost of requests from `header`.
+    pub fn new(header: HeaderName) -> Self {
+        Self {
+            header,
+            default: NonZeroU32::MIN,
+            max: NonZeroU32::MAX,
+        }
+    }
+
+    /// Set the cost of requests without a valid cost.
+    pub fn default_cost(mut self, default: NonZeroU32) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// Set the maximum cost, no cap by default.
+    pub fn max(mut self, max: NonZeroU32) -> Self {
+        self.max = max;
+        self
+    }
+}
+
+impl Default for HeaderCost {
+    /// Read the cost of requests from the [`DEFAULT_COST_HEADER`].
+    fn default() -> Self {
+        Self::new(HeaderName::from_static(DEFAULT_COST_HEADER))
+    }
+}
+
+impl CostExtractor for HeaderCost {
+    fn cost(&self, req: &Request<Incoming>) -> NonZeroU32 {
+        req.headers()
+            .get(&self.header)
+            .and_then(|hv| hv.to_str().ok())
+            .and_then(|s| s.trim().parse::<NonZeroU32>().ok())
+            .unwrap_or(self.default)
+            .min(self.max)
+    }
+}
@@ -1,9 +1,101 @@
-use http::{HeaderMap, Response, StatusCode};
+use http::{header::CONTENT_TYPE, HeaderMap, HeaderValue, Response, StatusCode};
+use serde_json::{json, Value};
 use std::mem;
 use thiserror::Error;
 
+/// Format of the built-in error responses, see [`GovernorConfigBuilder::error_format`].
+///
+/// [`GovernorConfigBuilder::error_format`]: crate::governor::GovernorConfigBuilder::error_format
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// Plain text bodies, `Too many requests` for rejections and [`GovernorError::as_response`]
+    /// otherwise. This is the default.
+    #[default]
+    PlainText,
+    /// JSON bodies, see [`GovernorError::as_json_response`].
+    Json,
+    /// RFC 9457 `application/problem+json` bodies, see [`GovernorError::as_problem_json_response`].
+    ProblemJson,
+    /// HTML pages, see [`GovernorError::as_html_response`].
+    Html,
+}
+
+impl ErrorFormat {
+    /// Pick the format preferred by an `Accept` header value, if any of them is acceptable.
+    ///
+    /// Wildcards don't select anything, so that the configured default format applies.
+    pub(crate) fn negotiate(accept: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let format = match params.next().unwrap_or_default().trim() {
+                "text/plain" => Self::PlainText,
+                "application/json" => Self::Json,
+                "application/problem+json" => Self::ProblemJson,
+                "text/html" => Self::Html,
+                _ => continue,
+            };
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((format, quality));
+            }
+        }
+        best.map(|(format, _)| format)
+    }
+}
+
+/// Pick the message of the language preferred by an `Accept-Language` header value, if any.
+///
+/// A range matches its exact tag, more specific tags (`en` matches `en-GB`) and, as a fallback,
+/// less specific ones (`en-US` matches `en`).
+pub(crate) fn negotiate_language<'a>(
+    accept_language: &str,
+    messages: &'a [(String, String)],
+) -> Option<&'a str> {
+    let mut best: Option<(&str, f32)> = None;
+    for range in accept_language.split(',') {
+        let mut params = range.split(';');
+        let range = params.next().unwrap_or_default().trim();
+        let matches = |language: &str, prefix: &str| {
+            language.len() > prefix.len()
+                && language.as_bytes()[prefix.len()] == b'-'
+                && language[..prefix.len()].eq_ignore_ascii_case(prefix)
+        };
+        let Some(message) = messages
+            .iter()
+            .find(|(language, _)| language.eq_ignore_ascii_case(range))
+            .or_else(|| {
+                messages
+                    .iter()
+                    .find(|(language, _)| matches(language, range))
+            })
+            .or_else(|| {
+                messages
+                    .iter()
+                    .find(|(language, _)| matches(range, language))
+            })
+        else {
+            continue;
+        };
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((&message.1, quality));
+        }
+    }
+    best.map(|(message, _)| message)
+}
+
 /// The error type returned by tower-governor.
+///
+/// New variants may be added in minor releases, matches must have a wildcard arm.
 #[derive(Debug, Error, Clone)]
+#[non_exhaustive]
 pub enum GovernorError {
     #[error("Too Many Requests! Wait for {wait_time}s")]
     TooManyRequests {
@@ -12,6 +104,9 @@ pub enum GovernorError {
     },
     #[error("Unable to extract key!")]
     UnableToExtractKey,
+    #[error("Request cost of {cost} exceeds the burst size of {burst_size}!")]
+    /// The request costs more tokens than the quota can ever hold, retrying is pointless
+    CostExceedsBurst { cost: u32, burst_size: u32 },
     #[error("Other Error")]
     /// Used for custom key extractors to return their own errors
     Other {
@@ -28,36 +123,265 @@ impl GovernorError {
     where
         ResB: From<String>,
     {
-        match mem::replace(self, Self::UnableToExtractKey) {
-            GovernorError::TooManyRequests { wait_time, headers } => {
-                let response = Response::new(format!("Too Many Requests! Wait for {}s", wait_time));
-                let (mut parts, body) = response.into_parts();
-                parts.status = StatusCode::TOO_MANY_REQUESTS;
-                if let Some(headers) = headers {
-                    parts.headers = headers;
-                }
-                Response::from_parts(parts, ResB::from(body))
+        let (status, headers, body) = match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { wait_time, headers } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                format!("Too Many Requests! Wait for {}s", wait_time),
+            ),
+            GovernorError::UnableToExtractKey => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                "Unable To Extract Key!".to_string(),
+            ),
+            error @ GovernorError::CostExceedsBurst { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, None, error.to_string())
             }
-            GovernorError::UnableToExtractKey => {
-                let response = Response::new("Unable To Extract Key!".to_string());
-                let (mut parts, body) = response.into_parts();
-                parts.status = StatusCode::INTERNAL_SERVER_ERROR;
+            GovernorError::Other { msg, code, headers } => (
+                code,
+                headers,
+                msg.unwrap_or_else(|| "Other Error!".to_string()),
+            ),
+        };
+        text_response(status, headers, body, "text/plain; charset=utf-8")
+    }
 
-                Response::from_parts(parts, ResB::from(body))
-            }
-            GovernorError::Other { msg, code, headers } => {
-                let response = Response::new("Other Error!".to_string());
-                let (mut parts, mut body) = response.into_parts();
-                parts.status = code;
-                if let Some(headers) = headers {
-                    parts.headers = headers;
-                }
-                if let Some(msg) = msg {
-                    body = msg;
-                }
-
-                Response::from_parts(parts, ResB::from(body))
+    /// Convert self into the response sent when no error handler nor error format is set:
+    /// rejections read `Too many requests`, other errors are rendered by [`GovernorError::as_response`].
+    pub(crate) fn as_default_response<ResB>(&mut self) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { headers, .. } => text_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                "Too many requests".to_string(),
+                "text/plain; charset=utf-8",
+            ),
+            mut error => error.as_response(),
+        }
+    }
+
+    /// Convert self into a JSON response, `limit` being the burst size of the quota.
+    ///
+    /// Rejections are rendered as `{"error":"rate_limited","retry_after":N,"limit":M}`,
+    /// other errors as `{"error":"unable_to_extract_key"}`, `{"error":"cost_exceeds_burst","cost":N,"limit":M}`
+    /// or `{"error":"other","message":"..."}`.
+    pub fn as_json_response<ResB>(&mut self, limit: u32) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        let (status, headers, body) = match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { wait_time, headers } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                json!({ "error": "rate_limited", "retry_after": wait_time, "limit": limit }),
+            ),
+            GovernorError::UnableToExtractKey => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                json!({ "error": "unable_to_extract_key" }),
+            ),
+            GovernorError::CostExceedsBurst { cost, burst_size } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                json!({ "error": "cost_exceeds_burst", "cost": cost, "limit": burst_size }),
+            ),
+            GovernorError::Other { code, msg, headers } => (
+                code,
+                headers,
+                match msg {
+                    Some(msg) => json!({ "error": "other", "message": msg }),
+                    None => json!({ "error": "other" }),
+                },
+            ),
+        };
+        json_response(status, headers, body, "application/json")
+    }
+
+    /// Convert self into an RFC 9457 problem details response, `limit` being the burst size of the quota.
+    ///
+    /// Rejections carry the `retry_after` and `limit` extension members, e.g.
+    /// `{"type":"about:blank","title":"Too Many Requests","status":429,"detail":"...","retry_after":N,"limit":M}`.
+    pub fn as_problem_json_response<ResB>(&mut self, limit: u32) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        self.problem_json_response(limit, None)
+    }
+
+    // The status is part of problem details, so it can't be overridden after rendering.
+    pub(crate) fn problem_json_response<ResB>(
+        &mut self,
+        limit: u32,
+        status: Option<StatusCode>,
+    ) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        let (default_status, headers, mut body) = match mem::replace(self, Self::UnableToExtractKey)
+        {
+            GovernorError::TooManyRequests { wait_time, headers } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                json!({
+                    "detail": format!("Rate limit exceeded, retry in {}s", wait_time),
+                    "retry_after": wait_time,
+                    "limit": limit,
+                }),
+            ),
+            GovernorError::UnableToExtractKey => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                json!({ "detail": "Unable to extract the rate limiting key" }),
+            ),
+            error @ GovernorError::CostExceedsBurst { cost, burst_size } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                None,
+                json!({ "detail": error.to_string(), "cost": cost, "limit": burst_size }),
+            ),
+            GovernorError::Other { code, msg, headers } => (
+                code,
+                headers,
+                match msg {
+                    Some(msg) => json!({ "detail": msg }),
+                    None => json!({}),
+                },
+            ),
+        };
+        let status = status.unwrap_or(default_status);
+        body["type"] = json!("about:blank");
+        body["title"] = json!(status.canonical_reason().unwrap_or("Error"));
+        body["status"] = json!(status.as_u16());
+        json_response(status, headers, body, "application/problem+json")
+    }
+
+    /// Convert self into an HTML page, `limit` being the burst size of the quota.
+    pub fn as_html_response<ResB>(&mut self, limit: u32) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        let (status, headers, message) = match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { wait_time, headers } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                format!(
+                    "You have sent more than {} requests, please wait {}s before trying again.",
+                    limit, wait_time
+                ),
+            ),
+            GovernorError::UnableToExtractKey => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                None,
+                "Unable To Extract Key!".to_string(),
+            ),
+            error @ GovernorError::CostExceedsBurst { .. } => {
+                (StatusCode::PAYLOAD_TOO_LARGE, None, error.to_string())
             }
+            GovernorError::Other { code, msg, headers } => (
+                code,
+                headers,
+                msg.as_deref()
+                    .map_or("Other Error!".to_string(), escape_html),
+            ),
+        };
+        let title = status.canonical_reason().unwrap_or("Error");
+        let body = format!(
+            "<!DOCTYPE html>\n<html><head><title>{title}</title></head>\
+            <body><h1>{title}</h1><p>{message}</p></body></html>\n"
+        );
+        text_response(status, headers, body, "text/html; charset=utf-8")
+    }
+
+    /// Convert self into an HTML page rendered from `template`, `limit` being the burst size of the quota.
+    ///
+    /// The `{wait_time}` and `{limit}` placeholders of the template are replaced for rejections,
+    /// other errors are rendered by [`GovernorError::as_html_response`].
+    pub fn as_html_template_response<ResB>(&mut self, template: &str, limit: u32) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { wait_time, headers } => text_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                render_template(template, wait_time, limit),
+                "text/html; charset=utf-8",
+            ),
+            mut error => error.as_html_response(limit),
         }
     }
+
+    /// Convert self into a plain text response rendered from `template`, `limit` being the burst size of the quota.
+    ///
+    /// The `{wait_time}` and `{limit}` placeholders of the template are replaced for rejections,
+    /// other errors are rendered by [`GovernorError::as_response`].
+    pub fn as_text_template_response<ResB>(&mut self, template: &str, limit: u32) -> Response<ResB>
+    where
+        ResB: From<String>,
+    {
+        match mem::replace(self, Self::UnableToExtractKey) {
+            GovernorError::TooManyRequests { wait_time, headers } => text_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                headers,
+                render_template(template, wait_time, limit),
+                "text/plain; charset=utf-8",
+            ),
+            mut error => error.as_response(),
+        }
+    }
+}
+
+fn render_template(template: &str, wait_time: u64, limit: u32) -> String {
+    template
+        .replace("{wait_time}", &wait_time.to_string())
+        .replace("{limit}", &limit.to_string())
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_response<ResB>(
+    status: StatusCode,
+    headers: Option<HeaderMap>,
+    body: Value,
+    content_type: &'static str,
+) -> Response<ResB>
+where
+    ResB: From<String>,
+{
+    text_response(status, headers, body.to_string(), content_type)
+}
+
+fn text_response<ResB>(
+    status: StatusCode,
+    headers: Option<HeaderMap>,
+    body: String,
+    content_type: &'static str,
+) -> Response<ResB>
+where
+    ResB: From<String>,
+{
+    let (mut parts, body) = Response::new(body).into_parts();
+    parts.status = status;
+    if let Some(headers) = headers {
+        parts.headers = headers;
+    }
+    parts
+        .headers
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Response::from_parts(parts, ResB::from(body))
 }
@@ -0,0 +1,314 @@
+//! A [`GovernorStore`] exchanging the state of the keys with the other instances of a service over
+//! UDP, so that together they enforce approximately one quota per key without running Redis.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, gossip::GossipStore};
+//! use std::{net::IpAddr, time::Duration};
+//!
+//! let store = GossipStore::<IpAddr>::bind("0.0.0.0:7946", Duration::from_millis(200)).unwrap();
+//! store.add_peer("10.0.0.2:7946").unwrap();
+//! store.add_peer("10.0.0.3:7946").unwrap();
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(2)
+//!     .burst_size(5)
+//!     .store(store)
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::store::{GovernorStore, MemoryStore};
+use dashmap::DashMap;
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// Keeps datagrams under the usual MTU.
+const MAX_DATAGRAM: usize = 1400;
+
+/// A [`GovernorStore`] replicated between instances, see the [module documentation](self).
+///
+/// Every `interval`, each instance sends to its peers the state of the keys whose quota it consumed,
+/// and checks its requests against the quota consumed by itself and by every peer, as last heard of.
+/// The quota is therefore only shared approximately: peers may exceed it together by what they
+/// consume during an interval, and the state of a peer that can't be reached is forgotten once
+/// the quota of its keys is replenished. Instances must have synchronized clocks.
+///
+/// The datagrams are neither authenticated nor encrypted, only exchange them on a trusted network.
+/// Keys are sent formatted with [`Display`](fmt::Display) and parsed back with [`FromStr`],
+/// keys containing tabs or line breaks aren't replicated.
+pub struct GossipStore<K: Hash + Eq> {
+    node: Arc<Node<K>>,
+    scope: Arc<Scope<K>>,
+}
+
+struct Node<K: Hash + Eq> {
+    socket: UdpSocket,
+    peers: Mutex<Vec<SocketAddr>>,
+    scopes: Mutex<HashMap<String, Arc<Scope<K>>>>,
+    // Set by `GovernorStore::shutdown`.
+    stopped: AtomicBool,
+}
+
+struct Scope<K: Hash + Eq> {
+    name: String,
+    // The quota consumed by this instance.
+    local: MemoryStore<K>,
+    // The states last received from each peer.
+    remote: DashMap<K, Vec<(SocketAddr, u64)>>,
+}
+
+impl<K> GossipStore<K>
+where
+    K: Hash + Eq + Clone + fmt::Display + FromStr + Send + Sync + 'static,
+{
+    /// A store exchanging states on the UDP socket bound to `address`, e.g. `0.0.0.0:7946`,
+    /// every `interval`. The exchanging thread stops once every rate limiter using it is dropped,
+    /// or when the store is [shut down](GovernorStore::shutdown), after sending the states one last time.
+    pub fn bind(address: impl ToSocketAddrs, interval: Duration) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        let node = Arc::new(Node {
+            socket: socket.try_clone()?,
+            peers: Mutex::default(),
+            scopes: Mutex::default(),
+            stopped: AtomicBool::new(false),
+        });
+        let weak = Arc::downgrade(&node);
+        thread::spawn(move || exchange(socket, weak, interval));
+        Ok(Self {
+            scope: node.scope(""),
+            node,
+        })
+    }
+}
+
+impl<K: Hash + Eq> GossipStore<K> {
+    /// Exchange states with the instance listening at `address`.
+    pub fn add_peer(&self, address: impl ToSocketAddrs) -> io::Result<()> {
+        self.node
+            .peers
+            .lock()
+            .unwrap()
+            .extend(address.to_socket_addrs()?);
+        Ok(())
+    }
+
+    /// The address of the socket, e.g. to find out the port it was bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.node.socket.local_addr()
+    }
+}
+
+impl<K: Hash + Eq> Node<K> {
+    fn scope(&self, name: &str) -> Arc<Scope<K>> {
+        self.scopes
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_insert_with(|| {
+                Arc::new(Scope {
+                    name: name.to_owned(),
+                    local: MemoryStore::default(),
+                    remote: DashMap::new(),
+                })
+            })
+            .clone()
+    }
+}
+
+impl<K: Hash + Eq> Scope<K> {
+    // The time the peers consumed from the quota of `key` that is not replenished yet.
+    fn remote_debt(&self, key: &K, now: u64) -> u64 {
+        self.remote.get(key).map_or(0, |states| {
+            states
+                .iter()
+                .map(|(_, state)| state.saturating_sub(now))
+                .sum()
+        })
+    }
+}
+
+impl<K> GovernorStore<K> for GossipStore<K>
+where
+    K: Hash + Eq + Clone + fmt::Display + Send + Sync + 'static,
+{
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        let now = unix_now();
+        let debt = self.scope.remote_debt(key, now);
+        if debt == 0 {
+            return self.scope.local.measure_and_replace(key, f);
+        }
+        // As if the quota consumed by the peers had been consumed here first.
+        self.scope.local.measure_and_replace(key, &mut |state| {
+            let combined = state.unwrap_or(0).max(now) + debt;
+            f(Some(combined)).map(|state| state.saturating_sub(debt).max(1))
+        });
+    }
+
+    fn get(&self, key: &K) -> Option<u64> {
+        let now = unix_now();
+        let debt = self.scope.remote_debt(key, now);
+        match self.scope.local.get(key) {
+            Some(state) => Some(state.max(now) + debt),
+            None => (debt > 0).then_some(now + debt),
+        }
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        self.scope.local.retain(keep);
+        let now = unix_now();
+        self.scope.remote.retain(|_, states| {
+            states.retain(|(_, state)| *state > now);
+            !states.is_empty()
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.scope.local.len()
+    }
+
+    fn shrink_to_fit(&self) {
+        self.scope.local.shrink_to_fit();
+        self.scope.remote.shrink_to_fit();
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            scope: self.node.scope(&format!("{}/{}", self.scope.name, scope)),
+            node: self.node.clone(),
+        })
+    }
+
+    fn shutdown(&self) {
+        if self.node.stopped.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        send(&self.node);
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+impl<K: Hash + Eq> fmt::Debug for GossipStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GossipStore")
+            .field("local_addr", &self.node.socket.local_addr().ok())
+            .field("peers", &self.node.peers.lock().unwrap())
+            .field("scope", &self.scope.name)
+            .finish()
+    }
+}
+
+// Receive the states of the peers and send ours every `interval`, until the node is dropped.
+fn exchange<K>(socket: UdpSocket, node: Weak<Node<K>>, interval: Duration)
+where
+    K: Hash + Eq + Clone + fmt::Display + FromStr + Send + Sync + 'static,
+{
+    let mut buffer = [0; 65536];
+    let mut next = Instant::now() + interval;
+    loop {
+        let timeout = next.saturating_duration_since(Instant::now());
+        // A zero timeout would block forever.
+        let received = socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))
+            .and_then(|()| socket.recv_from(&mut buffer));
+        let Some(node) = node
+            .upgrade()
+            .filter(|node| !node.stopped.load(Ordering::Relaxed))
+        else {
+            return;
+        };
+        if let Ok((length, peer)) = received {
+            if node.peers.lock().unwrap().contains(&peer) {
+                receive(&node, peer, &String::from_utf8_lossy(&buffer[..length]));
+            }
+        }
+        if Instant::now() >= next {
+            next += interval;
+            send(&node);
+        }
+    }
+}
+
+// Record the states sent by `peer`, one `{scope}\t{key}\t{state}` per line.
+fn receive<K>(node: &Node<K>, peer: SocketAddr, datagram: &str)
+where
+    K: Hash + Eq + FromStr,
+{
+    for line in datagram.lines() {
+        let mut fields = line.split('\t');
+        let (Some(scope), Some(key), Some(state), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let (Ok(key), Ok(state)) = (key.parse::<K>(), state.parse::<u64>()) else {
+            continue;
+        };
+        let scope = node.scope(scope);
+        let mut states = scope.remote.entry(key).or_default();
+        match states.iter_mut().find(|(address, _)| *address == peer) {
+            Some((_, previous)) => *previous = state,
+            None => states.push((peer, state)),
+        }
+    }
+}
+
+// Send the states of the keys whose quota this instance consumed to every peer.
+fn send<K>(node: &Node<K>)
+where
+    K: Hash + Eq + Clone + fmt::Display + Send + Sync + 'static,
+{
+    let peers = node.peers.lock().unwrap().clone();
+    if peers.is_empty() {
+        return;
+    }
+    let scopes: Vec<_> = node.scopes.lock().unwrap().values().cloned().collect();
+    let now = unix_now();
+    let mut datagrams = vec![String::new()];
+    for scope in scopes {
+        scope.local.retain(&mut |key, state| {
+            if state > now {
+                let line = format!("{}\t{}\t{}\n", scope.name, key, state);
+                if line.matches(['\t', '\n', '\r']).count() == 3 {
+                    let datagram = datagrams.last_mut().expect("there is a datagram");
+                    if datagram.len() + line.len() > MAX_DATAGRAM {
+                        datagrams.push(line);
+                    } else {
+                        datagram.push_str(&line);
+                    }
+                }
+            }
+            true
+        });
+    }
+    // A peer that can't be reached doesn't keep the others from being sent the states.
+    for peer in &peers {
+        for datagram in datagrams.iter().filter(|datagram| !datagram.is_empty()) {
+            if let Err(_e) = node.socket.send_to(datagram.as_bytes(), peer) {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("Unable to send the rate limiter state to {}: {}", peer, _e);
+                break;
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
@@ -1,25 +1,107 @@
 use crate::{
-    key_extractor::{KeyExtractor, PeerIpKeyExtractor},
-    GovernorError,
+    blocklist::Blocklist,
+    cost::CostExtractor,
+    errors::{negotiate_language, ErrorFormat},
+    key_extractor::{
+        AuthOrAnonymousKeyExtractor, GlobalKeyExtractor, KeyExtractor, NamedKey, NamedKeyExtractor,
+        OperatorHeaderKeyExtractor, PeerIpKeyExtractor,
+    },
+    offenders::{Offender, Offenders},
+    store::{Fnv1a, GovernorStore, KeyedStore, Snapshot, WorkerStore},
+    CacheHit, Delay, GovernorBypass, GovernorError, HighPriority, LimitLevel, MeteredBody,
+    PlanTier, RateLimitInfo, ResponseHook, RetryAttempt, RouteMatcher,
 };
-#[cfg(feature = "axum")]
-use axum::body::Body;
+use dashmap::DashMap;
 use governor::{
-    clock::{DefaultClock, QuantaInstant},
+    clock::{Clock, DefaultClock, QuantaInstant},
     middleware::{NoOpMiddleware, RateLimitingMiddleware, StateInformationMiddleware},
-    state::keyed::DefaultKeyedStateStore,
-    Quota, RateLimiter,
+    nanos::Nanos,
+    NotUntil, Quota, RateLimiter,
 };
-use http::{Method, Response};
-use std::{fmt, marker::PhantomData, num::NonZeroU32, sync::Arc, time::Duration};
+use http::{
+    header::{
+        HeaderName, HeaderValue, ACCEPT, ACCEPT_LANGUAGE, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_REQUEST_METHOD, CONTENT_LENGTH, RETRY_AFTER,
+    },
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper::body::Incoming;
+use ipnet::IpNet;
+use jsonrpsee::http_client::HttpBody;
+use serde::{de, de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    ffi::OsString,
+    fmt, fs,
+    future::Future,
+    hash::{Hash, Hasher},
+    io, iter,
+    marker::PhantomData,
+    mem,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU32, ParseIntError, TryFromIntError},
+    ops::Range,
+    path::Path,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+use tokio::time::Sleep;
 
 pub const DEFAULT_PERIOD: Duration = Duration::from_millis(500);
 pub const DEFAULT_BURST_SIZE: u32 = 8;
+/// Default header telling retried requests, see [`GovernorConfigBuilder::retry_budget`].
+pub const DEFAULT_RETRY_HEADER: &str = "x-retry-attempt";
 
 // Required by Governor's RateLimiter to share it across threads
 // See Governor User Guide: https://docs.rs/governor/0.6.0/governor/_guide/index.html
-pub type SharedRateLimiter<Key, M> =
-    Arc<RateLimiter<Key, DefaultKeyedStateStore<Key>, DefaultClock, M>>;
+pub type SharedRateLimiter<Key, M> = Arc<RateLimiter<Key, KeyedStore<Key>, DefaultClock, M>>;
+
+/// The rate limiting middlewares a [`Governor`] can run with:
+/// - [`NoOpMiddleware`], the default, only rejects requests over the quota.
+/// - [`StateInformationMiddleware`], set by [`GovernorConfigBuilder::use_headers`], also reports the quota state in x-ratelimit headers.
+///
+/// This trait is sealed and can't be implemented outside of this crate.
+pub trait GovernorMiddleware:
+    RateLimitingMiddleware<QuantaInstant, NegativeOutcome = NotUntil<QuantaInstant>> + sealed::Sealed
+{
+    /// Whether x-ratelimit headers are added to responses.
+    const USE_HEADERS: bool;
+
+    /// The burst size and remaining burst capacity after an allowed request, if tracked.
+    fn snapshot(outcome: &Self::PositiveOutcome) -> Option<(u32, u32)>;
+}
+
+impl GovernorMiddleware for NoOpMiddleware {
+    const USE_HEADERS: bool = false;
+
+    fn snapshot(_outcome: &Self::PositiveOutcome) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+impl GovernorMiddleware for StateInformationMiddleware {
+    const USE_HEADERS: bool = true;
+
+    fn snapshot(outcome: &Self::PositiveOutcome) -> Option<(u32, u32)> {
+        Some((
+            outcome.quota().burst_size().get(),
+            outcome.remaining_burst_capacity(),
+        ))
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for governor::middleware::NoOpMiddleware {}
+    impl Sealed for governor::middleware::StateInformationMiddleware {}
+}
 
 /// Helper struct for building a configuration for the governor middleware.
 ///
@@ -29,7 +111,7 @@ pub type SharedRateLimiter<Key, M> =
 /// that replenishes one element every minute.
 ///
 /// ```rust
-/// use tower_governor::governor::GovernorConfigBuilder;
+/// use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
 ///
 /// let config = GovernorConfigBuilder::default()
 ///     .per_second(60)
@@ -41,7 +123,7 @@ pub type SharedRateLimiter<Key, M> =
 /// with x-ratelimit headers
 ///
 /// ```rust
-/// use tower_governor::governor::GovernorConfigBuilder;
+/// use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
 ///
 /// let config = GovernorConfigBuilder::default()
 ///     .per_second(60)
@@ -57,19 +139,243 @@ pub struct GovernorConfigBuilder<K: KeyExtractor, M: RateLimitingMiddleware<Quan
     methods: Option<Vec<Method>>,
     key_extractor: K,
     error_handler: ErrorHandler,
+    rejection_body: RejectionBody<K::Key>,
+    on_allowed: OnAllowed<K::Key>,
+    quota_provider: QuotaOverrides<K::Key>,
+    tier_of: TierOf<K::Key>,
+    high_priority: HighPriorityOf<K::Key>,
+    failure_policy: FailurePolicy<K::Key>,
+    exempt_keys: HashSet<K::Key>,
+    blocklist: Option<Blocklist<K::Key>>,
+    store: StoreBackend<K::Key>,
+    options: Options,
     middleware: PhantomData<M>,
 }
 
-// function for handling GovernorError and produce valid http Response type.
-#[derive(Clone)]
-struct ErrorHandler(Arc<dyn Fn(GovernorError) -> Response<Body> + Send + Sync>);
+// Settings that don't depend on the key extractor or the middleware.
+#[derive(Debug, Default, Eq, Clone, PartialEq)]
+pub(crate) struct Options {
+    pub(crate) bytes_per_token: Option<u32>,
+    pub(crate) cache_hit_refunds: bool,
+    pub(crate) cache_hit_header: Option<(HeaderName, HeaderValue)>,
+    counted_responses: CountedResponses,
+    pub(crate) server_error_refunds: bool,
+    pub(crate) auth_failure_penalty: Option<u32>,
+    pub(crate) retry_after: RetryAfter,
+    pub(crate) header_style: HeaderStyle,
+    pub(crate) policy_header: bool,
+    pub(crate) reset_format: ResetFormat,
+    pub(crate) after_ms_header: bool,
+    pub(crate) round_up_wait_time: bool,
+    pub(crate) error_format: ErrorFormat,
+    pub(crate) negotiate_error_format: bool,
+    pub(crate) html_template: Option<String>,
+    pub(crate) rejection_status: Option<StatusCode>,
+    pub(crate) header_names: HeaderNames,
+    pub(crate) hide_headers: bool,
+    pub(crate) expose_headers: bool,
+    pub(crate) lightweight_headers: bool,
+    pub(crate) warning_threshold: Option<u8>,
+    pub(crate) hide_whitelisted_header: bool,
+    pub(crate) max_retry_after: Option<Duration>,
+    pub(crate) extraction_failure_status: Option<StatusCode>,
+    pub(crate) localized_messages: Vec<(String, String)>,
+    pub(crate) retry_after_http_date: bool,
+    request_filter: RequestFilter,
+    cost_extractor: CostOf,
+    pub(crate) exempt_paths: Vec<RouteMatcher>,
+    pub(crate) skip_preflight: bool,
+    pub(crate) method_quotas: Vec<(Method, Duration, u32)>,
+    pub(crate) additional_quotas: Vec<(Duration, u32)>,
+    pub(crate) tiers: Vec<(PlanTier, Quota)>,
+    pub(crate) dry_run: bool,
+    pub(crate) enforced_percent: Option<u8>,
+    pub(crate) exempt_networks: Vec<IpNet>,
+    pub(crate) auto_ban: Option<AutoBan>,
+    pub(crate) retry_budget: Option<RetryBudget>,
+    pub(crate) retry_header: Option<HeaderName>,
+    pub(crate) schedule: Vec<ScheduledQuota>,
+    pub(crate) schedule_utc_offset: i32,
+    load_signal: LoadSignal,
+    pub(crate) min_quota_percent: u8,
+    pub(crate) startup_grace: Option<Duration>,
+    pub(crate) grace_until: Option<Instant>,
+    pub(crate) idle_ttl: Duration,
+    pub(crate) gc_interval: Option<Duration>,
+    pub(crate) gc_threshold: Option<usize>,
+    pub(crate) offender_tracking: Option<(usize, Duration)>,
+    pub(crate) key_cap: Option<(usize, Duration, u32)>,
+    pub(crate) max_in_flight: Option<usize>,
+    pub(crate) max_delay: Option<Duration>,
+    pub(crate) max_queued: Option<usize>,
+    pub(crate) global_quota: Option<(Duration, u32)>,
+    pub(crate) global_backpressure: bool,
+    pub(crate) global_reserve: u8,
+    pub(crate) response_bytes_quota: Option<(Duration, u32)>,
+    pub(crate) request_bytes_quota: Option<(Duration, u32)>,
+    pub(crate) levels: Vec<LevelQuota>,
+    pub(crate) limiter_mode: LimiterMode,
+    pub(crate) algorithm: Algorithm,
+}
+
+impl Options {
+    // The share of `quota` enforced by each worker, see `LimiterMode::PerWorker`.
+    pub(crate) fn worker_quota(&self, quota: Quota) -> Quota {
+        let LimiterMode::PerWorker(workers) = self.limiter_mode else {
+            return quota;
+        };
+        let workers = u32::try_from(workers).unwrap_or(u32::MAX).max(1);
+        let burst_size = (quota.burst_size().get() / workers).max(1);
+        quota_of(
+            quota.replenish_interval().saturating_mul(workers),
+            burst_size,
+        )
+    }
+
+    // `store` enforcing `quota` with the algorithm, see `Algorithm`.
+    fn with_algorithm<Key: Hash + Eq + 'static>(
+        &self,
+        store: KeyedStore<Key>,
+        quota: Quota,
+    ) -> KeyedStore<Key> {
+        match self.algorithm {
+            Algorithm::Gcra => store,
+            Algorithm::SlidingWindowLog => store.logged(),
+            Algorithm::FixedWindow => store.fixed_windows(quota),
+        }
+    }
+}
+
+/// Names of the nonstandard `x-ratelimit-*` headers, see [`GovernorConfigBuilder::header_names`].
+///
+/// The standard `retry-after` header and the [`HeaderStyle::IetfDraft`] headers can't be renamed.
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub struct HeaderNames {
+    /// Request limit, `x-ratelimit-limit` by default.
+    pub limit: HeaderName,
+    /// The number of requests left, `x-ratelimit-remaining` by default.
+    pub remaining: HeaderName,
+    /// Time until the quota is fully replenished, `x-ratelimit-reset` by default.
+    pub reset: HeaderName,
+    /// Number of seconds to wait after a rejection, `x-ratelimit-after` by default.
+    pub after: HeaderName,
+    /// Number of milliseconds to wait after a rejection, `x-ratelimit-after-ms` by default.
+    pub after_ms: HeaderName,
+    /// Set on requests that aren't rate limited, `x-ratelimit-whitelisted` by default.
+    pub whitelisted: HeaderName,
+    /// Percentage of the burst size consumed, `x-ratelimit-warning` by default.
+    pub warning: HeaderName,
+    /// The plan tier of the client, `x-ratelimit-tier` by default.
+    pub tier: HeaderName,
+    /// The level whose quota rejected the request, `x-ratelimit-level` by default.
+    pub level: HeaderName,
+}
 
-impl Default for ErrorHandler {
+impl Default for HeaderNames {
     fn default() -> Self {
-        Self(Arc::new(|mut e| e.as_response()))
+        Self {
+            limit: HeaderName::from_static("x-ratelimit-limit"),
+            remaining: HeaderName::from_static("x-ratelimit-remaining"),
+            reset: HeaderName::from_static("x-ratelimit-reset"),
+            after: HeaderName::from_static("x-ratelimit-after"),
+            after_ms: HeaderName::from_static("x-ratelimit-after-ms"),
+            whitelisted: HeaderName::from_static("x-ratelimit-whitelisted"),
+            warning: HeaderName::from_static("x-ratelimit-warning"),
+            tier: HeaderName::from_static("x-ratelimit-tier"),
+            level: HeaderName::from_static("x-ratelimit-level"),
+        }
     }
 }
 
+/// What happens to requests whose key can't be extracted, see [`GovernorConfigBuilder::failure_policy`].
+#[derive(Debug, Default, Eq, Clone, PartialEq)]
+pub enum FailurePolicy<Key> {
+    /// Reject the request with [`GovernorError::UnableToExtractKey`]. This is the default.
+    #[default]
+    FailClosed,
+    /// Pass the request to the inner service without rate limiting it.
+    FailOpen,
+    /// Rate limit the request with this key, e.g. a key shared by all such requests.
+    FallbackKey(Key),
+}
+
+/// Format of the `x-ratelimit-reset` header, see [`GovernorConfigBuilder::reset_format`].
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum ResetFormat {
+    /// Number of seconds until the reset. This is the default.
+    #[default]
+    DeltaSeconds,
+    /// Unix timestamp of the reset, in seconds.
+    EpochSeconds,
+}
+
+/// Naming and semantics of the quota headers enabled by [`GovernorConfigBuilder::use_headers`],
+/// see [`GovernorConfigBuilder::header_style`].
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum HeaderStyle {
+    /// `x-ratelimit-limit`, `x-ratelimit-remaining` and `x-ratelimit-reset`. This is the default.
+    #[default]
+    XRateLimit,
+    /// The headers of [draft-ietf-httpapi-ratelimit-headers](https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/):
+    /// - `ratelimit-limit`     - The burst size
+    /// - `ratelimit-remaining` - The number of requests left before being rejected
+    /// - `ratelimit-reset`     - Number of seconds until the quota is fully replenished, or until the next request is allowed after a rejection
+    IetfDraft,
+}
+
+/// Which headers carry the wait time of rejected requests, see [`GovernorConfigBuilder::retry_after`].
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum RetryAfter {
+    /// Only the nonstandard `x-ratelimit-after` header. This is the default.
+    #[default]
+    Disabled,
+    /// Both the standard `retry-after` and the nonstandard `x-ratelimit-after` headers.
+    Both,
+    /// Only the standard `retry-after` header.
+    Only,
+}
+
+/// How the state of the keys is shared between the worker threads of the runtime,
+/// see [`GovernorConfigBuilder::limiter_mode`].
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum LimiterMode {
+    /// A single state shared by every thread, enforcing the quotas exactly at the cost of contention
+    /// between threads checking the same keys. This is the default.
+    #[default]
+    Shared,
+    /// A state per thread out of this many, each enforcing a share of the quotas: the period is
+    /// multiplied and the burst size divided (rounded down, at least 1) by the number of workers.
+    /// Threads never contend, but a key is only limited approximately, depending on how its requests
+    /// are spread over the threads.
+    PerWorker(usize),
+}
+
+/// How the quotas are enforced, see [`GovernorConfigBuilder::algorithm`].
+#[derive(Debug, Default, Eq, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    /// The generic cell rate algorithm of the `governor` crate: a key can make a burst of requests,
+    /// then gets a token back every period. Its state is a single timestamp per key. This is the default.
+    #[default]
+    Gcra,
+    /// An exact sliding window log: a key can make up to burst size requests over any window of
+    /// burst size times period, e.g. 100 requests per rolling minute with
+    /// [`quota_per(100, Duration::from_secs(60))`](GovernorConfigBuilder::quota_per). Tokens come
+    /// back when the requests that consumed them leave the window, rather than one every period.
+    /// Its state is the time of every request in the window, kept in memory.
+    SlidingWindowLog,
+    /// A fixed window counter: a key can make up to burst size requests in each window of burst size
+    /// times period, getting all its tokens back when the window ends. The windows are aligned on the
+    /// UNIX epoch with [shared](crate::store::GovernorStore::is_shared) stores, on the creation of the
+    /// rate limiter otherwise. Its state is a single number per key, cheap to keep in any store,
+    /// but a key can make up to twice the burst size around the end of a window.
+    FixedWindow,
+}
+
+// function for handling GovernorError and produce valid http Response type.
+// `None` renders the built-in responses selected by `GovernorConfigBuilder::error_format`.
+#[derive(Clone, Default)]
+struct ErrorHandler(Option<Arc<dyn Fn(GovernorError) -> Response<HttpBody> + Send + Sync>>);
+
 impl fmt::Debug for ErrorHandler {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ErrorHandler").finish()
@@ -85,6 +391,202 @@ impl PartialEq for ErrorHandler {
 
 impl Eq for ErrorHandler {}
 
+// optional user supplied function, see `RejectionBody` and `OnAllowed`.
+struct Callback<F: ?Sized>(Option<Arc<F>>);
+
+impl<F: ?Sized> Default for Callback<F> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<F: ?Sized> Clone for Callback<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Callback<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callback").finish()
+    }
+}
+
+impl<F: ?Sized> PartialEq for Callback<F> {
+    fn eq(&self, _: &Self) -> bool {
+        // there is no easy way to tell two object equals.
+        true
+    }
+}
+
+impl<F: ?Sized> Eq for Callback<F> {}
+
+// function extracting the key of a level, see `GovernorConfigBuilder::level`.
+type LevelKey = Callback<dyn Fn(&Request<Incoming>) -> Option<String> + Send + Sync>;
+
+// A quota of `GovernorConfigBuilder::level`.
+#[derive(Debug, Eq, Clone, PartialEq)]
+pub(crate) struct LevelQuota {
+    name: LimitLevel,
+    period: Duration,
+    burst_size: u32,
+    key: LevelKey,
+}
+
+// function building the body of rejections, see `GovernorConfigBuilder::rejection_body`.
+type RejectionBody<Key> = Callback<dyn Fn(&Key, Duration, &Quota) -> HttpBody + Send + Sync>;
+
+// predicate selecting the rate limited requests, see `GovernorConfigBuilder::filter`.
+type RequestFilter = Callback<dyn Fn(&Request<Incoming>) -> bool + Send + Sync>;
+
+// predicate selecting the responses whose requests count, see `GovernorConfigBuilder::count_responses`.
+type CountedResponses = Callback<dyn Fn(StatusCode) -> bool + Send + Sync>;
+
+// cost of requests, see `GovernorConfigBuilder::cost_extractor`.
+type CostOf = Callback<dyn CostExtractor>;
+
+// function called with allowed responses, see `GovernorConfigBuilder::on_allowed`.
+type OnAllowed<Key> = Callback<dyn Fn(&Key, &RateLimitInfo, &mut Response<HttpBody>) + Send + Sync>;
+
+// load of the service from 0.0 to 1.0, see `GovernorConfigBuilder::load_adaptive`.
+type LoadSignal = Callback<dyn Fn() -> f64 + Send + Sync>;
+
+// quotas of individual keys, see `GovernorConfigBuilder::quota_provider`.
+type QuotaOverrides<Key> = Callback<dyn QuotaProvider<Key>>;
+
+// function naming the plan tier of keys, see `GovernorConfigBuilder::tier_of`.
+type TierOf<Key> = Callback<dyn Fn(&Key) -> Option<&str> + Send + Sync>;
+
+// function telling the keys drawing from the reserve of the global quota, see
+// `GovernorConfigBuilder::high_priority`.
+type HighPriorityOf<Key> = Callback<dyn Fn(&Key) -> bool + Send + Sync>;
+
+// backend of the rate limiter state, see `GovernorConfigBuilder::store`.
+type StoreBackend<Key> = Callback<dyn GovernorStore<Key>>;
+
+// The last cleanup of the state, see `GovernorConfigBuilder::gc_interval`.
+#[derive(Debug)]
+pub(crate) struct Gc {
+    last: Mutex<Instant>,
+    running: AtomicBool,
+}
+
+// rejections and bans of keys, see `GovernorConfigBuilder::auto_ban`.
+type Bans<Key> = Arc<DashMap<Key, Violations>>;
+
+// requests in flight of each key, see `GovernorConfigBuilder::max_in_flight`, or delayed, see
+// `GovernorConfigBuilder::max_queued`.
+type InFlight<Key> = Arc<DashMap<Key, usize>>;
+
+// requests and retries of each key, see `GovernorConfigBuilder::retry_budget`.
+type Retries<Key> = Arc<DashMap<Key, RetryCounts>>;
+
+#[derive(Debug)]
+pub(crate) struct Violations {
+    window_start: Instant,
+    count: u32,
+    banned_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RetryCounts {
+    window_start: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+// Settings of `GovernorConfigBuilder::retry_budget`.
+#[derive(Debug, Eq, Clone, Copy, PartialEq)]
+pub(crate) struct RetryBudget {
+    percent: u8,
+    min_retries: u32,
+    window: Duration,
+}
+
+// Settings of `GovernorConfigBuilder::auto_ban`.
+#[derive(Debug, Eq, Clone, Copy, PartialEq)]
+pub(crate) struct AutoBan {
+    violations: u32,
+    window: Duration,
+    duration: Duration,
+}
+
+/// A day of the week, see [`GovernorConfigBuilder::scheduled_quota`].
+#[derive(Debug, Eq, Clone, Copy, PartialEq, Hash)]
+pub enum Weekday {
+    Monday,
+    Tuesday,
+    Wednesday,
+    Thursday,
+    Friday,
+    Saturday,
+    Sunday,
+}
+
+impl Weekday {
+    /// Every day of the week.
+    pub const ALL: [Weekday; 7] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+        Weekday::Saturday,
+        Weekday::Sunday,
+    ];
+    /// Monday to Friday.
+    pub const WORKDAYS: [Weekday; 5] = [
+        Weekday::Monday,
+        Weekday::Tuesday,
+        Weekday::Wednesday,
+        Weekday::Thursday,
+        Weekday::Friday,
+    ];
+}
+
+// A quota of `GovernorConfigBuilder::scheduled_quota`, `days` being a bit set of the weekdays from Monday.
+#[derive(Debug, Eq, Clone, Copy, PartialEq)]
+pub(crate) struct ScheduledQuota {
+    days: u8,
+    start_hour: u8,
+    end_hour: u8,
+    pub(crate) quota: Quota,
+}
+
+impl ScheduledQuota {
+    // Whether the quota applies at `hour` of `weekday`, 0 being Monday. Windows past midnight belong to the
+    // day they start on.
+    fn applies(&self, weekday: u32, hour: u8) -> bool {
+        let on = |weekday: u32| self.days & (1 << (weekday % 7)) != 0;
+        if self.start_hour < self.end_hour {
+            on(weekday) && (self.start_hour..self.end_hour).contains(&hour)
+        } else {
+            (hour >= self.start_hour && on(weekday)) || (hour < self.end_hour && on(weekday + 6))
+        }
+    }
+}
+
+// rate limiters of the quotas returned by the `QuotaProvider`, by replenish interval and burst size.
+type KeyBuckets<K, M> = Arc<DashMap<(Duration, u32), Bucket<K, M>>>;
+
+/// Gives individual keys their own quota, e.g. larger ones for premium customers or internal
+/// services, see [`GovernorConfigBuilder::quota_provider`].
+///
+/// Implemented for closures taking the key and returning its quota.
+pub trait QuotaProvider<Key>: Send + Sync {
+    /// The quota of `key`, the one of the configuration for keys without a dedicated quota.
+    fn quota_for(&self, key: &Key) -> Quota;
+}
+
+impl<Key, F> QuotaProvider<Key> for F
+where
+    F: Fn(&Key) -> Quota + Send + Sync,
+{
+    fn quota_for(&self, key: &Key) -> Quota {
+        self(key)
+    }
+}
+
 impl Default for GovernorConfigBuilder<PeerIpKeyExtractor, NoOpMiddleware> {
     /// The default configuration which is suitable for most services.
     /// Allows burst with up to eight requests and replenishes one element after 500ms, based on peer IP.
@@ -94,12 +596,290 @@ impl Default for GovernorConfigBuilder<PeerIpKeyExtractor, NoOpMiddleware> {
     }
 }
 
+/// Fields of a configuration file, see the `Deserialize` impl of `GovernorConfigBuilder`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BuilderFields {
+    per_second: Option<u64>,
+    per_millisecond: Option<u64>,
+    per_nanosecond: Option<u64>,
+    burst_size: Option<u32>,
+    methods: Option<Vec<String>>,
+    #[serde(default)]
+    key_extractor: NamedKeyExtractor,
+    #[serde(default)]
+    exempt_paths: Vec<RouteMatcher>,
+    #[serde(default)]
+    exempt_keys: Vec<String>,
+    #[serde(default)]
+    exempt_networks: Vec<String>,
+}
+
+/// Read the period, burst size, methods, key extractor and exempted paths, keys and networks from the
+/// application's config files. Missing fields keep their default value, only one of `per_second`,
+/// `per_millisecond` and `per_nanosecond` can be set. Exempted paths are written `{ "prefix": "/healthz" }`
+/// or `{ "glob": "/.well-known/*" }`, see [`exempt_path`](Self::exempt_path).
+///
+/// Exempted keys are IP addresses for the IP key extractors and operator identities for the operator
+/// header one, see [`exempt_key`](Self::exempt_key). Exempted networks are ranges of peer addresses
+/// such as `"10.0.0.0/8"`, or single addresses, see [`exempt_network`](Self::exempt_network).
+///
+/// # Example
+/// ```rust
+/// use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+/// use jsonrpsee_tower_governor::key_extractor::NamedKeyExtractor;
+/// use governor::middleware::NoOpMiddleware;
+///
+/// let config: GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> = serde_json::from_str(
+///     r#"{ "per_second": 4, "burst_size": 2, "methods": ["POST"], "key_extractor": "smart_ip" }"#,
+/// )
+/// .unwrap();
+/// ```
+impl<'de> Deserialize<'de> for GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let fields = BuilderFields::deserialize(deserializer)?;
+        let mut builder = GovernorConfigBuilder::default().key_extractor(fields.key_extractor);
+        match (
+            fields.per_second,
+            fields.per_millisecond,
+            fields.per_nanosecond,
+        ) {
+            (None, None, None) => {}
+            (Some(seconds), None, None) => {
+                builder.per_second(seconds);
+            }
+            (None, Some(milliseconds), None) => {
+                builder.per_millisecond(milliseconds);
+            }
+            (None, None, Some(nanoseconds)) => {
+                builder.per_nanosecond(nanoseconds);
+            }
+            _ => {
+                return Err(de::Error::custom(
+                    "only one of per_second, per_millisecond and per_nanosecond can be set",
+                ))
+            }
+        }
+        if let Some(burst_size) = fields.burst_size {
+            builder.burst_size(burst_size);
+        }
+        if let Some(methods) = fields.methods {
+            let methods = methods
+                .iter()
+                .map(|method| Method::from_bytes(method.as_bytes()).map_err(de::Error::custom))
+                .collect::<Result<_, _>>()?;
+            builder.methods(methods);
+        }
+        for matcher in fields.exempt_paths {
+            builder.exempt_path(matcher);
+        }
+        for key in fields.exempt_keys {
+            let key = match fields.key_extractor {
+                NamedKeyExtractor::PeerIp | NamedKeyExtractor::SmartIp => {
+                    NamedKey::Ip(key.parse().map_err(de::Error::custom)?)
+                }
+                NamedKeyExtractor::OperatorHeader => NamedKey::Operator(key.to_ascii_lowercase()),
+                NamedKeyExtractor::Global => {
+                    return Err(de::Error::custom(
+                        "the global key extractor has no keys to exempt",
+                    ))
+                }
+            };
+            builder.exempt_key(key);
+        }
+        for network in fields.exempt_networks {
+            let network = match network.parse::<IpAddr>() {
+                Ok(ip) => IpNet::from(ip),
+                Err(_) => network.parse().map_err(de::Error::custom)?,
+            };
+            builder.exempt_network(network);
+        }
+        Ok(builder)
+    }
+}
+
+/// Why a configuration is invalid, see [`GovernorConfigBuilder::try_build`].
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("The burst size must not be zero")]
+    ZeroBurstSize,
+    #[error("The period must not be zero")]
+    ZeroPeriod,
+    #[error("The bytes per token must not be zero")]
+    ZeroBytesPerToken,
+    #[error("The warning threshold must not be above 100 percent, got {0}")]
+    WarningThresholdAbove100(u8),
+    #[error("The enforced percentage of keys must not be above 100 percent, got {0}")]
+    EnforcedPercentAbove100(u8),
+    #[error("The minimum quota under load must be between 1 and 100 percent, got {0}")]
+    InvalidMinQuotaPercent(u8),
+    #[error("Auto-bans must not have zero violations, window or duration")]
+    InvalidAutoBan,
+    #[error("The retry budget must not be above 100 percent or have a zero window")]
+    InvalidRetryBudget,
+    #[error("Offender tracking must not have zero keys or window")]
+    InvalidOffenderTracking,
+    #[error("The key cap must not have zero keys, requests or window")]
+    InvalidKeyCap,
+    #[error("The maximum of requests in flight must not be zero")]
+    ZeroMaxInFlight,
+    #[error("The maximum delay must not be zero")]
+    ZeroMaxDelay,
+    #[error("The maximum of queued requests must not be zero and needs a maximum delay")]
+    InvalidMaxQueued,
+    #[error("The authentication failure penalty must not be zero")]
+    ZeroAuthFailurePenalty,
+    #[error("Per-worker limiters must have workers and can't be combined with a store")]
+    InvalidLimiterMode,
+    #[error("The sliding window log can't be combined with a store or per-worker limiters")]
+    InvalidAlgorithm,
+    #[error(
+        "Scheduled quotas must have days, distinct hours up to 24 and a UTC offset under a day"
+    )]
+    InvalidSchedule,
+    #[error("The quota of {0} must not have a zero burst size or period")]
+    InvalidMethodQuota(Method),
+    #[error("Additional quotas must not have zero requests or window")]
+    InvalidAdditionalQuota,
+    #[error("The global quota must not have zero requests or window")]
+    InvalidGlobalQuota,
+    #[error("Global backpressure needs a global quota")]
+    BackpressureWithoutGlobalQuota,
+    #[error("The global reserve must be below 100 percent and needs a global quota, got {0}")]
+    InvalidGlobalReserve(u8),
+    #[error("The response bytes quota must not have zero bytes or window")]
+    InvalidResponseBytesQuota,
+    #[error("The request bytes quota must not have zero bytes or window")]
+    InvalidRequestBytesQuota,
+    #[error("The quota of the level {0} must not have zero requests or window")]
+    InvalidLevel(String),
+    #[error("The list of rate limited methods must not be empty")]
+    EmptyMethods,
+    #[error("{0} has no effect with {1}")]
+    ConflictingHeaders(&'static str, &'static str),
+}
+
+impl<A, B, M> GovernorConfigBuilder<AuthOrAnonymousKeyExtractor<A, B>, M>
+where
+    A: KeyExtractor,
+    B: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    /// Give anonymous requests `quota`, usually stricter than the quota of the configuration,
+    /// which applies to authenticated requests.
+    ///
+    /// This is the `anonymous` plan tier, see [`tier_of`](Self::tier_of), which this replaces.
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use jsonrpsee_tower_governor::key_extractor::{AuthOrAnonymousKeyExtractor, OperatorHeaderKeyExtractor};
+    /// # use std::num::NonZeroU32;
+    /// GovernorConfigBuilder::default()
+    ///     .key_extractor(AuthOrAnonymousKeyExtractor::new(OperatorHeaderKeyExtractor::default()))
+    ///     .per_millisecond(100)
+    ///     .burst_size(50)
+    ///     .anonymous_quota(Quota::per_second(NonZeroU32::new(1).unwrap()));
+    /// ```
+    pub fn anonymous_quota(&mut self, quota: Quota) -> &mut Self {
+        self.tier(ANONYMOUS_TIER, quota)
+            .tier_of(|key| key.is_anonymous().then_some(ANONYMOUS_TIER))
+    }
+}
+
+const ANONYMOUS_TIER: &str = "anonymous";
+
+/// An environment variable read by [`GovernorConfigBuilder::from_env`] has an invalid value.
+#[derive(Debug, Error)]
+#[error("Invalid value for {name}: {reason}")]
+pub struct EnvVarError {
+    /// Name of the environment variable.
+    pub name: &'static str,
+    /// Why the value is invalid.
+    pub reason: String,
+}
+
+impl GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> {
+    /// Create a builder from the environment, so quotas can be tuned per deployment:
+    /// - `GOVERNOR_PERIOD_MS`     - The period in milliseconds, see [`per_millisecond`](Self::per_millisecond)
+    /// - `GOVERNOR_BURST`         - The burst size, see [`burst_size`](Self::burst_size)
+    /// - `GOVERNOR_METHODS`       - Comma separated list of rate limited methods, see [`methods`](Self::methods)
+    /// - `GOVERNOR_KEY_EXTRACTOR` - Name of the key extractor, e.g. `smart_ip`, see [`NamedKeyExtractor`]
+    ///
+    /// Unset variables keep their default value.
+    pub fn from_env() -> Result<Self, EnvVarError> {
+        Self::from_vars(|name| std::env::var_os(name))
+    }
+
+    pub(crate) fn from_vars(var: impl Fn(&str) -> Option<OsString>) -> Result<Self, EnvVarError> {
+        let var = |name: &'static str| {
+            var(name)
+                .map(|value| {
+                    value.into_string().map_err(|_| EnvVarError {
+                        name,
+                        reason: "not unicode".to_owned(),
+                    })
+                })
+                .transpose()
+        };
+        let number = |name: &'static str| {
+            var(name)?
+                .map(|value| {
+                    value
+                        .trim()
+                        .parse()
+                        .map_err(|e: ParseIntError| EnvVarError {
+                            name,
+                            reason: e.to_string(),
+                        })
+                })
+                .transpose()
+        };
+
+        let key_extractor = match var("GOVERNOR_KEY_EXTRACTOR")? {
+            Some(name) => NamedKeyExtractor::deserialize(de::value::StrDeserializer::<
+                de::value::Error,
+            >::new(name.trim()))
+            .map_err(|e| EnvVarError {
+                name: "GOVERNOR_KEY_EXTRACTOR",
+                reason: e.to_string(),
+            })?,
+            None => NamedKeyExtractor::default(),
+        };
+        let mut builder = GovernorConfigBuilder::default().key_extractor(key_extractor);
+        if let Some(milliseconds) = number("GOVERNOR_PERIOD_MS")? {
+            builder.per_millisecond(milliseconds);
+        }
+        if let Some(burst_size) = number("GOVERNOR_BURST")? {
+            builder.burst_size(burst_size.try_into().map_err(|e: TryFromIntError| {
+                EnvVarError {
+                    name: "GOVERNOR_BURST",
+                    reason: e.to_string(),
+                }
+            })?);
+        }
+        if let Some(methods) = var("GOVERNOR_METHODS")? {
+            let methods = methods
+                .split(',')
+                .map(|method| {
+                    Method::from_bytes(method.trim().as_bytes()).map_err(|e| EnvVarError {
+                        name: "GOVERNOR_METHODS",
+                        reason: e.to_string(),
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            builder.methods(methods);
+        }
+        Ok(builder)
+    }
+}
+
 impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBuilder<K, M> {
     /// Set handler function for handling [GovernorError]
     /// # Example
     /// ```rust
     /// # use http::Response;
-    /// # use tower_governor::governor::GovernorConfigBuilder;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
     /// GovernorConfigBuilder::default()
     ///     .error_handler(|mut error| {
     ///         // match against GovernorError and produce customized Response type.
@@ -110,112 +890,1311 @@ impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBu
     /// ```
     pub fn error_handler<F>(&mut self, func: F) -> &mut Self
     where
-        F: Fn(GovernorError) -> Response<Body> + Send + Sync + 'static,
+        F: Fn(GovernorError) -> Response<HttpBody> + Send + Sync + 'static,
     {
-        self.error_handler = ErrorHandler(Arc::new(func));
+        self.error_handler = ErrorHandler(Some(Arc::new(func)));
         self
     }
-}
 
-/// Sets the default Governor Config and defines all the different configuration functions
-/// This one is used when the default PeerIpKeyExtractor is used
-impl<M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBuilder<PeerIpKeyExtractor, M> {
-    pub fn const_default() -> Self {
-        GovernorConfigBuilder {
-            period: DEFAULT_PERIOD,
-            burst_size: DEFAULT_BURST_SIZE,
-            methods: None,
-            key_extractor: PeerIpKeyExtractor,
-            error_handler: ErrorHandler::default(),
-            middleware: PhantomData,
-        }
-    }
-    /// Set the interval after which one element of the quota is replenished.
+    /// Set the function building the body of rejections from the rate limiting key, the wait time
+    /// and the quota, e.g. to point clients to the limits of their plan. The status and headers
+    /// of rejections are kept, no `content-type` is set.
     ///
-    /// **The interval must not be zero.**
-    pub fn const_period(mut self, duration: Duration) -> Self {
-        self.period = duration;
+    /// This has no effect when a custom [`error_handler`](Self::error_handler) is set, and is reset
+    /// by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee::http_client::HttpBody;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// GovernorConfigBuilder::default()
+    ///     .rejection_body(|ip, wait_time, quota| {
+    ///         HttpBody::from(format!(
+    ///             "{} exceeded {} requests, retry in {}s",
+    ///             ip,
+    ///             quota.burst_size(),
+    ///             wait_time.as_secs()
+    ///         ))
+    ///     });
+    /// ```
+    pub fn rejection_body<F>(&mut self, func: F) -> &mut Self
+    where
+        F: Fn(&K::Key, Duration, &Quota) -> HttpBody + Send + Sync + 'static,
+    {
+        self.rejection_body = Callback(Some(Arc::new(func)));
         self
     }
-    /// Set the interval after which one element of the quota is replenished in seconds.
+
+    /// Set a function called with the responses of allowed requests, along with the rate limiting key
+    /// and the quota state after the request, e.g. to add headers describing the plan of the client.
+    /// It runs after the rate limiting headers were added.
     ///
-    /// **The interval must not be zero.**
-    pub fn const_per_second(mut self, seconds: u64) -> Self {
-        self.period = Duration::from_secs(seconds);
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    /// # Example
+    /// ```rust
+    /// # use http::HeaderValue;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// GovernorConfigBuilder::default()
+    ///     .on_allowed(|_ip, info, response| {
+    ///         if info.remaining == 0 {
+    ///             response
+    ///                 .headers_mut()
+    ///                 .insert("x-plan-upgrade", HeaderValue::from_static("/pricing"));
+    ///         }
+    ///     });
+    /// ```
+    pub fn on_allowed<F>(&mut self, func: F) -> &mut Self
+    where
+        F: Fn(&K::Key, &RateLimitInfo, &mut Response<HttpBody>) + Send + Sync + 'static,
+    {
+        self.on_allowed = Callback(Some(Arc::new(func)));
         self
     }
-    /// Set the interval after which one element of the quota is replenished in milliseconds.
+
+    /// Set a provider of per-key quotas, e.g. to give premium customers or internal services
+    /// larger quotas than the one of the configuration.
     ///
-    /// **The interval must not be zero.**
-    pub fn const_per_millisecond(mut self, milliseconds: u64) -> Self {
-        self.period = Duration::from_millis(milliseconds);
-        self
-    }
-    /// Set the interval after which one element of the quota is replenished in nanoseconds.
+    /// Keys sharing a quota share a rate limiter, created the first time the quota is returned.
+    /// Keys whose quota equals the one of the configuration use its rate limiter, and
+    /// [`method_quota`](Self::method_quota)s take precedence over the provided quotas.
     ///
-    /// **The interval must not be zero.**
-    pub fn const_per_nanosecond(mut self, nanoseconds: u64) -> Self {
-        self.period = Duration::from_nanos(nanoseconds);
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::{net::{IpAddr, Ipv4Addr}, num::NonZeroU32};
+    /// let internal = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    /// GovernorConfigBuilder::default()
+    ///     .per_second(1)
+    ///     .burst_size(10)
+    ///     .quota_provider(move |ip: &IpAddr| {
+    ///         let burst_size = if *ip == internal { 1000 } else { 10 };
+    ///         Quota::per_second(NonZeroU32::new(1).unwrap())
+    ///             .allow_burst(NonZeroU32::new(burst_size).unwrap())
+    ///     });
+    /// ```
+    pub fn quota_provider<P>(&mut self, provider: P) -> &mut Self
+    where
+        P: QuotaProvider<K::Key> + 'static,
+    {
+        self.quota_provider = Callback(Some(Arc::new(provider)));
         self
     }
-    /// Set quota size that defines how many requests can occur
-    /// before the governor middleware starts blocking requests from an IP address and
-    /// clients have to wait until the elements of the quota are replenished.
+
+    /// Never rate limit requests with `key`, e.g. monitoring agents or internal callers.
+    /// They are handled like requests excluded by [`filter`](Self::filter), before being checked
+    /// against the rate limiter, so they don't consume quota.
     ///
-    /// **The burst_size must not be zero.**
-    pub fn const_burst_size(mut self, burst_size: u32) -> Self {
-        self.burst_size = burst_size;
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    pub fn exempt_key(&mut self, key: K::Key) -> &mut Self {
+        self.exempt_keys.insert(key);
         self
     }
-}
 
-/// Sets configuration options when any Key Extractor is provided
-impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBuilder<K, M> {
-    /// Set the interval after which one element of the quota is replenished.
+    /// Reject the requests of the keys and IP ranges of `blocklist` before checking them against
+    /// the rate limiter, with the status of the blocklist, as [`GovernorError::Other`].
     ///
-    /// **The interval must not be zero.**
-    pub fn period(&mut self, duration: Duration) -> &mut Self {
-        self.period = duration;
+    /// This applies to rate limited requests, see [`methods`](Self::methods), and is reset by
+    /// [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    pub fn blocklist(&mut self, blocklist: Blocklist<K::Key>) -> &mut Self {
+        self.blocklist = Some(blocklist);
         self
     }
-    /// Set the interval after which one element of the quota is replenished in seconds.
+
+    /// Keep the state of the rate limiters in `store` instead of the default
+    /// [`MemoryStore`](crate::store::MemoryStore), e.g. a bounded or a shared one.
+    /// The rate limiters of the [`method_quota`](Self::method_quota)s, [`additional_quota`](Self::additional_quota)s
+    /// and the other quotas of keys use its [scoped](GovernorStore::scoped) stores.
     ///
-    /// **The interval must not be zero.**
-    pub fn per_second(&mut self, seconds: u64) -> &mut Self {
-        self.period = Duration::from_secs(seconds);
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    pub fn store<S: GovernorStore<K::Key>>(&mut self, store: S) -> &mut Self {
+        self.store = Callback(Some(Arc::new(store)));
         self
     }
-    /// Set the interval after which one element of the quota is replenished in milliseconds.
+
+    /// Set whether the worker threads share the state of the keys, see [`LimiterMode`].
     ///
-    /// **The interval must not be zero.**
-    pub fn per_millisecond(&mut self, milliseconds: u64) -> &mut Self {
-        self.period = Duration::from_millis(milliseconds);
-        self
-    }
-    /// Set the interval after which one element of the quota is replenished in nanoseconds.
+    /// Per-worker states are kept in memory, they can't be combined with a [`store`](Self::store).
+    /// The quota headers show the share of the quota of the worker handling the request.
     ///
-    /// **The interval must not be zero.**
-    pub fn per_nanosecond(&mut self, nanoseconds: u64) -> &mut Self {
-        self.period = Duration::from_nanos(nanoseconds);
-        self
-    }
-    /// Set quota size that defines how many requests can occur
-    /// before the governor middleware starts blocking requests from an IP address and
-    /// clients have to wait until the elements of the quota are replenished.
+    /// **The number of workers must not be zero.**
     ///
-    /// **The burst_size must not be zero.**
-    pub fn burst_size(&mut self, burst_size: u32) -> &mut Self {
-        self.burst_size = burst_size;
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::{GovernorConfigBuilder, LimiterMode};
+    /// // One state per thread of a 8 threads runtime, each allowing bursts of 100 / 8 = 12 requests.
+    /// GovernorConfigBuilder::default()
+    ///     .burst_size(100)
+    ///     .limiter_mode(LimiterMode::PerWorker(8));
+    /// ```
+    pub fn limiter_mode(&mut self, mode: LimiterMode) -> &mut Self {
+        self.options.limiter_mode = mode;
         self
     }
 
-    /// Set the HTTP methods this configuration should apply to.
+    /// Set the algorithm enforcing the quotas, see [`Algorithm`].
+    ///
+    /// The sliding window log is kept in memory, it can't be combined with a [`store`](Self::store)
+    /// or per-worker states, and isn't part of [`GovernorConfig::export_state`].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::{Algorithm, GovernorConfigBuilder};
+    /// # use std::time::Duration;
+    /// // At most 100 requests over any 60 seconds.
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(100, Duration::from_secs(60))
+    ///     .algorithm(Algorithm::SlidingWindowLog);
+    /// ```
+    pub fn algorithm(&mut self, algorithm: Algorithm) -> &mut Self {
+        self.options.algorithm = algorithm;
+        self
+    }
+
+    /// Never rate limit requests with one of `keys`, see [`exempt_key`](Self::exempt_key).
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// GovernorConfigBuilder::default()
+    ///     .exempt_keys([IpAddr::V4(Ipv4Addr::new(10, 0, 0, 7)), IpAddr::V4(Ipv4Addr::new(10, 0, 0, 8))]);
+    /// ```
+    pub fn exempt_keys(&mut self, keys: impl IntoIterator<Item = K::Key>) -> &mut Self {
+        self.exempt_keys.extend(keys);
+        self
+    }
+
+    /// Set what happens to requests whose key can't be extracted, they are rejected by default,
+    /// see [`FailurePolicy`].
+    ///
+    /// This applies to [`GovernorError::UnableToExtractKey`], other errors returned by the key
+    /// extractor, e.g. an invalid signature, are still returned.
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::{FailurePolicy, GovernorConfigBuilder};
+    /// # use std::net::{IpAddr, Ipv4Addr};
+    /// GovernorConfigBuilder::default()
+    ///     .failure_policy(FailurePolicy::FallbackKey(IpAddr::V4(Ipv4Addr::UNSPECIFIED)));
+    /// ```
+    pub fn failure_policy(&mut self, policy: FailurePolicy<K::Key>) -> &mut Self {
+        self.failure_policy = policy;
+        self
+    }
+
+    /// Ban keys rejected `violations` times within `window` for `duration`: their requests are
+    /// rejected right away with `403 Forbidden` and a `retry-after` header, as
+    /// [`GovernorError::Other`], without being checked against the rate limiter.
+    ///
+    /// Only enforced rejections count, see [`dry_run`](Self::dry_run).
+    ///
+    /// [`finish`](Self::finish) fails if `violations`, `window` or `duration` is zero.
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // 10 rejections within a minute ban the key for an hour.
+    /// GovernorConfigBuilder::default()
+    ///     .auto_ban(10, Duration::from_secs(60), Duration::from_secs(60 * 60));
+    /// ```
+    pub fn auto_ban(&mut self, violations: u32, window: Duration, duration: Duration) -> &mut Self {
+        self.options.auto_ban = Some(AutoBan {
+            violations,
+            window,
+            duration,
+        });
+        self
+    }
+
+    /// Allow each key retries up to `percent` of its other requests, plus `min_retries`, over
+    /// consecutive windows of `window`, rejecting the retries beyond this budget right away with
+    /// `429 Too Many Requests` and a `retry-after` header until the window ends, as
+    /// [`GovernorError::Other`], so that clients retrying in a loop don't amplify an overload.
+    ///
+    /// Retries are the requests with a [`retry_header`](Self::retry_header) whose value isn't `0`,
+    /// e.g. the number of the attempt, and the ones trusted outer middleware inserted
+    /// [`RetryAttempt`](crate::RetryAttempt) into the extensions of. Requests are counted when
+    /// received, whether the quota allows them or not. The counts are kept in memory for each instance.
+    ///
+    /// [`finish`](Self::finish) fails if `percent` is above 100 or `window` is zero.
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // Retries up to 10% of the requests of each minute, and at least 3.
+    /// GovernorConfigBuilder::default().retry_budget(10, 3, Duration::from_secs(60));
+    /// ```
+    pub fn retry_budget(&mut self, percent: u8, min_retries: u32, window: Duration) -> &mut Self {
+        self.options.retry_budget = Some(RetryBudget {
+            percent,
+            min_retries,
+            window,
+        });
+        self
+    }
+
+    /// Set the header telling retried requests, [`DEFAULT_RETRY_HEADER`] by default, see
+    /// [`retry_budget`](Self::retry_budget).
+    pub fn retry_header(&mut self, header: HeaderName) -> &mut Self {
+        self.options.retry_header = Some(header);
+        self
+    }
+
+    /// Keep track of the `top` keys rejected the most over a rolling `window`, see
+    /// [`GovernorConfig::top_offenders`], to find out who is hammering the service.
+    ///
+    /// The rejections are counted approximately, in a memory bounded by `top` whatever the number
+    /// of keys, see the [`offenders`](crate::offenders) module.
+    /// [`finish`](Self::finish) fails if `top` or `window` is zero.
+    pub fn track_offenders(&mut self, top: usize, window: Duration) -> &mut Self {
+        self.options.offender_tracking = Some((top, window));
+        self
+    }
+
+    /// Consult the rate limiter without enforcing its decisions: every request is passed to the inner
+    /// service, so quotas can be validated in production before being enforced.
+    ///
+    /// Requests that would have been rejected carry a [`ShadowRejection`](crate::ShadowRejection) in
+    /// their request and response extensions, without rate limiting headers, and are logged with the
+    /// `tracing` feature. Requests that would have failed, e.g. because the key can't be extracted,
+    /// are passed through as well.
+    pub fn dry_run(&mut self) -> &mut Self {
+        self.options.dry_run = true;
+        self
+    }
+
+    /// Only enforce rejections for `percent` percent of the keys, the rejections of the other keys
+    /// are handled like in [`dry_run`](Self::dry_run), to gradually roll out new quotas.
+    ///
+    /// Keys are assigned by a hash that is the same on every instance, platform and Rust release, so
+    /// a key stays enforced as long as the percentage doesn't decrease.
+    /// Requests whose key can't be extracted are always enforced.
+    ///
+    /// [`finish`](Self::finish) fails if `percent` is above 100.
+    pub fn enforced_percent(&mut self, percent: u8) -> &mut Self {
+        self.options.enforced_percent = Some(percent);
+        self
+    }
+
+    /// Keep the state of keys for `ttl` after their quota is fully replenished, instead of dropping it
+    /// as soon as it is, when [`GovernorConfig::retain_recent`] runs. Keys untouched for longer take
+    /// no memory, so it stays proportional to the set of active clients.
+    ///
+    /// Dropping a replenished key doesn't change its quota, this only trades memory for fewer
+    /// reallocations of keys coming back.
+    pub fn idle_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.options.idle_ttl = ttl;
+        self
+    }
+
+    /// Run [`GovernorConfig::retain_recent`] every `interval` in the background, so the state of clients
+    /// that stopped sending requests is dropped without a task of your own.
+    ///
+    /// The cleanup is triggered by the first request after the interval elapsed, and runs on the
+    /// blocking thread pool of its tokio runtime, or on the thread of the request outside of one.
+    /// Use [`GovernorHandle::gc_now`](crate::reload::GovernorHandle::gc_now) to run it right away.
+    pub fn gc_interval(&mut self, interval: Duration) -> &mut Self {
+        self.options.gc_interval = Some(interval);
+        self
+    }
+
+    /// Run [`GovernorConfig::retain_recent`] as soon as the rate limiter tracks `keys` keys, on top
+    /// of the [`gc_interval`](Self::gc_interval), keeping the memory bounded when clients churn
+    /// through keys faster than the interval, e.g. during an attack from many addresses.
+    ///
+    /// The cleanup is triggered like the one of the interval, but only one runs at a time, so when
+    /// the active keys alone exceed the threshold cleanups run back to back: set it well above the
+    /// expected number of active clients, and use an [`LruStore`](crate::store::LruStore) for a hard bound.
+    /// Keys of shared stores, which expire by themselves, aren't counted.
+    pub fn gc_threshold(&mut self, keys: usize) -> &mut Self {
+        self.options.gc_threshold = Some(keys);
+        self
+    }
+
+    /// Don't enforce rejections during `grace` after the configuration is built, they are handled like in
+    /// [`dry_run`](Self::dry_run), to avoid bursts of `429 Too Many Requests` while clients reconnect
+    /// after a deploy. Requests are still counted, so the quotas apply as usual once the grace ends.
+    ///
+    /// Configurations applied by [`GovernorHandle::update_config`](crate::reload::GovernorHandle::update_config)
+    /// keep the grace of the previous configuration instead of starting a new one.
+    pub fn startup_grace(&mut self, grace: Duration) -> &mut Self {
+        self.options.startup_grace = Some(grace);
+        self
+    }
+
+    /// Set a function naming the plan tier of keys, e.g. `free`, `pro` or `enterprise`.
+    /// Keys of a tier declared with [`tier`](Self::tier) get its quota, the others get the quota of
+    /// the configuration. Tiers take precedence over the [`quota_provider`](Self::quota_provider),
+    /// [`method_quota`](Self::method_quota)s take precedence over tiers.
+    ///
+    /// The tier is sent in the `x-ratelimit-tier` header, unless [`hide_headers`](Self::hide_headers)
+    /// is set, and inserted as a [`PlanTier`] into the request extensions of allowed requests and the
+    /// response extensions of rejections.
+    ///
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, key_extractor::OperatorHeaderKeyExtractor};
+    /// # use std::num::NonZeroU32;
+    /// let per_minute = |requests| Quota::per_minute(NonZeroU32::new(requests).unwrap());
+    /// GovernorConfigBuilder::default()
+    ///     .key_extractor(OperatorHeaderKeyExtractor::default())
+    ///     .per_second(6)
+    ///     .burst_size(10)
+    ///     .tier("pro", per_minute(600))
+    ///     .tier("enterprise", per_minute(6000))
+    ///     .tier_of(|operator: &String| match operator.as_str() {
+    ///         "acme" => Some("enterprise"),
+    ///         _ => Some("pro"),
+    ///     });
+    /// ```
+    pub fn tier_of<F>(&mut self, classify: F) -> &mut Self
+    where
+        F: Fn(&K::Key) -> Option<&str> + Send + Sync + 'static,
+    {
+        self.tier_of = Callback(Some(Arc::new(classify)));
+        self
+    }
+
+    /// Set a function telling the keys whose requests are high priority, drawing from the reserve
+    /// of the global quota, see [`global_reserve`](Self::global_reserve).
+    ///
+    /// This is reset by [`key_extractor`](GovernorConfigBuilder::key_extractor).
+    pub fn high_priority<F>(&mut self, is_high_priority: F) -> &mut Self
+    where
+        F: Fn(&K::Key) -> bool + Send + Sync + 'static,
+    {
+        self.high_priority = Callback(Some(Arc::new(is_high_priority)));
+        self
+    }
+
+    /// Give the keys of the tier `name` the quota `quota`, see [`tier_of`](Self::tier_of).
+    /// Declaring a tier again replaces its quota.
+    pub fn tier(&mut self, name: &str, quota: Quota) -> &mut Self {
+        match self
+            .options
+            .tiers
+            .iter_mut()
+            .find(|(tier, _)| tier.as_str() == name)
+        {
+            Some((_, tier_quota)) => *tier_quota = quota,
+            None => self.options.tiers.push((PlanTier(name.into()), quota)),
+        }
+        self
+    }
+
+    /// Set the format of the built-in error responses.
+    /// By default errors are rendered as plain text, see [`ErrorFormat::PlainText`].
+    ///
+    /// This has no effect when a custom [`error_handler`](Self::error_handler) is set.
+    pub fn error_format(&mut self, error_format: ErrorFormat) -> &mut Self {
+        self.options.error_format = error_format;
+        self
+    }
+
+    /// Pick the format of the built-in error responses from the request's `Accept` header,
+    /// falling back to the [`error_format`](Self::error_format) when no supported format is asked for.
+    pub fn negotiate_error_format(&mut self) -> &mut Self {
+        self.options.negotiate_error_format = true;
+        self
+    }
+
+    /// Add the message of plain text rejections for clients preferring `language`, e.g. `de` or `pt-BR`,
+    /// according to their `accept-language` header. `{wait_time}` and `{limit}` in the message are replaced
+    /// with the wait time in seconds and the burst size.
+    ///
+    /// Clients not asking for any of the added languages get the built-in message.
+    pub fn localized_message(
+        &mut self,
+        language: impl Into<String>,
+        message: impl Into<String>,
+    ) -> &mut Self {
+        self.options
+            .localized_messages
+            .push((language.into(), message.into()));
+        self
+    }
+
+    /// Set the HTML page sent for rejections rendered as [`ErrorFormat::Html`],
+    /// `{wait_time}` and `{limit}` in the template are replaced with the wait time in seconds
+    /// and the burst size.
+    pub fn html_template(&mut self, template: impl Into<String>) -> &mut Self {
+        self.options.html_template = Some(template.into());
+        self
+    }
+
+    /// Set the status code of the built-in rejection responses, `429 Too Many Requests` by default.
+    /// Useful when upstream load balancers react to another code, like `503 Service Unavailable`.
+    ///
+    /// This has no effect when a custom [`error_handler`](Self::error_handler) is set.
+    pub fn rejection_status(&mut self, status: StatusCode) -> &mut Self {
+        self.options.rejection_status = Some(status);
+        self
+    }
+
+    /// Set the status code of the built-in responses to [`GovernorError::UnableToExtractKey`],
+    /// `500 Internal Server Error` by default. Key extractors can pick their own status and body
+    /// by returning [`GovernorError::Other`] instead.
+    ///
+    /// This has no effect when a custom [`error_handler`](Self::error_handler) is set.
+    pub fn extraction_failure_status(&mut self, status: StatusCode) -> &mut Self {
+        self.options.extraction_failure_status = Some(status);
+        self
+    }
+}
+
+/// Sets the default Governor Config and defines all the different configuration functions
+/// This one is used when the default PeerIpKeyExtractor is used
+impl<M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBuilder<PeerIpKeyExtractor, M> {
+    pub fn const_default() -> Self {
+        GovernorConfigBuilder {
+            period: DEFAULT_PERIOD,
+            burst_size: DEFAULT_BURST_SIZE,
+            methods: None,
+            key_extractor: PeerIpKeyExtractor,
+            error_handler: ErrorHandler::default(),
+            rejection_body: RejectionBody::default(),
+            on_allowed: OnAllowed::default(),
+            quota_provider: QuotaOverrides::default(),
+            tier_of: TierOf::default(),
+            high_priority: HighPriorityOf::default(),
+            failure_policy: FailurePolicy::default(),
+            exempt_keys: HashSet::new(),
+            blocklist: None,
+            store: StoreBackend::default(),
+            options: Options::default(),
+            middleware: PhantomData,
+        }
+    }
+    /// Set the interval after which one element of the quota is replenished.
+    ///
+    /// **The interval must not be zero.**
+    pub fn const_period(mut self, duration: Duration) -> Self {
+        self.period = duration;
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in seconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn const_per_second(mut self, seconds: u64) -> Self {
+        self.period = Duration::from_secs(seconds);
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in milliseconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn const_per_millisecond(mut self, milliseconds: u64) -> Self {
+        self.period = Duration::from_millis(milliseconds);
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in nanoseconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn const_per_nanosecond(mut self, nanoseconds: u64) -> Self {
+        self.period = Duration::from_nanos(nanoseconds);
+        self
+    }
+    /// Set quota size that defines how many requests can occur
+    /// before the governor middleware starts blocking requests from an IP address and
+    /// clients have to wait until the elements of the quota are replenished.
+    ///
+    /// **The burst_size must not be zero.**
+    pub fn const_burst_size(mut self, burst_size: u32) -> Self {
+        self.burst_size = burst_size;
+        self
+    }
+}
+
+/// Sets configuration options when any Key Extractor is provided
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBuilder<K, M> {
+    /// Set the interval after which one element of the quota is replenished.
+    ///
+    /// **The interval must not be zero.**
+    pub fn period(&mut self, duration: Duration) -> &mut Self {
+        self.period = duration;
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in seconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn per_second(&mut self, seconds: u64) -> &mut Self {
+        self.period = Duration::from_secs(seconds);
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in milliseconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn per_millisecond(&mut self, milliseconds: u64) -> &mut Self {
+        self.period = Duration::from_millis(milliseconds);
+        self
+    }
+    /// Set the interval after which one element of the quota is replenished in nanoseconds.
+    ///
+    /// **The interval must not be zero.**
+    pub fn per_nanosecond(&mut self, nanoseconds: u64) -> &mut Self {
+        self.period = Duration::from_nanos(nanoseconds);
+        self
+    }
+    /// Allow `requests` requests per `window`: the burst size is set to `requests` and one
+    /// element of the quota is replenished every `window / requests`.
+    ///
+    /// Unlike [`per_second`](Self::per_second) and friends, which set the replenish interval,
+    /// this sets the number of requests per window.
+    ///
+    /// **Neither requests nor window must be zero.**
+    pub fn quota_per(&mut self, requests: u32, window: Duration) -> &mut Self {
+        self.burst_size = requests;
+        self.period = window.checked_div(requests).unwrap_or(window);
+        self
+    }
+    /// Also allow at most `requests` requests per `window`, on top of the quota of the configuration,
+    /// e.g. 10 requests per second and 1000 per hour. Can be called several times, every quota is checked
+    /// and a request is only allowed if all of them allow it.
+    ///
+    /// Quota headers and [`RateLimitInfo`] describe the most restrictive quota: the one with the fewest
+    /// remaining requests, or the one rejecting the request.
+    ///
+    /// **Neither requests nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(10, Duration::from_secs(1))
+    ///     .additional_quota(1000, Duration::from_secs(60 * 60));
+    /// ```
+    pub fn additional_quota(&mut self, requests: u32, window: Duration) -> &mut Self {
+        self.options
+            .additional_quotas
+            .push((window.checked_div(requests).unwrap_or(window), requests));
+        self
+    }
+
+    /// Also check requests against a ceiling of `requests` per `window` shared by every key, e.g. the
+    /// capacity of the server, besides the quota of their key: a request is only allowed if both
+    /// allow it, and its headers describe the most restrictive of the two.
+    ///
+    /// The ceiling is kept in memory for each instance, even with a [`store`](Self::store).
+    /// Exempt keys and requests that aren't rate limited don't count against it.
+    ///
+    /// **Neither requests nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // 10 requests per second for each client, 1000 for all of them together.
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(10, Duration::from_secs(1))
+    ///     .global_quota(1000, Duration::from_secs(1));
+    /// ```
+    pub fn global_quota(&mut self, requests: u32, window: Duration) -> &mut Self {
+        self.options.global_quota =
+            Some((window.checked_div(requests).unwrap_or(window), requests));
+        self
+    }
+
+    /// Make the service not ready while the [global quota](Self::global_quota) has no request
+    /// left, so that load balancers and buffers in front of it, e.g. `tower::balance` or
+    /// `tower::buffer`, hold requests or send them elsewhere instead of getting a flood of
+    /// rejections. The service checks again every time the global quota replenishes a request.
+    ///
+    /// Being ready doesn't reserve the request: requests racing for the last one are still
+    /// checked and may be rejected. The middleware is always ready in [dry run](Self::dry_run).
+    ///
+    /// **The global quota must be set.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// GovernorConfigBuilder::default()
+    ///     .global_quota(1000, Duration::from_secs(1))
+    ///     .global_backpressure();
+    /// ```
+    pub fn global_backpressure(&mut self) -> &mut Self {
+        self.options.global_backpressure = true;
+        self
+    }
+
+    /// Keep `percent` of the [global quota](Self::global_quota) for high priority requests, e.g.
+    /// of paying or internal clients, so they still succeed when the others consumed the rest:
+    /// requests that aren't high priority are rejected once only the reserve is left.
+    ///
+    /// Requests are high priority if their key is, see [`high_priority`](Self::high_priority), or
+    /// if trusted outer middleware inserted [`HighPriority`](crate::HighPriority) into their
+    /// extensions. Quota headers show the whole global quota left, reserve included.
+    ///
+    /// **percent must be below 100, and the global quota must be set.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::{
+    /// #     governor::GovernorConfigBuilder,
+    /// #     key_extractor::{AuthOrAnonymousKeyExtractor, OperatorHeaderKeyExtractor},
+    /// # };
+    /// # use std::time::Duration;
+    /// // Anonymous clients share 800 of the 1000 requests per second.
+    /// GovernorConfigBuilder::default()
+    ///     .key_extractor(AuthOrAnonymousKeyExtractor::new(OperatorHeaderKeyExtractor::default()))
+    ///     .global_quota(1000, Duration::from_secs(1))
+    ///     .global_reserve(20)
+    ///     .high_priority(|key| !key.is_anonymous());
+    /// ```
+    pub fn global_reserve(&mut self, percent: u8) -> &mut Self {
+        self.options.global_reserve = percent;
+        self
+    }
+
+    /// Also charge requests to the level `name`, e.g. the tenant or the user of a multi-tenant
+    /// service, keyed by `extractor` with a quota of `requests` per `window` for each of its keys.
+    ///
+    /// Levels are checked in order after the quotas of the key of the configuration, e.g. the IP
+    /// address, and a request is only allowed if all of them allow it: its headers describe the most
+    /// restrictive quota, and rejections by a level carry its name in the `x-ratelimit-level` header
+    /// and the [`LimitLevel`] response extension. Requests without a key for a level, e.g. anonymous
+    /// ones without a user, skip it. Declaring a level again replaces it.
+    ///
+    /// The keys of a level are told apart by their [`Display`](fmt::Display) form, and their state is
+    /// kept in memory for each instance, even with a [`store`](Self::store).
+    ///
+    /// **Neither requests nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use http::HeaderName;
+    /// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, key_extractor::OperatorHeaderKeyExtractor};
+    /// # use std::time::Duration;
+    /// // 10 requests per second for each IP address, 100 for each tenant.
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(10, Duration::from_secs(1))
+    ///     .level(
+    ///         "tenant",
+    ///         100,
+    ///         Duration::from_secs(1),
+    ///         OperatorHeaderKeyExtractor::new(HeaderName::from_static("x-tenant-id")),
+    ///     );
+    /// ```
+    pub fn level<E>(
+        &mut self,
+        name: &str,
+        requests: u32,
+        window: Duration,
+        extractor: E,
+    ) -> &mut Self
+    where
+        E: KeyExtractor + Send + Sync + 'static,
+        E::Key: fmt::Display,
+    {
+        let level = LevelQuota {
+            name: LimitLevel(name.into()),
+            period: window.checked_div(requests).unwrap_or(window),
+            burst_size: requests,
+            key: Callback(Some(Arc::new(move |req: &Request<Incoming>| {
+                extractor.extract(req).ok().map(|key| key.to_string())
+            }))),
+        };
+        match self
+            .options
+            .levels
+            .iter_mut()
+            .find(|previous| previous.name == level.name)
+        {
+            Some(previous) => *previous = level,
+            None => self.options.levels.push(level),
+        }
+        self
+    }
+
+    /// Also limit each client IP address to `requests` per `window`, besides the quota of the key of
+    /// the configuration, e.g. an API key: a request is rejected as soon as either is exhausted,
+    /// with `ip` in the `x-ratelimit-level` header when its IP address is.
+    ///
+    /// This is the [level](Self::level) `ip` keyed by the [peer IP address](PeerIpKeyExtractor).
+    /// Behind a reverse proxy, declare the level with a
+    /// [`SmartIpKeyExtractor`](crate::key_extractor::SmartIpKeyExtractor) instead.
+    ///
+    /// **Neither requests nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use http::HeaderName;
+    /// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, key_extractor::OperatorHeaderKeyExtractor};
+    /// # use std::time::Duration;
+    /// // 1000 requests per minute for each API key, 100 for each IP address.
+    /// GovernorConfigBuilder::default()
+    ///     .key_extractor(OperatorHeaderKeyExtractor::new(HeaderName::from_static("x-api-key")))
+    ///     .quota_per(1000, Duration::from_secs(60))
+    ///     .ip_quota(100, Duration::from_secs(60));
+    /// ```
+    pub fn ip_quota(&mut self, requests: u32, window: Duration) -> &mut Self {
+        self.level("ip", requests, window, PeerIpKeyExtractor)
+    }
+
+    /// Reject the requests of a key with `max` requests in flight, i.e. whose response isn't
+    /// ready yet, besides its quota, e.g. to keep clients from tying up a slow, expensive endpoint.
+    ///
+    /// A request takes a slot of its key when allowed and releases it once its response is ready
+    /// or dropped, the streaming of the response body not being counted. Rejections have the
+    /// `429 Too Many Requests` status without quota headers, since there's no time to wait for.
+    /// The slots are kept in memory for each instance, even with a [`store`](Self::store).
+    ///
+    /// **max must not be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// // At most 4 requests per client being handled at the same time.
+    /// GovernorConfigBuilder::default().max_in_flight(4);
+    /// ```
+    pub fn max_in_flight(&mut self, max: usize) -> &mut Self {
+        self.options.max_in_flight = Some(max);
+        self
+    }
+
+    /// Delay the requests that would have to wait up to `max_delay` for their quota until it allows
+    /// them, instead of rejecting them, e.g. to smooth the bursts of internal clients that tolerate
+    /// latency. Requests with longer waits are still rejected, as are the ones beyond the
+    /// [`max_queued`](Self::max_queued) requests of a key waiting at the same time.
+    ///
+    /// The tokens of a delayed request are taken right away, even beyond the burst size, so the
+    /// following requests wait after it. The inner service is only called once the wait is over,
+    /// with the copy that was ready when the request arrived, a clone taking its place meanwhile.
+    /// Quota headers and [`RateLimitInfo`] show no request left, and the wait as the reset.
+    ///
+    /// **max_delay must not be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // Hold requests for up to 2 seconds before rejecting them.
+    /// GovernorConfigBuilder::default().max_delay(Duration::from_secs(2));
+    /// ```
+    pub fn max_delay(&mut self, max_delay: Duration) -> &mut Self {
+        self.options.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Delay at most `max` requests of a key at the same time, see [`max_delay`](Self::max_delay),
+    /// rejecting the others right away, to bound the memory and the latency taken by a key sending
+    /// requests faster than its quota allows. A request leaves the queue of its key once its wait
+    /// is over, or when it's dropped. The queues are kept in memory for each instance.
+    ///
+    /// **max must not be zero, and the maximum delay must be set.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // Up to 10 requests per client waiting up to a second.
+    /// GovernorConfigBuilder::default()
+    ///     .max_delay(Duration::from_secs(1))
+    ///     .max_queued(10);
+    /// ```
+    pub fn max_queued(&mut self, max: usize) -> &mut Self {
+        self.options.max_queued = Some(max);
+        self
+    }
+
+    /// Once the rate limiter tracks `max_keys` keys, check the requests of any other key against a
+    /// single overflow quota of `requests` per `window`, shared by all of them, until the state of
+    /// tracked keys is dropped, see [`gc_threshold`](Self::gc_threshold). This trades the fairness
+    /// between keys for a memory bounded under attacks from many addresses.
+    ///
+    /// Overflowing requests are rejected and counted as a single key, e.g. for
+    /// [`auto_ban`](Self::auto_ban), and don't get the [`quota_provider`](Self::quota_provider),
+    /// tier or method quotas of their key. Keys of shared stores, which expire by themselves,
+    /// aren't counted.
+    ///
+    /// **Neither max_keys, requests nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // Beyond 100 000 clients, new ones share 1000 requests per second.
+    /// GovernorConfigBuilder::default()
+    ///     .key_cap(100_000, 1000, Duration::from_secs(1));
+    /// ```
+    pub fn key_cap(&mut self, max_keys: usize, requests: u32, window: Duration) -> &mut Self {
+        self.options.key_cap = Some((
+            max_keys,
+            window.checked_div(requests).unwrap_or(window),
+            requests,
+        ));
+        self
+    }
+
+    /// Replace the quota of the configuration with `quota` on `days` from `hours.start` to `hours.end`,
+    /// e.g. stricter limits during peak hours. Hours wrap past midnight when the start is after the end,
+    /// e.g. `22..6`, and then belong to the day they start on. The first matching scheduled quota applies,
+    /// in the order they were added.
+    ///
+    /// The time of day is evaluated with the [`schedule_utc_offset`](Self::schedule_utc_offset).
+    /// Keys keep their method, tier and [`quota_provider`](Self::quota_provider) quotas.
+    ///
+    /// [`finish`](Self::finish) fails if `days` is empty, the hours are equal or above 24.
+    /// # Example
+    /// ```rust
+    /// # use governor::Quota;
+    /// # use jsonrpsee_tower_governor::governor::{GovernorConfigBuilder, Weekday};
+    /// # use std::num::NonZeroU32;
+    /// // 1 request per second during business hours in New York, 10 otherwise.
+    /// GovernorConfigBuilder::default()
+    ///     .per_second(10)
+    ///     .scheduled_quota(&Weekday::WORKDAYS, 9..17, Quota::per_second(NonZeroU32::MIN))
+    ///     .schedule_utc_offset(-5 * 60);
+    /// ```
+    pub fn scheduled_quota(
+        &mut self,
+        days: &[Weekday],
+        hours: Range<u8>,
+        quota: Quota,
+    ) -> &mut Self {
+        self.options.schedule.push(ScheduledQuota {
+            days: days.iter().fold(0, |set, day| set | 1 << *day as u8),
+            start_hour: hours.start,
+            end_hour: hours.end,
+            quota,
+        });
+        self
+    }
+    /// Set the offset from UTC in minutes of the time zone of the
+    /// [`scheduled_quota`](Self::scheduled_quota)s, e.g. `60` for CET, UTC by default.
+    ///
+    /// [`finish`](Self::finish) fails if the offset isn't under a day.
+    pub fn schedule_utc_offset(&mut self, minutes: i32) -> &mut Self {
+        self.options.schedule_utc_offset = minutes;
+        self
+    }
+    /// Shrink the quotas as the load of the service reported by `signal` rises, so the rate limiter doubles
+    /// as an overload protection: `signal` returns the load from `0.0` to `1.0`, e.g. the CPU usage or the
+    /// fill ratio of a queue, and quotas are scaled down linearly to `min_percent` of their burst size and
+    /// rate at full load. Loads are rounded to tenths, keys share their state within each tenth.
+    ///
+    /// `signal` is called on every rate limited request and should be cheap, e.g. reading a value
+    /// sampled in the background.
+    ///
+    /// [`finish`](Self::finish) fails if `min_percent` is zero or above 100.
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::sync::{atomic::{AtomicU8, Ordering}, Arc};
+    /// // Updated by a background task with the current CPU usage in percent.
+    /// let cpu = Arc::new(AtomicU8::new(0));
+    /// GovernorConfigBuilder::default()
+    ///     .per_second(10)
+    ///     .load_adaptive(move || f64::from(cpu.load(Ordering::Relaxed)) / 100.0, 20);
+    /// ```
+    pub fn load_adaptive<F>(&mut self, signal: F, min_percent: u8) -> &mut Self
+    where
+        F: Fn() -> f64 + Send + Sync + 'static,
+    {
+        self.options.load_signal = Callback(Some(Arc::new(signal)));
+        self.options.min_quota_percent = min_percent;
+        self
+    }
+    /// Allow `requests` requests per hour, see [`quota_per`](Self::quota_per).
+    ///
+    /// **The requests must not be zero.**
+    pub fn per_hour(&mut self, requests: u32) -> &mut Self {
+        self.quota_per(requests, Duration::from_secs(60 * 60))
+    }
+    /// Allow `requests` requests per day, see [`quota_per`](Self::quota_per).
+    ///
+    /// **The requests must not be zero.**
+    pub fn per_day(&mut self, requests: u32) -> &mut Self {
+        self.quota_per(requests, Duration::from_secs(24 * 60 * 60))
+    }
+    /// Allow `requests` requests per week, see [`quota_per`](Self::quota_per).
+    ///
+    /// **The requests must not be zero.**
+    pub fn per_week(&mut self, requests: u32) -> &mut Self {
+        self.quota_per(requests, Duration::from_secs(7 * 24 * 60 * 60))
+    }
+    /// Set quota size that defines how many requests can occur
+    /// before the governor middleware starts blocking requests from an IP address and
+    /// clients have to wait until the elements of the quota are replenished.
+    ///
+    /// **The burst_size must not be zero.**
+    pub fn burst_size(&mut self, burst_size: u32) -> &mut Self {
+        self.burst_size = burst_size;
+        self
+    }
+
+    /// Set the HTTP methods this configuration should apply to.
     /// By default this is all methods.
     pub fn methods(&mut self, methods: Vec<Method>) -> &mut Self {
         self.methods = Some(methods);
         self
     }
 
+    /// Only rate limit the requests for which `predicate` returns `true`, e.g. to exclude
+    /// requests by path, header or extension. The other requests are handled like requests
+    /// whose method isn't configured with [`methods`](Self::methods).
+    /// By default all requests are rate limited.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// GovernorConfigBuilder::default()
+    ///     .filter(|req| !req.uri().path().starts_with("/health"));
+    /// ```
+    pub fn filter<F>(&mut self, predicate: F) -> &mut Self
+    where
+        F: Fn(&Request<Incoming>) -> bool + Send + Sync + 'static,
+    {
+        self.options.request_filter = Callback(Some(Arc::new(predicate)));
+        self
+    }
+
+    /// Exempt the paths matched by `matcher` from rate limiting, e.g. `/healthz`, `/metrics` or
+    /// `/.well-known/*`. Requests to exempted paths are passed to the inner service untouched,
+    /// without quota headers.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, RouteMatcher};
+    /// GovernorConfigBuilder::default()
+    ///     .exempt_path(RouteMatcher::prefix("/healthz"))
+    ///     .exempt_path(RouteMatcher::glob("/.well-known/*"));
+    /// ```
+    pub fn exempt_path(&mut self, matcher: RouteMatcher) -> &mut Self {
+        self.options.exempt_paths.push(matcher);
+        self
+    }
+
+    /// Exempt the clients of `network` from rate limiting, e.g. office ranges or partner NAT gateways.
+    /// Their requests are passed to the inner service untouched, without quota headers.
+    ///
+    /// The client is the peer address of the connection, whatever the key extractor, never the
+    /// address of `X-Forwarded-For`, `X-Real-IP` or `Forwarded` headers, which any client can set to
+    /// exempt itself. Behind a reverse proxy, every request comes from the proxy: exempting its
+    /// network exempts all of them.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// GovernorConfigBuilder::default()
+    ///     .exempt_network("10.0.0.0/8".parse().unwrap())
+    ///     .exempt_network("2001:db8::/32".parse().unwrap());
+    /// ```
+    pub fn exempt_network(&mut self, network: IpNet) -> &mut Self {
+        self.options.exempt_networks.push(network);
+        self
+    }
+
+    /// Exempt loopback and private clients from rate limiting, e.g. health checks and sidecars: the IPv4
+    /// loopback and RFC 1918 ranges, the IPv6 loopback address and unique local addresses,
+    /// see [`exempt_network`](Self::exempt_network).
+    ///
+    /// Only use this when the client IP address can be trusted, i.e. requests from the internet can't
+    /// arrive through a local proxy without a key extractor reading the forwarded client address.
+    pub fn trust_loopback_and_private(&mut self) -> &mut Self {
+        for network in [
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "::1/128",
+            "fc00::/7",
+        ] {
+            self.exempt_network(network.parse().unwrap());
+        }
+        self
+    }
+
+    /// Don't rate limit CORS preflight requests, i.e. `OPTIONS` requests with an
+    /// `access-control-request-method` header, so browsers can still send the actual request
+    /// under strict per-IP quotas. Preflight requests are passed to the inner service untouched.
+    pub fn skip_preflight(&mut self) -> &mut Self {
+        self.options.skip_preflight = true;
+        self
+    }
+
+    /// Give requests with `method` their own quota, replenishing one element every `period`
+    /// with bursts of up to `burst_size` requests, instead of the quota of the configuration.
+    /// Each method quota has its own rate limiter, so e.g. `GET` and `POST` requests of a key
+    /// are counted separately.
+    ///
+    /// **The period and burst_size must not be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use http::Method;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// GovernorConfigBuilder::default()
+    ///     .per_millisecond(10)
+    ///     .burst_size(100)
+    ///     .method_quota(Method::POST, Duration::from_millis(100), 10);
+    /// ```
+    pub fn method_quota(&mut self, method: Method, period: Duration, burst_size: u32) -> &mut Self {
+        self.options
+            .method_quotas
+            .push((method, period, burst_size));
+        self
+    }
+
+    /// Scale the number of tokens a request consumes with its `Content-Length`.
+    /// A request consumes one token per started `bytes_per_token` bytes of body, and at least one token.
+    /// Requests without a `Content-Length` header consume a single token.
+    /// By default every request consumes a single token.
+    ///
+    /// Requests consuming more tokens than the burst size are rejected with
+    /// [`GovernorError::CostExceedsBurst`], a `413 Payload Too Large` by default.
+    ///
+    /// **The bytes_per_token must not be zero.**
+    pub fn bytes_per_token(&mut self, bytes_per_token: u32) -> &mut Self {
+        self.options.bytes_per_token = Some(bytes_per_token);
+        self
+    }
+
+    /// Compute the number of tokens each request consumes from the quota with `extractor`, e.g. so
+    /// that expensive endpoints consume more of it than cheap ones, see [`crate::cost`].
+    /// It takes precedence over [`bytes_per_token`](Self::bytes_per_token).
+    ///
+    /// The cost applies to every quota the request is checked against. Requests consuming more
+    /// tokens than the burst size are rejected with [`GovernorError::CostExceedsBurst`], a
+    /// `413 Payload Too Large` by default.
+    pub fn cost_extractor<C>(&mut self, extractor: C) -> &mut Self
+    where
+        C: CostExtractor + 'static,
+    {
+        self.options.cost_extractor = Callback(Some(Arc::new(extractor)));
+        self
+    }
+
+    /// Also limit the bytes of request bodies uploaded by each key to `bytes` per `window`, besides
+    /// its quota of requests, so that clients can't get around it with enormous payloads.
+    ///
+    /// Requests are charged their `Content-Length` and rejected when their key doesn't have as many
+    /// bytes left, or with [`GovernorError::CostExceedsBurst`] when the body is larger than the
+    /// quota. Requests without a `Content-Length`, e.g. chunked ones, aren't charged: limit the size
+    /// of their body in the inner service. The charge follows the [`store`](Self::store) of the
+    /// configuration.
+    ///
+    /// **Neither bytes nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // 10 requests per second and 100 MiB per hour for each client.
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(10, Duration::from_secs(1))
+    ///     .request_bytes_quota(100 << 20, Duration::from_secs(60 * 60));
+    /// ```
+    pub fn request_bytes_quota(&mut self, bytes: u32, window: Duration) -> &mut Self {
+        self.options.request_bytes_quota =
+            Some((window.checked_div(bytes).unwrap_or(window), bytes));
+        self
+    }
+
+    /// Also limit the bytes of response bodies sent to each key to `bytes` per `window`, besides
+    /// its quota of requests, e.g. to protect the egress of a service serving large downloads.
+    ///
+    /// The size of a response is only known once it's sent, so the body is charged to its key as
+    /// it is streamed, letting the key go over its quota, and the following requests of the key
+    /// are rejected until its quota has a byte again. The charge follows the
+    /// [`store`](Self::store) of the configuration.
+    ///
+    /// **Neither bytes nor window must be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// # use std::time::Duration;
+    /// // 10 requests and 1 MiB per second for each client.
+    /// GovernorConfigBuilder::default()
+    ///     .quota_per(10, Duration::from_secs(1))
+    ///     .response_bytes_quota(1 << 20, Duration::from_secs(1));
+    /// ```
+    pub fn response_bytes_quota(&mut self, bytes: u32, window: Duration) -> &mut Self {
+        self.options.response_bytes_quota =
+            Some((window.checked_div(bytes).unwrap_or(window), bytes));
+        self
+    }
+
+    /// Give the tokens consumed by a request back when its response was served from cache.
+    ///
+    /// The inner service marks such responses by inserting [`CacheHit`] into the response extensions,
+    /// or by setting the header configured with [`cache_hit_header`](Self::cache_hit_header).
+    /// Refunded tokens are available to the next requests of the same key, but never exceed the burst size.
+    pub fn cache_hit_refunds(&mut self) -> &mut Self {
+        self.options.cache_hit_refunds = true;
+        self
+    }
+
+    /// Also treat responses carrying the header `name` with the given `value` (compared case-insensitively)
+    /// as served from cache, e.g. `x-cache: HIT`. This enables [`cache_hit_refunds`](Self::cache_hit_refunds).
+    pub fn cache_hit_header(&mut self, name: HeaderName, value: HeaderValue) -> &mut Self {
+        self.options.cache_hit_refunds = true;
+        self.options.cache_hit_header = Some((name, value));
+        self
+    }
+
+    /// Only count the requests whose response has a status for which `predicate` returns `true`,
+    /// e.g. only successful ones, or only failed logins for a login limiter.
+    ///
+    /// Requests consume their tokens when allowed, as the response isn't known yet, and get them
+    /// back once their response turns out not to count, like [`cache_hit_refunds`](Self::cache_hit_refunds).
+    /// Requests failing in the inner service, without a response, count.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use http::StatusCode;
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// // 5 failed logins per minute.
+    /// GovernorConfigBuilder::default()
+    ///     .per_second(12)
+    ///     .burst_size(5)
+    ///     .count_responses(|status| {
+    ///         status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+    ///     });
+    /// ```
+    pub fn count_responses<F>(&mut self, predicate: F) -> &mut Self
+    where
+        F: Fn(StatusCode) -> bool + Send + Sync + 'static,
+    {
+        self.options.counted_responses = Callback(Some(Arc::new(predicate)));
+        self
+    }
+
+    /// Give the tokens consumed by a request back when its response is a server error (`5xx`), so
+    /// that clients aren't pushed into backing off by the failures of the service itself.
+    pub fn server_error_refunds(&mut self) -> &mut Self {
+        self.options.server_error_refunds = true;
+        self
+    }
+
+    /// Make requests whose response is `401 Unauthorized` or `403 Forbidden` consume `multiplier`
+    /// times their tokens, so that credential stuffing exhausts the quota of its key much faster
+    /// than legitimate traffic.
+    ///
+    /// The extra tokens are taken from the quotas of the key once the response is known, and may
+    /// take it beyond its burst size, its following requests waiting for the excess to be replenished.
+    ///
+    /// **The multiplier must not be zero.**
+    ///
+    /// # Example
+    /// ```rust
+    /// # use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+    /// // Failed logins cost 10 requests.
+    /// GovernorConfigBuilder::default().auth_failure_penalty(10);
+    /// ```
+    pub fn auth_failure_penalty(&mut self, multiplier: u32) -> &mut Self {
+        self.options.auth_failure_penalty = Some(multiplier);
+        self
+    }
+
+    /// Set which headers carry the wait time of rejected requests.
+    /// By default only `x-ratelimit-after` is sent, many HTTP clients only honor the standard `retry-after` header.
+    pub fn retry_after(&mut self, retry_after: RetryAfter) -> &mut Self {
+        self.options.retry_after = retry_after;
+        self
+    }
+
+    /// Send the `retry-after` header enabled by [`retry_after`](Self::retry_after) as an HTTP-date,
+    /// e.g. `Wed, 21 Oct 2015 07:28:00 GMT`, instead of a number of seconds.
+    pub fn retry_after_http_date(&mut self) -> &mut Self {
+        self.options.retry_after_http_date = true;
+        self
+    }
+
+    /// Set the naming of the quota headers enabled by [`use_headers`](Self::use_headers).
+    /// By default these are the `x-ratelimit-*` headers.
+    pub fn header_style(&mut self, header_style: HeaderStyle) -> &mut Self {
+        self.options.header_style = header_style;
+        self
+    }
+
+    /// Set the format of the `x-ratelimit-reset` header sent by [`use_headers`](Self::use_headers).
+    /// By default this is the number of seconds until the quota is fully replenished, or until the next request is allowed after a rejection.
+    pub fn reset_format(&mut self, reset_format: ResetFormat) -> &mut Self {
+        self.options.reset_format = reset_format;
+        self
+    }
+
+    /// Also describe the configured quota in a `ratelimit-policy` header when [`use_headers`](Self::use_headers) is enabled,
+    /// e.g. `8;w=4` for a burst size of eight requests that is fully replenished in four seconds.
+    pub fn policy_header(&mut self) -> &mut Self {
+        self.options.policy_header = true;
+        self
+    }
+
+    /// Also send the wait time of rejected requests in milliseconds, in the `x-ratelimit-after-ms` header.
+    /// The seconds based headers truncate sub-second waits to `0`.
+    pub fn after_ms_header(&mut self) -> &mut Self {
+        self.options.after_ms_header = true;
+        self
+    }
+
+    /// Round the wait time of rejected requests up to the next second instead of truncating it,
+    /// so clients are never told to retry immediately.
+    pub fn round_up_wait_time(&mut self) -> &mut Self {
+        self.options.round_up_wait_time = true;
+        self
+    }
+
+    /// Cap the wait time advertised to rejected clients, in the `x-ratelimit-after`, `x-ratelimit-after-ms`
+    /// and `retry-after` headers and in the built-in error bodies. Clients retrying earlier are still rejected
+    /// until the quota allows them again.
+    pub fn max_retry_after(&mut self, max: Duration) -> &mut Self {
+        self.options.max_retry_after = Some(max);
+        self
+    }
+
+    /// Rename the `x-ratelimit-*` headers, e.g. to match the contract of an existing gateway.
+    pub fn header_names(&mut self, header_names: HeaderNames) -> &mut Self {
+        self.options.header_names = header_names;
+        self
+    }
+
+    /// Don't send any rate limiting header, neither on allowed nor on rejected requests,
+    /// so clients can't probe the limiting policy. This overrides [`use_headers`](Self::use_headers)
+    /// and [`retry_after`](Self::retry_after).
+    pub fn hide_headers(&mut self) -> &mut Self {
+        self.options.hide_headers = true;
+        self
+    }
+
+    /// Don't send the `x-ratelimit-whitelisted` header on requests whose method isn't rate limited,
+    /// so clients can't tell which methods bypass the limiter. Its name can be changed with
+    /// [`header_names`](Self::header_names) instead.
+    pub fn hide_whitelisted_header(&mut self) -> &mut Self {
+        self.options.hide_whitelisted_header = true;
+        self
+    }
+
+    /// List the rate limiting headers of each response in `access-control-expose-headers`,
+    /// so browser clients can read them on cross-origin requests.
+    pub fn expose_headers(&mut self) -> &mut Self {
+        self.options.expose_headers = true;
+        self
+    }
+
+    /// Send the same headers as [`use_headers`](Self::use_headers) without switching to the
+    /// [`StateInformationMiddleware`], the remaining burst capacity is read back from the
+    /// rate limiter state after each allowed request instead.
+    pub fn lightweight_headers(&mut self) -> &mut Self {
+        self.options.lightweight_headers = true;
+        self
+    }
+
+    /// Add the `x-ratelimit-warning` header to allowed responses once at least `percent` of the
+    /// burst size is consumed, so clients can back off before being rejected.
+    /// The header value is the consumed percentage, e.g. `80`.
+    ///
+    /// [`finish`](Self::finish) fails if `percent` is above 100.
+    pub fn warning_threshold(&mut self, percent: u8) -> &mut Self {
+        self.options.warning_threshold = Some(percent);
+        self
+    }
+
     /// Set the key extractor this configuration should use.
     /// By default this is using the [PeerIpKeyExtractor].
     pub fn key_extractor<K2: KeyExtractor>(
@@ -228,66 +2207,959 @@ impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfigBu
             methods: self.methods.to_owned(),
             key_extractor,
             error_handler: self.error_handler.clone(),
+            rejection_body: RejectionBody::default(),
+            on_allowed: OnAllowed::default(),
+            quota_provider: QuotaOverrides::default(),
+            tier_of: TierOf::default(),
+            high_priority: HighPriorityOf::default(),
+            failure_policy: FailurePolicy::default(),
+            exempt_keys: HashSet::new(),
+            blocklist: None,
+            store: StoreBackend::default(),
+            options: self.options.clone(),
+            middleware: PhantomData,
+        }
+    }
+    /// Set ratelimit headers to response, the headers is
+    /// - `x-ratelimit-limit`       - Request limit
+    /// - `x-ratelimit-remaining`   - The number of requests left for the time window
+    /// - `x-ratelimit-reset`       - Number of seconds until the quota is fully replenished, see [`reset_format`]
+    /// - `x-ratelimit-after`       - Number of seconds in which the API will become available after its rate limit has been exceeded
+    /// - `retry-after`             - Same value as `x-ratelimit-after`, see [`retry_after`]
+    /// - `x-ratelimit-whitelisted` - If the request method not in methods or the request is excluded by [`filter`], this header will be add it, use [`methods`] to add methods
+    ///
+    /// By default `x-ratelimit-after` is enabled, with [`use_headers`] will enable `x-ratelimit-limit`, `x-ratelimit-whitelisted` and `x-ratelimit-remaining`
+    ///
+    /// [`methods`]: Self::methods()
+    /// [`filter`]: Self::filter
+    /// [`use_headers`]: Self::use_headers
+    /// [`retry_after`]: Self::retry_after
+    /// [`reset_format`]: Self::reset_format
+    pub fn use_headers(&mut self) -> GovernorConfigBuilder<K, StateInformationMiddleware> {
+        GovernorConfigBuilder {
+            period: self.period,
+            burst_size: self.burst_size,
+            methods: self.methods.to_owned(),
+            key_extractor: self.key_extractor.clone(),
+            error_handler: self.error_handler.clone(),
+            rejection_body: self.rejection_body.clone(),
+            on_allowed: self.on_allowed.clone(),
+            quota_provider: self.quota_provider.clone(),
+            tier_of: self.tier_of.clone(),
+            high_priority: self.high_priority.clone(),
+            failure_policy: self.failure_policy.clone(),
+            exempt_keys: self.exempt_keys.clone(),
+            blocklist: self.blocklist.clone(),
+            store: self.store.clone(),
+            options: self.options.clone(),
             middleware: PhantomData,
         }
     }
-    /// Set ratelimit headers to response, the headers is
-    /// - `x-ratelimit-limit`       - Request limit
-    /// - `x-ratelimit-remaining`   - The number of requests left for the time window
-    /// - `x-ratelimit-after`       - Number of seconds in which the API will become available after its rate limit has been exceeded
-    /// - `retry-after`             - Same value as `x-ratelimit-after`
-    /// - `x-ratelimit-whitelisted` - If the request method not in methods, this header will be add it, use [`methods`] to add methods
+
+    /// Finish building the configuration and return the configuration for the middleware.
+    /// Returns `None` if either burst size, period interval or bytes per token are zero,
+    /// if a method or additional quota has a zero burst size or period, or if the warning threshold
+    /// or the enforced percentage of keys is above 100 percent, or if an auto-ban setting is zero.
+    ///
+    /// Use [`try_build`](Self::try_build) to know why the configuration is invalid.
+    pub fn finish(&mut self) -> Option<GovernorConfig<K, M>> {
+        self.check_quotas().ok()?;
+        Some(self.build())
+    }
+
+    /// Finish building the configuration like [`finish`](Self::finish), returning why it is invalid
+    /// instead of `None`.
+    ///
+    /// Unlike [`finish`](Self::finish), this also rejects an empty list of [`methods`](Self::methods),
+    /// header settings that have no effect because of [`hide_headers`](Self::hide_headers),
+    /// and [`retry_after_http_date`](Self::retry_after_http_date) without a `retry-after` header.
+    pub fn try_build(&mut self) -> Result<GovernorConfig<K, M>, ConfigError>
+    where
+        M: GovernorMiddleware,
+    {
+        self.check_quotas()?;
+        if self.methods.as_ref().is_some_and(Vec::is_empty) {
+            return Err(ConfigError::EmptyMethods);
+        }
+        let options = &self.options;
+        if options.hide_headers {
+            let shown = [
+                ("use_headers", M::USE_HEADERS),
+                ("lightweight_headers", options.lightweight_headers),
+                ("expose_headers", options.expose_headers),
+                ("policy_header", options.policy_header),
+                ("after_ms_header", options.after_ms_header),
+                ("retry_after", options.retry_after != RetryAfter::Disabled),
+                ("warning_threshold", options.warning_threshold.is_some()),
+            ];
+            if let Some((setting, _)) = shown.into_iter().find(|(_, enabled)| *enabled) {
+                return Err(ConfigError::ConflictingHeaders(setting, "hide_headers"));
+            }
+        }
+        if options.retry_after_http_date && options.retry_after == RetryAfter::Disabled {
+            return Err(ConfigError::ConflictingHeaders(
+                "retry_after_http_date",
+                "retry_after(RetryAfter::Disabled)",
+            ));
+        }
+        Ok(self.build())
+    }
+
+    fn check_quotas(&self) -> Result<(), ConfigError> {
+        if self.burst_size == 0 {
+            return Err(ConfigError::ZeroBurstSize);
+        }
+        if self.period.as_nanos() == 0 {
+            return Err(ConfigError::ZeroPeriod);
+        }
+        if self.options.bytes_per_token == Some(0) {
+            return Err(ConfigError::ZeroBytesPerToken);
+        }
+        if let Some(percent) = self
+            .options
+            .warning_threshold
+            .filter(|percent| *percent > 100)
+        {
+            return Err(ConfigError::WarningThresholdAbove100(percent));
+        }
+        if let Some(percent) = self
+            .options
+            .enforced_percent
+            .filter(|percent| *percent > 100)
+        {
+            return Err(ConfigError::EnforcedPercentAbove100(percent));
+        }
+        if self.options.load_signal.0.is_some()
+            && !(1..=100).contains(&self.options.min_quota_percent)
+        {
+            return Err(ConfigError::InvalidMinQuotaPercent(
+                self.options.min_quota_percent,
+            ));
+        }
+        if self
+            .options
+            .retry_budget
+            .is_some_and(|budget| budget.percent > 100 || budget.window.is_zero())
+        {
+            return Err(ConfigError::InvalidRetryBudget);
+        }
+        if self.options.auto_ban.is_some_and(|ban| {
+            ban.violations == 0 || ban.window.is_zero() || ban.duration.is_zero()
+        }) {
+            return Err(ConfigError::InvalidAutoBan);
+        }
+        if self
+            .options
+            .offender_tracking
+            .is_some_and(|(top, window)| top == 0 || window.is_zero())
+        {
+            return Err(ConfigError::InvalidOffenderTracking);
+        }
+        if self
+            .options
+            .key_cap
+            .is_some_and(|(max_keys, period, burst_size)| {
+                max_keys == 0 || burst_size == 0 || period.is_zero()
+            })
+        {
+            return Err(ConfigError::InvalidKeyCap);
+        }
+        if self.options.max_in_flight == Some(0) {
+            return Err(ConfigError::ZeroMaxInFlight);
+        }
+        if self.options.max_delay.is_some_and(|max| max.is_zero()) {
+            return Err(ConfigError::ZeroMaxDelay);
+        }
+        if self.options.max_queued == Some(0)
+            || (self.options.max_queued.is_some() && self.options.max_delay.is_none())
+        {
+            return Err(ConfigError::InvalidMaxQueued);
+        }
+        if self.options.auth_failure_penalty == Some(0) {
+            return Err(ConfigError::ZeroAuthFailurePenalty);
+        }
+        if self.options.limiter_mode == LimiterMode::PerWorker(0)
+            || (self.options.limiter_mode != LimiterMode::Shared && self.store.0.is_some())
+        {
+            return Err(ConfigError::InvalidLimiterMode);
+        }
+        if self.options.algorithm == Algorithm::SlidingWindowLog
+            && (self.options.limiter_mode != LimiterMode::Shared || self.store.0.is_some())
+        {
+            return Err(ConfigError::InvalidAlgorithm);
+        }
+        if self.options.schedule_utc_offset.unsigned_abs() >= 24 * 60
+            || self.options.schedule.iter().any(|scheduled| {
+                scheduled.days == 0
+                    || scheduled.start_hour == scheduled.end_hour
+                    || scheduled.start_hour > 24
+                    || scheduled.end_hour > 24
+            })
+        {
+            return Err(ConfigError::InvalidSchedule);
+        }
+        if let Some((method, _, _)) = self
+            .options
+            .method_quotas
+            .iter()
+            .find(|(_, period, burst_size)| *burst_size == 0 || period.as_nanos() == 0)
+        {
+            return Err(ConfigError::InvalidMethodQuota(method.clone()));
+        }
+        if self
+            .options
+            .additional_quotas
+            .iter()
+            .any(|(period, burst_size)| *burst_size == 0 || period.as_nanos() == 0)
+        {
+            return Err(ConfigError::InvalidAdditionalQuota);
+        }
+        if self
+            .options
+            .global_quota
+            .is_some_and(|(period, burst_size)| burst_size == 0 || period.is_zero())
+        {
+            return Err(ConfigError::InvalidGlobalQuota);
+        }
+        if self.options.global_backpressure && self.options.global_quota.is_none() {
+            return Err(ConfigError::BackpressureWithoutGlobalQuota);
+        }
+        if self.options.global_reserve >= 100
+            || (self.options.global_reserve > 0 && self.options.global_quota.is_none())
+        {
+            return Err(ConfigError::InvalidGlobalReserve(
+                self.options.global_reserve,
+            ));
+        }
+        if self
+            .options
+            .response_bytes_quota
+            .is_some_and(|(period, burst_size)| burst_size == 0 || period.is_zero())
+        {
+            return Err(ConfigError::InvalidResponseBytesQuota);
+        }
+        if self
+            .options
+            .request_bytes_quota
+            .is_some_and(|(period, burst_size)| burst_size == 0 || period.is_zero())
+        {
+            return Err(ConfigError::InvalidRequestBytesQuota);
+        }
+        if let Some(level) = self
+            .options
+            .levels
+            .iter()
+            .find(|level| level.burst_size == 0 || level.period.is_zero())
+        {
+            return Err(ConfigError::InvalidLevel(level.name.as_str().to_owned()));
+        }
+        Ok(())
+    }
+
+    fn build(&self) -> GovernorConfig<K, M> {
+        let quota_of = |period, burst_size| self.options.worker_quota(quota_of(period, burst_size));
+        let quota = quota_of(self.period, self.burst_size);
+        let store = match (&self.store.0, self.options.limiter_mode) {
+            (Some(backend), _) => KeyedStore::new(backend.scoped("default")),
+            (None, LimiterMode::PerWorker(workers)) => {
+                KeyedStore::new(Arc::new(WorkerStore::new(workers)))
+            }
+            (None, LimiterMode::Shared) => KeyedStore::<K::Key>::default(),
+        };
+        let store = self.options.with_algorithm(store, quota);
+        let method_limiters = self
+            .options
+            .method_quotas
+            .iter()
+            .map(|(method, period, burst_size)| {
+                let store = store.scoped(&format!("method:{}", method));
+                (
+                    method.clone(),
+                    Bucket::new(quota_of(*period, *burst_size), store),
+                )
+            })
+            .collect();
+        let additional_limiters = self
+            .options
+            .additional_quotas
+            .iter()
+            .enumerate()
+            .map(|(i, (period, burst_size))| {
+                let store = store.scoped(&format!("additional:{}", i));
+                Bucket::new(quota_of(*period, *burst_size), store)
+            })
+            .collect();
+        let response_bytes = self
+            .options
+            .response_bytes_quota
+            .map(|(period, burst_size)| {
+                Bucket::new(quota_of(period, burst_size), store.scoped("response-bytes"))
+            });
+        let request_bytes = self
+            .options
+            .request_bytes_quota
+            .map(|(period, burst_size)| {
+                Bucket::new(quota_of(period, burst_size), store.scoped("request-bytes"))
+            });
+        let global = self.options.global_quota.map(|(period, burst_size)| {
+            let quota = quota_of(period, burst_size);
+            GlobalBucket::new(
+                quota,
+                self.options.with_algorithm(KeyedStore::default(), quota),
+            )
+        });
+        let levels = self
+            .options
+            .levels
+            .iter()
+            .map(|level| {
+                let quota = quota_of(level.period, level.burst_size);
+                Level {
+                    name: level.name.clone(),
+                    key: level.key.clone(),
+                    bucket: LevelBucket::new(
+                        quota,
+                        self.options.with_algorithm(KeyedStore::default(), quota),
+                    ),
+                }
+            })
+            .collect();
+        let overflow = self.options.key_cap.map(|(max_keys, period, burst_size)| {
+            Arc::new(Overflow {
+                max_keys,
+                bucket: Bucket::new(quota_of(period, burst_size), store.scoped("overflow")),
+                key: OnceLock::new(),
+            })
+        });
+        GovernorConfig {
+            key_extractor: self.key_extractor.clone(),
+            limiter: new_limiter(quota, store.clone()),
+            store,
+            quota,
+            method_limiters,
+            additional_limiters,
+            methods: self.methods.clone(),
+            error_handler: self.error_handler.clone(),
+            rejection_body: self.rejection_body.clone(),
+            on_allowed: self.on_allowed.clone(),
+            quota_provider: self.quota_provider.clone(),
+            tier_of: self.tier_of.clone(),
+            high_priority: self.high_priority.clone(),
+            failure_policy: self.failure_policy.clone(),
+            exempt_keys: Arc::new(self.exempt_keys.clone()),
+            blocklist: self.blocklist.clone(),
+            key_buckets: KeyBuckets::default(),
+            bans: Bans::default(),
+            retries: Retries::default(),
+            in_flight: InFlight::default(),
+            queued: InFlight::default(),
+            offenders: self
+                .options
+                .offender_tracking
+                .map(|(top, window)| Arc::new(Offenders::new(top, window))),
+            overflow,
+            global,
+            levels,
+            response_bytes,
+            request_bytes,
+            gc: Arc::new(Gc {
+                last: Mutex::new(Instant::now()),
+                running: AtomicBool::new(false),
+            }),
+            options: Arc::new(Options {
+                grace_until: self
+                    .options
+                    .startup_grace
+                    .map(|grace| Instant::now() + grace),
+                ..self.options.clone()
+            }),
+        }
+    }
+}
+
+// The quota of a validated period and burst size.
+fn quota_of(period: Duration, burst_size: u32) -> Quota {
+    Quota::with_period(period)
+        .unwrap()
+        .allow_burst(NonZeroU32::new(burst_size).unwrap())
+}
+
+fn new_limiter<Key, M>(quota: Quota, store: KeyedStore<Key>) -> SharedRateLimiter<Key, M>
+where
+    Key: Clone + Hash + Eq + 'static,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    Arc::new(
+        RateLimiter::<_, _, _, NoOpMiddleware>::new(quota, store, DefaultClock::default())
+            .with_middleware::<M>(),
+    )
+}
+
+// Outcome of `Governor::check`: the state of the most restrictive quota when allowed,
+// or how long to wait for the quota denying the request, with its level if it has one.
+pub(crate) type CheckOutcome =
+    Result<(RateLimitInfo, Quota), (Duration, Quota, Option<LimitLevel>)>;
+
+// Outcome of `Governor::check_or_delay`: a `CheckOutcome` with the delay of allowed requests.
+pub(crate) type DelayOutcome =
+    Result<(RateLimitInfo, Quota, Option<Delay>), (Duration, Quota, Option<LimitLevel>)>;
+
+// The rate limiter shared by the keys beyond `GovernorConfigBuilder::key_cap`.
+#[derive(Debug)]
+pub(crate) struct Overflow<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> {
+    max_keys: usize,
+    bucket: Bucket<K, M>,
+    // The key of the first overflowing request, standing for all of them.
+    key: OnceLock<K::Key>,
+}
+
+// A request of `key` in flight, see `GovernorConfigBuilder::max_in_flight`, or delayed, see
+// `GovernorConfigBuilder::max_queued`, released when dropped.
+pub(crate) struct InFlightSlot<Key: Hash + Eq> {
+    in_flight: InFlight<Key>,
+    key: Key,
+}
+
+impl<Key: Hash + Eq + Clone> InFlightSlot<Key> {
+    // A slot of `key` out of `slots`, unless it has `max` of them.
+    fn take(slots: &InFlight<Key>, key: &Key, max: usize) -> Option<Self> {
+        let mut count = slots.entry(key.clone()).or_insert(0);
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Self {
+            in_flight: slots.clone(),
+            key: key.clone(),
+        })
+    }
+}
+
+impl<Key: Hash + Eq> Drop for InFlightSlot<Key> {
+    fn drop(&mut self) {
+        self.in_flight.remove_if_mut(&self.key, |_, count| {
+            *count -= 1;
+            *count == 0
+        });
+    }
+}
+
+// The rate limiter of `GovernorConfigBuilder::global_quota`, with a single key.
+pub(crate) type GlobalBucket = Bucket<GlobalKeyExtractor, NoOpMiddleware>;
+
+// A rate limiter keyed by strings, as the ones of `GovernorConfigBuilder::level`.
+type LevelBucket = Bucket<OperatorHeaderKeyExtractor, NoOpMiddleware>;
+
+// A level of `GovernorConfigBuilder::level` with its rate limiter.
+#[derive(Debug, Clone)]
+pub(crate) struct Level {
+    name: LimitLevel,
+    key: LevelKey,
+    bucket: LevelBucket,
+}
+
+// A rate limiter with its store and quota. Besides the one of the configuration, there are the ones of
+// `GovernorConfigBuilder::method_quota` and `GovernorConfigBuilder::additional_quota`.
+#[derive(Debug)]
+pub(crate) struct Bucket<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> {
+    limiter: SharedRateLimiter<K::Key, M>,
+    store: KeyedStore<K::Key>,
+    pub(crate) quota: Quota,
+    // The plan tier the quota belongs to, see `GovernorConfigBuilder::tier_of`.
+    pub(crate) tier: Option<PlanTier>,
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for Bucket<K, M> {
+    fn clone(&self) -> Self {
+        Self {
+            limiter: self.limiter.clone(),
+            store: self.store.clone(),
+            quota: self.quota,
+            tier: self.tier.clone(),
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Bucket<K, M> {
+    fn new(quota: Quota, store: KeyedStore<K::Key>) -> Self {
+        let store = store.enforcing(quota);
+        Self {
+            limiter: new_limiter(quota, store.clone()),
+            store,
+            quota,
+            tier: None,
+        }
+    }
+
+    /// The store and the amount of replenishment worth `cost` tokens, to refund them later.
+    fn refund_of(&self, cost: NonZeroU32) -> (KeyedStore<K::Key>, Nanos) {
+        let amount = Nanos::from(self.quota.replenish_interval()) * u64::from(cost.get());
+        (self.store.clone(), amount)
+    }
+
+    /// Give `cost` tokens back to `key`.
+    fn refund(&self, key: &K::Key, cost: NonZeroU32) {
+        let (store, amount) = self.refund_of(cost);
+        store.refund(key, amount);
+    }
+
+    /// Check `cost` tokens for `key` if the store replaces the GCRA, see [`Algorithm`], or its backend
+    /// runs it, see [`GovernorStore::check_gcra`](crate::store::GovernorStore::check_gcra).
+    fn check_window(
+        &self,
+        key: &K::Key,
+        cost: NonZeroU32,
+    ) -> Option<Result<Result<RateLimitInfo, Duration>, GovernorError>> {
+        if cost > self.quota.burst_size() {
+            return Some(Err(GovernorError::CostExceedsBurst {
+                cost: cost.get(),
+                burst_size: self.quota.burst_size().get(),
+            }));
+        }
+        let (_, amount) = self.refund_of(cost);
+        Some(Ok(self.store.check_window(key, amount, &self.quota)?))
+    }
+
+    /// A rate limiter enforcing `quota`, where keys keep the tokens they consumed in this one.
+    fn migrate(&self, quota: Quota) -> Self {
+        if quota == self.quota {
+            return self.clone();
+        }
+        let store = self.store.migrate(&self.quota, &quota);
+        Self {
+            limiter: new_limiter(quota, store.clone()),
+            store,
+            quota,
+            tier: None,
+        }
+    }
+}
+
+impl<K: KeyExtractor> Bucket<K, NoOpMiddleware> {
+    /// Check `cost` tokens for `key`, returning the state of its quota when allowed, or how long to wait.
+    fn check_n(
+        &self,
+        key: &K::Key,
+        cost: NonZeroU32,
+    ) -> Result<Result<RateLimitInfo, Duration>, GovernorError> {
+        if let Some(checked) = self.check_window(key, cost) {
+            return checked;
+        }
+        match self.limiter.check_key_n(key, cost) {
+            Ok(Ok(())) => {
+                let limit = self.quota.burst_size().get();
+                let remaining = self.store.remaining_burst_capacity(key, &self.quota);
+                Ok(Ok(RateLimitInfo {
+                    limit,
+                    remaining,
+                    reset: self.quota.replenish_interval() * (limit - remaining),
+                }))
+            }
+            Ok(Err(negative)) => Ok(Err(negative.wait_time_from(DefaultClock::default().now()))),
+            Err(_) => Err(GovernorError::CostExceedsBurst {
+                cost: cost.get(),
+                burst_size: self.quota.burst_size().get(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Configuration for the Governor middleware.
+pub struct GovernorConfig<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> {
+    key_extractor: K,
+    limiter: SharedRateLimiter<K::Key, M>,
+    store: KeyedStore<K::Key>,
+    quota: Quota,
+    method_limiters: Arc<[(Method, Bucket<K, M>)]>,
+    additional_limiters: Arc<[Bucket<K, M>]>,
+    methods: Option<Vec<Method>>,
+    error_handler: ErrorHandler,
+    rejection_body: RejectionBody<K::Key>,
+    on_allowed: OnAllowed<K::Key>,
+    quota_provider: QuotaOverrides<K::Key>,
+    tier_of: TierOf<K::Key>,
+    high_priority: HighPriorityOf<K::Key>,
+    failure_policy: FailurePolicy<K::Key>,
+    exempt_keys: Arc<HashSet<K::Key>>,
+    blocklist: Option<Blocklist<K::Key>>,
+    key_buckets: KeyBuckets<K, M>,
+    bans: Bans<K::Key>,
+    retries: Retries<K::Key>,
+    in_flight: InFlight<K::Key>,
+    queued: InFlight<K::Key>,
+    offenders: Option<Arc<Offenders<K::Key>>>,
+    overflow: Option<Arc<Overflow<K, M>>>,
+    global: Option<GlobalBucket>,
+    levels: Arc<[Level]>,
+    response_bytes: Option<Bucket<K, NoOpMiddleware>>,
+    request_bytes: Option<Bucket<K, NoOpMiddleware>>,
+    gc: Arc<Gc>,
+    options: Arc<Options>,
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfig<K, M> {
+    pub fn limiter(&self) -> &SharedRateLimiter<K::Key, M> {
+        &self.limiter
+    }
+
+    /// The quota enforced by the limiter.
+    pub fn quota(&self) -> Quota {
+        self.quota
+    }
+
+    /// Drop the state of the keys whose quota has been fully replenished for longer than the
+    /// [`idle_ttl`](GovernorConfigBuilder::idle_ttl) from every rate limiter of this configuration,
+    /// as well as the auto-bans and violations that expired, see [`GovernorConfigBuilder::auto_ban`],
+    /// and the retry counts of the windows that ended, see [`GovernorConfigBuilder::retry_budget`].
+    ///
+    /// Call this periodically, e.g. from a background task, to bound the memory used by clients
+    /// that stopped sending requests.
+    pub fn retain_recent(&self) {
+        self.cleanup().run();
+    }
+
+    /// The rate limiting state of the keys whose quotas aren't fully replenished, e.g. to inspect it
+    /// or to hand it over to another instance, see [`save_state`](Self::save_state).
+    pub fn export_state(&self) -> Snapshot<K::Key> {
+        let mut snapshot = Snapshot::default();
+        let mut add = |scope: String, bucket: &KeyedStore<K::Key>, quota: &Quota| {
+            let keys = bucket.export(quota);
+            if !keys.is_empty() {
+                snapshot.limiters.insert(scope, keys);
+            }
+        };
+        add(String::from("default"), &self.store, &self.quota);
+        for (method, bucket) in self.method_limiters.iter() {
+            add(format!("method:{}", method), &bucket.store, &bucket.quota);
+        }
+        for (i, bucket) in self.additional_limiters.iter().enumerate() {
+            add(format!("additional:{}", i), &bucket.store, &bucket.quota);
+        }
+        for bucket in self.key_buckets.iter() {
+            add(quota_scope(&bucket.quota), &bucket.store, &bucket.quota);
+        }
+        snapshot
+    }
+
+    /// Consume the tokens of the keys of `snapshot`, taken by [`export_state`](Self::export_state),
+    /// see [`restore_state`](Self::restore_state).
+    pub fn import_state(&self, snapshot: &Snapshot<K::Key>) {
+        for (scope, keys) in &snapshot.limiters {
+            if scope == "default" {
+                self.store.import(keys);
+            } else if let Some(method) = scope.strip_prefix("method:") {
+                if let Some((_, bucket)) = self
+                    .method_limiters
+                    .iter()
+                    .find(|(m, _)| m.as_str() == method)
+                {
+                    bucket.store.import(keys);
+                }
+            } else if let Some(i) = scope.strip_prefix("additional:") {
+                if let Some(bucket) = i
+                    .parse()
+                    .ok()
+                    .and_then(|i: usize| self.additional_limiters.get(i))
+                {
+                    bucket.store.import(keys);
+                }
+            } else if let Some(quota) = scope.strip_prefix("quota:").and_then(parse_quota_scope) {
+                quota_bucket(&self.key_buckets, &self.store, quota)
+                    .store
+                    .import(keys);
+            }
+        }
+    }
+
+    /// Write the pending updates of the rate limiters to their stores and stop the background tasks
+    /// of the stores, e.g. when the instance shuts down, so a distributed store like the
+    /// [`GossipStore`](crate::gossip::GossipStore) doesn't lose the requests checked since its last
+    /// exchange, see [`GovernorStore::shutdown`]. Requests can still be checked afterwards.
+    ///
+    /// Stores writing every update right away, like the default one, have nothing to do.
+    pub fn shutdown(&self) {
+        let cleanup = self.cleanup();
+        let overflow = self
+            .overflow
+            .as_ref()
+            .map(|overflow| &overflow.bucket.store);
+        for store in cleanup.stores.iter().chain(overflow) {
+            store.shutdown();
+        }
+    }
+
+    /// The keys rejected the most over the window of [`GovernorConfigBuilder::track_offenders`], the
+    /// most rejected first, none if it isn't set.
+    pub fn top_offenders(&self) -> Vec<Offender<K::Key>> {
+        self.offenders
+            .as_ref()
+            .map_or_else(Vec::new, |offenders| offenders.top())
+    }
+
+    /// Write the rate limiting state of every key to `path` as JSON, e.g. on shutdown, so that
+    /// [`restore_state`](Self::restore_state) can keep consuming the tokens after a restart instead
+    /// of giving every key a full quota again.
     ///
-    /// By default `x-ratelimit-after` and `retry-after` are enabled, with [`use_headers`] will enable `x-ratelimit-limit`, `x-ratelimit-whitelisted` and `x-ratelimit-remaining`
+    /// The time each key gets its full quota back is saved as a wall-clock time, so the downtime
+    /// counts as time replenishing. The state of [shared](GovernorStore::is_shared) stores, which
+    /// outlives the instance, isn't saved.
+    pub fn save_state(&self, path: impl AsRef<Path>) -> io::Result<()>
+    where
+        K::Key: Serialize,
+    {
+        let path = path.as_ref();
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        // Never leave a partially written state behind.
+        fs::write(&temporary, serde_json::to_vec(&self.export_state())?)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Consume the tokens of the keys saved in `path` by [`save_state`](Self::save_state), e.g. on
+    /// startup. Keys that consumed more tokens since keep their state, and the rate limiters that
+    /// aren't part of this configuration anymore, e.g. removed method quotas, are ignored.
     ///
-    /// [`methods`]: crate::GovernorConfigBuilder::methods()
-    /// [`use_headers`]: Self::use_headers
-    pub fn use_headers(&mut self) -> GovernorConfigBuilder<K, StateInformationMiddleware> {
-        GovernorConfigBuilder {
-            period: self.period,
-            burst_size: self.burst_size,
-            methods: self.methods.to_owned(),
-            key_extractor: self.key_extractor.clone(),
-            error_handler: self.error_handler.clone(),
-            middleware: PhantomData,
-        }
+    /// A missing file isn't an error, every key has its full quota then.
+    pub fn restore_state(&self, path: impl AsRef<Path>) -> io::Result<()>
+    where
+        K::Key: DeserializeOwned,
+    {
+        let text = match fs::read(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        self.import_state(&serde_json::from_slice(&text)?);
+        Ok(())
     }
 
-    /// Finish building the configuration and return the configuration for the middleware.
-    /// Returns `None` if either burst size or period interval are zero.
-    pub fn finish(&mut self) -> Option<GovernorConfig<K, M>> {
-        if self.burst_size != 0 && self.period.as_nanos() != 0 {
-            Some(GovernorConfig {
-                key_extractor: self.key_extractor.clone(),
-                limiter: Arc::new(
-                    RateLimiter::keyed(
-                        Quota::with_period(self.period)
-                            .unwrap()
-                            .allow_burst(NonZeroU32::new(self.burst_size).unwrap()),
-                    )
-                    .with_middleware::<M>(),
-                ),
-                methods: self.methods.clone(),
-                error_handler: self.error_handler.clone(),
+    fn cleanup(&self) -> Cleanup<K::Key> {
+        Cleanup::new(
+            iter::once(&self.store)
+                .chain(self.response_bytes.iter().map(|b| &b.store))
+                .chain(self.request_bytes.iter().map(|b| &b.store)),
+            &self.method_limiters,
+            &self.additional_limiters,
+            &self.key_buckets,
+            &self.levels,
+            &self.bans,
+            &self.options,
+        )
+        .retries(&self.retries, &self.options)
+    }
+
+    /// This configuration, continuing the rate limiting state of `previous`: keys keep the tokens
+    /// they consumed, method and additional quotas being matched by method and position.
+    pub(crate) fn carry_over(mut self, previous: &Self) -> Self {
+        let main = Bucket::<K, M> {
+            limiter: previous.limiter.clone(),
+            store: previous.store.clone(),
+            quota: previous.quota,
+            tier: None,
+        }
+        .migrate(self.quota);
+        self.limiter = main.limiter;
+        self.store = main.store;
+        self.method_limiters = self
+            .method_limiters
+            .iter()
+            .map(|(method, bucket)| {
+                let bucket = match previous.method_limiters.iter().find(|(m, _)| m == method) {
+                    Some((_, previous)) => previous.migrate(bucket.quota),
+                    None => bucket.clone(),
+                };
+                (method.clone(), bucket)
             })
-        } else {
-            None
+            .collect();
+        self.additional_limiters = self
+            .additional_limiters
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| match previous.additional_limiters.get(i) {
+                Some(previous) => previous.migrate(bucket.quota),
+                None => bucket.clone(),
+            })
+            .collect();
+        // Their rate limiters are keyed by quota, they apply as is.
+        self.key_buckets = previous.key_buckets.clone();
+        self.bans = previous.bans.clone();
+        self.retries = previous.retries.clone();
+        self.in_flight = previous.in_flight.clone();
+        self.queued = previous.queued.clone();
+        if let (Some(offenders), Some((top, window))) =
+            (&previous.offenders, self.options.offender_tracking)
+        {
+            if offenders.tracks(top, window) {
+                self.offenders = Some(offenders.clone());
+            }
+        }
+        if previous.options.key_cap == self.options.key_cap {
+            self.overflow = previous.overflow.clone();
+        }
+        if let (Some(global), Some(previous)) = (&self.global, &previous.global) {
+            self.global = Some(previous.migrate(global.quota));
+        }
+        if let (Some(bytes), Some(previous)) = (&self.response_bytes, &previous.response_bytes) {
+            self.response_bytes = Some(previous.migrate(bytes.quota));
+        }
+        if let (Some(bytes), Some(previous)) = (&self.request_bytes, &previous.request_bytes) {
+            self.request_bytes = Some(previous.migrate(bytes.quota));
         }
+        self.levels = self
+            .levels
+            .iter()
+            .map(
+                |level| match previous.levels.iter().find(|p| p.name == level.name) {
+                    Some(previous) => Level {
+                        bucket: previous.bucket.migrate(level.bucket.quota),
+                        ..level.clone()
+                    },
+                    None => level.clone(),
+                },
+            )
+            .collect();
+        Arc::make_mut(&mut self.options).grace_until = previous.options.grace_until;
+        self
     }
 }
 
-#[derive(Debug, Clone)]
-/// Configuration for the Governor middleware.
-pub struct GovernorConfig<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> {
-    key_extractor: K,
-    limiter: SharedRateLimiter<K::Key, M>,
-    methods: Option<Vec<Method>>,
-    error_handler: ErrorHandler,
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S> Governor<K, M, S> {
+    /// Run the cleanup of the state if the [`GovernorConfigBuilder::gc_interval`] elapsed since the
+    /// last one, or the [`GovernorConfigBuilder::gc_threshold`] is reached, unless one is running.
+    pub(crate) fn collect_garbage(&self) {
+        let Options {
+            gc_interval,
+            gc_threshold,
+            ..
+        } = *self.options;
+        if (gc_interval.is_none() && gc_threshold.is_none())
+            || self.gc.running.load(Ordering::Relaxed)
+        {
+            return;
+        }
+        let due = gc_interval.is_some_and(|interval| {
+            // Another request is checking.
+            self.gc
+                .last
+                .try_lock()
+                .is_ok_and(|last| last.elapsed() >= interval)
+        }) || gc_threshold.is_some_and(|threshold| self.limiter.len() >= threshold);
+        if !due || self.gc.running.swap(true, Ordering::Acquire) {
+            return;
+        }
+        *self.gc.last.lock().unwrap() = Instant::now();
+        let gc = self.gc.clone();
+        let cleanup = Cleanup::new(
+            iter::once(&self.store)
+                .chain(self.response_bytes.iter().map(|b| &b.store))
+                .chain(self.request_bytes.iter().map(|b| &b.store)),
+            &self.method_limiters,
+            &self.additional_limiters,
+            &self.key_buckets,
+            &self.levels,
+            &self.bans,
+            &self.options,
+        )
+        .retries(&self.retries, &self.options);
+        let run = move || {
+            cleanup.run();
+            gc.running.store(false, Ordering::Release);
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(run)),
+            Err(_) => run(),
+        }
+    }
 }
 
-impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> GovernorConfig<K, M> {
-    pub fn limiter(&self) -> &SharedRateLimiter<K::Key, M> {
-        &self.limiter
+// The rate limiter of the keys with their own `quota`, other than the main one.
+fn quota_bucket<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>>(
+    key_buckets: &KeyBuckets<K, M>,
+    store: &KeyedStore<K::Key>,
+    quota: Quota,
+) -> Bucket<K, M> {
+    key_buckets
+        .entry((quota.replenish_interval(), quota.burst_size().get()))
+        .or_insert_with(|| Bucket::new(quota, store.scoped(&quota_scope(&quota))))
+        .clone()
+}
+
+// The scope of the rate limiter of `quota` in the store and snapshots.
+fn quota_scope(quota: &Quota) -> String {
+    format!(
+        "quota:{}:{}",
+        quota.replenish_interval().as_nanos(),
+        quota.burst_size()
+    )
+}
+
+// The quota of a `quota_scope` without its `quota:` prefix.
+fn parse_quota_scope(scope: &str) -> Option<Quota> {
+    let (nanos, burst) = scope.split_once(':')?;
+    let period = Duration::from_nanos(nanos.parse().ok()?);
+    Some(Quota::with_period(period)?.allow_burst(burst.parse().ok()?))
+}
+
+// The state dropped by `GovernorConfig::retain_recent`, shared with the configuration.
+struct Cleanup<Key: Hash + Eq> {
+    stores: Vec<KeyedStore<Key>>,
+    level_stores: Vec<KeyedStore<String>>,
+    bans: Bans<Key>,
+    retries: Option<(Retries<Key>, Duration)>,
+    idle_ttl: Duration,
+    ban_window: Duration,
+}
+
+impl<Key: Clone + Hash + Eq + Send + Sync + 'static> Cleanup<Key> {
+    fn new<'a, K: KeyExtractor<Key = Key>, M: RateLimitingMiddleware<QuantaInstant>>(
+        stores: impl Iterator<Item = &'a KeyedStore<Key>>,
+        method_limiters: &[(Method, Bucket<K, M>)],
+        additional_limiters: &[Bucket<K, M>],
+        key_buckets: &KeyBuckets<K, M>,
+        levels: &[Level],
+        bans: &Bans<Key>,
+        options: &Options,
+    ) -> Self {
+        let stores = stores
+            .cloned()
+            .chain(method_limiters.iter().map(|(_, b)| b.store.clone()))
+            .chain(additional_limiters.iter().map(|b| b.store.clone()))
+            .chain(key_buckets.iter().map(|b| b.store.clone()))
+            .collect();
+        Self {
+            stores,
+            level_stores: levels.iter().map(|l| l.bucket.store.clone()).collect(),
+            bans: bans.clone(),
+            retries: None,
+            idle_ttl: options.idle_ttl,
+            ban_window: options.auto_ban.map_or(Duration::ZERO, |ban| ban.window),
+        }
+    }
+
+    // Also drop the retry counts of the windows that ended, see `GovernorConfigBuilder::retry_budget`.
+    fn retries(mut self, retries: &Retries<Key>, options: &Options) -> Self {
+        self.retries = options
+            .retry_budget
+            .map(|budget| (retries.clone(), budget.window));
+        self
+    }
+
+    fn run(&self) {
+        for store in &self.stores {
+            store.retain_active(self.idle_ttl);
+        }
+        for store in &self.level_stores {
+            store.retain_active(self.idle_ttl);
+        }
+        let now = Instant::now();
+        self.bans.retain(|_, violations| {
+            violations.banned_until.is_some_and(|until| until > now)
+                || now.duration_since(violations.window_start) <= self.ban_window
+        });
+        if let Some((retries, window)) = &self.retries {
+            retries.retain(|_, counts| now.duration_since(counts.window_start) <= *window);
+        }
     }
 }
 
@@ -312,6 +3184,16 @@ impl<M: RateLimitingMiddleware<QuantaInstant>> GovernorConfig<PeerIpKeyExtractor
             methods: None,
             key_extractor: PeerIpKeyExtractor,
             error_handler: ErrorHandler::default(),
+            rejection_body: RejectionBody::default(),
+            on_allowed: OnAllowed::default(),
+            quota_provider: QuotaOverrides::default(),
+            tier_of: TierOf::default(),
+            high_priority: HighPriorityOf::default(),
+            failure_policy: FailurePolicy::default(),
+            exempt_keys: HashSet::new(),
+            blocklist: None,
+            store: StoreBackend::default(),
+            options: Options::default(),
             middleware: PhantomData,
         }
         .finish()
@@ -319,6 +3201,19 @@ impl<M: RateLimitingMiddleware<QuantaInstant>> GovernorConfig<PeerIpKeyExtractor
     }
 }
 
+// The `Content-Length` of `req`, if valid.
+fn content_length<B>(req: &Request<B>) -> Option<u64> {
+    req.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|hv| hv.to_str().ok())
+        .and_then(|s| s.parse::<u64>().ok())
+}
+
+/// Round a duration up to whole seconds.
+pub(crate) fn as_secs_ceil(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
 /// Governor middleware factory. Hand this a GovernorConfig and it'll create this struct, which
 /// contains everything needed to implement a middleware
 /// https://stegosaurusdormant.com/understanding-derive-clone/
@@ -328,7 +3223,34 @@ pub struct Governor<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S
     pub limiter: SharedRateLimiter<K::Key, M>,
     pub methods: Option<Vec<Method>>,
     pub inner: S,
+    pub(crate) store: KeyedStore<K::Key>,
+    pub(crate) quota: Quota,
+    method_limiters: Arc<[(Method, Bucket<K, M>)]>,
+    additional_limiters: Arc<[Bucket<K, M>]>,
     error_handler: ErrorHandler,
+    rejection_body: RejectionBody<K::Key>,
+    on_allowed: OnAllowed<K::Key>,
+    quota_provider: QuotaOverrides<K::Key>,
+    tier_of: TierOf<K::Key>,
+    high_priority: HighPriorityOf<K::Key>,
+    failure_policy: FailurePolicy<K::Key>,
+    exempt_keys: Arc<HashSet<K::Key>>,
+    blocklist: Option<Blocklist<K::Key>>,
+    key_buckets: KeyBuckets<K, M>,
+    bans: Bans<K::Key>,
+    retries: Retries<K::Key>,
+    in_flight: InFlight<K::Key>,
+    queued: InFlight<K::Key>,
+    offenders: Option<Arc<Offenders<K::Key>>>,
+    overflow: Option<Arc<Overflow<K, M>>>,
+    global: Option<GlobalBucket>,
+    levels: Arc<[Level]>,
+    response_bytes: Option<Bucket<K, NoOpMiddleware>>,
+    request_bytes: Option<Bucket<K, NoOpMiddleware>>,
+    gc: Arc<Gc>,
+    pub(crate) options: Arc<Options>,
+    // The wait for the global quota of this copy of the service, see `Self::poll_global`.
+    backpressure: Option<Pin<Box<Sleep>>>,
 }
 
 impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S: Clone> Clone
@@ -340,7 +3262,33 @@ impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S: Clone> Clone
             limiter: self.limiter.clone(),
             methods: self.methods.clone(),
             inner: self.inner.clone(),
+            store: self.store.clone(),
+            quota: self.quota,
+            method_limiters: self.method_limiters.clone(),
+            additional_limiters: self.additional_limiters.clone(),
             error_handler: self.error_handler.clone(),
+            rejection_body: self.rejection_body.clone(),
+            on_allowed: self.on_allowed.clone(),
+            quota_provider: self.quota_provider.clone(),
+            tier_of: self.tier_of.clone(),
+            high_priority: self.high_priority.clone(),
+            failure_policy: self.failure_policy.clone(),
+            exempt_keys: self.exempt_keys.clone(),
+            blocklist: self.blocklist.clone(),
+            key_buckets: self.key_buckets.clone(),
+            bans: self.bans.clone(),
+            retries: self.retries.clone(),
+            in_flight: self.in_flight.clone(),
+            queued: self.queued.clone(),
+            offenders: self.offenders.clone(),
+            overflow: self.overflow.clone(),
+            global: self.global.clone(),
+            response_bytes: self.response_bytes.clone(),
+            request_bytes: self.request_bytes.clone(),
+            levels: self.levels.clone(),
+            gc: self.gc.clone(),
+            options: self.options.clone(),
+            backpressure: None,
         }
     }
 }
@@ -353,11 +3301,1129 @@ impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S> Governor<K, M
             limiter: config.limiter.clone(),
             methods: config.methods.clone(),
             inner,
+            store: config.store.clone(),
+            quota: config.quota,
+            method_limiters: config.method_limiters.clone(),
+            additional_limiters: config.additional_limiters.clone(),
             error_handler: config.error_handler.clone(),
+            rejection_body: config.rejection_body.clone(),
+            on_allowed: config.on_allowed.clone(),
+            quota_provider: config.quota_provider.clone(),
+            tier_of: config.tier_of.clone(),
+            high_priority: config.high_priority.clone(),
+            failure_policy: config.failure_policy.clone(),
+            exempt_keys: config.exempt_keys.clone(),
+            blocklist: config.blocklist.clone(),
+            key_buckets: config.key_buckets.clone(),
+            bans: config.bans.clone(),
+            retries: config.retries.clone(),
+            in_flight: config.in_flight.clone(),
+            queued: config.queued.clone(),
+            offenders: config.offenders.clone(),
+            overflow: config.overflow.clone(),
+            global: config.global.clone(),
+            response_bytes: config.response_bytes.clone(),
+            request_bytes: config.request_bytes.clone(),
+            levels: config.levels.clone(),
+            gc: config.gc.clone(),
+            options: config.options.clone(),
+            backpressure: None,
+        }
+    }
+
+    /// Switch to `config`, keeping the inner service.
+    pub(crate) fn reconfigure(&mut self, config: &GovernorConfig<K, M>) {
+        self.key_extractor = config.key_extractor.clone();
+        self.limiter = config.limiter.clone();
+        self.methods = config.methods.clone();
+        self.store = config.store.clone();
+        self.quota = config.quota;
+        self.method_limiters = config.method_limiters.clone();
+        self.additional_limiters = config.additional_limiters.clone();
+        self.error_handler = config.error_handler.clone();
+        self.rejection_body = config.rejection_body.clone();
+        self.on_allowed = config.on_allowed.clone();
+        self.quota_provider = config.quota_provider.clone();
+        self.tier_of = config.tier_of.clone();
+        self.high_priority = config.high_priority.clone();
+        self.failure_policy = config.failure_policy.clone();
+        self.exempt_keys = config.exempt_keys.clone();
+        self.blocklist = config.blocklist.clone();
+        self.key_buckets = config.key_buckets.clone();
+        self.bans = config.bans.clone();
+        self.retries = config.retries.clone();
+        self.in_flight = config.in_flight.clone();
+        self.queued = config.queued.clone();
+        self.offenders = config.offenders.clone();
+        self.overflow = config.overflow.clone();
+        self.global = config.global.clone();
+        self.response_bytes = config.response_bytes.clone();
+        self.request_bytes = config.request_bytes.clone();
+        self.levels = config.levels.clone();
+        self.gc = config.gc.clone();
+        self.options = config.options.clone();
+    }
+
+    /// Number of tokens `req` consumes from the quota.
+    pub(crate) fn request_cost(&self, req: &Request<Incoming>) -> NonZeroU32 {
+        if let Some(extractor) = &self.options.cost_extractor.0 {
+            return extractor.cost(req);
+        }
+        let Some(bytes_per_token) = self.options.bytes_per_token else {
+            return NonZeroU32::MIN;
+        };
+        content_length(req)
+            .map(|len| len.div_ceil(u64::from(bytes_per_token)))
+            .and_then(|cost| NonZeroU32::new(cost.try_into().unwrap_or(u32::MAX)))
+            .unwrap_or(NonZeroU32::MIN)
+    }
+
+    /// The bytes `req` is charged, see [`GovernorConfigBuilder::request_bytes_quota`],
+    /// `None` if it isn't set or if the request has no body or no `Content-Length`.
+    pub(crate) fn body_len<B>(&self, req: &Request<B>) -> Option<NonZeroU32> {
+        self.request_bytes.as_ref()?;
+        NonZeroU32::new(content_length(req)?.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// The rate limiter requests with `method` from `key` are checked against, see
+    /// [`GovernorConfigBuilder::method_quota`], [`GovernorConfigBuilder::tier_of`],
+    /// [`GovernorConfigBuilder::quota_provider`] and [`GovernorConfigBuilder::load_adaptive`].
+    pub(crate) fn bucket(&self, method: &Method, key: Option<&K::Key>) -> Bucket<K, M> {
+        let bucket = self.configured_bucket(method, key);
+        let Some(signal) = &self.options.load_signal.0 else {
+            return bucket;
+        };
+        // Percent of the quota left at the current load, rounded to tenths.
+        let load = (signal().clamp(0.0, 1.0) * 10.0).round() as u32;
+        let percent = 100 - (100 - u32::from(self.options.min_quota_percent)) * load / 10;
+        if percent == 100 {
+            return bucket;
+        }
+        let burst_size = u64::from(bucket.quota.burst_size().get()) * u64::from(percent) / 100;
+        let burst_size = burst_size.max(1) as u32;
+        let period = bucket.quota.replenish_interval() * 100 / percent;
+        let mut scaled = self.key_bucket(quota_of(period, burst_size));
+        scaled.tier = bucket.tier;
+        scaled
+    }
+
+    // The rate limiter of the configured quotas, before adapting them to the load.
+    fn configured_bucket(&self, method: &Method, key: Option<&K::Key>) -> Bucket<K, M> {
+        if let Some((_, bucket)) = self.method_limiters.iter().find(|(m, _)| m == method) {
+            return bucket.clone();
+        }
+        if let (Some(tier_of), Some(key)) = (&self.tier_of.0, key) {
+            let tier = tier_of(key).and_then(|name| {
+                self.options
+                    .tiers
+                    .iter()
+                    .find(|(tier, _)| tier.as_str() == name)
+            });
+            if let Some((tier, quota)) = tier {
+                let mut bucket = self.key_bucket(self.options.worker_quota(*quota));
+                bucket.tier = Some(tier.clone());
+                return bucket;
+            }
+        }
+        if let (Some(provider), Some(key)) = (&self.quota_provider.0, key) {
+            let quota = self.options.worker_quota(provider.quota_for(key));
+            if quota != self.quota {
+                return self.key_bucket(quota);
+            }
+        }
+        if let Some(quota) = self.scheduled_quota() {
+            return self.key_bucket(self.options.worker_quota(quota));
+        }
+        Bucket {
+            limiter: self.limiter.clone(),
+            store: self.store.clone(),
+            quota: self.quota,
+            tier: None,
+        }
+    }
+
+    // The scheduled quota applying now, see `GovernorConfigBuilder::scheduled_quota`.
+    fn scheduled_quota(&self) -> Option<Quota> {
+        if self.options.schedule.is_empty() {
+            return None;
+        }
+        let utc = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let minutes = (utc / 60 + i64::from(self.options.schedule_utc_offset)) as u64;
+        let (days, hour) = (minutes / (24 * 60), (minutes / 60 % 24) as u8);
+        // The epoch was a Thursday.
+        let weekday = ((days + 3) % 7) as u32;
+        self.options
+            .schedule
+            .iter()
+            .find(|scheduled| scheduled.applies(weekday, hour))
+            .map(|scheduled| scheduled.quota)
+    }
+
+    // The rate limiter of the keys with their own `quota`.
+    fn key_bucket(&self, quota: Quota) -> Bucket<K, M> {
+        if quota == self.quota {
+            return Bucket {
+                limiter: self.limiter.clone(),
+                store: self.store.clone(),
+                quota,
+                tier: None,
+            };
+        }
+        quota_bucket(&self.key_buckets, &self.store, quota)
+    }
+
+    /// Check `cost` tokens for `key` against the limiter of `bucket`, returning how long to wait when denied.
+    ///
+    /// A cost larger than the burst size can never be accommodated, this is the outer error.
+    pub(crate) fn check_key_n(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+    ) -> Result<Result<M::PositiveOutcome, Duration>, GovernorError>
+    where
+        M: RateLimitingMiddleware<QuantaInstant, NegativeOutcome = NotUntil<QuantaInstant>>,
+    {
+        match bucket.limiter.check_key_n(key, cost) {
+            Ok(Ok(outcome)) => Ok(Ok(outcome)),
+            Ok(Err(negative)) => Ok(Err(negative.wait_time_from(DefaultClock::default().now()))),
+            Err(_) => Err(GovernorError::CostExceedsBurst {
+                cost: cost.get(),
+                burst_size: bucket.quota.burst_size().get(),
+            }),
+        }
+    }
+
+    /// Check `cost` tokens for `key` against `bucket`, returning the state of its quota when allowed,
+    /// or how long to wait.
+    fn check_bucket(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+    ) -> Result<Result<RateLimitInfo, Duration>, GovernorError>
+    where
+        M: GovernorMiddleware,
+    {
+        if let Some(checked) = bucket.check_window(key, cost) {
+            return checked;
+        }
+        Ok(self
+            .check_key_n(bucket, key, cost)?
+            .map(|outcome| self.rate_limit_info(bucket, key, &outcome)))
+    }
+
+    /// Check `cost` tokens for `key` against `bucket` and the additional quotas, see [`GovernorConfigBuilder::additional_quota`],
+    /// then against the global quota, the `levels` of the request, see [`Self::level_keys`], and the
+    /// quota of request bytes with the `body_len` of the request, see [`Self::body_len`].
+    /// Requests that aren't of `high_priority` are denied the reserve of the global quota.
+    ///
+    /// Returns the state of the most restrictive quota when allowed, or how long to wait for the quota denying the request.
+    /// Tokens taken from the other quotas are given back when the request is denied.
+    pub(crate) fn check(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+        levels: &[(usize, String)],
+        body_len: Option<NonZeroU32>,
+        high_priority: bool,
+    ) -> Result<CheckOutcome, GovernorError>
+    where
+        M: GovernorMiddleware,
+    {
+        if let Some(bytes) = &self.response_bytes {
+            // At least a byte must be left, the size of the response isn't known yet. The byte is
+            // only probed, the response is charged once sent.
+            match bytes.check_n(key, NonZeroU32::MIN)? {
+                Ok(_) => bytes.refund(key, NonZeroU32::MIN),
+                Err(wait) => return Ok(Err((wait, bytes.quota, None))),
+            }
+        }
+        let mut info = match self.check_bucket(bucket, key, cost)? {
+            Ok(info) => info,
+            Err(wait) => return Ok(Err((wait, bucket.quota, None))),
+        };
+        let mut quota = bucket.quota;
+        for (i, window) in self.additional_limiters.iter().enumerate() {
+            let denied = match self.check_bucket(window, key, cost) {
+                Ok(Ok(window_info)) => {
+                    if (window_info.remaining, Reverse(window_info.reset))
+                        < (info.remaining, Reverse(info.reset))
+                    {
+                        info = window_info;
+                        quota = window.quota;
+                    }
+                    continue;
+                }
+                Ok(Err(wait)) => Ok(Err((wait, window.quota, None))),
+                Err(e) => Err(e),
+            };
+            bucket.refund(key, cost);
+            for window in &self.additional_limiters[..i] {
+                window.refund(key, cost);
+            }
+            return denied;
+        }
+        let mut restrict = |checked: RateLimitInfo, checked_quota: Quota| {
+            if (checked.remaining, Reverse(checked.reset)) < (info.remaining, Reverse(info.reset)) {
+                info = checked;
+                quota = checked_quota;
+            }
+        };
+        // The global quota and the levels charged before the one denying the request.
+        let mut global_charged = false;
+        let mut levels_charged = 0;
+        let denied = 'denied: {
+            if let Some(global) = &self.global {
+                if let Some(wait) = self.reserve_wait(global, cost).filter(|_| !high_priority) {
+                    break 'denied Ok(Err((wait, global.quota, None)));
+                }
+                match global.check_n(&(), cost) {
+                    Ok(Ok(checked)) => restrict(checked, global.quota),
+                    Ok(Err(wait)) => break 'denied Ok(Err((wait, global.quota, None))),
+                    Err(e) => break 'denied Err(e),
+                }
+                global_charged = true;
+            }
+            for (i, level_key) in levels {
+                let level = &self.levels[*i];
+                match level.bucket.check_n(level_key, cost) {
+                    Ok(Ok(checked)) => restrict(checked, level.bucket.quota),
+                    Ok(Err(wait)) => {
+                        break 'denied Ok(Err((wait, level.bucket.quota, Some(level.name.clone()))))
+                    }
+                    Err(e) => break 'denied Err(e),
+                }
+                levels_charged += 1;
+            }
+            if let (Some(bytes), Some(body_len)) = (&self.request_bytes, body_len) {
+                match bytes.check_n(key, body_len) {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(wait)) => break 'denied Ok(Err((wait, bytes.quota, None))),
+                    Err(e) => break 'denied Err(e),
+                }
+            }
+            return Ok(Ok((info, quota)));
+        };
+
+        bucket.refund(key, cost);
+        for window in self.additional_limiters.iter() {
+            window.refund(key, cost);
+        }
+        if let Some(global) = self.global.as_ref().filter(|_| global_charged) {
+            global.refund(&(), cost);
+        }
+        for (i, level_key) in &levels[..levels_charged] {
+            self.levels[*i].bucket.refund(level_key, cost);
+        }
+        denied
+    }
+
+    /// [`check`](Self::check), delaying the requests that would have to wait up to the
+    /// [`GovernorConfigBuilder::max_delay`] rather than rejecting them, unless the queue of their
+    /// key is full, see [`GovernorConfigBuilder::max_queued`]: their tokens are taken right away
+    /// and they are allowed with the state of the quota denying them and their delay.
+    pub(crate) fn check_or_delay(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+        levels: &[(usize, String)],
+        body_len: Option<NonZeroU32>,
+        high_priority: bool,
+    ) -> Result<DelayOutcome, GovernorError>
+    where
+        K::Key: Send + Sync + 'static,
+        M: GovernorMiddleware,
+    {
+        let (wait, quota, level) =
+            match self.check(bucket, key, cost, levels, body_len, high_priority)? {
+                Ok((info, quota)) => return Ok(Ok((info, quota, None))),
+                Err(denied) => denied,
+            };
+        if self.options.max_delay.is_none_or(|max| wait > max) || !self.enforces(Some(key)) {
+            return Ok(Err((wait, quota, level)));
+        }
+        let slot = match self.options.max_queued {
+            Some(max) => match InFlightSlot::take(&self.queued, key, max) {
+                Some(slot) => Some(slot),
+                None => return Ok(Err((wait, quota, level))),
+            },
+            None => None,
+        };
+        self.reserve(bucket, key, cost, levels, body_len);
+        let info = RateLimitInfo {
+            limit: quota.burst_size().get(),
+            remaining: 0,
+            reset: wait,
+        };
+        Ok(Ok((info, quota, Some(Delay::new(wait, slot)))))
+    }
+
+    // Take `cost` tokens from `key` in every quota `check` checks, even beyond their burst size.
+    fn reserve(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+        levels: &[(usize, String)],
+        body_len: Option<NonZeroU32>,
+    ) {
+        for bucket in iter::once(bucket).chain(self.additional_limiters.iter()) {
+            let (store, amount) = bucket.refund_of(cost);
+            store.charge(key, amount);
+        }
+        if let Some(global) = &self.global {
+            let (store, amount) = global.refund_of(cost);
+            store.charge(&(), amount);
+        }
+        for (i, level_key) in levels {
+            let (store, amount) = self.levels[*i].bucket.refund_of(cost);
+            store.charge(level_key, amount);
+        }
+        if let (Some(bytes), Some(body_len)) = (&self.request_bytes, body_len) {
+            let (store, amount) = bytes.refund_of(body_len);
+            store.charge(key, amount);
+        }
+    }
+
+    /// Wait while the global quota has no request left, see
+    /// [`GovernorConfigBuilder::global_backpressure`].
+    pub(crate) fn poll_global(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            if let Some(sleep) = &mut self.backpressure {
+                ready!(sleep.as_mut().poll(cx));
+                self.backpressure = None;
+            }
+            let Some(global) = self
+                .global
+                .as_ref()
+                .filter(|_| self.options.global_backpressure && !self.options.dry_run)
+            else {
+                return Poll::Ready(());
+            };
+            if global.store.remaining_burst_capacity(&(), &global.quota) > 0 {
+                return Poll::Ready(());
+            }
+            let wait = global.quota.replenish_interval();
+            self.backpressure = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+
+    // How long requests that aren't high priority wait for `cost` tokens above the reserve of the
+    // global quota, see `GovernorConfigBuilder::global_reserve`.
+    fn reserve_wait(&self, global: &GlobalBucket, cost: NonZeroU32) -> Option<Duration> {
+        let burst_size = u64::from(global.quota.burst_size().get());
+        let reserved = burst_size * u64::from(self.options.global_reserve) / 100;
+        if reserved == 0 {
+            return None;
+        }
+        let remaining = u64::from(global.store.remaining_burst_capacity(&(), &global.quota));
+        let missing = (reserved + u64::from(cost.get())).checked_sub(remaining)?;
+        (missing > 0).then(|| {
+            let missing = u32::try_from(missing).unwrap_or(u32::MAX);
+            global.quota.replenish_interval().saturating_mul(missing)
+        })
+    }
+
+    /// Whether `req` of `key` is high priority, see [`GovernorConfigBuilder::global_reserve`].
+    pub(crate) fn is_high_priority<B>(&self, req: &Request<B>, key: &K::Key) -> bool {
+        req.extensions().get::<HighPriority>().is_some()
+            || self
+                .high_priority
+                .0
+                .as_ref()
+                .is_some_and(|is_high_priority| is_high_priority(key))
+    }
+
+    /// The levels `req` is charged to with its key in each of them, see [`GovernorConfigBuilder::level`].
+    pub(crate) fn level_keys(&self, req: &Request<Incoming>) -> Vec<(usize, String)> {
+        self.levels
+            .iter()
+            .enumerate()
+            .filter_map(|(i, level)| Some((i, level.key.0.as_ref()?(req)?)))
+            .collect()
+    }
+
+    /// The wait time advertised to clients, see [`GovernorConfigBuilder::max_retry_after`].
+    fn advertised_wait_time(&self, wait_time: Duration) -> Duration {
+        match self.options.max_retry_after {
+            Some(max) => wait_time.min(max),
+            None => wait_time,
+        }
+    }
+
+    /// The wait time in whole seconds, as advertised to clients.
+    pub(crate) fn wait_time_secs(&self, wait_time: Duration) -> u64 {
+        let wait_time = self.advertised_wait_time(wait_time);
+        if self.options.round_up_wait_time {
+            as_secs_ceil(wait_time)
+        } else {
+            wait_time.as_secs()
+        }
+    }
+
+    /// Headers suggested for a rejection by `quota` with the given wait time.
+    pub(crate) fn rejection_headers(
+        &self,
+        quota: &Quota,
+        tier: Option<&PlanTier>,
+        level: Option<&LimitLevel>,
+        wait_time: Duration,
+    ) -> HeaderMap
+    where
+        M: GovernorMiddleware,
+    {
+        let reset = as_secs_ceil(wait_time);
+        let advertised_wait_time = self.advertised_wait_time(wait_time);
+        let wait_time_ms = advertised_wait_time.as_millis();
+        let wait_time = self.wait_time_secs(wait_time);
+        let mut headers = HeaderMap::new();
+        if self.options.hide_headers {
+            return headers;
+        }
+        if self.options.after_ms_header {
+            headers.insert(
+                self.options.header_names.after_ms.clone(),
+                HeaderValue::from(u64::try_from(wait_time_ms).unwrap_or(u64::MAX)),
+            );
+        }
+        if self.options.retry_after != RetryAfter::Only {
+            headers.insert(
+                self.options.header_names.after.clone(),
+                HeaderValue::from(wait_time),
+            );
+        }
+        if self.options.retry_after != RetryAfter::Disabled {
+            let retry_after = if self.options.retry_after_http_date {
+                let wait_time = Duration::from_secs(as_secs_ceil(advertised_wait_time));
+                HeaderValue::try_from(httpdate::fmt_http_date(SystemTime::now() + wait_time))
+                    .unwrap()
+            } else {
+                HeaderValue::from(wait_time)
+            };
+            headers.insert(RETRY_AFTER, retry_after);
+        }
+        if self.use_headers() {
+            self.quota_headers(&mut headers, quota, quota.burst_size().get(), 0, reset);
+        }
+        self.tier_header(&mut headers, tier);
+        if let Some(value) = level.and_then(|level| HeaderValue::try_from(level.as_str()).ok()) {
+            headers.insert(self.options.header_names.level.clone(), value);
+        }
+        self.expose(&mut headers);
+        headers
+    }
+
+    /// Whether quota headers are added to responses.
+    pub(crate) fn use_headers(&self) -> bool
+    where
+        M: GovernorMiddleware,
+    {
+        (M::USE_HEADERS || self.options.lightweight_headers) && !self.options.hide_headers
+    }
+
+    /// The quota state of `key` in `bucket` after an allowed request.
+    pub(crate) fn rate_limit_info(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        outcome: &M::PositiveOutcome,
+    ) -> RateLimitInfo
+    where
+        M: GovernorMiddleware,
+    {
+        let (limit, remaining) = M::snapshot(outcome).unwrap_or_else(|| {
+            (
+                bucket.quota.burst_size().get(),
+                bucket.store.remaining_burst_capacity(key, &bucket.quota),
+            )
+        });
+        RateLimitInfo {
+            limit,
+            remaining,
+            reset: bucket.quota.replenish_interval() * (limit - remaining),
+        }
+    }
+
+    /// Headers describing the state of `quota` after an allowed request, if any are enabled.
+    pub(crate) fn allowed_headers(
+        &self,
+        quota: &Quota,
+        tier: Option<&PlanTier>,
+        info: &RateLimitInfo,
+    ) -> Option<HeaderMap>
+    where
+        M: GovernorMiddleware,
+    {
+        let warning_threshold = self.options.warning_threshold;
+        if self.options.hide_headers
+            || !self.use_headers() && warning_threshold.is_none() && tier.is_none()
+        {
+            return None;
+        }
+        let mut headers = HeaderMap::new();
+        if self.use_headers() {
+            let reset = as_secs_ceil(info.reset);
+            self.quota_headers(&mut headers, quota, info.limit, info.remaining, reset);
+        }
+        if let Some(threshold) = warning_threshold {
+            let consumed = u64::from(info.limit - info.remaining) * 100 / u64::from(info.limit);
+            if consumed >= u64::from(threshold) {
+                headers.insert(
+                    self.options.header_names.warning.clone(),
+                    HeaderValue::from(consumed),
+                );
+            }
+        }
+        self.tier_header(&mut headers, tier);
+        self.expose(&mut headers);
+        (!headers.is_empty()).then_some(headers)
+    }
+
+    fn tier_header(&self, headers: &mut HeaderMap, tier: Option<&PlanTier>) {
+        let Some(value) = tier.and_then(|tier| HeaderValue::try_from(tier.as_str()).ok()) else {
+            return;
+        };
+        headers.insert(self.options.header_names.tier.clone(), value);
+    }
+
+    /// The error rejecting `req` if it is blocked, see [`GovernorConfigBuilder::blocklist`].
+    pub(crate) fn blocked<B>(
+        &self,
+        req: &Request<B>,
+        key: Option<&K::Key>,
+    ) -> Option<GovernorError> {
+        let blocklist = self.blocklist.as_ref()?;
+        if !blocklist.blocks(key, self.key_extractor.client_ip(req)) {
+            return None;
+        }
+        Some(GovernorError::Other {
+            code: blocklist.rejection_status(),
+            msg: Some("Blocked".to_owned()),
+            headers: None,
+        })
+    }
+
+    /// How long `key` remains banned, see [`GovernorConfigBuilder::auto_ban`].
+    pub(crate) fn ban_remaining(&self, key: &K::Key) -> Option<Duration> {
+        self.options.auto_ban?;
+        let now = Instant::now();
+        let banned_until = self.bans.get(key)?.banned_until?;
+        if banned_until > now {
+            return Some(banned_until - now);
+        }
+        self.bans.remove_if(key, |_, violations| {
+            violations.banned_until == Some(banned_until)
+        });
+        None
+    }
+
+    /// A slot for a request of `key`, or the error rejecting it if it has the maximum of requests in
+    /// flight, see [`GovernorConfigBuilder::max_in_flight`].
+    pub(crate) fn in_flight_slot(
+        &self,
+        key: &K::Key,
+    ) -> Result<Option<InFlightSlot<K::Key>>, GovernorError> {
+        let Some(max) = self.options.max_in_flight else {
+            return Ok(None);
+        };
+        match InFlightSlot::take(&self.in_flight, key, max) {
+            Some(slot) => Ok(Some(slot)),
+            None => Err(GovernorError::Other {
+                code: StatusCode::TOO_MANY_REQUESTS,
+                msg: Some("Too many requests in flight".to_owned()),
+                headers: None,
+            }),
+        }
+    }
+
+    /// Count `req` of `key` against the retry budget of the key, rejecting it if it's a retry beyond
+    /// the budget, see [`GovernorConfigBuilder::retry_budget`].
+    pub(crate) fn check_retry_budget<B>(
+        &self,
+        req: &Request<B>,
+        key: &K::Key,
+    ) -> Result<(), GovernorError> {
+        let Some(budget) = self.options.retry_budget else {
+            return Ok(());
+        };
+        let retry = self.is_retry(req);
+        let now = Instant::now();
+        let fresh = || RetryCounts {
+            window_start: now,
+            requests: 0,
+            retries: 0,
+        };
+        let mut counts = self.retries.entry(key.clone()).or_insert_with(fresh);
+        if now.duration_since(counts.window_start) > budget.window {
+            *counts = fresh();
+        }
+        if !retry {
+            counts.requests = counts.requests.saturating_add(1);
+            return Ok(());
+        }
+        let allowed = u64::from(budget.min_retries)
+            + u64::from(counts.requests) * u64::from(budget.percent) / 100;
+        if u64::from(counts.retries) < allowed {
+            counts.retries += 1;
+            return Ok(());
+        }
+        let remaining = budget
+            .window
+            .saturating_sub(now.duration_since(counts.window_start));
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(as_secs_ceil(remaining)));
+        Err(GovernorError::Other {
+            code: StatusCode::TOO_MANY_REQUESTS,
+            msg: Some("Retry budget exceeded".to_owned()),
+            headers: Some(headers),
+        })
+    }
+
+    // Whether `req` is a retry, see `GovernorConfigBuilder::retry_budget`.
+    fn is_retry<B>(&self, req: &Request<B>) -> bool {
+        if req.extensions().get::<RetryAttempt>().is_some() {
+            return true;
+        }
+        let header = match &self.options.retry_header {
+            Some(header) => req.headers().get(header),
+            None => req.headers().get(DEFAULT_RETRY_HEADER),
+        };
+        header.is_some_and(|value| value.as_bytes().trim_ascii() != b"0")
+    }
+
+    /// Count a rejection of `key`, banning it after too many, see [`GovernorConfigBuilder::auto_ban`].
+    pub(crate) fn record_violation(&self, key: &K::Key) {
+        let Some(auto_ban) = self.options.auto_ban else {
+            return;
+        };
+        let now = Instant::now();
+        let mut violations = self.bans.entry(key.clone()).or_insert(Violations {
+            window_start: now,
+            count: 0,
+            banned_until: None,
+        });
+        if now.duration_since(violations.window_start) > auto_ban.window {
+            violations.window_start = now;
+            violations.count = 0;
+        }
+        violations.count += 1;
+        if violations.count >= auto_ban.violations {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                "Banning {} for {}s after {} rate limit violations",
+                self.key_extractor.name(),
+                auto_ban.duration.as_secs(),
+                violations.count
+            );
+            violations.count = 0;
+            violations.banned_until = Some(now + auto_ban.duration);
+        }
+    }
+
+    /// The rate limiter and key to check the requests of `key` with, when it is a new key beyond the
+    /// [`GovernorConfigBuilder::key_cap`].
+    pub(crate) fn overflow(&self, key: &K::Key) -> Option<(Bucket<K, M>, K::Key)> {
+        let overflow = self.overflow.as_ref()?;
+        if self.limiter.len() < overflow.max_keys || self.store.contains(key) {
+            return None;
+        }
+        let key = overflow.key.get_or_init(|| key.clone()).clone();
+        Some((overflow.bucket.clone(), key))
+    }
+
+    /// Count a rejection of `key` among the offenders, see [`GovernorConfigBuilder::track_offenders`].
+    pub(crate) fn record_offender(&self, key: &K::Key) {
+        if let Some(offenders) = &self.offenders {
+            offenders.record(key);
+        }
+    }
+
+    /// The error rejecting the requests of a key banned for `remaining`.
+    pub(crate) fn ban_error(&self, remaining: Duration) -> GovernorError {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from(as_secs_ceil(remaining)));
+        GovernorError::Other {
+            code: StatusCode::FORBIDDEN,
+            msg: Some("Temporarily banned after repeated rate limit violations".to_owned()),
+            headers: Some(headers),
+        }
+    }
+
+    /// Whether `key` is never rate limited, see [`GovernorConfigBuilder::exempt_key`].
+    pub(crate) fn is_exempt_key(&self, key: &K::Key) -> bool {
+        self.exempt_keys.contains(key)
+    }
+
+    /// What happens to requests whose key can't be extracted, see [`GovernorConfigBuilder::failure_policy`].
+    pub(crate) fn failure_policy(&self) -> &FailurePolicy<K::Key> {
+        &self.failure_policy
+    }
+
+    /// Whether rejections of `key` are enforced, see [`GovernorConfigBuilder::dry_run`],
+    /// [`GovernorConfigBuilder::enforced_percent`] and [`GovernorConfigBuilder::startup_grace`].
+    pub(crate) fn enforces(&self, key: Option<&K::Key>) -> bool {
+        if self.options.dry_run
+            || self
+                .options
+                .grace_until
+                .is_some_and(|until| Instant::now() < until)
+        {
+            return false;
+        }
+        match (self.options.enforced_percent, key) {
+            (Some(percent), Some(key)) => {
+                let mut hasher = Fnv1a::default();
+                key.hash(&mut hasher);
+                hasher.finish() % 100 < u64::from(percent)
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether `req` bypasses rate limiting, see [`GovernorBypass`], [`GovernorConfigBuilder::exempt_path`],
+    /// [`GovernorConfigBuilder::exempt_network`] and [`GovernorConfigBuilder::skip_preflight`].
+    pub(crate) fn is_exempt<B>(&self, req: &Request<B>) -> bool {
+        if req.extensions().get::<GovernorBypass>().is_some() {
+            return true;
+        }
+        if self.options.skip_preflight
+            && req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return true;
+        }
+        let path = req.uri().path();
+        if self
+            .options
+            .exempt_paths
+            .iter()
+            .any(|matcher| matcher.matches(path))
+        {
+            return true;
+        }
+        // The peer address, forwarding headers can be spoofed.
+        !self.options.exempt_networks.is_empty()
+            && req.extensions().get::<SocketAddr>().is_some_and(|peer| {
+                // IPv4 clients of dual stack listeners have IPv4-mapped addresses.
+                let ip = peer.ip().to_canonical();
+                self.options
+                    .exempt_networks
+                    .iter()
+                    .any(|network| network.contains(&ip))
+            })
+    }
+
+    /// Whether `req` is rate limited, see [`GovernorConfigBuilder::methods`] and [`GovernorConfigBuilder::filter`].
+    pub(crate) fn is_rate_limited(&self, req: &Request<Incoming>) -> bool {
+        self.methods
+            .as_ref()
+            .is_none_or(|methods| methods.contains(req.method()))
+            && self
+                .options
+                .request_filter
+                .0
+                .as_ref()
+                .is_none_or(|predicate| predicate(req))
+    }
+
+    /// Headers marking a request that isn't rate limited, if enabled.
+    pub(crate) fn whitelisted_headers(&self) -> Option<HeaderMap>
+    where
+        M: GovernorMiddleware,
+    {
+        if !self.use_headers() || self.options.hide_whitelisted_header {
+            return None;
+        }
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            self.options.header_names.whitelisted.clone(),
+            HeaderValue::from_static("true"),
+        );
+        self.expose(&mut headers);
+        Some(headers)
+    }
+
+    fn expose(&self, headers: &mut HeaderMap) {
+        if !self.options.expose_headers || headers.is_empty() {
+            return;
+        }
+        let names = headers.keys().map(HeaderName::as_str).collect::<Vec<_>>();
+        headers.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::try_from(names.join(", ")).unwrap(),
+        );
+    }
+
+    fn quota_headers(
+        &self,
+        headers: &mut HeaderMap,
+        quota: &Quota,
+        limit: u32,
+        remaining: u32,
+        reset: u64,
+    ) {
+        match self.options.header_style {
+            HeaderStyle::XRateLimit => {
+                headers.insert(
+                    self.options.header_names.limit.clone(),
+                    HeaderValue::from(limit),
+                );
+                headers.insert(
+                    self.options.header_names.remaining.clone(),
+                    HeaderValue::from(remaining),
+                );
+                let reset = match self.options.reset_format {
+                    ResetFormat::DeltaSeconds => reset,
+                    ResetFormat::EpochSeconds => SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |now| now.as_secs() + reset),
+                };
+                headers.insert(
+                    self.options.header_names.reset.clone(),
+                    HeaderValue::from(reset),
+                );
+            }
+            HeaderStyle::IetfDraft => {
+                headers.insert(
+                    HeaderName::from_static("ratelimit-limit"),
+                    HeaderValue::from(limit),
+                );
+                headers.insert(
+                    HeaderName::from_static("ratelimit-remaining"),
+                    HeaderValue::from(remaining),
+                );
+                headers.insert(
+                    HeaderName::from_static("ratelimit-reset"),
+                    HeaderValue::from(reset),
+                );
+            }
+        }
+        if self.options.policy_header {
+            let window = as_secs_ceil(quota.burst_size_replenished_in());
+            headers.insert(
+                HeaderName::from_static("ratelimit-policy"),
+                HeaderValue::try_from(format!("{};w={}", quota.burst_size(), window)).unwrap(),
+            );
+        }
+    }
+
+    /// Hook giving `cost` tokens of `bucket`, of the additional quotas and of the `levels` back to `key`
+    /// if the response was served from cache, when [`GovernorConfigBuilder::cache_hit_refunds`] is enabled,
+    /// if it doesn't count, see [`GovernorConfigBuilder::count_responses`], or if it is a server error,
+    /// when [`GovernorConfigBuilder::server_error_refunds`] is enabled.
+    pub(crate) fn refund_hook(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+        levels: Vec<(usize, String)>,
+    ) -> Option<ResponseHook>
+    where
+        K::Key: Send + Sync + 'static,
+    {
+        if !self.options.cache_hit_refunds
+            && !self.options.server_error_refunds
+            && self.options.counted_responses.0.is_none()
+        {
+            return None;
+        }
+        let refunds = iter::once(bucket.refund_of(cost))
+            .chain(
+                self.additional_limiters
+                    .iter()
+                    .map(|window| window.refund_of(cost)),
+            )
+            .collect::<Vec<_>>();
+        let global_refund = self.global.as_ref().map(|global| global.refund_of(cost));
+        let level_refunds = levels
+            .into_iter()
+            .map(|(i, level_key)| (self.levels[i].bucket.refund_of(cost), level_key))
+            .collect::<Vec<_>>();
+        let options = self.options.clone();
+        let key = key.clone();
+        Some(ResponseHook::new(move |response| {
+            let counted = &options.counted_responses.0;
+            let uncounted = counted
+                .as_ref()
+                .is_some_and(|counted| !counted(response.status()));
+            let header_hit = options
+                .cache_hit_header
+                .as_ref()
+                .is_some_and(|(name, value)| {
+                    response
+                        .headers()
+                        .get(name)
+                        .is_some_and(|hv| hv.as_bytes().eq_ignore_ascii_case(value.as_bytes()))
+                });
+            let cache_hit = options.cache_hit_refunds
+                && (header_hit || response.extensions().get::<CacheHit>().is_some());
+            let server_error = options.server_error_refunds && response.status().is_server_error();
+            if cache_hit || uncounted || server_error {
+                for (store, amount) in &refunds {
+                    store.refund(&key, *amount);
+                }
+                if let Some((store, amount)) = &global_refund {
+                    store.refund(&(), *amount);
+                }
+                for ((store, amount), level_key) in &level_refunds {
+                    store.refund(level_key, *amount);
+                }
+            }
+        }))
+    }
+
+    /// Hook taking the extra tokens of `cost` from `bucket` and the additional quotas of `key` if the
+    /// authentication failed, see [`GovernorConfigBuilder::auth_failure_penalty`].
+    pub(crate) fn penalty_hook(
+        &self,
+        bucket: &Bucket<K, M>,
+        key: &K::Key,
+        cost: NonZeroU32,
+    ) -> Option<ResponseHook>
+    where
+        K::Key: Send + Sync + 'static,
+    {
+        let extra = u64::from(self.options.auth_failure_penalty? - 1);
+        if extra == 0 {
+            return None;
+        }
+        let penalties = iter::once(bucket)
+            .chain(self.additional_limiters.iter())
+            .map(|bucket| {
+                let (store, amount) = bucket.refund_of(cost);
+                (store, amount * extra)
+            })
+            .collect::<Vec<_>>();
+        let key = key.clone();
+        Some(ResponseHook::new(move |response| {
+            if matches!(
+                response.status(),
+                StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+            ) {
+                for (store, amount) in &penalties {
+                    store.charge(&key, *amount);
+                }
+            }
+        }))
+    }
+
+    /// Hook charging the bytes of the response body to `key` as they're sent, see
+    /// [`GovernorConfigBuilder::response_bytes_quota`].
+    pub(crate) fn response_bytes_hook(&self, key: &K::Key) -> Option<ResponseHook>
+    where
+        K::Key: Send + Sync + 'static,
+    {
+        let bytes = self.response_bytes.as_ref()?;
+        let (store, per_byte) = bytes.refund_of(NonZeroU32::MIN);
+        let key = key.clone();
+        Some(ResponseHook::new(move |response| {
+            let body = mem::take(response.body_mut());
+            *response.body_mut() = HttpBody::new(MeteredBody::new(body, move |len| {
+                store.charge(&key, per_byte * len)
+            }));
+        }))
+    }
+
+    /// Hook calling the [`GovernorConfigBuilder::on_allowed`] function, if set.
+    pub(crate) fn on_allowed_hook(&self, key: &K::Key, info: RateLimitInfo) -> Option<ResponseHook>
+    where
+        K::Key: Send + Sync + 'static,
+    {
+        let on_allowed = self.on_allowed.0.clone()?;
+        let key = key.clone();
+        Some(ResponseHook::new(move |response| {
+            on_allowed(&key, &info, response)
+        }))
+    }
+
+    /// Response rejecting a request of `key` that has to wait `wait_time` for `quota` of `level`, `headers` being the request's headers.
+    pub(crate) fn rejection_response(
+        &self,
+        quota: &Quota,
+        tier: Option<&PlanTier>,
+        level: Option<&LimitLevel>,
+        key: &K::Key,
+        wait_time: Duration,
+        headers: &HeaderMap,
+    ) -> Response<HttpBody>
+    where
+        M: GovernorMiddleware,
+    {
+        let rejection_headers = self.rejection_headers(quota, tier, level, wait_time);
+        match &self.rejection_body.0 {
+            Some(rejection_body) if self.error_handler.0.is_none() => {
+                let body = rejection_body(key, self.advertised_wait_time(wait_time), quota);
+                let mut response = Response::new(body);
+                *response.status_mut() = self
+                    .options
+                    .rejection_status
+                    .unwrap_or(StatusCode::TOO_MANY_REQUESTS);
+                *response.headers_mut() = rejection_headers;
+                response
+            }
+            _ => self.error_response(
+                quota,
+                GovernorError::TooManyRequests {
+                    wait_time: self.wait_time_secs(wait_time),
+                    headers: Some(rejection_headers),
+                },
+                headers,
+            ),
         }
     }
 
-    pub(crate) fn error_handler(&self) -> &(dyn Fn(GovernorError) -> Response<Body> + Send + Sync) {
-        &*self.error_handler.0
+    /// Turn `error` into a response to a request with `headers` limited by `quota`, using the custom error handler if set.
+    pub(crate) fn error_response(
+        &self,
+        quota: &Quota,
+        mut error: GovernorError,
+        headers: &HeaderMap,
+    ) -> Response<HttpBody> {
+        if let Some(error_handler) = &self.error_handler.0 {
+            return error_handler(error);
+        }
+        let negotiated = match self.options.negotiate_error_format {
+            true => headers
+                .get_all(ACCEPT)
+                .iter()
+                .filter_map(|accept| accept.to_str().ok())
+                .find_map(ErrorFormat::negotiate),
+            false => None,
+        };
+        let status = match error {
+            GovernorError::TooManyRequests { .. } => self.options.rejection_status,
+            GovernorError::UnableToExtractKey => self.options.extraction_failure_status,
+            GovernorError::CostExceedsBurst { .. } | GovernorError::Other { .. } => None,
+        };
+        let limit = quota.burst_size().get();
+        let localized = headers
+            .get_all(ACCEPT_LANGUAGE)
+            .iter()
+            .filter_map(|accept_language| accept_language.to_str().ok())
+            .find_map(|accept_language| {
+                negotiate_language(accept_language, &self.options.localized_messages)
+            });
+        let mut response = match negotiated.unwrap_or(self.options.error_format) {
+            ErrorFormat::PlainText => match localized {
+                Some(message) => error.as_text_template_response(message, limit),
+                None => error.as_default_response(),
+            },
+            ErrorFormat::Json => error.as_json_response(limit),
+            ErrorFormat::ProblemJson => error.problem_json_response(limit, status),
+            ErrorFormat::Html => match &self.options.html_template {
+                Some(template) => error.as_html_template_response(template, limit),
+                None => error.as_html_response(limit),
+            },
+        };
+        if let Some(status) = status {
+            *response.status_mut() = status;
+        }
+        response
     }
 }
@@ -0,0 +1,231 @@
+//! A [`CostExtractor`] deriving the cost of GraphQL queries from their complexity, so that a deeply
+//! nested query consumes more of the quota than a trivial one.
+//!
+//! Only `GET` requests are costed by the middleware, from their `query` URL parameter. It hands
+//! the body of requests to the inner service as hyper received it, an [`Incoming`] body that
+//! can't be rebuilt once read, so `POST` requests cost the default cost whatever their query.
+//! To cost them, a gateway buffering the body can compute the cost with
+//! [`GraphQlCost::body_cost`] and pass it on in a header read by
+//! [`HeaderCost`](crate::cost::HeaderCost).
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, graphql::GraphQlCost};
+//! use std::num::NonZeroU32;
+//!
+//! // Queries cost one token per field, those nested deeper than 8 levels 100 tokens.
+//! let config = GovernorConfigBuilder::default()
+//!     .burst_size(200)
+//!     .cost_extractor(GraphQlCost::new(8, NonZeroU32::new(100).unwrap()))
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::cost::CostExtractor;
+use http::{Method, Request};
+use hyper::body::Incoming;
+use std::{iter::Peekable, num::NonZeroU32, str::Chars};
+
+/// A [`CostExtractor`] charging GraphQL queries one token per field, see the
+/// [module documentation](self).
+///
+/// Fields are counted where they appear, those of a fragment once however often it is spread.
+/// Queries nested deeper than the maximum depth cost the maximum cost, as do the ones with more
+/// fields, so a maximum cost above the burst size rejects them with
+/// [`GovernorError::CostExceedsBurst`](crate::GovernorError::CostExceedsBurst).
+/// Queries with an unclosed argument list, whose fields can't be told from their arguments, cost
+/// the maximum cost as well. `GET` requests without a query and all other requests cost the
+/// default cost, 1 unless set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphQlCost {
+    max_depth: usize,
+    max_cost: NonZeroU32,
+    default: NonZeroU32,
+}
+
+impl GraphQlCost {
+    /// Charge queries up to `max_cost`, the cost of queries nested deeper than `max_depth`.
+    pub fn new(max_depth: usize, max_cost: NonZeroU32) -> Self {
+        Self {
+            max_depth,
+            max_cost,
+            default: NonZeroU32::MIN,
+        }
+    }
+
+    /// Set the cost of requests without a query, and of requests other than `GET`.
+    pub fn default_cost(mut self, default: NonZeroU32) -> Self {
+        self.default = default;
+        self
+    }
+
+    /// The cost of the GraphQL document `query`.
+    pub fn query_cost(&self, query: &str) -> NonZeroU32 {
+        let Some((fields, depth)) = complexity(query) else {
+            return self.max_cost;
+        };
+        if depth > self.max_depth {
+            return self.max_cost;
+        }
+        NonZeroU32::new(fields.try_into().unwrap_or(u32::MAX))
+            .unwrap_or(NonZeroU32::MIN)
+            .min(self.max_cost)
+    }
+
+    /// The cost of the JSON `body` of a `POST` request, `{"query": "..."}`, or the sum of the costs
+    /// of a batch of them up to the maximum cost. Bodies without a query cost the default cost.
+    pub fn body_cost(&self, body: &[u8]) -> NonZeroU32 {
+        let cost = |operation: &serde_json::Value| {
+            operation
+                .get("query")
+                .and_then(serde_json::Value::as_str)
+                .map(|query| self.query_cost(query))
+        };
+        let cost = match serde_json::from_slice(body) {
+            Ok(serde_json::Value::Array(batch)) => batch
+                .iter()
+                .filter_map(cost)
+                .reduce(|total, cost| total.saturating_add(cost.get()).min(self.max_cost)),
+            Ok(operation) => cost(&operation),
+            Err(_) => None,
+        };
+        cost.unwrap_or(self.default)
+    }
+}
+
+impl CostExtractor for GraphQlCost {
+    fn cost(&self, req: &Request<Incoming>) -> NonZeroU32 {
+        if req.method() != Method::GET {
+            return self.default;
+        }
+        let query = req.uri().query().and_then(|params| {
+            params.split('&').find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                (name == "query").then(|| percent_decode(value))
+            })
+        });
+        match query {
+            Some(query) => self.query_cost(&query),
+            None => self.default,
+        }
+    }
+}
+
+// What the previous token makes of the next name.
+#[derive(PartialEq)]
+enum Previous {
+    Other,
+    // A directive, `@name`.
+    At,
+    // A fragment spread, `...name`, or an inline fragment, `... on Type`.
+    Spread,
+    // The type condition of an inline fragment.
+    On,
+}
+
+// The number of fields of `query` and the depth of its deepest selection set, if its argument
+// lists are all closed.
+fn complexity(query: &str) -> Option<(usize, usize)> {
+    let (mut fields, mut depth, mut max_depth, mut arguments) = (0, 0usize, 0, 0usize);
+    let mut previous = Previous::Other;
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => {
+                chars.by_ref().find(|&c| c == '\n');
+            }
+            '"' => skip_string(&mut chars),
+            '(' => arguments += 1,
+            // Unbalanced parentheses don't close argument lists that weren't opened.
+            ')' => arguments = arguments.saturating_sub(1),
+            // Arguments, e.g. input objects, aren't selections.
+            _ if arguments > 0 => {}
+            '{' => {
+                depth += 1;
+                max_depth = max_depth.max(depth);
+                previous = Previous::Other;
+            }
+            '}' => {
+                // Unbalanced braces don't go below the top level.
+                depth = depth.saturating_sub(1);
+                previous = Previous::Other;
+            }
+            '.' => {
+                chars.next_if_eq(&'.');
+                chars.next_if_eq(&'.');
+                previous = Previous::Spread;
+            }
+            '@' => previous = Previous::At,
+            c if c == '_' || c.is_ascii_alphabetic() => {
+                let mut name = String::from(c);
+                while let Some(c) = chars.next_if(|&c| c == '_' || c.is_ascii_alphanumeric()) {
+                    name.push(c);
+                }
+                while chars.next_if(|&c| c.is_whitespace() || c == ',').is_some() {}
+                let alias = chars.peek() == Some(&':');
+                previous = match previous {
+                    Previous::Spread if name == "on" => Previous::On,
+                    Previous::Other if depth > 0 && !alias => {
+                        fields += 1;
+                        Previous::Other
+                    }
+                    _ => Previous::Other,
+                };
+            }
+            c if c.is_whitespace() || c == ',' => {}
+            _ => previous = Previous::Other,
+        }
+    }
+    (arguments == 0).then_some((fields, max_depth))
+}
+
+// Skip a string whose opening quote was read, be it a block string or not.
+fn skip_string(chars: &mut Peekable<Chars<'_>>) {
+    if chars.next_if_eq(&'"').is_some() {
+        if chars.next_if_eq(&'"').is_none() {
+            // An empty string.
+            return;
+        }
+        let mut quotes = 0;
+        for c in chars.by_ref() {
+            quotes = if c == '"' { quotes + 1 } else { 0 };
+            if quotes == 3 {
+                return;
+            }
+        }
+        return;
+    }
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' | '\n' => return,
+            _ => {}
+        }
+    }
+}
+
+// Decode a URL encoded parameter value, keeping invalid escapes as they are.
+fn percent_decode(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => match rest
+                .get(..2)
+                .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok())
+            {
+                Some(decoded) => {
+                    bytes.push(decoded);
+                    rest = &rest[2..];
+                }
+                None => bytes.push(b),
+            },
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -1,15 +1,17 @@
 use crate::errors::GovernorError;
 use forwarded_header_value::{ForwardedHeaderValue, Identifier};
 use http::request::Request;
-use http::{header::FORWARDED, HeaderMap};
-use std::fmt::Debug;
+use http::{header::FORWARDED, HeaderMap, HeaderName, StatusCode};
+use serde::Deserialize;
+use std::fmt::{self, Debug};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use std::{hash::Hash, net::IpAddr};
 
 /// Generic structure of what is needed to extract a rate-limiting key from an incoming request.
 pub trait KeyExtractor: Clone {
     /// The type of the key.
-    type Key: Clone + Hash + Eq + Debug;
+    type Key: Clone + Hash + Eq + Debug + Send + Sync + 'static;
 
     /// The type of the error that can occur if key extraction from the request fails.
     /// Should be one of the predefined GovernorErrors, or GovernorError::Other
@@ -20,6 +22,12 @@ pub trait KeyExtractor: Clone {
     /// Extraction method, will return [`GovernorError`] response when the extract failed
     fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError>;
 
+    /// The IP address of the client, for the key extractors keying requests by IP address.
+    /// It is checked against the [`Blocklist`](crate::blocklist::Blocklist) before extracting the key.
+    fn client_ip<T>(&self, _req: &Request<T>) -> Option<IpAddr> {
+        None
+    }
+
     #[cfg(feature = "tracing")]
     /// Value of the extracted key (only used in tracing).
     fn key_name(&self, _key: &Self::Key) -> Option<String> {
@@ -76,6 +84,10 @@ impl KeyExtractor for PeerIpKeyExtractor {
         maybe_connect_info(req).ok_or(GovernorError::UnableToExtractKey)
     }
 
+    fn client_ip<T>(&self, req: &Request<T>) -> Option<IpAddr> {
+        maybe_connect_info(req)
+    }
+
     #[cfg(feature = "tracing")]
     fn key_name(&self, key: &Self::Key) -> Option<String> {
         Some(key.to_string())
@@ -112,12 +124,298 @@ impl KeyExtractor for SmartIpKeyExtractor {
             .ok_or(GovernorError::UnableToExtractKey)
     }
 
+    fn client_ip<T>(&self, req: &Request<T>) -> Option<IpAddr> {
+        self.extract(req).ok()
+    }
+
     #[cfg(feature = "tracing")]
     fn key_name(&self, key: &Self::Key) -> Option<String> {
         Some(key.to_string())
     }
 }
 
+/// Default header carrying the operator identity set by Drosera relays.
+pub const DEFAULT_OPERATOR_HEADER: &str = "x-drosera-operator";
+/// Default header carrying the operator signature set by Drosera relays.
+pub const DEFAULT_OPERATOR_SIGNATURE_HEADER: &str = "x-drosera-signature";
+
+// function verifying that the signature header was produced by the operator.
+type SignatureVerifier = Arc<dyn Fn(&str, &str, &HeaderMap) -> bool + Send + Sync>;
+
+/// A [KeyExtractor] that uses the operator identity header set by Drosera relays as key.
+///
+/// The operator identity is trimmed and lowercased, so checksummed and plain addresses share the same quota.
+/// Requests without the header fail with [`GovernorError::UnableToExtractKey`].
+///
+/// Optionally, a signature header can be verified with [`verify_signature`](Self::verify_signature).
+/// Requests with a missing or invalid signature are rejected with `401 Unauthorized`.
+///
+/// **Warning:** without signature verification, only use this key extractor if the header is set by a trusted relay.
+///
+/// # Example
+///
+/// ```rust
+/// use jsonrpsee_tower_governor::key_extractor::OperatorHeaderKeyExtractor;
+///
+/// let extractor = OperatorHeaderKeyExtractor::default()
+///     .verify_signature(|operator, signature, _headers| {
+///         // check `signature` against `operator` here
+///         !operator.is_empty() && !signature.is_empty()
+///     });
+/// ```
+#[derive(Clone)]
+pub struct OperatorHeaderKeyExtractor {
+    header: HeaderName,
+    signature_header: HeaderName,
+    verifier: Option<SignatureVerifier>,
+}
+
+impl Default for OperatorHeaderKeyExtractor {
+    fn default() -> Self {
+        Self::new(HeaderName::from_static(DEFAULT_OPERATOR_HEADER))
+    }
+}
+
+impl fmt::Debug for OperatorHeaderKeyExtractor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OperatorHeaderKeyExtractor")
+            .field("header", &self.header)
+            .field("signature_header", &self.signature_header)
+            .field("verify_signature", &self.verifier.is_some())
+            .finish()
+    }
+}
+
+impl OperatorHeaderKeyExtractor {
+    /// Create an extractor reading the operator identity from `header`.
+    pub fn new(header: HeaderName) -> Self {
+        Self {
+            header,
+            signature_header: HeaderName::from_static(DEFAULT_OPERATOR_SIGNATURE_HEADER),
+            verifier: None,
+        }
+    }
+
+    /// Set the header carrying the operator signature.
+    /// By default this is `x-drosera-signature`.
+    pub fn signature_header(mut self, header: HeaderName) -> Self {
+        self.signature_header = header;
+        self
+    }
+
+    /// Require a valid signature for every request.
+    ///
+    /// The verifier is called with the trimmed operator identity as sent, before lowercasing,
+    /// the raw signature header value and the request headers.
+    pub fn verify_signature<F>(mut self, verifier: F) -> Self
+    where
+        F: Fn(&str, &str, &HeaderMap) -> bool + Send + Sync + 'static,
+    {
+        self.verifier = Some(Arc::new(verifier));
+        self
+    }
+}
+
+impl KeyExtractor for OperatorHeaderKeyExtractor {
+    type Key = String;
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        "operator header"
+    }
+
+    fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+        let headers = req.headers();
+        let operator = headers
+            .get(&self.header)
+            .and_then(|hv| hv.to_str().ok())
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .ok_or(GovernorError::UnableToExtractKey)?;
+
+        if let Some(verifier) = &self.verifier {
+            let verified = headers
+                .get(&self.signature_header)
+                .and_then(|hv| hv.to_str().ok())
+                .is_some_and(|signature| verifier(operator, signature.trim(), headers));
+            if !verified {
+                return Err(GovernorError::Other {
+                    code: StatusCode::UNAUTHORIZED,
+                    msg: Some("Invalid operator signature".to_string()),
+                    headers: None,
+                });
+            }
+        }
+
+        Ok(operator.to_ascii_lowercase())
+    }
+
+    #[cfg(feature = "tracing")]
+    fn key_name(&self, key: &Self::Key) -> Option<String> {
+        Some(key.clone())
+    }
+}
+
+/// A [KeyExtractor] keying requests with a recognized identity by `authenticated`, e.g. an
+/// [OperatorHeaderKeyExtractor], and the other requests by `anonymous`, the peer IP address by default.
+///
+/// Requests for which `authenticated` returns [`GovernorError::UnableToExtractKey`] are anonymous,
+/// other errors, e.g. an invalid signature, are returned as is.
+/// Use [`GovernorConfigBuilder::anonymous_quota`](crate::governor::GovernorConfigBuilder::anonymous_quota)
+/// to give anonymous requests a stricter quota.
+///
+/// # Example
+///
+/// ```rust
+/// use jsonrpsee_tower_governor::key_extractor::{AuthOrAnonymousKeyExtractor, OperatorHeaderKeyExtractor};
+///
+/// let extractor = AuthOrAnonymousKeyExtractor::new(OperatorHeaderKeyExtractor::default());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuthOrAnonymousKeyExtractor<A, B = PeerIpKeyExtractor> {
+    authenticated: A,
+    anonymous: B,
+}
+
+impl<A: KeyExtractor> AuthOrAnonymousKeyExtractor<A> {
+    /// Key anonymous requests by peer IP address.
+    pub fn new(authenticated: A) -> Self {
+        Self {
+            authenticated,
+            anonymous: PeerIpKeyExtractor,
+        }
+    }
+}
+
+impl<A: KeyExtractor, B: KeyExtractor> AuthOrAnonymousKeyExtractor<A, B> {
+    /// Set the key extractor of anonymous requests, e.g. [SmartIpKeyExtractor] behind a reverse proxy.
+    pub fn anonymous<B2: KeyExtractor>(self, anonymous: B2) -> AuthOrAnonymousKeyExtractor<A, B2> {
+        AuthOrAnonymousKeyExtractor {
+            authenticated: self.authenticated,
+            anonymous,
+        }
+    }
+}
+
+/// The key of [AuthOrAnonymousKeyExtractor].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AuthOrAnonymous<A, B> {
+    /// The identity of an authenticated request.
+    Authenticated(A),
+    /// The key of an anonymous request.
+    Anonymous(B),
+}
+
+impl<A, B> AuthOrAnonymous<A, B> {
+    /// Whether the request has no recognized identity.
+    pub fn is_anonymous(&self) -> bool {
+        matches!(self, Self::Anonymous(_))
+    }
+}
+
+impl<A: KeyExtractor, B: KeyExtractor> KeyExtractor for AuthOrAnonymousKeyExtractor<A, B> {
+    type Key = AuthOrAnonymous<A::Key, B::Key>;
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        "authenticated or anonymous"
+    }
+
+    fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+        match self.authenticated.extract(req) {
+            Ok(identity) => Ok(AuthOrAnonymous::Authenticated(identity)),
+            Err(GovernorError::UnableToExtractKey) => {
+                self.anonymous.extract(req).map(AuthOrAnonymous::Anonymous)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn client_ip<T>(&self, req: &Request<T>) -> Option<IpAddr> {
+        self.anonymous.client_ip(req)
+    }
+
+    #[cfg(feature = "tracing")]
+    fn key_name(&self, key: &Self::Key) -> Option<String> {
+        match key {
+            AuthOrAnonymous::Authenticated(identity) => self.authenticated.key_name(identity),
+            AuthOrAnonymous::Anonymous(key) => self.anonymous.key_name(key),
+        }
+    }
+}
+
+/// One of the built-in key extractors, selected by name.
+///
+/// This is the key extractor of configurations deserialized from the application's config files,
+/// see the `Deserialize` impl of [`GovernorConfigBuilder`](crate::governor::GovernorConfigBuilder).
+/// Names are written in snake case, e.g. `"smart_ip"`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NamedKeyExtractor {
+    /// [PeerIpKeyExtractor], the default.
+    #[default]
+    PeerIp,
+    /// [SmartIpKeyExtractor].
+    SmartIp,
+    /// [GlobalKeyExtractor].
+    Global,
+    /// [OperatorHeaderKeyExtractor] with the default header, without signature verification.
+    OperatorHeader,
+}
+
+/// The key of [NamedKeyExtractor].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NamedKey {
+    /// Key of [NamedKeyExtractor::PeerIp] and [NamedKeyExtractor::SmartIp].
+    Ip(IpAddr),
+    /// Key of [NamedKeyExtractor::Global].
+    Global,
+    /// Key of [NamedKeyExtractor::OperatorHeader].
+    Operator(String),
+}
+
+impl KeyExtractor for NamedKeyExtractor {
+    type Key = NamedKey;
+
+    #[cfg(feature = "tracing")]
+    fn name(&self) -> &'static str {
+        match self {
+            Self::PeerIp => PeerIpKeyExtractor.name(),
+            Self::SmartIp => SmartIpKeyExtractor.name(),
+            Self::Global => GlobalKeyExtractor.name(),
+            Self::OperatorHeader => "operator header",
+        }
+    }
+
+    fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+        match self {
+            Self::PeerIp => PeerIpKeyExtractor.extract(req).map(NamedKey::Ip),
+            Self::SmartIp => SmartIpKeyExtractor.extract(req).map(NamedKey::Ip),
+            Self::Global => GlobalKeyExtractor.extract(req).map(|()| NamedKey::Global),
+            Self::OperatorHeader => OperatorHeaderKeyExtractor::default()
+                .extract(req)
+                .map(NamedKey::Operator),
+        }
+    }
+
+    fn client_ip<T>(&self, req: &Request<T>) -> Option<IpAddr> {
+        match self {
+            Self::PeerIp => PeerIpKeyExtractor.client_ip(req),
+            Self::SmartIp => SmartIpKeyExtractor.client_ip(req),
+            Self::Global | Self::OperatorHeader => None,
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    fn key_name(&self, key: &Self::Key) -> Option<String> {
+        match key {
+            NamedKey::Ip(ip) => Some(ip.to_string()),
+            NamedKey::Global => None,
+            NamedKey::Operator(operator) => Some(operator.clone()),
+        }
+    }
+}
+
 // Utility functions for the SmartIpExtractor
 // Shamelessly snatched from the axum-client-ip crate here:
 // https://crates.io/crates/axum-client-ip
@@ -161,7 +459,5 @@ fn maybe_forwarded(headers: &HeaderMap) -> Option<IpAddr> {
 
 /// Looks in `ConnectInfo` extension
 fn maybe_connect_info<T>(req: &Request<T>) -> Option<IpAddr> {
-    req.extensions()
-        .get::<SocketAddr>()
-        .map(|addr| addr.ip())
+    req.extensions().get::<SocketAddr>().map(|addr| addr.ip())
 }
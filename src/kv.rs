@@ -0,0 +1,206 @@
+//! A [`GovernorStore`] keeping the rate limiter state in a managed key-value service with conditional
+//! writes, e.g. DynamoDB or Cloud Bigtable, for deployments where running Redis isn't an option.
+//!
+//! The service is accessed through the [`ConditionalKv`] trait, implemented with the client of its
+//! SDK. With DynamoDB for instance, a table with a string partition key `pk`, a number attribute
+//! `state` and time to live enabled on a number attribute `ttl`:
+//! - [`get`](ConditionalKv::get) is a `GetItem` with a consistent read, returning `state`,
+//! - [`put_if`](ConditionalKv::put_if) is a `PutItem` of `pk`, `state` and `ttl` with the condition
+//!   `attribute_not_exists(pk)` when there is no expected state, `#state = :expected` otherwise,
+//!   a `ConditionalCheckFailedException` meaning `Ok(false)`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::{
+//!     governor::GovernorConfigBuilder,
+//!     kv::{ConditionalKv, KvStore},
+//! };
+//! use std::{collections::HashMap, convert::Infallible, sync::Mutex, time::SystemTime};
+//!
+//! // Stands for the client of the service.
+//! #[derive(Default)]
+//! struct Table(Mutex<HashMap<String, u64>>);
+//!
+//! impl ConditionalKv for Table {
+//!     type Error = Infallible;
+//!
+//!     fn get(&self, key: &str) -> Result<Option<u64>, Infallible> {
+//!         Ok(self.0.lock().unwrap().get(key).copied())
+//!     }
+//!
+//!     fn put_if(
+//!         &self,
+//!         key: &str,
+//!         expected: Option<u64>,
+//!         state: u64,
+//!         _expires_at: SystemTime,
+//!     ) -> Result<bool, Infallible> {
+//!         let mut table = self.0.lock().unwrap();
+//!         if table.get(key).copied() != expected {
+//!             return Ok(false);
+//!         }
+//!         table.insert(key.to_owned(), state);
+//!         Ok(true)
+//!     }
+//! }
+//!
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(2)
+//!     .burst_size(5)
+//!     .store(KvStore::new(Table::default()).prefix("my-service"))
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::{
+    store::{blocking, Breaker, GovernorStore, MAX_ATTEMPTS},
+    BoxError,
+};
+use std::{
+    fmt,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A key-value service with conditional writes backing a [`KvStore`], see the [module documentation](self).
+///
+/// The calls are blocking, see [shared stores](crate::store#shared-stores), so give the client
+/// short timeouts.
+pub trait ConditionalKv: Send + Sync + 'static {
+    /// The error of the calls.
+    type Error: fmt::Display;
+
+    /// The state of `key`, `None` if it has none or if it expired.
+    fn get(&self, key: &str) -> Result<Option<u64>, Self::Error>;
+
+    /// Replace the state of `key` with `state` if it still is `expected`, `None` meaning that it has
+    /// none, returning whether it was replaced. The key may be deleted after `expires_at`.
+    fn put_if(
+        &self,
+        key: &str,
+        expected: Option<u64>,
+        state: u64,
+        expires_at: SystemTime,
+    ) -> Result<bool, Self::Error>;
+}
+
+/// A [`GovernorStore`] shared through a [`ConditionalKv`] service, see the [module documentation](self).
+///
+/// The state of a key is read, updated by the rate limiter and written back if it didn't change
+/// meanwhile, retrying a few times otherwise, and expires a second after the quota of the key is
+/// fully replenished. Instances must have synchronized clocks. When the service fails, or other
+/// instances keep changing a key, requests are allowed, see [shared stores](crate::store#shared-stores).
+///
+/// Keys are stored as `{prefix}:{scope}:{key}`, the key being formatted with [`Display`](fmt::Display).
+pub struct KvStore<C> {
+    client: Arc<C>,
+    prefix: String,
+    breaker: Arc<Breaker>,
+}
+
+impl<C: ConditionalKv> KvStore<C> {
+    /// A store calling `client`.
+    pub fn new(client: C) -> Self {
+        Self {
+            client: Arc::new(client),
+            prefix: String::from("governor"),
+            breaker: Arc::default(),
+        }
+    }
+
+    /// Set the prefix of the keys, `governor` by default, to keep the ones of several services apart.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn update(
+        &self,
+        key: &str,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        let failed = |e: C::Error| BoxError::from(e.to_string());
+        let updated = self.breaker.call(|| -> Result<bool, BoxError> {
+            blocking(|| {
+                for _ in 0..MAX_ATTEMPTS {
+                    let current = self.client.get(key).map_err(failed)?;
+                    let Some(new) = f(current) else {
+                        return Ok(true);
+                    };
+                    let expires_at =
+                        UNIX_EPOCH + Duration::from_nanos(new) + Duration::from_secs(1);
+                    if self
+                        .client
+                        .put_if(key, current, new, expires_at)
+                        .map_err(failed)?
+                    {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            })
+        })?;
+        match updated {
+            true => Ok(()),
+            false => Err("The state keeps being changed by other instances".into()),
+        }
+    }
+}
+
+impl<C> Clone for KvStore<C> {
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            prefix: self.prefix.clone(),
+            breaker: self.breaker.clone(),
+        }
+    }
+}
+
+impl<C: ConditionalKv, K: fmt::Display + Send + Sync + 'static> GovernorStore<K> for KvStore<C> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        if let Err(_e) = GovernorStore::try_measure_and_replace(self, key, f) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                "Unable to update the rate limiter state in the key-value service: {}",
+                _e
+            );
+            f(None);
+        }
+    }
+
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        self.update(&format!("{}:{}", self.prefix, key), f)
+    }
+
+    fn retain(&self, _keep: &mut dyn FnMut(&K, u64) -> bool) {
+        // Keys expire by themselves.
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            prefix: format!("{}:{}", self.prefix, scope),
+            ..self.clone()
+        })
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+impl<C> fmt::Debug for KvStore<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvStore")
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
@@ -3,26 +3,48 @@
 #[cfg(test)]
 mod tests;
 
+pub mod blocklist;
+pub mod cost;
 pub mod errors;
+#[cfg(feature = "gossip")]
+pub mod gossip;
 pub mod governor;
+#[cfg(feature = "graphql")]
+pub mod graphql;
 pub mod key_extractor;
-use crate::governor::{Governor, GovernorConfig};
+pub mod kv;
+#[cfg(feature = "memcached")]
+pub mod memcached;
+pub mod offenders;
+pub mod quota_cache;
+#[cfg(feature = "redis")]
+pub mod redis;
+pub mod registry;
+pub mod reload;
+#[cfg(feature = "ring")]
+pub mod ring;
+pub mod store;
+use crate::governor::{FailurePolicy, Governor, GovernorConfig, GovernorMiddleware};
 use ::governor::clock::{Clock, DefaultClock, QuantaInstant};
-use ::governor::middleware::{NoOpMiddleware, RateLimitingMiddleware, StateInformationMiddleware};
+use ::governor::middleware::RateLimitingMiddleware;
+use ::governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
 
+use bytes::Bytes;
 pub use errors::GovernorError;
-use http::header::{HeaderName, HeaderValue};
-use http::HeaderMap;
-use hyper::body::Incoming;
+use http::header::{ACCESS_CONTROL_EXPOSE_HEADERS, RETRY_AFTER};
+use http::{HeaderMap, HeaderValue};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
 use hyper::Request;
 use hyper::Response;
+use jsonrpsee::http_client::HttpBody;
 use key_extractor::KeyExtractor;
 use pin_project::pin_project;
+use serde::Deserialize;
 use std::sync::Arc;
 use std::task::{Context, Poll};
-use std::{future::Future, pin::Pin, task::ready};
+use std::time::Duration;
+use std::{fmt, future::Future, mem, pin::Pin, task::ready};
 use tower::{Layer, Service};
-use jsonrpsee::http_client::HttpBody;
 
 /// The Layer type that implements tower::Layer and is passed into `.layer()`
 pub struct GovernorLayer<K, M>
@@ -45,6 +67,20 @@ where
     }
 }
 
+impl<K, M> GovernorLayer<K, M>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    M: RateLimitingMiddleware<QuantaInstant> + Send + Sync + 'static,
+{
+    /// A layer sharing the configuration registered under `name`, registering the one built by `make`
+    /// if there is none, see [`registry::get_or_register`].
+    pub fn named(name: &str, make: impl FnOnce() -> GovernorConfig<K, M>) -> Self {
+        Self {
+            config: registry::get_or_register(name, make),
+        }
+    }
+}
+
 /// https://stegosaurusdormant.com/understanding-derive-clone/
 impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for GovernorLayer<K, M> {
     fn clone(&self) -> Self {
@@ -54,10 +90,12 @@ impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for Govern
     }
 }
 // Implement tower::Service for Governor
-impl<K, S> Service<Request<Incoming>> for Governor<K, NoOpMiddleware, S>
+impl<K, M, S> Service<Request<Incoming>> for Governor<K, M, S>
 where
     K: KeyExtractor,
-    S: Service<Request<Incoming>, Response = Response<HttpBody>>,
+    K::Key: Send + Sync + 'static,
+    M: GovernorMiddleware,
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
     S::Error: Into<BoxError>,
 {
     type Response = S::Response;
@@ -65,175 +103,646 @@ where
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Unless the global quota is exhausted with backpressure enabled, our middleware
+        // is ready as long as the inner service is ready.
+        ready!(self.poll_global(cx));
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: Request<Incoming>) -> Self::Future {
-        if let Some(configured_methods) = &self.methods {
-            if !configured_methods.contains(req.method()) {
-                // The request method is not configured, we're ignoring this one.
-                let future = self.inner.call(req);
-                return ResponseFuture {
-                    inner: Kind::Passthrough { future },
-                };
+        match self.admit(req) {
+            Admission::Allowed(future) => future,
+            Admission::Denied(
+                headers,
+                quota,
+                Denied::RateLimited {
+                    key,
+                    wait,
+                    tier,
+                    level,
+                },
+            ) => {
+                let mut error_response = self.rejection_response(
+                    &quota,
+                    tier.as_ref(),
+                    level.as_ref(),
+                    &key,
+                    wait,
+                    &headers,
+                );
+                error_response.extensions_mut().insert(RateLimitInfo {
+                    limit: quota.burst_size().get(),
+                    remaining: 0,
+                    reset: wait,
+                });
+                if let Some(tier) = tier {
+                    error_response.extensions_mut().insert(tier);
+                }
+                if let Some(level) = level {
+                    error_response.extensions_mut().insert(level);
+                }
+                ResponseFuture::error(error_response)
+            }
+            Admission::Denied(headers, quota, Denied::Failed(e)) => {
+                ResponseFuture::error(self.error_response(&quota, e, &headers))
             }
         }
+    }
+}
+
+// Outcome of `Governor::admit`.
+enum Admission<F, Key> {
+    // The request was handed to the inner service.
+    Allowed(ResponseFuture<F>),
+    // The request wasn't allowed, with the request headers and the quota it was checked against.
+    Denied(HeaderMap, Quota, Denied<Key>),
+}
+
+enum Denied<Key> {
+    // The key is over its quota and has to wait.
+    RateLimited {
+        key: Key,
+        wait: Duration,
+        tier: Option<PlanTier>,
+        // The level whose quota was exceeded, see `GovernorConfigBuilder::level`.
+        level: Option<LimitLevel>,
+    },
+    // The request can't be rate limited or can never be allowed.
+    Failed(GovernorError),
+}
+
+impl<K, M, S> Governor<K, M, S>
+where
+    K: KeyExtractor,
+    K::Key: Send + Sync + 'static,
+    M: GovernorMiddleware,
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
+{
+    // Forward `req` to the inner service if the rate limiter allows it.
+    fn admit(&mut self, mut req: Request<Incoming>) -> Admission<S::Future, K::Key> {
+        self.collect_garbage();
+        if self.is_exempt(&req) {
+            return self.pass_through(req, None);
+        }
+        if !self.is_rate_limited(&req) {
+            // The request method is not configured or the request is filtered out, we're ignoring this one.
+            return self.whitelist(req);
+        }
+        let cost = self.request_cost(&req);
         // Use the provided key extractor to extract the rate limiting key from the request.
-        match self.key_extractor.extract(&req) {
+        let key = match self.key_extractor.extract(&req) {
+            Err(GovernorError::UnableToExtractKey) => match self.failure_policy() {
+                FailurePolicy::FailClosed => Err(GovernorError::UnableToExtractKey),
+                FailurePolicy::FailOpen => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        "Unable to extract the {} key, passing the request through",
+                        self.key_extractor.name()
+                    );
+                    return self.pass_through(req, None);
+                }
+                FailurePolicy::FallbackKey(key) => Ok(key.clone()),
+            },
+            key => key,
+        };
+        if key.as_ref().is_ok_and(|key| self.is_exempt_key(key)) {
+            return self.whitelist(req);
+        }
+        let overflow = key.as_ref().ok().and_then(|key| self.overflow(key));
+        let bucket = match &overflow {
+            Some((bucket, _)) => bucket.clone(),
+            None => self.bucket(req.method(), key.as_ref().ok()),
+        };
+        let quota = bucket.quota;
+        if let Some(error) = self.blocked(&req, key.as_ref().ok()) {
+            return Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(error));
+        }
+        if let Some(remaining) = key.as_ref().ok().and_then(|key| self.ban_remaining(key)) {
+            let error = self.ban_error(remaining);
+            return Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(error));
+        }
+        let key = match overflow {
+            Some((_, key)) => Ok(key),
+            None => key,
+        };
+        let levels = self.level_keys(&req);
+        let body_len = self.body_len(&req);
+        let high_priority = key
+            .as_ref()
+            .is_ok_and(|key| self.is_high_priority(&req, key));
+        if let Ok(key) = &key {
+            if let Err(e) = self.check_retry_budget(&req, key) {
+                if self.enforces(Some(key)) {
+                    return Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(e));
+                }
+            }
+        }
+        let slot = match &key {
+            Ok(key) => match self.in_flight_slot(key) {
+                Ok(slot) => slot,
+                Err(e) if self.enforces(Some(key)) => {
+                    return Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(e));
+                }
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+        match key {
             // Extraction worked, let's check if rate limiting is needed.
-            Ok(key) => match self.limiter.check_key(&key) {
-                Ok(_) => {
-                    let future = self.inner.call(req);
-                    ResponseFuture {
-                        inner: Kind::Passthrough { future },
+            Ok(key) => {
+                match self.check_or_delay(&bucket, &key, cost, &levels, body_len, high_priority) {
+                    Ok(Ok((info, quota, delay))) => {
+                        let headers = self.allowed_headers(&quota, bucket.tier.as_ref(), &info);
+                        let on_response = ResponseHook::chain(
+                            ResponseHook::chain(
+                                ResponseHook::chain(
+                                    self.refund_hook(&bucket, &key, cost, levels),
+                                    self.penalty_hook(&bucket, &key, cost),
+                                ),
+                                self.on_allowed_hook(&key, info),
+                            ),
+                            ResponseHook::chain(
+                                self.response_bytes_hook(&key),
+                                // Released once the response is ready, or when the future is dropped.
+                                slot.map(|slot| ResponseHook::new(move |_| drop(slot))),
+                            ),
+                        );
+                        req.extensions_mut().insert(info);
+                        if let Some(tier) = bucket.tier {
+                            req.extensions_mut().insert(tier);
+                        }
+                        let inner = match delay {
+                            // Call the copy of the inner service that was polled ready once the
+                            // wait is over, keeping a fresh one for the next requests.
+                            Some(delay) => {
+                                let clone = self.inner.clone();
+                                let mut inner = mem::replace(&mut self.inner, clone);
+                                let call = move || Kind::allowed(inner.call(req), headers);
+                                Kind::Delayed {
+                                    delay,
+                                    call: Some(DelayedCall(Box::new(call))),
+                                }
+                            }
+                            None => Kind::allowed(self.inner.call(req), headers),
+                        };
+                        Admission::Allowed(ResponseFuture {
+                            inner,
+                            info: Some(info),
+                            on_response,
+                        })
                     }
-                }
 
-                Err(negative) => {
-                    let wait_time = negative
-                        .wait_time_from(DefaultClock::default().now())
-                        .as_secs();
+                    Ok(Err((wait, quota, level))) => {
+                        #[cfg(feature = "tracing")]
+                        {
+                            let wait_time = self.wait_time_secs(wait);
+                            let key_name = match self.key_extractor.key_name(&key) {
+                                Some(n) => format!(" [{}]", &n),
+                                None => "".to_owned(),
+                            };
+                            let enforced = if self.enforces(Some(&key)) {
+                                ""
+                            } else {
+                                " (not enforced)"
+                            };
+                            tracing::info!(
+                                "Rate limit exceeded for {}{}, quota reset in {}s{}",
+                                self.key_extractor.name(),
+                                key_name,
+                                &wait_time,
+                                enforced
+                            );
+                        }
 
-                    #[cfg(feature = "tracing")]
-                    {
-                        let key_name = match self.key_extractor.key_name(&key) {
-                            Some(n) => format!(" [{}]", &n),
-                            None => "".to_owned(),
-                        };
-                        tracing::info!(
-                            "Rate limit exceeded for {}{}, quota reset in {}s",
-                            self.key_extractor.name(),
-                            key_name,
-                            &wait_time
-                        );
+                        if !self.enforces(Some(&key)) {
+                            let rejection = ShadowRejection {
+                                limit: quota.burst_size().get(),
+                                wait,
+                            };
+                            req.extensions_mut().insert(rejection);
+                            return self.pass_through(req, Some(rejection));
+                        }
+                        self.record_violation(&key);
+                        self.record_offender(&key);
+                        Admission::Denied(
+                            req.into_parts().0.headers,
+                            quota,
+                            Denied::RateLimited {
+                                key,
+                                wait,
+                                tier: bucket.tier,
+                                level,
+                            },
+                        )
                     }
-                    
-                    let body = HttpBody::from("Too many requests".to_string());
-                    let response = Response::builder()
-                      .status(429)      
-                      .header("x-ratelimit-after", wait_time.to_string())        
-                      .body(body)
-                      .unwrap();
-
-                    ResponseFuture {
-                        inner: Kind::Error {
-                            error_response: Some(response),
-                        },
+
+                    // The request can never be allowed.
+                    Err(e) if self.enforces(Some(&key)) => {
+                        Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(e))
                     }
+                    Err(_) => self.pass_through(req, None),
                 }
-            },
+            }
 
-            Err(e) => {
-                let body = HttpBody::from(e.to_string());
-                let response = Response::builder()
-                  .status(500)              
-                  .body(body)
-                  .unwrap();
-                
-              
-                ResponseFuture {
-                    inner: Kind::Error {
-                        error_response: Some(response),
-                    },
-                }
+            // Extraction failed, stop right now.
+            Err(e) if self.enforces(None) => {
+                Admission::Denied(req.into_parts().0.headers, quota, Denied::Failed(e))
             }
+            Err(_) => self.pass_through(req, None),
         }
     }
+
+    // Forward `req` to the inner service, it isn't rate limited.
+    fn whitelist(&mut self, req: Request<Incoming>) -> Admission<S::Future, K::Key> {
+        let future = self.inner.call(req);
+        Admission::Allowed(ResponseFuture {
+            inner: Kind::allowed(future, self.whitelisted_headers()),
+            info: None,
+            on_response: None,
+        })
+    }
+
+    // Forward `req` to the inner service without rate limiting headers.
+    fn pass_through(
+        &mut self,
+        req: Request<Incoming>,
+        rejection: Option<ShadowRejection>,
+    ) -> Admission<S::Future, K::Key> {
+        Admission::Allowed(ResponseFuture {
+            inner: Kind::Passthrough {
+                future: self.inner.call(req),
+            },
+            info: None,
+            on_response: rejection.map(|rejection| {
+                ResponseHook::new(move |response| {
+                    response.extensions_mut().insert(rejection);
+                })
+            }),
+        })
+    }
 }
 
-#[derive(Debug)]
-#[pin_project]
-/// Response future for [`Governor`].
-pub struct ResponseFuture<F> {
-    #[pin]
-    inner: Kind<F>,
+/// The Layer type of [`TryGovernor`], used like [`GovernorLayer`].
+pub struct TryGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    pub config: Arc<GovernorConfig<K, M>>,
+}
+
+impl<K, M, S> Layer<S> for TryGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    type Service = TryGovernor<K, M, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TryGovernor {
+            governor: Governor::new(inner, &self.config),
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for TryGovernorLayer<K, M> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// A [`Governor`] returning rejections and key extraction failures as a boxed [`GovernorError`]
+/// instead of turning them into responses, so outer layers decide how to respond.
+///
+/// The error handler and the error format of the configuration are not used, rejections carry
+/// their headers in [`GovernorError::TooManyRequests`].
+pub struct TryGovernor<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S> {
+    governor: Governor<K, M, S>,
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S: Clone> Clone
+    for TryGovernor<K, M, S>
+{
+    fn clone(&self) -> Self {
+        Self {
+            governor: self.governor.clone(),
+        }
+    }
+}
+
+impl<K, M, S> Service<Request<Incoming>> for TryGovernor<K, M, S>
+where
+    K: KeyExtractor,
+    K::Key: Send + Sync + 'static,
+    M: GovernorMiddleware,
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = TryResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.governor.poll_global(cx));
+        self.governor.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let error = match self.governor.admit(req) {
+            Admission::Allowed(future) => return TryResponseFuture::Allowed { future },
+            Admission::Denied(
+                _,
+                quota,
+                Denied::RateLimited {
+                    wait, tier, level, ..
+                },
+            ) => GovernorError::TooManyRequests {
+                wait_time: self.governor.wait_time_secs(wait),
+                headers: Some(self.governor.rejection_headers(
+                    &quota,
+                    tier.as_ref(),
+                    level.as_ref(),
+                    wait,
+                )),
+            },
+            Admission::Denied(_, _, Denied::Failed(e)) => e,
+        };
+        TryResponseFuture::Denied { error: Some(error) }
+    }
 }
 
 #[derive(Debug)]
-#[pin_project(project = KindProj)]
-enum Kind<F> {
-    Passthrough {
-        #[pin]
-        future: F,
-    },
-    RateLimitHeader {
+#[pin_project(project = TryResponseFutureProj)]
+/// Response future for [`TryGovernor`].
+pub enum TryResponseFuture<F> {
+    #[doc(hidden)]
+    Allowed {
         #[pin]
-        future: F,
-        #[pin]
-        burst_size: u32,
-        #[pin]
-        remaining_burst_capacity: u32,
-    },
-    WhitelistedHeader {
-        #[pin]
-        future: F,
-    },
-    Error {
-        error_response: Option<Response<HttpBody>>
+        future: ResponseFuture<F>,
     },
+    #[doc(hidden)]
+    Denied { error: Option<GovernorError> },
 }
-pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
 
-impl<F, Error> Future for ResponseFuture<F>
+impl<F, Error> Future for TryResponseFuture<F>
 where
     F: Future<Output = Result<Response<HttpBody>, Error>>,
-    Error: Into<BoxError>
+    Error: Into<BoxError>,
 {
-    type Output = Result<Response<HttpBody>, Error>;
+    type Output = Result<Response<HttpBody>, BoxError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.project().inner.project() {
-            KindProj::Passthrough { future } => future.poll(cx),
-            KindProj::RateLimitHeader {
-                future,
-                burst_size,
-                remaining_burst_capacity,
-            } => {
-                let mut response = ready!(future.poll(cx))?;
-
-                let mut headers = HeaderMap::new();
-                headers.insert(
-                    HeaderName::from_static("x-ratelimit-limit"),
-                    HeaderValue::from(*burst_size),
-                );
-                headers.insert(
-                    HeaderName::from_static("x-ratelimit-remaining"),
-                    HeaderValue::from(*remaining_burst_capacity),
-                );
-                response.headers_mut().extend(headers.drain());
-   
+        match self.project() {
+            TryResponseFutureProj::Allowed { future } => future.poll(cx).map_err(Into::into),
+            TryResponseFutureProj::Denied { error } => Poll::Ready(Err(error
+                .take()
+                .expect("TryResponseFuture polled after completion")
+                .into())),
+        }
+    }
+}
 
-                Poll::Ready(Ok(response))
-            }
-            KindProj::WhitelistedHeader { future } => {
-                let mut response = ready!(future.poll(cx))?;
+/// Matches request paths for [`RouteGovernorLayer`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteMatcher {
+    /// Matches the path and everything below it: `/login` matches `/login` and `/login/otp`,
+    /// but not `/loginx`.
+    Prefix(String),
+    /// Matches the whole path against a glob: `*` matches anything but `/`, `**` matches anything
+    /// and `?` matches a single character other than `/`.
+    Glob(String),
+}
 
-                let headers = response.headers_mut();
-                headers.insert(
-                    HeaderName::from_static("x-ratelimit-whitelisted"),
-                    HeaderValue::from_static("true"),
-                );
+impl RouteMatcher {
+    /// Create a [`RouteMatcher::Prefix`].
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self::Prefix(prefix.into())
+    }
 
-                Poll::Ready(Ok(response))
-            }
-            KindProj::Error { error_response } => {
-              let error = error_response.as_ref().unwrap();
-              let body = HttpBody::from("Too many requests".to_string());
-              let response = Response::builder()
-                .status(error.status())              
-                .body(body)
-                .unwrap();
-              
-              Poll::Ready(Ok(response))
-            },
+    /// Create a [`RouteMatcher::Glob`].
+    pub fn glob(glob: impl Into<String>) -> Self {
+        Self::Glob(glob.into())
+    }
+
+    /// Whether `path` is matched.
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::Prefix(prefix) => path.strip_prefix(prefix.as_str()).is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+            }),
+            Self::Glob(glob) => glob_matches(glob.as_bytes(), path.as_bytes()),
         }
     }
 }
 
-// Implementation of Service for Governor using the StateInformationMiddleware.
-impl<K, S> Service<Request<Incoming>> for Governor<K, StateInformationMiddleware, S>
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        [b'*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == b'/').unwrap_or(path.len());
+            (0..=segment).any(|i| glob_matches(rest, &path[i..]))
+        }
+        [b'?', rest @ ..] => {
+            matches!(path, [c, ..] if *c != b'/') && glob_matches(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+/// A Layer rate limiting each route with its own [`GovernorConfig`], so `/login` can get a strict
+/// quota and `/static` a generous one without stacking layers.
+///
+/// Routes are tried in the order they were added and the first matching one is used.
+/// Requests matching no route are passed to the inner service without rate limiting, add a
+/// [`RouteMatcher::prefix("/")`](RouteMatcher::prefix) route last to limit them too.
+///
+/// # Example
+///
+/// ```rust
+/// use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, RouteGovernorLayer, RouteMatcher};
+/// use std::sync::Arc;
+///
+/// let strict = GovernorConfigBuilder::default().per_second(60).burst_size(3).finish().unwrap();
+/// let generous = GovernorConfigBuilder::default().burst_size(100).finish().unwrap();
+///
+/// let layer = RouteGovernorLayer::new()
+///     .route(RouteMatcher::prefix("/login"), Arc::new(strict))
+///     .route(RouteMatcher::glob("/static/**"), Arc::new(generous));
+/// ```
+pub struct RouteGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    pub routes: Vec<(RouteMatcher, Arc<GovernorConfig<K, M>>)>,
+}
+
+impl<K, M> RouteGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    /// Create a layer without routes.
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    /// Rate limit the requests matched by `matcher` with `config`.
+    pub fn route(mut self, matcher: RouteMatcher, config: Arc<GovernorConfig<K, M>>) -> Self {
+        self.routes.push((matcher, config));
+        self
+    }
+}
+
+impl<K, M> Default for RouteGovernorLayer<K, M>
 where
     K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, M, S> Layer<S> for RouteGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+    S: Clone,
+{
+    type Service = RouteGovernor<K, M, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RouteGovernor {
+            routes: self
+                .routes
+                .iter()
+                .map(|(matcher, config)| (matcher.clone(), Governor::new(inner.clone(), config)))
+                .collect(),
+            inner,
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for RouteGovernorLayer<K, M> {
+    fn clone(&self) -> Self {
+        Self {
+            routes: self.routes.clone(),
+        }
+    }
+}
+
+/// A middleware dispatching requests to the [`Governor`] of the first matching route,
+/// see [`RouteGovernorLayer`].
+pub struct RouteGovernor<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S> {
+    routes: Vec<(RouteMatcher, Governor<K, M, S>)>,
+    inner: S,
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S: Clone> Clone
+    for RouteGovernor<K, M, S>
+{
+    fn clone(&self) -> Self {
+        Self {
+            routes: self.routes.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, M, S> Service<Request<Incoming>> for RouteGovernor<K, M, S>
+where
+    K: KeyExtractor,
+    K::Key: Send + Sync + 'static,
+    M: GovernorMiddleware,
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        // Any route may be picked, so every copy of the inner service has to be ready.
+        for (_, governor) in &mut self.routes {
+            ready!(governor.poll_ready(cx))?;
+        }
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        let path = req.uri().path();
+        match self
+            .routes
+            .iter_mut()
+            .find(|(matcher, _)| matcher.matches(path))
+        {
+            Some((_, governor)) => governor.call(req),
+            None => ResponseFuture {
+                inner: Kind::Passthrough {
+                    future: self.inner.call(req),
+                },
+                info: None,
+                on_response: None,
+            },
+        }
+    }
+}
+
+/// A layer enforcing a single `quota` shared by every request, e.g. to protect a whole service at
+/// N requests per second, with a [`NotKeyed`](::governor::state::NotKeyed) rate limiter.
+///
+/// Unlike a [`GovernorLayer`] with a [`GlobalKeyExtractor`](key_extractor::GlobalKeyExtractor),
+/// there is no key to extract nor to look up, and nothing else to check: every request is rate
+/// limited, and rejections are plain text `429 Too Many Requests` responses reading `Too many requests`, with the
+/// `x-ratelimit-after` and `retry-after` headers. Clones share the same rate limiter.
+///
+/// # Example
+/// ```rust
+/// use governor::Quota;
+/// use jsonrpsee_tower_governor::GlobalGovernorLayer;
+/// use std::num::NonZeroU32;
+///
+/// let layer = GlobalGovernorLayer::new(Quota::per_second(NonZeroU32::new(1000).unwrap()));
+/// ```
+#[derive(Debug, Clone)]
+pub struct GlobalGovernorLayer {
+    limiter: Arc<DefaultDirectRateLimiter>,
+}
+
+impl GlobalGovernorLayer {
+    /// Create a layer allowing `quota` to all requests together.
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter::direct(quota)),
+        }
+    }
+}
+
+impl<S> Layer<S> for GlobalGovernorLayer {
+    type Service = GlobalGovernor<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        GlobalGovernor {
+            limiter: self.limiter.clone(),
+            inner,
+        }
+    }
+}
+
+/// A middleware checking every request against a single rate limiter, see [`GlobalGovernorLayer`].
+#[derive(Debug, Clone)]
+pub struct GlobalGovernor<S> {
+    limiter: Arc<DefaultDirectRateLimiter>,
+    inner: S,
+}
+
+impl<S> Service<Request<Incoming>> for GlobalGovernor<S>
+where
     S: Service<Request<Incoming>, Response = Response<HttpBody>>,
     S::Error: Into<BoxError>,
 {
@@ -242,87 +751,332 @@ where
     type Future = ResponseFuture<S::Future>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        // Our middleware doesn't care about backpressure so its ready as long
-        // as the inner service is ready.
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: Request<Incoming>) -> Self::Future {
-        if let Some(configured_methods) = &self.methods {
-            if !configured_methods.contains(req.method()) {
-                // The request method is not configured, we're ignoring this one.
-                let fut = self.inner.call(req);
-                return ResponseFuture {
-                    inner: Kind::WhitelistedHeader { future: fut },
-                };
+        let Err(not_until) = self.limiter.check() else {
+            return ResponseFuture::passthrough(self.inner.call(req));
+        };
+        let wait = not_until.wait_time_from(DefaultClock::default().now());
+        let wait_time = governor::as_secs_ceil(wait);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-after", HeaderValue::from(wait_time));
+        headers.insert(RETRY_AFTER, HeaderValue::from(wait_time));
+        ResponseFuture::error(
+            GovernorError::TooManyRequests {
+                wait_time,
+                headers: Some(headers),
             }
+            .as_default_response(),
+        )
+    }
+}
+
+#[derive(Debug)]
+#[pin_project]
+/// Response future for [`Governor`].
+pub struct ResponseFuture<F> {
+    #[pin]
+    inner: Kind<F>,
+    info: Option<RateLimitInfo>,
+    on_response: Option<ResponseHook>,
+}
+
+impl<F> ResponseFuture<F> {
+    fn error(error_response: Response<HttpBody>) -> Self {
+        ResponseFuture {
+            inner: Kind::Error {
+                error_response: Some(error_response),
+            },
+            info: None,
+            on_response: None,
         }
-        // Use the provided key extractor to extract the rate limiting key from the request.
-        match self.key_extractor.extract(&req) {
-            // Extraction worked, let's check if rate limiting is needed.
-            Ok(key) => match self.limiter.check_key(&key) {
-                Ok(snapshot) => {
-                    let fut = self.inner.call(req);
-                    ResponseFuture {
-                        inner: Kind::RateLimitHeader {
-                            future: fut,
-                            burst_size: snapshot.quota().burst_size().get(),
-                            remaining_burst_capacity: snapshot.remaining_burst_capacity(),
-                        },
-                    }
-                }
+    }
 
-                Err(negative) => {
-                    let wait_time = negative
-                        .wait_time_from(DefaultClock::default().now())
-                        .as_secs();
+    fn passthrough(future: F) -> Self {
+        ResponseFuture {
+            inner: Kind::Passthrough { future },
+            info: None,
+            on_response: None,
+        }
+    }
+}
 
-                    #[cfg(feature = "tracing")]
-                    {
-                        let key_name = match self.key_extractor.key_name(&key) {
-                            Some(n) => format!(" [{}]", &n),
-                            None => "".to_owned(),
-                        };
-                        tracing::info!(
-                            "Rate limit exceeded for {}{}, quota reset in {}s",
-                            self.key_extractor.name(),
-                            key_name,
-                            &wait_time
-                        );
-                    }
+/// Marker inserted into the response extensions by the inner service when the response was
+/// served from cache, see [`GovernorConfigBuilder::cache_hit_refunds`](crate::governor::GovernorConfigBuilder::cache_hit_refunds).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheHit;
 
+/// Marker inserted into the request extensions by trusted outer middleware, e.g. after authenticating
+/// an internal caller, to exempt the request from rate limiting like an
+/// [`exempt_path`](crate::governor::GovernorConfigBuilder::exempt_path).
+///
+/// Only trusted middleware must be able to insert it, it's never derived from the request itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct GovernorBypass;
 
-                    let body = HttpBody::from("Too many requests".to_string());
-                    let response = Response::builder()
-                      .status(429)      
-                      .header("x-ratelimit-after", wait_time.to_string())
-                      .header("x-ratelimit-limit", negative.quota().burst_size().get().to_string())
-                      .header("x-ratelimit-remaining", "0")
-                      .body(body)
-                      .unwrap();
+/// Marker inserted into the request extensions by trusted outer middleware to make the request
+/// high priority, drawing from the reserve of the global quota, see
+/// [`GovernorConfigBuilder::global_reserve`](crate::governor::GovernorConfigBuilder::global_reserve).
+///
+/// Only trusted middleware must be able to insert it, it's never derived from the request itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HighPriority;
 
-                    ResponseFuture {
-                        inner: Kind::Error {
-                            error_response: Some(response),
-                        },
-                    }
-                }
+/// Marker inserted into the request extensions by trusted outer middleware when the request is a
+/// retry, e.g. by a retrying client in the same process, counting it against the
+/// [`retry_budget`](crate::governor::GovernorConfigBuilder::retry_budget) of its key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RetryAttempt;
+
+/// Quota state of the rate limiting key after a rate limiting decision.
+///
+/// For allowed requests it is inserted into the request extensions before calling the inner
+/// service, so handlers can adapt to the remaining quota, and into the response extensions.
+/// Rejections carry it in the response extensions, so outer layers can record the quota state
+/// without parsing headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    /// The burst size of the quota.
+    pub limit: u32,
+    /// The number of requests left before being rejected.
+    pub remaining: u32,
+    /// Time until the quota is fully replenished, or until the next request is allowed after a rejection.
+    pub reset: Duration,
+}
+
+/// A rejection that wasn't enforced, see [`GovernorConfigBuilder::dry_run`](governor::GovernorConfigBuilder::dry_run)
+/// and [`GovernorConfigBuilder::enforced_percent`](governor::GovernorConfigBuilder::enforced_percent).
+///
+/// It is inserted into the request and response extensions of the requests that would have been
+/// rejected, so outer layers can record them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShadowRejection {
+    /// The burst size of the quota that would have rejected the request.
+    pub limit: u32,
+    /// How long the client would have had to wait.
+    pub wait: Duration,
+}
+
+/// The plan tier of the client, see [`GovernorConfigBuilder::tier_of`](governor::GovernorConfigBuilder::tier_of).
+///
+/// It is inserted into the request extensions of allowed requests and the response extensions
+/// of rejections, e.g. to record metrics per tier.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PlanTier(pub Arc<str>);
+
+impl PlanTier {
+    /// The name of the tier.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// The level whose quota rejected a request, see [`GovernorConfigBuilder::level`](governor::GovernorConfigBuilder::level).
+///
+/// It is inserted into the response extensions of rejections by a level, e.g. to record metrics per level.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LimitLevel(pub Arc<str>);
+
+impl LimitLevel {
+    /// The name of the level.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+// A response body calling a function with the length of each chunk of data as it's sent,
+// see `GovernorConfigBuilder::response_bytes_quota`.
+#[pin_project]
+pub(crate) struct MeteredBody<F> {
+    #[pin]
+    body: HttpBody,
+    on_data: F,
+}
+
+impl<F: FnMut(u64)> MeteredBody<F> {
+    pub(crate) fn new(body: HttpBody, on_data: F) -> Self {
+        Self { body, on_data }
+    }
+}
+
+impl<F: FnMut(u64)> Body for MeteredBody<F> {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, BoxError>>> {
+        let this = self.project();
+        let frame = ready!(this.body.poll_frame(cx));
+        if let Some(data) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok()?.data_ref())
+        {
+            (this.on_data)(data.len() as u64);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+// The wait of a delayed request, see `GovernorConfigBuilder::max_delay`, keeping its place in the
+// queue of its key until it's over, see `GovernorConfigBuilder::max_queued`.
+pub(crate) struct Delay(Pin<Box<dyn Future<Output = ()> + Send>>);
+
+impl Delay {
+    pub(crate) fn new<T: Send + 'static>(wait: Duration, place: Option<T>) -> Self {
+        Self(Box::pin(async move {
+            tokio::time::sleep(wait).await;
+            drop(place);
+        }))
+    }
+}
+
+impl fmt::Debug for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Delay").finish()
+    }
+}
+
+type ResponseHookFn = dyn FnOnce(&mut Response<HttpBody>) + Send;
+
+// function called with the inner service's response of an allowed request.
+pub(crate) struct ResponseHook(Box<ResponseHookFn>);
+
+impl ResponseHook {
+    pub(crate) fn new<F>(func: F) -> Self
+    where
+        F: FnOnce(&mut Response<HttpBody>) + Send + 'static,
+    {
+        Self(Box::new(func))
+    }
+
+    /// Hook running `first`, then `second`.
+    pub(crate) fn chain(first: Option<Self>, second: Option<Self>) -> Option<Self> {
+        match (first, second) {
+            (Some(first), Some(second)) => Some(Self::new(move |response| {
+                (first.0)(response);
+                (second.0)(response);
+            })),
+            (first, second) => first.or(second),
+        }
+    }
+}
+
+impl fmt::Debug for ResponseHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseHook").finish()
+    }
+}
+
+#[derive(Debug)]
+#[pin_project(project = KindProj)]
+enum Kind<F> {
+    Passthrough {
+        #[pin]
+        future: F,
+    },
+    RateLimitHeader {
+        #[pin]
+        future: F,
+        headers: Option<HeaderMap>,
+    },
+    Error {
+        error_response: Option<Response<HttpBody>>,
+    },
+    // A request waiting for its quota, see `GovernorConfigBuilder::max_delay`.
+    Delayed {
+        delay: Delay,
+        call: Option<DelayedCall<F>>,
+    },
+}
+
+impl<F> Kind<F> {
+    // The future of an allowed request, adding `headers` to its response if any.
+    fn allowed(future: F, headers: Option<HeaderMap>) -> Self {
+        match headers {
+            Some(headers) => Kind::RateLimitHeader {
+                future,
+                headers: Some(headers),
             },
+            None => Kind::Passthrough { future },
+        }
+    }
+}
 
-            // Extraction failed, stop right now.
-            Err(e) => {
-              let body = HttpBody::from(e.to_string());
-              let response = Response::builder()
-                .status(500)              
-                .body(body)
-                .unwrap();
-              
-                ResponseFuture {
-                    inner: Kind::Error {
-                        error_response: Some(response),
-                    },
+// The call to the inner service of a delayed request, made once its wait is over.
+struct DelayedCall<F>(Box<dyn FnOnce() -> Kind<F> + Send>);
+
+impl<F> fmt::Debug for DelayedCall<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DelayedCall").finish()
+    }
+}
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+impl<F, Error> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<HttpBody>, Error>>,
+    Error: Into<BoxError>,
+{
+    type Output = Result<Response<HttpBody>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut response = ready!(poll_kind(this.inner, cx))?;
+        if let Some(info) = this.info.take() {
+            response.extensions_mut().insert(info);
+        }
+        if let Some(hook) = this.on_response.take() {
+            (hook.0)(&mut response);
+        }
+        Poll::Ready(Ok(response))
+    }
+}
+
+fn poll_kind<F, Error>(
+    mut kind: Pin<&mut Kind<F>>,
+    cx: &mut Context<'_>,
+) -> Poll<Result<Response<HttpBody>, Error>>
+where
+    F: Future<Output = Result<Response<HttpBody>, Error>>,
+{
+    if let KindProj::Delayed { delay, call } = kind.as_mut().project() {
+        ready!(delay.0.as_mut().poll(cx));
+        let call = call.take().expect("the delayed request is called once");
+        kind.set((call.0)());
+    }
+    match kind.project() {
+        KindProj::Passthrough { future } => future.poll(cx),
+        KindProj::RateLimitHeader { future, headers } => {
+            let mut response = ready!(future.poll(cx))?;
+
+            if let Some(mut headers) = headers.take() {
+                // Keep the headers the inner service exposes, the header is a list.
+                let expose = headers.remove(ACCESS_CONTROL_EXPOSE_HEADERS);
+                response.headers_mut().extend(headers);
+                if let Some(expose) = expose {
+                    response
+                        .headers_mut()
+                        .append(ACCESS_CONTROL_EXPOSE_HEADERS, expose);
                 }
             }
+
+            Poll::Ready(Ok(response))
         }
+        KindProj::Error { error_response } => Poll::Ready(Ok(error_response
+            .take()
+            .expect("<Governor as Service<Request<_>>>::call must produce Response"))),
+        KindProj::Delayed { .. } => unreachable!("the delayed request was called"),
     }
 }
@@ -0,0 +1,277 @@
+//! A [`GovernorStore`] keeping the rate limiter state in memcached, for environments already running
+//! memcached clusters, so every instance of a service enforces one shared quota per key.
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, memcached::MemcachedStore};
+//!
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(2)
+//!     .burst_size(5)
+//!     .store(MemcachedStore::new(["10.0.0.1:11211", "10.0.0.2:11211"]).prefix("my-service"))
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::{
+    store::{blocking, fnv1a, Breaker, GovernorStore, MAX_ATTEMPTS},
+    BoxError,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A [`GovernorStore`] shared through memcached servers, see the [module documentation](self).
+///
+/// The state of a key is read with `gets` and written back with `cas`, or `add` for new keys,
+/// retrying a few times when another instance changed it meanwhile. Keys are spread over the servers by hash,
+/// and expire about a second after their quota is fully replenished. Instances must have
+/// synchronized clocks.
+///
+/// The consistency is best-effort:
+/// - memcached may evict keys under memory pressure, giving them a full quota again,
+/// - the keys of a server that restarts or becomes unreachable are lost, and while it can't be
+///   reached their requests are allowed, as are the ones of keys that other instances keep
+///   changing, see [shared stores](crate::store#shared-stores),
+/// - adding or removing servers moves keys to other servers, where they start with a full quota.
+///
+/// The round trips are blocking, so set a short [`timeout`](Self::timeout).
+///
+/// Keys are stored as `{prefix}:{scope}:{key}`, the key being formatted with [`Display`](fmt::Display).
+/// Keys that aren't valid memcached keys, e.g. containing spaces or longer than 250 bytes, are
+/// replaced by their hash.
+#[derive(Clone)]
+pub struct MemcachedStore {
+    servers: Arc<[Server]>,
+    prefix: String,
+    timeout: Duration,
+}
+
+struct Server {
+    address: String,
+    // Idle connections.
+    pool: Mutex<Vec<Connection>>,
+    breaker: Breaker,
+}
+
+impl MemcachedStore {
+    /// A store using the memcached `servers`, e.g. `127.0.0.1:11211`, connecting on first use.
+    ///
+    /// # Panics
+    ///
+    /// If `servers` is empty.
+    pub fn new<I>(servers: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let servers: Arc<[Server]> = servers
+            .into_iter()
+            .map(|address| Server {
+                address: address.into(),
+                pool: Mutex::default(),
+                breaker: Breaker::default(),
+            })
+            .collect();
+        assert!(!servers.is_empty(), "No memcached server");
+        Self {
+            servers,
+            prefix: String::from("governor"),
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    /// Set the prefix of the memcached keys, `governor` by default, to keep the ones of several services apart.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set the timeout of connections and replies, 100 milliseconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn connection(&self, server: &Server) -> io::Result<Connection> {
+        if let Some(connection) = server.pool.lock().unwrap().pop() {
+            return Ok(connection);
+        }
+        let address = server.address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Unresolved memcached address")
+        })?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection(BufReader::new(stream)))
+    }
+
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) -> io::Result<()> {
+        let hash = fnv1a(key.as_bytes());
+        let server = &self.servers[(hash % self.servers.len() as u64) as usize];
+        let stored = server.breaker.call(|| {
+            blocking(|| {
+                let mut connection = self.connection(server)?;
+                let mut stored = false;
+                for _ in 0..MAX_ATTEMPTS {
+                    let current = connection.gets(key)?;
+                    stored = match f(current.map(|(state, _)| state)) {
+                        Some(new) => {
+                            let now = SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .map_or(0, |since| since.as_nanos() as u64);
+                            let expiry = new.saturating_sub(now) / 1_000_000_000 + 2;
+                            connection.store(key, new, expiry, current.map(|(_, cas)| cas))?
+                        }
+                        None => true,
+                    };
+                    if stored {
+                        break;
+                    }
+                }
+                server.pool.lock().unwrap().push(connection);
+                Ok::<_, io::Error>(stored)
+            })
+        })?;
+        match stored {
+            true => Ok(()),
+            false => Err(io::Error::other(
+                "The state keeps being changed by other instances",
+            )),
+        }
+    }
+}
+
+impl<K: fmt::Display + Send + Sync + 'static> GovernorStore<K> for MemcachedStore {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        if let Err(_e) = GovernorStore::try_measure_and_replace(self, key, f) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                "Unable to update the rate limiter state in memcached: {}",
+                _e
+            );
+            f(None);
+        }
+    }
+
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        let mut key = format!("{}:{}", self.prefix, key);
+        if key.len() > 250
+            || key
+                .bytes()
+                .any(|b| b.is_ascii_whitespace() || b.is_ascii_control())
+        {
+            key = format!("{}:{:016x}", self.prefix, fnv1a(key.as_bytes()));
+        }
+        Ok(self.update(&key, f)?)
+    }
+
+    fn retain(&self, _keep: &mut dyn FnMut(&K, u64) -> bool) {
+        // Keys expire by themselves.
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            prefix: format!("{}:{}", self.prefix, scope),
+            ..self.clone()
+        })
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for MemcachedStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let servers: Vec<_> = self.servers.iter().map(|server| &server.address).collect();
+        f.debug_struct("MemcachedStore")
+            .field("servers", &servers)
+            .field("prefix", &self.prefix)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+struct Connection(BufReader<TcpStream>);
+
+impl Connection {
+    // The state of `key` and its CAS unique, if any.
+    fn gets(&mut self, key: &str) -> io::Result<Option<(u64, u64)>> {
+        self.0
+            .get_mut()
+            .write_all(format!("gets {}\r\n", key).as_bytes())?;
+        let line = self.line()?;
+        if line == "END" {
+            return Ok(None);
+        }
+        // VALUE <key> <flags> <bytes> <cas unique>
+        let fields: Vec<&str> = line.split(' ').collect();
+        let ["VALUE", _, _, length, cas] = fields.as_slice() else {
+            return Err(invalid(&line));
+        };
+        let length: usize = length.parse().map_err(|_| invalid(&line))?;
+        let cas = cas.parse().map_err(|_| invalid(&line))?;
+        let mut data = vec![0; length + 2];
+        self.0.read_exact(&mut data)?;
+        let state = std::str::from_utf8(&data[..length])
+            .ok()
+            .and_then(|state| state.parse().ok())
+            .ok_or_else(|| invalid("Invalid state"))?;
+        if self.line()? != "END" {
+            return Err(invalid("Missing END"));
+        }
+        Ok(Some((state, cas)))
+    }
+
+    // Store `state` with `cas` if the key had this CAS unique, or add it if it had none,
+    // returning whether it was stored.
+    fn store(&mut self, key: &str, state: u64, expiry: u64, cas: Option<u64>) -> io::Result<bool> {
+        let state = state.to_string();
+        let command = match cas {
+            Some(cas) => format!(
+                "cas {} 0 {} {} {}\r\n{}\r\n",
+                key,
+                expiry,
+                state.len(),
+                cas,
+                state
+            ),
+            None => format!("add {} 0 {} {}\r\n{}\r\n", key, expiry, state.len(), state),
+        };
+        self.0.get_mut().write_all(command.as_bytes())?;
+        match self.line()?.as_str() {
+            "STORED" => Ok(true),
+            "EXISTS" | "NOT_FOUND" | "NOT_STORED" => Ok(false),
+            line => Err(invalid(line)),
+        }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.0.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+fn invalid(reply: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected memcached reply: {}", reply),
+    )
+}
@@ -0,0 +1,151 @@
+//! The keys rejected the most over a rolling window, see
+//! [`GovernorConfigBuilder::track_offenders`](crate::governor::GovernorConfigBuilder::track_offenders).
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::governor::GovernorConfigBuilder;
+//! use std::time::Duration;
+//!
+//! let config = GovernorConfigBuilder::default()
+//!     .track_offenders(10, Duration::from_secs(60))
+//!     .finish()
+//!     .unwrap();
+//! for offender in config.top_offenders() {
+//!     println!("{} rejected {} times", offender.key, offender.rejections);
+//! }
+//! ```
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    mem,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// One of the keys rejected the most, see
+/// [`GovernorConfig::top_offenders`](crate::governor::GovernorConfig::top_offenders).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Offender<Key> {
+    /// The key.
+    pub key: Key,
+    /// Its rejections over the window, overestimated by at most [`error`](Self::error).
+    pub rejections: u64,
+    /// How much [`rejections`](Self::rejections) may be overestimated, when the key took the
+    /// counter of another one that rejected less.
+    pub error: u64,
+}
+
+/// The `top` keys rejected the most, counted with the Space-Saving algorithm: at most `4 * top`
+/// counters are kept per window, whatever the number of keys.
+///
+/// Rejections are counted in consecutive windows, the current and the previous one being reported,
+/// i.e. the rejections of the last `window` to twice `window`.
+pub(crate) struct Offenders<Key> {
+    top: usize,
+    window: Duration,
+    windows: Mutex<Windows<Key>>,
+}
+
+struct Windows<Key> {
+    start: Instant,
+    // Rejections and overestimate by key.
+    current: HashMap<Key, (u64, u64)>,
+    previous: HashMap<Key, (u64, u64)>,
+}
+
+impl<Key: Hash + Eq + Clone> Offenders<Key> {
+    pub(crate) fn new(top: usize, window: Duration) -> Self {
+        Self {
+            top,
+            window,
+            windows: Mutex::new(Windows {
+                start: Instant::now(),
+                current: HashMap::new(),
+                previous: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Whether it tracks the `top` keys over `window`.
+    pub(crate) fn tracks(&self, top: usize, window: Duration) -> bool {
+        self.top == top && self.window == window
+    }
+
+    /// Count a rejection of `key`.
+    pub(crate) fn record(&self, key: &Key) {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window);
+        if let Some((rejections, _)) = windows.current.get_mut(key) {
+            *rejections += 1;
+            return;
+        }
+        if windows.current.len() < self.top * 4 {
+            windows.current.insert(key.clone(), (1, 0));
+            return;
+        }
+        // The new key takes over the counter of the key rejected the least.
+        let (least, &(rejections, _)) = windows
+            .current
+            .iter()
+            .min_by_key(|(_, (rejections, _))| *rejections)
+            .expect("the counters are full");
+        let least = least.clone();
+        windows.current.remove(&least);
+        windows
+            .current
+            .insert(key.clone(), (rejections + 1, rejections));
+    }
+
+    /// The keys rejected the most, the most rejected first.
+    pub(crate) fn top(&self) -> Vec<Offender<Key>> {
+        let mut windows = self.windows.lock().unwrap();
+        windows.rotate(self.window);
+        let mut totals = windows.previous.clone();
+        for (key, (rejections, error)) in &windows.current {
+            let total = totals.entry(key.clone()).or_default();
+            total.0 += rejections;
+            total.1 += error;
+        }
+        let mut offenders: Vec<_> = totals
+            .into_iter()
+            .map(|(key, (rejections, error))| Offender {
+                key,
+                rejections,
+                error,
+            })
+            .collect();
+        offenders.sort_by_key(|offender| Reverse(offender.rejections));
+        offenders.truncate(self.top);
+        offenders
+    }
+}
+
+impl<Key> Windows<Key> {
+    fn rotate(&mut self, window: Duration) {
+        let elapsed = self.start.elapsed();
+        if elapsed < window {
+            return;
+        }
+        self.previous = match elapsed < window * 2 {
+            true => mem::take(&mut self.current),
+            false => {
+                self.current.clear();
+                HashMap::new()
+            }
+        };
+        self.start = Instant::now();
+    }
+}
+
+impl<Key> fmt::Debug for Offenders<Key> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Offenders")
+            .field("top", &self.top)
+            .field("window", &self.window)
+            .finish()
+    }
+}
@@ -0,0 +1,189 @@
+//! Per-key quotas fetched asynchronously, e.g. from a database or a control plane, and cached.
+//!
+//! # Example
+//!
+//! ```rust
+//! use governor::Quota;
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, quota_cache::CachedQuotaProvider};
+//! use std::{net::IpAddr, num::NonZeroU32, time::Duration};
+//!
+//! let default = Quota::per_second(NonZeroU32::new(1).unwrap());
+//! let provider = CachedQuotaProvider::new(default, Duration::from_secs(60), move |ip: &IpAddr| {
+//!     let ip = *ip;
+//!     async move {
+//!         // Look up the plan of `ip` here.
+//!         let burst_size = if ip.is_loopback() { 100 } else { 1 };
+//!         Ok(default.allow_burst(NonZeroU32::new(burst_size).unwrap()))
+//!     }
+//! });
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(1)
+//!     .burst_size(1)
+//!     .quota_provider(provider)
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::{governor::QuotaProvider, BoxError};
+use dashmap::DashMap;
+use governor::Quota;
+use std::{
+    fmt,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// A future resolving to the quota of a key.
+pub type QuotaFuture = Pin<Box<dyn Future<Output = Result<Quota, BoxError>> + Send>>;
+
+/// Fetches the quota of a key asynchronously, see [`CachedQuotaProvider`].
+///
+/// Implemented for closures taking the key and returning a future of its quota.
+pub trait AsyncQuotaProvider<Key>: Send + Sync + 'static {
+    /// Fetch the quota of `key`.
+    fn fetch_quota(&self, key: &Key) -> QuotaFuture;
+}
+
+impl<Key, F, Fut> AsyncQuotaProvider<Key> for F
+where
+    F: Fn(&Key) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Quota, BoxError>> + Send + 'static,
+{
+    fn fetch_quota(&self, key: &Key) -> QuotaFuture {
+        Box::pin(self(key))
+    }
+}
+
+/// A [`QuotaProvider`] caching the quotas of an [`AsyncQuotaProvider`] for a time to live.
+///
+/// Requests never wait for a quota to be fetched: a key without a cached quota gets the default
+/// one, and a key whose quota expired keeps it, while the quota is fetched on the tokio runtime
+/// of the request. Failed fetches keep the previous quota and are retried once the time to live
+/// elapsed again.
+pub struct CachedQuotaProvider<Key: Hash + Eq, P> {
+    provider: Arc<P>,
+    default: Quota,
+    ttl: Duration,
+    cache: Arc<DashMap<Key, Entry>>,
+}
+
+struct Entry {
+    quota: Quota,
+    // When the quota expires, `None` while it is being fetched.
+    expires: Option<Instant>,
+}
+
+impl<Key, P> CachedQuotaProvider<Key, P>
+where
+    Key: Hash + Eq + Clone + Send + Sync + 'static,
+    P: AsyncQuotaProvider<Key>,
+{
+    /// Cache the quotas fetched by `provider` for `ttl`, keys get the `default` quota until theirs is fetched.
+    pub fn new(default: Quota, ttl: Duration, provider: P) -> Self {
+        Self {
+            provider: Arc::new(provider),
+            default,
+            ttl,
+            cache: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Forget the cached quota of `key`, it's fetched again on its next request.
+    pub fn invalidate(&self, key: &Key) {
+        self.cache.remove(key);
+    }
+
+    /// Forget the quotas that expired, to bound the memory used by keys that stopped sending requests.
+    pub fn retain_recent(&self) {
+        let now = Instant::now();
+        self.cache
+            .retain(|_, entry| entry.expires.is_none_or(|expires| expires > now));
+    }
+
+    /// Number of cached quotas.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Whether no quota is cached.
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    fn refresh(&self, key: &Key) {
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let future = self.provider.fetch_quota(key);
+        let cache = self.cache.clone();
+        let key = key.clone();
+        let ttl = self.ttl;
+        runtime.spawn(async move {
+            let fetched = future.await;
+            let Some(mut entry) = cache.get_mut(&key) else {
+                // Invalidated meanwhile.
+                return;
+            };
+            match fetched {
+                Ok(quota) => entry.quota = quota,
+                Err(_e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("Unable to fetch a quota, keeping the previous one: {}", _e);
+                }
+            }
+            entry.expires = Some(Instant::now() + ttl);
+        });
+    }
+}
+
+impl<Key, P> QuotaProvider<Key> for CachedQuotaProvider<Key, P>
+where
+    Key: Hash + Eq + Clone + Send + Sync + 'static,
+    P: AsyncQuotaProvider<Key>,
+{
+    fn quota_for(&self, key: &Key) -> Quota {
+        let now = Instant::now();
+        if let Some(entry) = self.cache.get(key) {
+            if entry.expires.is_none_or(|expires| expires > now) {
+                return entry.quota;
+            }
+        }
+        let quota = {
+            let mut entry = self.cache.entry(key.clone()).or_insert(Entry {
+                quota: self.default,
+                expires: Some(now),
+            });
+            match entry.expires {
+                // Another request is fetching it.
+                None => return entry.quota,
+                Some(expires) if expires > now => return entry.quota,
+                Some(_) => entry.expires = None,
+            }
+            entry.quota
+        };
+        self.refresh(key);
+        quota
+    }
+}
+
+impl<Key: Hash + Eq, P> Clone for CachedQuotaProvider<Key, P> {
+    fn clone(&self) -> Self {
+        Self {
+            provider: self.provider.clone(),
+            default: self.default,
+            ttl: self.ttl,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl<Key: Hash + Eq, P> fmt::Debug for CachedQuotaProvider<Key, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedQuotaProvider")
+            .field("default", &self.default)
+            .field("ttl", &self.ttl)
+            .finish()
+    }
+}
@@ -0,0 +1,342 @@
+//! A [`GovernorStore`] keeping the rate limiter state in Redis, so every instance of a horizontally
+//! scaled service enforces one shared quota per key instead of one each.
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, redis::RedisStore};
+//! use std::time::Duration;
+//!
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(2)
+//!     .burst_size(5)
+//!     .store(
+//!         RedisStore::new("127.0.0.1:6379")
+//!             .prefix("my-service")
+//!             .timeout(Duration::from_millis(50)),
+//!     )
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::{
+    store::{blocking, Breaker, GovernorStore, MAX_ATTEMPTS},
+    BoxError,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Replaces the state of KEYS[1] with ARGV[2], expiring after ARGV[3] milliseconds, if it's still ARGV[1].
+const COMPARE_AND_SWAP: &str = "\
+local current = redis.call('GET', KEYS[1])
+if (current or '') ~= ARGV[1] then return 0 end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+return 1";
+
+// Governor's GCRA checking ARGV[2] nanoseconds of replenishment for KEYS[1] at ARGV[1], with a burst
+// worth ARGV[3]. Returns {1, the state minus ARGV[1]} when allowed, {0, the wait} otherwise.
+// Lua numbers are doubles, so states are split into seconds and nanoseconds to stay exact.
+const GCRA: &str = "\
+local function split(nanos) return tonumber(string.sub(nanos, 1, -10)) or 0, tonumber(string.sub(nanos, -9)) end
+local now_secs, now_nanos = split(ARGV[1])
+local increment, capacity = tonumber(ARGV[2]), tonumber(ARGV[3])
+local debt = 0
+local current = redis.call('GET', KEYS[1])
+if current then
+  local secs, nanos = split(current)
+  debt = math.max((secs - now_secs) * 1e9 + nanos - now_nanos, 0)
+end
+if debt + increment > capacity then return {0, debt + increment - capacity} end
+local new = debt + increment
+local nanos = now_nanos + new % 1e9
+local secs = now_secs + math.floor(new / 1e9) + math.floor(nanos / 1e9)
+redis.call('SET', KEYS[1], string.format('%d%09d', secs, nanos % 1e9), 'PX', math.floor(new / 1e6) + 1)
+return {1, new}";
+
+/// A [`GovernorStore`] shared through a Redis server, see the [module documentation](self).
+///
+/// Keys are checked by a Lua script running governor's GCRA in Redis, in a single round trip.
+/// The other updates, e.g. refunds, read the state and write it back with a compare-and-swap script,
+/// retrying a few times when another instance changed it meanwhile. The state of a key expires once
+/// its quota is fully replenished. Instances must have synchronized clocks.
+///
+/// The round trips are blocking, so set a short [`timeout`](Self::timeout). When Redis can't be
+/// reached, or a key keeps being changed by other instances, requests are allowed, see
+/// [shared stores](crate::store#shared-stores).
+///
+/// Keys are stored as `{prefix}:{scope}:{key}`, the key being formatted with [`Display`](fmt::Display).
+#[derive(Clone)]
+pub struct RedisStore {
+    address: String,
+    password: Option<String>,
+    prefix: String,
+    timeout: Duration,
+    // Idle connections.
+    pool: Arc<Mutex<Vec<Connection>>>,
+    breaker: Arc<Breaker>,
+}
+
+impl RedisStore {
+    /// A store using the Redis server at `address`, e.g. `127.0.0.1:6379`, connecting on first use.
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            password: None,
+            prefix: String::from("governor"),
+            timeout: Duration::from_millis(100),
+            pool: Arc::default(),
+            breaker: Arc::default(),
+        }
+    }
+
+    /// Authenticate with `password`.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Set the prefix of the Redis keys, `governor` by default, to keep the ones of several services apart.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set the timeout of connections and replies, 100 milliseconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn connection(&self) -> io::Result<Connection> {
+        if let Some(connection) = self.pool.lock().unwrap().pop() {
+            return Ok(connection);
+        }
+        let address =
+            self.address.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Unresolved Redis address")
+            })?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection(BufReader::new(stream));
+        if let Some(password) = &self.password {
+            connection.command(&["AUTH", password])?;
+        }
+        Ok(connection)
+    }
+
+    // Run `f` with a connection, which is kept for later unless it failed.
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.breaker.call(|| {
+            blocking(|| {
+                let mut connection = self.connection()?;
+                let result = f(&mut connection)?;
+                self.pool.lock().unwrap().push(connection);
+                Ok(result)
+            })
+        })
+    }
+
+    fn check(
+        &self,
+        key: &str,
+        now: u64,
+        increment: u64,
+        capacity: u64,
+    ) -> io::Result<Result<u64, u64>> {
+        let reply = self.with_connection(|connection| {
+            connection.command(&[
+                "EVAL",
+                GCRA,
+                "1",
+                key,
+                &now.to_string(),
+                &increment.to_string(),
+                &capacity.to_string(),
+            ])
+        })?;
+        match reply {
+            Reply::Array(reply) => match reply[..] {
+                [Reply::Integer(1), Reply::Integer(state)] => Ok(Ok(now + state as u64)),
+                [Reply::Integer(0), Reply::Integer(wait)] => Ok(Err(now + wait as u64)),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid reply")),
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid reply")),
+        }
+    }
+
+    fn update(&self, key: &str, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) -> io::Result<()> {
+        let updated = self.with_connection(|connection| {
+            for _ in 0..MAX_ATTEMPTS {
+                let current = match connection.command(&["GET", key])? {
+                    Reply::Bulk(Some(state)) => Some(parse_state(&state)?),
+                    _ => None,
+                };
+                let Some(new) = f(current) else {
+                    return Ok(true);
+                };
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| since.as_nanos() as u64);
+                let expiry = new.saturating_sub(now) / 1_000_000 + 1;
+                let current = current.map(|state| state.to_string()).unwrap_or_default();
+                let swapped = connection.command(&[
+                    "EVAL",
+                    COMPARE_AND_SWAP,
+                    "1",
+                    key,
+                    &current,
+                    &new.to_string(),
+                    &expiry.to_string(),
+                ])?;
+                if swapped == Reply::Integer(1) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
+        })?;
+        match updated {
+            true => Ok(()),
+            false => Err(io::Error::other(
+                "The state keeps being changed by other instances",
+            )),
+        }
+    }
+}
+
+impl<K: fmt::Display + Send + Sync + 'static> GovernorStore<K> for RedisStore {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        if let Err(_e) = GovernorStore::try_measure_and_replace(self, key, f) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!("Unable to update the rate limiter state in Redis: {}", _e);
+            f(None);
+        }
+    }
+
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        Ok(self.update(&format!("{}:{}", self.prefix, key), f)?)
+    }
+
+    fn check_gcra(
+        &self,
+        key: &K,
+        now: u64,
+        increment: u64,
+        capacity: u64,
+    ) -> Option<Result<Result<u64, u64>, BoxError>> {
+        let key = format!("{}:{}", self.prefix, key);
+        Some(
+            self.check(&key, now, increment, capacity)
+                .map_err(Into::into),
+        )
+    }
+
+    fn retain(&self, _keep: &mut dyn FnMut(&K, u64) -> bool) {
+        // Keys expire by themselves.
+    }
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            prefix: format!("{}:{}", self.prefix, scope),
+            ..self.clone()
+        })
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for RedisStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RedisStore")
+            .field("address", &self.address)
+            .field("prefix", &self.prefix)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+fn parse_state(state: &str) -> io::Result<u64> {
+    state
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// A reply of the RESP protocol.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+struct Connection(BufReader<TcpStream>);
+
+impl Connection {
+    // Send `args` as a command and read the reply, failing on error replies.
+    fn command(&mut self, args: &[&str]) -> io::Result<Reply> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.0.get_mut().write_all(request.as_bytes())?;
+        self.reply()
+    }
+
+    fn reply(&mut self) -> io::Result<Reply> {
+        let line = self.line()?;
+        let (kind, value) = line.split_at(line.len().min(1));
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_owned());
+        let length = || value.parse::<i64>().map_err(|_| invalid("Invalid length"));
+        match kind {
+            "+" => Ok(Reply::Simple(value.to_owned())),
+            "-" => Err(io::Error::other(value.to_owned())),
+            ":" => Ok(Reply::Integer(length()?)),
+            "$" => match usize::try_from(length()?) {
+                Ok(length) => {
+                    let mut bulk = vec![0; length + 2];
+                    io::Read::read_exact(&mut self.0, &mut bulk)?;
+                    bulk.truncate(length);
+                    String::from_utf8(bulk)
+                        .map(|bulk| Reply::Bulk(Some(bulk)))
+                        .map_err(|_| invalid("Invalid bulk string"))
+                }
+                Err(_) => Ok(Reply::Bulk(None)),
+            },
+            "*" => match usize::try_from(length()?) {
+                Ok(length) => (0..length)
+                    .map(|_| self.reply())
+                    .collect::<io::Result<_>>()
+                    .map(Reply::Array),
+                Err(_) => Ok(Reply::Array(Vec::new())),
+            },
+            _ => Err(invalid("Invalid reply")),
+        }
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.0.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
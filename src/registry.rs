@@ -0,0 +1,91 @@
+//! A process-global registry of configurations by name, so that layers on different routers or
+//! listeners can share the same rate limiting state without passing the configuration around.
+//!
+//! # Example
+//!
+//! ```rust
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, GovernorLayer};
+//!
+//! let make = || GovernorConfigBuilder::default().per_second(2).finish().unwrap();
+//! // Both layers count the requests of a key together.
+//! let public = GovernorLayer::named("api", make);
+//! let admin = GovernorLayer::named("api", make);
+//! assert!(std::sync::Arc::ptr_eq(&public.config, &admin.config));
+//! ```
+use crate::{governor::GovernorConfig, key_extractor::KeyExtractor};
+use governor::{clock::QuantaInstant, middleware::RateLimitingMiddleware};
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+type Entries = Mutex<HashMap<String, Arc<dyn Any + Send + Sync>>>;
+
+fn entries() -> &'static Entries {
+    static ENTRIES: OnceLock<Entries> = OnceLock::new();
+    ENTRIES.get_or_init(Entries::default)
+}
+
+/// Register `config` under `name`, replacing the configuration registered under it if any.
+///
+/// Layers already built keep the configuration they were built with.
+pub fn register<K, M>(
+    name: impl Into<String>,
+    config: GovernorConfig<K, M>,
+) -> Arc<GovernorConfig<K, M>>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    M: RateLimitingMiddleware<QuantaInstant> + Send + Sync + 'static,
+{
+    let config = Arc::new(config);
+    entries()
+        .lock()
+        .unwrap()
+        .insert(name.into(), config.clone());
+    config
+}
+
+/// The configuration registered under `name`, `None` if there is none or if it has another key
+/// extractor or middleware type.
+pub fn get<K, M>(name: &str) -> Option<Arc<GovernorConfig<K, M>>>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    M: RateLimitingMiddleware<QuantaInstant> + Send + Sync + 'static,
+{
+    let config = entries().lock().unwrap().get(name)?.clone();
+    config.downcast().ok()
+}
+
+/// The configuration registered under `name`, registering the one built by `make` if there is none.
+///
+/// # Panics
+///
+/// If the configuration registered under `name` has another key extractor or middleware type.
+pub fn get_or_register<K, M>(
+    name: &str,
+    make: impl FnOnce() -> GovernorConfig<K, M>,
+) -> Arc<GovernorConfig<K, M>>
+where
+    K: KeyExtractor + Send + Sync + 'static,
+    M: RateLimitingMiddleware<QuantaInstant> + Send + Sync + 'static,
+{
+    let mut entries = entries().lock().unwrap();
+    let config = entries
+        .entry(name.to_owned())
+        .or_insert_with(|| Arc::new(make()))
+        .clone();
+    config.downcast().unwrap_or_else(|_| {
+        panic!(
+            "The limiter {} is registered with another key extractor or middleware",
+            name
+        )
+    })
+}
+
+/// Remove the configuration registered under `name`, returning whether there was one.
+///
+/// Layers already built keep the configuration they were built with.
+pub fn unregister(name: &str) -> bool {
+    entries().lock().unwrap().remove(name).is_some()
+}
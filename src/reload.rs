@@ -0,0 +1,414 @@
+//! Configurations swappable at runtime, optionally reloaded from a config file.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use jsonrpsee_tower_governor::reload::watch_file;
+//! use std::time::Duration;
+//!
+//! // governor.toml:
+//! //   per_second = 2
+//! //   burst_size = 5
+//! //   key_extractor = "smart_ip"
+//! //   exempt_paths = [{ prefix = "/healthz" }]
+//! //   exempt_networks = ["10.0.0.0/8"]
+//! let config = watch_file("governor.toml", Duration::from_secs(5)).unwrap();
+//! let layer = config.layer();
+//! ```
+use crate::{
+    governor::{ConfigError, Governor, GovernorConfig, GovernorConfigBuilder, GovernorMiddleware},
+    key_extractor::{KeyExtractor, NamedKeyExtractor},
+    offenders::Offender,
+    store::Snapshot,
+    BoxError, ResponseFuture,
+};
+use governor::{
+    clock::QuantaInstant, middleware::NoOpMiddleware, middleware::RateLimitingMiddleware,
+};
+use http::{header::CONTENT_TYPE, HeaderValue, StatusCode};
+use hyper::{body::Incoming, Request, Response};
+use jsonrpsee::http_client::HttpBody;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, RwLock,
+    },
+    task::{Context, Poll},
+    thread,
+    time::Duration,
+};
+use thiserror::Error;
+use tower::{Layer, Service};
+
+/// A [`GovernorConfig`] that can be replaced while the middleware is running.
+///
+/// Clones share the same configuration. Replacing it starts with fresh rate limiters,
+/// the quota consumed so far is forgotten.
+pub struct ReloadableConfig<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    current: Arc<RwLock<Arc<GovernorConfig<K, M>>>>,
+    maintenance: Arc<AtomicU8>,
+}
+
+impl<K, M> ReloadableConfig<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    /// Start with `config`.
+    pub fn new(config: GovernorConfig<K, M>) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+            maintenance: Arc::default(),
+        }
+    }
+
+    /// The active configuration.
+    pub fn get(&self) -> Arc<GovernorConfig<K, M>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Replace the active configuration, requests being handled keep the previous one.
+    pub fn set(&self, config: GovernorConfig<K, M>) {
+        *self.current.write().unwrap() = Arc::new(config);
+    }
+
+    /// A layer rate limiting with the active configuration.
+    pub fn layer(&self) -> ReloadGovernorLayer<K, M> {
+        ReloadGovernorLayer {
+            config: self.clone(),
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for ReloadableConfig<K, M> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current.clone(),
+            maintenance: self.maintenance.clone(),
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> fmt::Debug
+    for ReloadableConfig<K, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadableConfig").finish()
+    }
+}
+
+/// A handle changing the configuration of running middlewares, e.g. their period, burst size or
+/// exempt paths, while keeping the rate limiting state of every key.
+///
+/// Unlike [`ReloadableConfig::set`], keys keep the tokens they consumed: a key that used one token of
+/// a burst of 5 has 9 left after switching to a burst of 10. Requests being checked while the
+/// configuration is updated may not be carried over.
+///
+/// # Example
+///
+/// ```rust
+/// use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, reload::GovernorHandle};
+///
+/// let handle = GovernorHandle::new(GovernorConfigBuilder::default().finish().unwrap());
+/// let layer = handle.layer();
+/// // later, e.g. from an admin endpoint
+/// handle.update_config(GovernorConfigBuilder::default().burst_size(20).finish().unwrap());
+/// ```
+pub struct GovernorHandle<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    config: ReloadableConfig<K, M>,
+}
+
+impl<K, M> GovernorHandle<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    /// Start with `config`.
+    pub fn new(config: GovernorConfig<K, M>) -> Self {
+        Self {
+            config: ReloadableConfig::new(config),
+        }
+    }
+
+    /// The active configuration.
+    pub fn config(&self) -> Arc<GovernorConfig<K, M>> {
+        self.config.get()
+    }
+
+    /// Switch to `config`, carrying over the rate limiting state of the active configuration.
+    pub fn update_config(&self, config: GovernorConfig<K, M>) {
+        let mut current = self.config.current.write().unwrap();
+        *current = Arc::new(config.carry_over(&current));
+    }
+
+    /// A layer rate limiting with the active configuration.
+    pub fn layer(&self) -> ReloadGovernorLayer<K, M> {
+        self.config.layer()
+    }
+
+    /// Drop the state of idle keys from the active configuration right away, see
+    /// [`GovernorConfig::retain_recent`].
+    pub fn gc_now(&self) {
+        self.config().retain_recent();
+    }
+
+    /// Flush the stores of the active configuration and stop their background tasks, see
+    /// [`GovernorConfig::shutdown`].
+    pub fn shutdown(&self) {
+        self.config().shutdown();
+    }
+
+    /// The keys rejected the most by the active configuration, see [`GovernorConfig::top_offenders`].
+    pub fn top_offenders(&self) -> Vec<Offender<K::Key>> {
+        self.config().top_offenders()
+    }
+
+    /// The rate limiting state of the active configuration, e.g. to debug it or to warm up a standby
+    /// with [`import_state`](Self::import_state), see [`GovernorConfig::export_state`].
+    pub fn export_state(&self) -> Snapshot<K::Key> {
+        self.config().export_state()
+    }
+
+    /// Consume the tokens of the keys of `snapshot` in the active configuration, see
+    /// [`GovernorConfig::import_state`].
+    pub fn import_state(&self, snapshot: &Snapshot<K::Key>) {
+        self.config().import_state(snapshot);
+    }
+
+    /// Temporarily allow or reject all traffic, e.g. during an incident, see [`Maintenance`].
+    pub fn set_maintenance(&self, maintenance: Maintenance) {
+        self.config
+            .maintenance
+            .store(maintenance as u8, Ordering::Relaxed);
+    }
+
+    /// The active maintenance mode.
+    pub fn maintenance(&self) -> Maintenance {
+        Maintenance::from_u8(self.config.maintenance.load(Ordering::Relaxed))
+    }
+}
+
+/// Maintenance mode of the middlewares of a [`GovernorHandle`], see [`GovernorHandle::set_maintenance`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Maintenance {
+    /// Rate limit requests with the active configuration. This is the default.
+    #[default]
+    Off,
+    /// Pass every request to the inner service without rate limiting it.
+    AllowAll,
+    /// Reject every request with `503 Service Unavailable`, telling clients the service is under
+    /// maintenance rather than rate limiting them.
+    RejectAll,
+}
+
+impl Maintenance {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::AllowAll,
+            2 => Self::RejectAll,
+            _ => Self::Off,
+        }
+    }
+}
+
+fn maintenance_response() -> Response<HttpBody> {
+    let mut response = Response::new(HttpBody::from("Service unavailable for maintenance"));
+    *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone for GovernorHandle<K, M> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> fmt::Debug
+    for GovernorHandle<K, M>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GovernorHandle").finish()
+    }
+}
+
+/// The Layer type of [`ReloadGovernor`], created by [`ReloadableConfig::layer`].
+pub struct ReloadGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    pub config: ReloadableConfig<K, M>,
+}
+
+impl<K, M, S> Layer<S> for ReloadGovernorLayer<K, M>
+where
+    K: KeyExtractor,
+    M: RateLimitingMiddleware<QuantaInstant>,
+{
+    type Service = ReloadGovernor<K, M, S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let current = self.config.get();
+        ReloadGovernor {
+            governor: Governor::new(inner, &current),
+            config: self.config.clone(),
+            current,
+        }
+    }
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>> Clone
+    for ReloadGovernorLayer<K, M>
+{
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// A [`Governor`] picking up the changes of a [`ReloadableConfig`].
+pub struct ReloadGovernor<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S> {
+    governor: Governor<K, M, S>,
+    config: ReloadableConfig<K, M>,
+    current: Arc<GovernorConfig<K, M>>,
+}
+
+impl<K: KeyExtractor, M: RateLimitingMiddleware<QuantaInstant>, S: Clone> Clone
+    for ReloadGovernor<K, M, S>
+{
+    fn clone(&self) -> Self {
+        Self {
+            governor: self.governor.clone(),
+            config: self.config.clone(),
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<K, M, S> Service<Request<Incoming>> for ReloadGovernor<K, M, S>
+where
+    K: KeyExtractor,
+    K::Key: Send + Sync + 'static,
+    M: GovernorMiddleware,
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.governor.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
+        match Maintenance::from_u8(self.config.maintenance.load(Ordering::Relaxed)) {
+            Maintenance::Off => {}
+            Maintenance::AllowAll => {
+                return ResponseFuture::passthrough(self.governor.inner.call(req))
+            }
+            Maintenance::RejectAll => return ResponseFuture::error(maintenance_response()),
+        }
+        let latest = self.config.get();
+        if !Arc::ptr_eq(&latest, &self.current) {
+            self.governor.reconfigure(&latest);
+            self.current = latest;
+        }
+        self.governor.call(req)
+    }
+}
+
+/// Error reading a config file, see [`load_file`].
+#[derive(Debug, Error)]
+pub enum ConfigFileError {
+    #[error("Unable to read the config file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid config file: {0}")]
+    Parse(String),
+    #[error("Invalid configuration: {0}")]
+    Invalid(#[from] ConfigError),
+}
+
+/// Read a configuration from a TOML file, a JSON file if the extension is `.json`, or a YAML file
+/// if it is `.yaml` or `.yml`, which needs the `yaml` feature.
+///
+/// The file has the fields read by the `Deserialize` impl of [`GovernorConfigBuilder`].
+pub fn load_file(
+    path: impl AsRef<Path>,
+) -> Result<GovernorConfig<NamedKeyExtractor, NoOpMiddleware>, ConfigFileError> {
+    let path = path.as_ref();
+    let contents = fs::read_to_string(path)?;
+    let mut builder: GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> =
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => serde_json::from_str(&contents)
+                .map_err(|e| ConfigFileError::Parse(e.to_string()))?,
+            #[cfg(feature = "yaml")]
+            Some("yaml" | "yml") => serde_yaml_ng::from_str(&contents)
+                .map_err(|e| ConfigFileError::Parse(e.to_string()))?,
+            #[cfg(not(feature = "yaml"))]
+            Some("yaml" | "yml") => {
+                return Err(ConfigFileError::Parse(
+                    "YAML config files need the `yaml` feature".to_string(),
+                ))
+            }
+            _ => toml::from_str(&contents).map_err(|e| ConfigFileError::Parse(e.to_string()))?,
+        };
+    Ok(builder.try_build()?)
+}
+
+/// Read a configuration with [`load_file`] and reload it whenever the file is modified,
+/// checking its modification time every `interval`.
+///
+/// Like [`GovernorHandle::update_config`], reloading carries over the rate limiting state of every key.
+/// A modified file that can't be read keeps the previous configuration active.
+/// The watching thread stops once every clone of the returned configuration is dropped.
+pub fn watch_file(
+    path: impl Into<PathBuf>,
+    interval: Duration,
+) -> Result<ReloadableConfig<NamedKeyExtractor, NoOpMiddleware>, ConfigFileError> {
+    let path = path.into();
+    let mut modified = fs::metadata(&path)?.modified()?;
+    let config = ReloadableConfig::new(load_file(&path)?);
+    let current = Arc::downgrade(&config.current);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let Some(current) = current.upgrade() else {
+            return;
+        };
+        let Ok(latest) = fs::metadata(&path).and_then(|m| m.modified()) else {
+            continue;
+        };
+        if latest == modified {
+            continue;
+        }
+        modified = latest;
+        match load_file(&path) {
+            Ok(config) => {
+                let mut current = current.write().unwrap();
+                *current = Arc::new(config.carry_over(&current));
+            }
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("Keeping the previous rate limiting configuration: {}", _e);
+            }
+        }
+    });
+    Ok(config)
+}
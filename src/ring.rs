@@ -0,0 +1,464 @@
+//! A [`GovernorStore`] spreading the keys over the instances of a service by consistent hashing,
+//! each instance keeping the state of the keys it owns and checking the other keys with their
+//! owner, for fleets behind a load balancer that don't run a central store.
+//!
+//! # Example
+//!
+//! ```rust,no_run
+//! use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, ring::RingStore};
+//!
+//! // The same members on every instance, each one binding its own address.
+//! let members = ["10.0.0.1:7947", "10.0.0.2:7947", "10.0.0.3:7947"];
+//! let store = RingStore::bind("10.0.0.1:7947", members).unwrap();
+//! let config = GovernorConfigBuilder::default()
+//!     .per_second(2)
+//!     .burst_size(5)
+//!     .store(store)
+//!     .finish()
+//!     .unwrap();
+//! ```
+use crate::{
+    store::{blocking, fnv1a, Breaker, GovernorStore, MemoryStore, MAX_ATTEMPTS},
+    BoxError,
+};
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Points of each member on the ring, to spread the keys evenly.
+const VIRTUAL_NODES: u64 = 64;
+
+// Connections served at once, the other ones are closed right away.
+const MAX_CONNECTIONS: usize = 256;
+
+/// A [`GovernorStore`] partitioned between instances, see the [module documentation](self).
+///
+/// Every instance is given the same members, and owns the keys closest to its points on a hash
+/// ring, so adding or removing a member only moves the keys of its neighbors. The state of an owned
+/// key is checked locally, the other keys are checked with a compare-and-swap on their owner over
+/// TCP, retried a few times when another instance changed the key meanwhile. The round trips are
+/// blocking, so set a short [`timeout`](Self::timeout). Instances must have synchronized clocks.
+///
+/// When the owner of a key can't be reached, or other instances keep changing the key, the key is
+/// checked against a local state instead, so each instance enforces the quota on its own until the
+/// owner is back, see [shared stores](crate::store#shared-stores).
+///
+/// Only the members can connect: connections from other addresses than the ones of the members,
+/// resolved when binding, are closed, and at most 256 connections are served at once. The
+/// connections are neither authenticated nor encrypted though, so any host able to use the address
+/// of a member can read and change the states, only use it on a trusted network.
+///
+/// Keys are sent formatted with [`Display`](fmt::Display), keys containing whitespace are replaced
+/// by their hash.
+#[derive(Clone)]
+pub struct RingStore {
+    node: Arc<Node>,
+    prefix: String,
+    timeout: Duration,
+}
+
+struct Node {
+    this: usize,
+    members: Vec<Member>,
+    // Sorted points of the ring and the index of their member.
+    ring: Vec<(u64, usize)>,
+    // The keys owned by this instance.
+    owned: MemoryStore<String>,
+    // The keys whose owner can't be reached.
+    fallback: MemoryStore<String>,
+    // Set by `GovernorStore::shutdown`.
+    stopped: AtomicBool,
+    // The address of the listener, to wake it up when stopping.
+    local: SocketAddr,
+}
+
+struct Member {
+    address: String,
+    // Idle connections.
+    pool: Mutex<Vec<Connection>>,
+    breaker: Breaker,
+}
+
+impl RingStore {
+    /// A store owning its share of the keys between `members`, e.g. `10.0.0.1:7947`, serving the
+    /// other members on `address`, which must be one of them.
+    ///
+    /// The serving thread stops once every rate limiter using the store is dropped, or when the
+    /// store is [shut down](GovernorStore::shutdown): the other members then check the keys owned
+    /// by this instance against their local state until it is replaced.
+    ///
+    /// Fails with [`InvalidInput`](io::ErrorKind::InvalidInput) if `address` isn't one of `members`,
+    /// or if the address of a member can't be resolved.
+    pub fn bind<I>(address: &str, members: I) -> io::Result<Self>
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        let members: Vec<Member> = members
+            .into_iter()
+            .map(|address| Member {
+                address: address.into(),
+                pool: Mutex::default(),
+                breaker: Breaker::default(),
+            })
+            .collect();
+        let this = members
+            .iter()
+            .position(|member| member.address == address)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "The address of the instance isn't one of the members",
+                )
+            })?;
+        let mut ring: Vec<_> = members
+            .iter()
+            .enumerate()
+            .flat_map(|(i, member)| {
+                (0..VIRTUAL_NODES).map(move |point| {
+                    (fnv1a(format!("{}#{}", member.address, point).as_bytes()), i)
+                })
+            })
+            .collect();
+        ring.sort_unstable();
+        let mut peers = Vec::new();
+        for member in &members {
+            peers.extend(
+                member
+                    .address
+                    .to_socket_addrs()?
+                    .map(|address| address.ip()),
+            );
+        }
+        let listener = TcpListener::bind(address)?;
+        let node = Arc::new(Node {
+            this,
+            members,
+            ring,
+            owned: MemoryStore::default(),
+            fallback: MemoryStore::default(),
+            stopped: AtomicBool::new(false),
+            local: listener.local_addr()?,
+        });
+        let weak = Arc::downgrade(&node);
+        thread::spawn(move || serve(listener, peers, weak));
+        Ok(Self {
+            node,
+            prefix: String::from("governor"),
+            timeout: Duration::from_millis(100),
+        })
+    }
+
+    /// Set the prefix of the keys, `governor` by default, to keep the ones of several services apart.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set the timeout of connections and replies to the other members, 100 milliseconds by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn connection(&self, member: &Member) -> io::Result<Connection> {
+        if let Some(connection) = member.pool.lock().unwrap().pop() {
+            return Ok(connection);
+        }
+        let address =
+            member.address.to_socket_addrs()?.next().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "Unresolved member address")
+            })?;
+        let stream = TcpStream::connect_timeout(&address, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Connection(BufReader::new(stream)))
+    }
+
+    fn key(&self, key: &impl fmt::Display) -> String {
+        let key = format!("{}:{}", self.prefix, key);
+        match key.contains(char::is_whitespace) {
+            true => format!("{}:{:016x}", self.prefix, fnv1a(key.as_bytes())),
+            false => key,
+        }
+    }
+
+    // Check `key` with its owner.
+    fn check(&self, key: &str, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) -> io::Result<()> {
+        let owner = self.node.owner(key);
+        if owner == self.node.this {
+            self.node.owned.measure_and_replace(&key.to_owned(), f);
+            return Ok(());
+        }
+        self.forward(&self.node.members[owner], key, f)
+    }
+
+    // Check `key` with its owner `member`.
+    fn forward(
+        &self,
+        member: &Member,
+        key: &str,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> io::Result<()> {
+        let swapped = member.breaker.call(|| {
+            blocking(|| {
+                let mut connection = self.connection(member)?;
+                let mut swapped = false;
+                for _ in 0..MAX_ATTEMPTS {
+                    let current = connection.get(key)?;
+                    swapped = match f(current) {
+                        Some(new) => connection.compare_and_swap(key, current, new)?,
+                        None => true,
+                    };
+                    if swapped {
+                        break;
+                    }
+                }
+                member.pool.lock().unwrap().push(connection);
+                Ok::<_, io::Error>(swapped)
+            })
+        })?;
+        match swapped {
+            true => Ok(()),
+            false => Err(io::Error::other(
+                "The state keeps being changed by other instances",
+            )),
+        }
+    }
+}
+
+impl Node {
+    fn owner(&self, key: &str) -> usize {
+        let hash = fnv1a(key.as_bytes());
+        let point = self.ring.partition_point(|(point, _)| *point < hash);
+        self.ring[point % self.ring.len()].1
+    }
+
+    // Unblock the serving thread, which then notices the node was stopped or dropped.
+    fn wake(&self) {
+        let mut local = self.local;
+        if local.ip().is_unspecified() {
+            local.set_ip(match local {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        let _ = TcpStream::connect_timeout(&local, Duration::from_secs(1));
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        if !self.stopped.load(Ordering::Relaxed) {
+            self.wake();
+        }
+    }
+}
+
+impl<K: fmt::Display + Send + Sync + 'static> GovernorStore<K> for RingStore {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        let key = self.key(key);
+        if let Err(_e) = self.check(&key, f) {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(
+                "Unable to check the rate limiter state with its owner: {}",
+                _e
+            );
+            self.node.fallback.measure_and_replace(&key, f);
+        }
+    }
+
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        Ok(self.check(&self.key(key), f)?)
+    }
+
+    fn retain(&self, _keep: &mut dyn FnMut(&K, u64) -> bool) {
+        // The states are shared by every scope and member, keep the ones still replenishing.
+        let now = unix_now();
+        self.node.owned.retain(&mut |_, state| state > now);
+        self.node.fallback.retain(&mut |_, state| state > now);
+    }
+
+    fn len(&self) -> usize {
+        self.node.owned.len()
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            prefix: format!("{}:{}", self.prefix, scope),
+            ..self.clone()
+        })
+    }
+
+    fn shutdown(&self) {
+        if !self.node.stopped.swap(true, Ordering::Relaxed) {
+            self.node.wake();
+        }
+        for member in &self.node.members {
+            member.pool.lock().unwrap().clear();
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+impl fmt::Debug for RingStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let members: Vec<_> = self.node.members.iter().map(|m| &m.address).collect();
+        f.debug_struct("RingStore")
+            .field("address", members[self.node.this])
+            .field("members", &members)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+// Accept the connections of the other members, from `peers`, until the node is dropped or shut
+// down.
+fn serve(listener: TcpListener, peers: Vec<IpAddr>, node: Weak<Node>) {
+    let served = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        if stopped(&node) {
+            return;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        let member = stream
+            .peer_addr()
+            .is_ok_and(|peer| peers.contains(&peer.ip().to_canonical()));
+        if !member {
+            continue;
+        }
+        if served.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+            served.fetch_sub(1, Ordering::Relaxed);
+            continue;
+        }
+        let node = node.clone();
+        let served = served.clone();
+        thread::spawn(move || {
+            let _ = serve_connection(stream, node);
+            served.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+// Answer `GET <key>` with `VALUE <state>` or `NONE`, and `CAS <key> <state or -> <new>`
+// with `STORED` or `EXISTS`.
+fn serve_connection(stream: TcpStream, node: Weak<Node>) -> io::Result<()> {
+    // Wakes up to notice the node was dropped.
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let mut connection = Connection(BufReader::new(stream));
+    loop {
+        let line = match connection.line() {
+            Err(e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                if stopped(&node) {
+                    return Ok(());
+                }
+                continue;
+            }
+            line => line?,
+        };
+        let Some(node) = node
+            .upgrade()
+            .filter(|node| !node.stopped.load(Ordering::Relaxed))
+        else {
+            return Ok(());
+        };
+        let fields: Vec<&str> = line.split(' ').collect();
+        let reply = match fields.as_slice() {
+            ["GET", key] => match GovernorStore::<String>::get(&node.owned, &key.to_string()) {
+                Some(state) => format!("VALUE {}", state),
+                None => String::from("NONE"),
+            },
+            ["CAS", key, expected, new] => {
+                let expected = expected.parse().ok();
+                let new = new.parse().map_err(|_| invalid(&line))?;
+                let mut stored = false;
+                node.owned
+                    .measure_and_replace(&key.to_string(), &mut |current| {
+                        stored = current == expected;
+                        stored.then_some(new)
+                    });
+                String::from(if stored { "STORED" } else { "EXISTS" })
+            }
+            _ => return Err(invalid(&line)),
+        };
+        connection.write_line(&reply)?;
+    }
+}
+
+// Whether the node was dropped or shut down.
+fn stopped(node: &Weak<Node>) -> bool {
+    node.upgrade()
+        .is_none_or(|node| node.stopped.load(Ordering::Relaxed))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+struct Connection(BufReader<TcpStream>);
+
+impl Connection {
+    fn get(&mut self, key: &str) -> io::Result<Option<u64>> {
+        self.write_line(&format!("GET {}", key))?;
+        let line = self.line()?;
+        match line.split_once(' ') {
+            Some(("VALUE", state)) => state.parse().map(Some).map_err(|_| invalid(&line)),
+            _ if line == "NONE" => Ok(None),
+            _ => Err(invalid(&line)),
+        }
+    }
+
+    // Replace the state of `key` with `new` if it still is `current`, returning whether it was.
+    fn compare_and_swap(&mut self, key: &str, current: Option<u64>, new: u64) -> io::Result<bool> {
+        let current = current.map_or_else(|| String::from("-"), |state| state.to_string());
+        self.write_line(&format!("CAS {} {} {}", key, current, new))?;
+        match self.line()?.as_str() {
+            "STORED" => Ok(true),
+            "EXISTS" => Ok(false),
+            line => Err(invalid(line)),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.0
+            .get_mut()
+            .write_all(format!("{}\r\n", line).as_bytes())
+    }
+
+    fn line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        if self.0.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+fn invalid(reply: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unexpected ring store message: {}", reply),
+    )
+}
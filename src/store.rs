@@ -0,0 +1,1447 @@
+//! The backends keeping the state of the rate limiters, see [`GovernorStore`].
+//!
+//! # Shared stores
+//!
+//! The stores shared by the instances of a service, `redis::RedisStore`, `memcached::MemcachedStore`,
+//! `ring::RingStore` and [`kv::KvStore`](crate::kv::KvStore), are called on the thread handling the
+//! request and block it until their backend answers. On a multi-threaded runtime the other tasks of
+//! the worker thread are moved elsewhere meanwhile, see [`block_in_place`], on a current-thread
+//! runtime they wait, so keep the timeouts of the stores short.
+//!
+//! When the backend fails or times out, or other instances keep changing a key, the request is checked
+//! as if its key had no state, i.e. it is allowed. The `RingStore` checks it against a local state
+//! instead. This doesn't depend on the [`FailurePolicy`](crate::governor::FailurePolicy), which only
+//! applies to key extraction.
+//!
+//! After 3 failures in a row, a store stops calling its backend, or the failing server or member,
+//! for 5 seconds: requests are then checked right away, without waiting for timeouts. To check the
+//! keys in memory meanwhile, wrap the store in a [`CircuitBreakerStore`].
+use crate::{BoxError, RateLimitInfo};
+use dashmap::DashMap;
+use governor::{
+    clock::{Clock, DefaultClock, QuantaInstant, Reference},
+    nanos::Nanos,
+    state::{keyed::ShrinkableKeyedStateStore, StateStore},
+    Quota,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    hash::{BuildHasher, Hash, Hasher, RandomState},
+    io,
+    num::NonZeroU64,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task::block_in_place,
+};
+
+/// A backend keeping the state of the keys of a rate limiter, e.g. in memory or in a database shared
+/// by several instances of a service, see [`GovernorConfigBuilder::store`](crate::governor::GovernorConfigBuilder::store).
+///
+/// The state of a key is its theoretical arrival time (a GCRA term) in nanoseconds, since the creation
+/// of the store, or since the UNIX epoch for [shared](Self::is_shared) stores.
+pub trait GovernorStore<K>: Send + Sync + 'static {
+    /// Call `f` with the state of `key`, `None` if it has none, and replace the state with the one `f`
+    /// returns, if any, unless the state changed meanwhile: then call `f` again with the new state.
+    ///
+    /// `f` must be called at least once. Stores unable to read the state decide how to fail,
+    /// e.g. calling `f` with `None` allows the request.
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>);
+
+    /// Like [`measure_and_replace`](Self::measure_and_replace), but returning the error of stores unable
+    /// to read or write the state instead of deciding how to fail, see [`CircuitBreakerStore`].
+    ///
+    /// `f` may have been called before the error, the caller calls it again.
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        self.measure_and_replace(key, f);
+        Ok(())
+    }
+
+    /// Check `increment` nanoseconds of replenishment for `key` at `now`, against a quota whose burst
+    /// is worth `capacity` nanoseconds, running governor's GCRA in the backend itself in one step, e.g.
+    /// as a script run by a database, instead of reading and replacing the state.
+    ///
+    /// Returns the new state when allowed, or the earliest time the increment fits when denied.
+    /// Stores unable to run it return `None`, the default, and the key is checked with
+    /// [`measure_and_replace`](Self::measure_and_replace). When the backend fails, the error is
+    /// returned and the key is checked as if it had no state.
+    fn check_gcra(
+        &self,
+        _key: &K,
+        _now: u64,
+        _increment: u64,
+        _capacity: u64,
+    ) -> Option<Result<Result<u64, u64>, BoxError>> {
+        None
+    }
+
+    /// The state of `key`, `None` if it has none.
+    fn get(&self, key: &K) -> Option<u64> {
+        let mut state = None;
+        self.measure_and_replace(key, &mut |current| {
+            state = current;
+            None
+        });
+        state
+    }
+
+    /// Only keep the keys for which `keep` returns `true` given their state.
+    ///
+    /// Stores expiring their keys by themselves may do nothing.
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool);
+
+    /// Number of keys, `0` for stores unable to tell.
+    fn len(&self) -> usize;
+
+    /// Whether there are no keys.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Release unused memory, if any.
+    fn shrink_to_fit(&self) {}
+
+    /// Write the pending updates to the backend, if any, and stop the background tasks of the store,
+    /// e.g. before the instance exits during a rolling deploy, see
+    /// [`GovernorConfig::shutdown`](crate::governor::GovernorConfig::shutdown).
+    ///
+    /// This may be called once per scope. The store keeps checking keys afterwards, if only locally.
+    fn shutdown(&self) {}
+
+    /// The store of another rate limiter of the same configuration, e.g. the one of a
+    /// [`method_quota`](crate::governor::GovernorConfigBuilder::method_quota), named `scope`.
+    ///
+    /// Local stores return a new empty store, shared stores should keep the keys of each scope apart.
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>>;
+
+    /// A new empty store like [`scoped`](Self::scoped) holding the keys of this one, their state mapped
+    /// by `migrate`, those it maps to `None` being left out. Only called on local stores.
+    ///
+    /// Stores spreading the keys over several stores should keep each key in the matching one.
+    fn migrated(&self, migrate: &mut dyn FnMut(u64) -> Option<u64>) -> Arc<dyn GovernorStore<K>>
+    where
+        K: 'static,
+    {
+        let migrated = self.scoped("");
+        copy_migrated(self, &*migrated, migrate);
+        migrated
+    }
+
+    /// Whether the states are shared with other instances, their time being counted since the UNIX epoch
+    /// so every instance agrees on it as long as their clocks are in sync.
+    fn is_shared(&self) -> bool {
+        false
+    }
+}
+
+// Put the keys of `from` in `into`, their state mapped by `migrate`.
+fn copy_migrated<K>(
+    from: &(impl GovernorStore<K> + ?Sized),
+    into: &(impl GovernorStore<K> + ?Sized),
+    migrate: &mut dyn FnMut(u64) -> Option<u64>,
+) {
+    // Walks the keys, keeping all of them.
+    from.retain(&mut |key, state| {
+        if let Some(state) = migrate(state) {
+            into.measure_and_replace(key, &mut |_| Some(state));
+        }
+        true
+    });
+}
+
+/// The default in-memory [`GovernorStore`], a concurrent map from the keys to their state.
+pub struct MemoryStore<K: Hash + Eq> {
+    map: DashMap<K, AtomicU64>,
+}
+
+impl<K: Hash + Eq> Default for MemoryStore<K> {
+    fn default() -> Self {
+        Self {
+            map: DashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq> fmt::Debug for MemoryStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryStore")
+            .field("len", &self.map.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> GovernorStore<K> for MemoryStore<K> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        if let Some(state) = self.map.get(key) {
+            // fast path: measure existing entry
+            return measure_and_replace_one(&state, f);
+        }
+        // make an entry and measure that:
+        let state = self.map.entry(key.clone()).or_default();
+        measure_and_replace_one(&state, f)
+    }
+
+    fn get(&self, key: &K) -> Option<u64> {
+        self.map
+            .get(key)
+            .and_then(|state| NonZeroU64::new(state.load(Ordering::Acquire)))
+            .map(NonZeroU64::get)
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        self.map
+            .retain(|key, tat| keep(key, tat.load(Ordering::Relaxed)));
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn shrink_to_fit(&self) {
+        self.map.shrink_to_fit();
+    }
+
+    fn scoped(&self, _scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self::default())
+    }
+}
+
+/// A [`GovernorStore`] spreading the keys over several stores by hash, to reduce the contention between
+/// threads on hot multi-core servers, or the locking of stores like the [`LruStore`].
+///
+/// # Example
+/// ```rust
+/// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, store::ShardedStore};
+/// GovernorConfigBuilder::default().store(ShardedStore::new(64));
+/// ```
+pub struct ShardedStore<K> {
+    shards: Box<[Arc<dyn GovernorStore<K>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> ShardedStore<K> {
+    /// A store of `shards` [`MemoryStore`]s.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn new(shards: usize) -> Self {
+        Self::with(shards, MemoryStore::default)
+    }
+}
+
+impl<K: Hash + 'static> ShardedStore<K> {
+    /// A store of `shards` stores made by `make`.
+    ///
+    /// # Panics
+    ///
+    /// If `shards` is zero.
+    pub fn with<S: GovernorStore<K>>(shards: usize, mut make: impl FnMut() -> S) -> Self {
+        assert!(shards > 0, "No shard");
+        Self {
+            shards: (0..shards)
+                .map(|_| Arc::new(make()) as Arc<dyn GovernorStore<K>>)
+                .collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &dyn GovernorStore<K> {
+        let index = self.hasher.hash_one(key) % self.shards.len() as u64;
+        self.shards[index as usize].as_ref()
+    }
+}
+
+impl<K> fmt::Debug for ShardedStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedStore")
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Send + Sync + 'static> GovernorStore<K> for ShardedStore<K> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        self.shard(key).measure_and_replace(key, f)
+    }
+
+    fn get(&self, key: &K) -> Option<u64> {
+        self.shard(key).get(key)
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        for shard in self.shards.iter() {
+            shard.retain(keep);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    fn shrink_to_fit(&self) {
+        for shard in self.shards.iter() {
+            shard.shrink_to_fit();
+        }
+    }
+
+    fn shutdown(&self) {
+        for shard in self.shards.iter() {
+            shard.shutdown();
+        }
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            shards: self
+                .shards
+                .iter()
+                .map(|shard| shard.scoped(scope))
+                .collect(),
+            hasher: RandomState::new(),
+        })
+    }
+}
+
+/// A [`GovernorStore`] keeping at most a number of keys, evicting the least recently used ones, so
+/// clients rotating through millions of IP addresses can't grow the rate limiter state without bound.
+///
+/// Evicted keys get a full quota again, so the bound should be well above the number of keys active
+/// within the period of the quota. Checks lock the whole store, spread the keys over several of them
+/// with a [`ShardedStore`] on busy services.
+///
+/// # Example
+/// ```rust
+/// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, store::{LruStore, ShardedStore}};
+/// // At most a million keys, in 16 shards.
+/// GovernorConfigBuilder::default().store(ShardedStore::with(16, || LruStore::new(1_000_000 / 16)));
+/// ```
+pub struct LruStore<K> {
+    max_keys: usize,
+    lru: Mutex<Lru<K>>,
+}
+
+struct Lru<K> {
+    // The state and last use of the keys.
+    states: HashMap<K, (u64, u64)>,
+    // The keys by last use.
+    uses: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Hash + Eq + Clone> LruStore<K> {
+    /// A store of at most `max_keys` keys.
+    ///
+    /// # Panics
+    ///
+    /// If `max_keys` is zero.
+    pub fn new(max_keys: usize) -> Self {
+        assert!(max_keys > 0, "No key allowed");
+        Self {
+            max_keys,
+            lru: Mutex::new(Lru {
+                states: HashMap::new(),
+                uses: BTreeMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+}
+
+impl<K> fmt::Debug for LruStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LruStore")
+            .field("max_keys", &self.max_keys)
+            .field("len", &self.lru.lock().unwrap().states.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> GovernorStore<K> for LruStore<K> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        lru.clock += 1;
+        match lru.states.get_mut(key) {
+            Some((state, used)) => {
+                lru.uses.remove(used);
+                lru.uses.insert(lru.clock, key.clone());
+                *used = lru.clock;
+                if let Some(new) = f(Some(*state)) {
+                    *state = new;
+                }
+            }
+            None => {
+                let Some(new) = f(None) else {
+                    return;
+                };
+                if lru.states.len() == self.max_keys {
+                    if let Some((_, evicted)) = lru.uses.pop_first() {
+                        lru.states.remove(&evicted);
+                    }
+                }
+                lru.uses.insert(lru.clock, key.clone());
+                lru.states.insert(key.clone(), (new, lru.clock));
+            }
+        }
+    }
+
+    fn get(&self, key: &K) -> Option<u64> {
+        let lru = self.lru.lock().unwrap();
+        lru.states.get(key).map(|(state, _)| *state)
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        let mut lru = self.lru.lock().unwrap();
+        let lru = &mut *lru;
+        let uses = &mut lru.uses;
+        lru.states.retain(|key, (state, used)| {
+            let kept = keep(key, *state);
+            if !kept {
+                uses.remove(used);
+            }
+            kept
+        });
+    }
+
+    fn len(&self) -> usize {
+        self.lru.lock().unwrap().states.len()
+    }
+
+    fn shrink_to_fit(&self) {
+        self.lru.lock().unwrap().states.shrink_to_fit();
+    }
+
+    fn scoped(&self, _scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self::new(self.max_keys))
+    }
+}
+
+// The `GovernorStore` of `LimiterMode::PerWorker`, a `MemoryStore` per thread out of `workers`.
+pub(crate) struct WorkerStore<K: Hash + Eq> {
+    workers: Box<[MemoryStore<K>]>,
+}
+
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The index of the current thread, in the order they first checked a key.
+    static WORKER: usize = NEXT_WORKER.fetch_add(1, Ordering::Relaxed);
+}
+
+impl<K: Hash + Eq> WorkerStore<K> {
+    pub(crate) fn new(workers: usize) -> Self {
+        Self {
+            workers: (0..workers.max(1))
+                .map(|_| MemoryStore::default())
+                .collect(),
+        }
+    }
+
+    fn worker(&self) -> &MemoryStore<K> {
+        &self.workers[WORKER.with(|worker| *worker) % self.workers.len()]
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> GovernorStore<K> for WorkerStore<K> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        self.worker().measure_and_replace(key, f)
+    }
+
+    fn get(&self, key: &K) -> Option<u64> {
+        self.worker().get(key)
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        for worker in self.workers.iter() {
+            worker.retain(keep);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.workers.iter().map(MemoryStore::len).sum()
+    }
+
+    fn shrink_to_fit(&self) {
+        for worker in self.workers.iter() {
+            worker.shrink_to_fit();
+        }
+    }
+
+    fn scoped(&self, _scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self::new(self.workers.len()))
+    }
+
+    // Each thread keeps checking its keys in the same worker.
+    fn migrated(&self, migrate: &mut dyn FnMut(u64) -> Option<u64>) -> Arc<dyn GovernorStore<K>> {
+        let workers = self
+            .workers
+            .iter()
+            .map(|worker| {
+                let migrated = MemoryStore::default();
+                copy_migrated(worker, &migrated, migrate);
+                migrated
+            })
+            .collect();
+        Arc::new(Self { workers })
+    }
+}
+
+// The requests of the keys over the window of their quota, see `Algorithm::SlidingWindowLog`.
+// Requests are logged with the replenishment they consumed, in nanoseconds, so that they can be
+// refunded and charged like the theoretical arrival times of the GCRA.
+pub(crate) struct WindowLog<K: Hash + Eq> {
+    logs: DashMap<K, Log>,
+    start: Instant,
+}
+
+#[derive(Default)]
+struct Log {
+    // The time of the requests since `start`, oldest first, with the replenishment they consumed.
+    entries: VecDeque<(u64, u64)>,
+    // The replenishment consumed by the entries.
+    total: u64,
+    // The window of the quota the key was last checked against.
+    window: u64,
+}
+
+impl Log {
+    // Drop the requests that left the window.
+    fn expire(&mut self, now: u64) {
+        while let Some(&(at, amount)) = self.entries.front() {
+            if at.saturating_add(self.window) > now {
+                break;
+            }
+            self.entries.pop_front();
+            self.total -= amount;
+        }
+    }
+
+    // Time until the last request leaves the window.
+    fn reset(&self, now: u64) -> Duration {
+        let end = self
+            .entries
+            .back()
+            .map_or(0, |(at, _)| at.saturating_add(self.window));
+        Duration::from_nanos(end.saturating_sub(now))
+    }
+}
+
+impl<K: Hash + Eq> WindowLog<K> {
+    fn new() -> Self {
+        Self {
+            logs: DashMap::new(),
+            start: Instant::now(),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+impl<K: Hash + Eq + Clone> WindowLog<K> {
+    /// Log a request of `key` consuming `amount` of replenishment if `quota` allows it over its
+    /// window, of burst size times period, returning the state of the quota, or how long to wait.
+    /// The amount must not exceed the burst size.
+    pub(crate) fn check(
+        &self,
+        key: &K,
+        amount: Nanos,
+        quota: &Quota,
+    ) -> Result<RateLimitInfo, Duration> {
+        let now = self.now();
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let limit = quota.burst_size().get();
+        let capacity = t * u64::from(limit);
+        let amount = amount.as_u64();
+        let mut log = self.logs.entry(key.clone()).or_default();
+        log.window = capacity;
+        log.expire(now);
+        let excess = (log.total + amount).saturating_sub(capacity);
+        if excess > 0 {
+            // Wait for enough requests to leave the window.
+            let mut freed = 0;
+            let at = log
+                .entries
+                .iter()
+                .find(|(_, amount)| {
+                    freed += amount;
+                    freed >= excess
+                })
+                .map_or(now, |(at, _)| *at);
+            return Err(Duration::from_nanos(
+                at.saturating_add(capacity).saturating_sub(now),
+            ));
+        }
+        log.entries.push_back((now, amount));
+        log.total += amount;
+        Ok(RateLimitInfo {
+            limit,
+            remaining: ((capacity - log.total) / t) as u32,
+            reset: log.reset(now),
+        })
+    }
+
+    // Give `amount` of replenishment back to `key`, taken from its last requests.
+    fn refund(&self, key: &K, mut amount: u64) {
+        let Some(mut log) = self.logs.get_mut(key) else {
+            return;
+        };
+        while let Some(last) = log.entries.back_mut().filter(|_| amount > 0) {
+            let refunded = last.1.min(amount);
+            last.1 -= refunded;
+            if last.1 == 0 {
+                log.entries.pop_back();
+            }
+            log.total -= refunded;
+            amount -= refunded;
+        }
+    }
+
+    // Log `amount` of replenishment taken from `key` now, even beyond the capacity of its quota.
+    fn charge(&self, key: &K, amount: u64) {
+        let now = self.now();
+        let mut log = self.logs.entry(key.clone()).or_default();
+        log.expire(now);
+        log.entries.push_back((now, amount));
+        log.total += amount;
+    }
+
+    // The number of requests `key` could make right now under `quota`.
+    fn remaining(&self, key: &K, quota: &Quota) -> u32 {
+        let now = self.now();
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let capacity = t * u64::from(quota.burst_size().get());
+        let consumed = self.logs.get(key).map_or(0, |log| {
+            log.entries
+                .iter()
+                .filter(|(at, _)| at.saturating_add(capacity) > now)
+                .map(|(_, amount)| amount)
+                .sum()
+        });
+        (capacity.saturating_sub(consumed) / t) as u32
+    }
+
+    // Drop the keys whose last request left the window for longer than `idle` nanoseconds.
+    fn retain_active(&self, idle: u64) {
+        let now = self.now();
+        self.logs.retain(|_, log| {
+            log.entries
+                .back()
+                .is_some_and(|(at, _)| at.saturating_add(log.window).saturating_add(idle) > now)
+        });
+    }
+}
+
+// The current fixed window of a quota, see `Algorithm::FixedWindow`. The state of a key is the end
+// of the window it last consumed tokens in, in the time of the backend, plus their number.
+struct FixedWindow {
+    now: u64,
+    end: u64,
+    length: u64,
+    t: u64,
+    limit: u32,
+}
+
+impl FixedWindow {
+    fn new(quota: &Quota, now: u64) -> Self {
+        let t = (quota.replenish_interval().as_nanos() as u64).max(1);
+        let limit = quota.burst_size().get();
+        // The number of tokens must stay below the length to be told apart from the end.
+        let length = (t * u64::from(limit)).max(u64::from(limit) + 1);
+        Self {
+            now,
+            end: (now / length + 1) * length,
+            length,
+            t,
+            limit,
+        }
+    }
+
+    // The tokens consumed in this window according to `state`.
+    fn consumed(&self, state: Option<u64>) -> u32 {
+        state
+            .filter(|state| state - state % self.length == self.end)
+            .map_or(0, |state| (state % self.length) as u32)
+    }
+
+    // The tokens worth `amount` of replenishment, up to the burst size.
+    fn tokens(&self, amount: u64) -> u32 {
+        amount.div_ceil(self.t).min(u64::from(self.limit)) as u32
+    }
+
+    fn state(&self, consumed: u32) -> u64 {
+        self.end + u64::from(consumed)
+    }
+
+    fn reset(&self) -> Duration {
+        Duration::from_nanos(self.end - self.now)
+    }
+}
+
+/// A [`GovernorStore`] protecting the rate limiter from a failing backend, e.g. a remote store that
+/// is down or too slow: after `failures` consecutive errors, the backend isn't called for `cooldown`,
+/// during which the keys are checked in memory instead, then it is tried again.
+///
+/// The in-memory state only knows of the requests checked by this instance while the backend
+/// was failing. Use [`fail_open`](Self::fail_open) to allow every request meanwhile instead.
+///
+/// # Example
+/// ```rust
+/// # use jsonrpsee_tower_governor::{governor::GovernorConfigBuilder, store::{CircuitBreakerStore, MemoryStore}};
+/// # use std::{net::IpAddr, time::Duration};
+/// # let backend = MemoryStore::<IpAddr>::default();
+/// GovernorConfigBuilder::default().store(CircuitBreakerStore::new(backend, 5, Duration::from_secs(10)));
+/// ```
+pub struct CircuitBreakerStore<K: Hash + Eq> {
+    backend: Arc<dyn GovernorStore<K>>,
+    fallback: Option<Arc<MemoryStore<K>>>,
+    breaker: Arc<Breaker>,
+}
+
+// The state of a circuit, shared by the scopes of a store.
+pub(crate) struct Breaker {
+    failures: u32,
+    cooldown: Duration,
+    consecutive: AtomicU32,
+    // When the circuit was opened, in nanoseconds since `start`, 0 if it is closed.
+    opened_at: AtomicU64,
+    start: Instant,
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> CircuitBreakerStore<K> {
+    /// A store calling `backend` until it fails `failures` times in a row, then checking the keys in
+    /// memory for `cooldown`.
+    pub fn new(backend: impl GovernorStore<K>, failures: u32, cooldown: Duration) -> Self {
+        Self {
+            backend: Arc::new(backend),
+            fallback: Some(Arc::new(MemoryStore::default())),
+            breaker: Arc::new(Breaker::new(failures, cooldown)),
+        }
+    }
+
+    /// Allow every request while the circuit is open, instead of checking the keys in memory.
+    pub fn fail_open(mut self) -> Self {
+        self.fallback = None;
+        self
+    }
+
+    /// Whether the backend is currently bypassed.
+    pub fn is_open(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    fn fall_back(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        match &self.fallback {
+            Some(fallback) => fallback.measure_and_replace(key, f),
+            None => {
+                f(None);
+            }
+        }
+    }
+}
+
+impl Default for Breaker {
+    // The built-in circuit of the shared stores, see the module documentation.
+    fn default() -> Self {
+        Self::new(3, Duration::from_secs(5))
+    }
+}
+
+impl Breaker {
+    fn new(failures: u32, cooldown: Duration) -> Self {
+        Self {
+            failures: failures.max(1),
+            cooldown,
+            consecutive: AtomicU32::new(0),
+            opened_at: AtomicU64::new(0),
+            start: Instant::now(),
+        }
+    }
+
+    /// Call the backend with `f` unless the circuit is open, counting its errors.
+    pub(crate) fn call<T, E: From<io::Error>>(
+        &self,
+        f: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        if self.is_open() {
+            return Err(io::Error::other("The backend failed too often, retrying later").into());
+        }
+        let result = f();
+        match result {
+            Ok(_) => self.succeeded(),
+            Err(_) => self.failed(),
+        }
+        result
+    }
+
+    fn now(&self) -> u64 {
+        // Never 0, which means closed.
+        (self.start.elapsed().as_nanos() as u64).max(1)
+    }
+
+    fn is_open(&self) -> bool {
+        let opened_at = self.opened_at.load(Ordering::Relaxed);
+        opened_at != 0 && self.now() - opened_at < self.cooldown.as_nanos() as u64
+    }
+
+    fn succeeded(&self) {
+        self.consecutive.store(0, Ordering::Relaxed);
+        self.opened_at.store(0, Ordering::Relaxed);
+    }
+
+    fn failed(&self) {
+        if self.consecutive.fetch_add(1, Ordering::Relaxed) + 1 >= self.failures {
+            self.opened_at.store(self.now(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K: Hash + Eq> fmt::Debug for CircuitBreakerStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreakerStore")
+            .field("failures", &self.breaker.failures)
+            .field("cooldown", &self.breaker.cooldown)
+            .field("open", &self.breaker.is_open())
+            .field("fail_open", &self.fallback.is_none())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> GovernorStore<K> for CircuitBreakerStore<K> {
+    fn measure_and_replace(&self, key: &K, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+        if self.breaker.is_open() {
+            return self.fall_back(key, f);
+        }
+        match self.backend.try_measure_and_replace(key, f) {
+            Ok(()) => self.breaker.succeeded(),
+            Err(_e) => {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("The rate limiter store failed: {}", _e);
+                self.breaker.failed();
+                self.fall_back(key, f);
+            }
+        }
+    }
+
+    fn try_measure_and_replace(
+        &self,
+        key: &K,
+        f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+    ) -> Result<(), BoxError> {
+        self.measure_and_replace(key, f);
+        Ok(())
+    }
+
+    fn check_gcra(
+        &self,
+        key: &K,
+        now: u64,
+        increment: u64,
+        capacity: u64,
+    ) -> Option<Result<Result<u64, u64>, BoxError>> {
+        if !self.breaker.is_open() {
+            match self.backend.check_gcra(key, now, increment, capacity)? {
+                Ok(checked) => {
+                    self.breaker.succeeded();
+                    return Some(Ok(checked));
+                }
+                Err(_e) => {
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!("The rate limiter store failed: {}", _e);
+                    self.breaker.failed();
+                }
+            }
+        }
+        let mut checked = Ok(0);
+        self.fall_back(key, &mut |state| {
+            checked = gcra(state, now, increment, capacity);
+            checked.ok()
+        });
+        Some(Ok(checked))
+    }
+
+    fn retain(&self, keep: &mut dyn FnMut(&K, u64) -> bool) {
+        self.backend.retain(keep);
+        if let Some(fallback) = &self.fallback {
+            fallback.retain(keep);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.backend.len() + self.fallback.as_ref().map_or(0, |fallback| fallback.len())
+    }
+
+    fn shrink_to_fit(&self) {
+        self.backend.shrink_to_fit();
+        if let Some(fallback) = &self.fallback {
+            fallback.shrink_to_fit();
+        }
+    }
+
+    fn shutdown(&self) {
+        self.backend.shutdown();
+    }
+
+    fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<K>> {
+        Arc::new(Self {
+            backend: self.backend.scoped(scope),
+            fallback: self
+                .fallback
+                .as_ref()
+                .map(|_| Arc::new(MemoryStore::default())),
+            breaker: self.breaker.clone(),
+        })
+    }
+
+    fn is_shared(&self) -> bool {
+        self.backend.is_shared()
+    }
+}
+
+/// The rate limiting state of a [`GovernorConfig`](crate::governor::GovernorConfig), see
+/// [`GovernorConfig::save_state`](crate::governor::GovernorConfig::save_state) and
+/// [`GovernorHandle::export_state`](crate::reload::GovernorHandle::export_state).
+///
+/// Its rate limiters are identified by scope: `default` for the main quota, `method:{method}` for
+/// the method quotas, `additional:{index}` for the additional quotas and `quota:{nanos}:{burst}`
+/// for the quotas of individual keys. Only the keys whose quota isn't fully replenished are kept.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot<Key> {
+    /// The keys of each rate limiter, by scope.
+    pub limiters: BTreeMap<String, Vec<KeyState<Key>>>,
+}
+
+impl<Key> Default for Snapshot<Key> {
+    fn default() -> Self {
+        Self {
+            limiters: BTreeMap::new(),
+        }
+    }
+}
+
+/// The state of a key in a [`Snapshot`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyState<Key> {
+    /// The key.
+    pub key: Key,
+    /// When the quota of the key is fully replenished, in nanoseconds since the UNIX epoch.
+    pub replenished_at: u64,
+    /// The number of requests the key could make when the snapshot was taken, for information:
+    /// imports only use [`replenished_at`](Self::replenished_at).
+    #[serde(default)]
+    pub remaining: u32,
+}
+
+/// The keyed state store backing the rate limiter of a [`GovernorConfig`](crate::governor::GovernorConfig).
+///
+/// It behaves like governor's default [`DashMapStateStore`](governor::state::keyed::DashMapStateStore),
+/// except that clones share the same state, which lets the middleware give tokens back to a key
+/// after the rate limiting decision was made, and that the state is kept by a [`GovernorStore`],
+/// a [`MemoryStore`] by default.
+///
+/// Each entry is the theoretical arrival time (a GCRA term) of the key, in nanoseconds since the
+/// rate limiter was created.
+pub struct KeyedStore<K: Hash + Eq> {
+    backend: Arc<dyn GovernorStore<K>>,
+    // Created before the rate limiter, so at most a few nanoseconds before its own start.
+    start: QuantaInstant,
+    // Nanoseconds between the UNIX epoch and `start` for shared backends, 0 otherwise.
+    offset: u64,
+    // Replaces the backend with `Algorithm::SlidingWindowLog`.
+    log: Option<Arc<WindowLog<K>>>,
+    // The quota whose fixed windows replace the GCRA with `Algorithm::FixedWindow`.
+    windows: Option<Quota>,
+}
+
+impl<K: Hash + Eq + Clone + Send + Sync + 'static> Default for KeyedStore<K> {
+    fn default() -> Self {
+        Self::new(Arc::new(MemoryStore::default()))
+    }
+}
+
+impl<K: Hash + Eq> Clone for KeyedStore<K> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            start: self.start,
+            offset: self.offset,
+            log: self.log.clone(),
+            windows: self.windows,
+        }
+    }
+}
+
+impl<K: Hash + Eq + 'static> fmt::Debug for KeyedStore<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyedStore")
+            .field("len", &self.backend.len())
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq + 'static> KeyedStore<K> {
+    /// A store keeping the state in `backend`.
+    pub fn new(backend: Arc<dyn GovernorStore<K>>) -> Self {
+        let offset = match backend.is_shared() {
+            true => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as u64),
+            false => 0,
+        };
+        Self {
+            backend,
+            start: DefaultClock::default().now(),
+            offset,
+            log: None,
+            windows: None,
+        }
+    }
+
+    /// This store logging the requests of the keys instead of keeping their state in the backend,
+    /// see [`Algorithm::SlidingWindowLog`](crate::governor::Algorithm::SlidingWindowLog).
+    pub(crate) fn logged(mut self) -> Self {
+        self.log = Some(Arc::new(WindowLog::new()));
+        self
+    }
+
+    /// This store counting the tokens the keys consume in fixed windows of `quota` in the backend,
+    /// see [`Algorithm::FixedWindow`](crate::governor::Algorithm::FixedWindow).
+    pub(crate) fn fixed_windows(mut self, quota: Quota) -> Self {
+        self.windows = Some(quota);
+        self
+    }
+
+    /// This store enforcing `quota`, the length of its fixed windows if it has some.
+    pub(crate) fn enforcing(mut self, quota: Quota) -> Self {
+        self.windows = self.windows.map(|_| quota);
+        self
+    }
+
+    /// The store of another rate limiter, see [`GovernorStore::scoped`], replacing the GCRA the same way.
+    pub(crate) fn scoped(&self, scope: &str) -> Self {
+        Self {
+            log: self.log.as_ref().map(|_| Arc::new(WindowLog::new())),
+            windows: self.windows,
+            ..Self::new(self.backend.scoped(scope))
+        }
+    }
+
+    /// Whether the state isn't a theoretical arrival time, see [`Algorithm`](crate::governor::Algorithm).
+    pub(crate) fn replaces_gcra(&self) -> bool {
+        self.log.is_some() || self.windows.is_some()
+    }
+
+    /// See [`GovernorStore::shutdown`].
+    pub(crate) fn shutdown(&self) {
+        self.backend.shutdown();
+    }
+
+    /// Whether `key` has a state.
+    pub(crate) fn contains(&self, key: &K) -> bool {
+        if let Some(log) = &self.log {
+            return log.logs.contains_key(key);
+        }
+        self.backend.get(key).is_some()
+    }
+
+    // The time since `start` of a state of the backend.
+    fn local(&self, state: u64) -> u64 {
+        state.saturating_sub(self.offset)
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> KeyedStore<K> {
+    /// Give `amount` worth of replenishment back to `key`.
+    ///
+    /// The theoretical arrival time never moves before the start of the rate limiter, and the limiter
+    /// itself never allows more than the burst size, so refunds can't exceed a full quota.
+    pub(crate) fn refund(&self, key: &K, amount: Nanos) {
+        if let Some(log) = &self.log {
+            return log.refund(key, amount.as_u64());
+        }
+        if let Some(window) = self.fixed_window() {
+            let tokens = window.tokens(amount.as_u64());
+            return self.backend.measure_and_replace(key, &mut |state| {
+                let consumed = window.consumed(state);
+                (consumed > 0).then(|| window.state(consumed.saturating_sub(tokens)))
+            });
+        }
+        let offset = self.offset;
+        self.backend.measure_and_replace(key, &mut |state| {
+            // 0 means "no state", keep at least one nanosecond so the key stays tracked.
+            state.map(|tat| {
+                let tat = tat.saturating_sub(offset).saturating_sub(amount.as_u64());
+                tat.max(1) + offset
+            })
+        });
+    }
+
+    /// Take `amount` worth of replenishment from `key`, e.g. for a usage only known once the request
+    /// was allowed. Unlike the rate limiter, it lets the key go beyond its burst size, the following
+    /// requests waiting until the excess is replenished.
+    pub(crate) fn charge(&self, key: &K, amount: Nanos) {
+        if let Some(log) = &self.log {
+            return log.charge(key, amount.as_u64());
+        }
+        if let Some(window) = self.fixed_window() {
+            // The excess isn't carried over to the next window.
+            let tokens = window.tokens(amount.as_u64());
+            return self.backend.measure_and_replace(key, &mut |state| {
+                let consumed = window.consumed(state).saturating_add(tokens);
+                Some(window.state(consumed.min(window.limit)))
+            });
+        }
+        let (now, _) = self.now();
+        let offset = self.offset;
+        self.backend.measure_and_replace(key, &mut |state| {
+            let tat = state.map_or(0, |tat| tat.saturating_sub(offset)).max(now);
+            Some(tat.saturating_add(amount.as_u64()) + offset)
+        });
+    }
+
+    /// Drop the keys whose quota has been fully replenished for longer than `idle`.
+    pub(crate) fn retain_active(&self, idle: Duration) {
+        let idle_nanos = u64::try_from(idle.as_nanos()).unwrap_or(u64::MAX);
+        if let Some(log) = &self.log {
+            return log.retain_active(idle_nanos);
+        }
+        let now = DefaultClock::default()
+            .now()
+            .duration_since(self.start)
+            .as_u64();
+        let drop_below = now.saturating_sub(idle_nanos);
+        self.backend
+            .retain(&mut |_, tat| self.local(tat) > drop_below);
+    }
+
+    /// A new store where every key has consumed as many tokens of `to` as it has of `from` in this
+    /// store, capped at the burst size of `to`. Keys whose quota is fully replenished are left out.
+    ///
+    /// Shared stores are kept as they are, since other instances may still use them, as are the
+    /// ones replacing the GCRA: logged requests are checked against the window of `to` as they are,
+    /// and the tokens consumed in a fixed window are kept if `to` has the same windows.
+    pub(crate) fn migrate(&self, from: &Quota, to: &Quota) -> Self {
+        if self.backend.is_shared() || self.replaces_gcra() {
+            return self.clone().enforcing(*to);
+        }
+        let now = DefaultClock::default()
+            .now()
+            .duration_since(self.start)
+            .as_u64();
+        let from_t = from.replenish_interval().as_nanos().max(1);
+        let to_t = to.replenish_interval().as_nanos();
+        let capacity = to_t * u128::from(to.burst_size().get());
+        Self::new(self.backend.migrated(&mut |tat| {
+            // Time until the key's quota is fully replenished.
+            let debt = tat.saturating_sub(now);
+            (debt > 0).then(|| {
+                let debt = (u128::from(debt) * to_t / from_t).min(capacity);
+                u64::try_from(debt).unwrap_or(u64::MAX).max(1)
+            })
+        }))
+    }
+
+    /// The keys whose `quota` isn't fully replenished. Shared stores outlive the instance, they have
+    /// none, nor do the ones replacing the GCRA.
+    pub(crate) fn export(&self, quota: &Quota) -> Vec<KeyState<K>> {
+        let mut keys = Vec::new();
+        if self.backend.is_shared() || self.replaces_gcra() {
+            return keys;
+        }
+        let (now, unix_now) = self.now();
+        let t = quota.replenish_interval().as_nanos().max(1) as u64;
+        let capacity = t * u64::from(quota.burst_size().get());
+        self.backend.retain(&mut |key, tat| {
+            if tat > now {
+                keys.push(KeyState {
+                    key: key.clone(),
+                    replenished_at: unix_now.saturating_add(tat - now),
+                    remaining: (capacity.saturating_sub(tat - now) / t) as u32,
+                });
+            }
+            true
+        });
+        keys
+    }
+
+    /// Consume the tokens of `keys` in this store, keeping the ones a key consumed since if more.
+    /// Keys already replenished are skipped, as is everything for shared stores and the ones
+    /// replacing the GCRA.
+    pub(crate) fn import(&self, keys: &[KeyState<K>]) {
+        if self.backend.is_shared() || self.replaces_gcra() {
+            return;
+        }
+        let (now, unix_now) = self.now();
+        for state in keys {
+            let Some(debt) = state
+                .replenished_at
+                .checked_sub(unix_now)
+                .filter(|&d| d > 0)
+            else {
+                continue;
+            };
+            let tat = now.saturating_add(debt);
+            self.backend
+                .measure_and_replace(&state.key, &mut |current| {
+                    current.is_none_or(|current| current < tat).then_some(tat)
+                });
+        }
+    }
+
+    /// Check `amount` of replenishment for `key` against `quota` if the store replaces the GCRA,
+    /// see [`Algorithm`](crate::governor::Algorithm), or its backend runs it, see
+    /// [`GovernorStore::check_gcra`], returning the state of the quota when allowed,
+    /// or how long to wait. The amount must not exceed the burst size.
+    pub(crate) fn check_window(
+        &self,
+        key: &K,
+        amount: Nanos,
+        quota: &Quota,
+    ) -> Option<Result<RateLimitInfo, Duration>> {
+        if let Some(log) = &self.log {
+            return Some(log.check(key, amount, quota));
+        }
+        let Some(window) = self.fixed_window() else {
+            return self.check_gcra(key, amount, quota);
+        };
+        let tokens = window.tokens(amount.as_u64());
+        let mut consumed = 0;
+        self.backend.measure_and_replace(key, &mut |state| {
+            consumed = window.consumed(state);
+            (consumed + tokens <= window.limit).then(|| window.state(consumed + tokens))
+        });
+        if consumed + tokens > window.limit {
+            return Some(Err(window.reset()));
+        }
+        Some(Ok(RateLimitInfo {
+            limit: window.limit,
+            remaining: window.limit - consumed - tokens,
+            reset: window.reset(),
+        }))
+    }
+
+    // Check `amount` for `key` with the GCRA run by the backend, if it can.
+    fn check_gcra(
+        &self,
+        key: &K,
+        amount: Nanos,
+        quota: &Quota,
+    ) -> Option<Result<RateLimitInfo, Duration>> {
+        let (now, _) = self.now();
+        let t = Nanos::from(quota.replenish_interval()).as_u64().max(1);
+        let limit = quota.burst_size().get();
+        let capacity = t * u64::from(limit);
+        let checked = self
+            .backend
+            .check_gcra(key, now + self.offset, amount.as_u64(), capacity)?
+            .unwrap_or_else(|_e| {
+                #[cfg(feature = "tracing")]
+                tracing::warn!("Unable to check the rate limiter state: {}", _e);
+                gcra(None, now + self.offset, amount.as_u64(), capacity)
+            });
+        Some(match checked {
+            Ok(tat) => {
+                let available = (now + capacity).saturating_sub(self.local(tat));
+                let remaining = (available.min(capacity) / t) as u32;
+                Ok(RateLimitInfo {
+                    limit,
+                    remaining,
+                    reset: quota.replenish_interval() * (limit - remaining),
+                })
+            }
+            Err(earliest) => Err(Duration::from_nanos(
+                self.local(earliest).saturating_sub(now),
+            )),
+        })
+    }
+
+    // The current fixed window, if the store has some.
+    fn fixed_window(&self) -> Option<FixedWindow> {
+        let quota = self.windows.as_ref()?;
+        Some(FixedWindow::new(quota, self.now().0 + self.offset))
+    }
+
+    // The time since `start` and since the UNIX epoch, in nanoseconds.
+    fn now(&self) -> (u64, u64) {
+        let now = DefaultClock::default()
+            .now()
+            .duration_since(self.start)
+            .as_u64();
+        let unix_now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64);
+        (now, unix_now)
+    }
+
+    /// The number of requests `key` could make right now under `quota`,
+    /// computed like governor's [`StateSnapshot::remaining_burst_capacity`](governor::middleware::StateSnapshot::remaining_burst_capacity).
+    pub(crate) fn remaining_burst_capacity(&self, key: &K, quota: &Quota) -> u32 {
+        if let Some(log) = &self.log {
+            return log.remaining(key, quota);
+        }
+        if let Some(window) = self.fixed_window() {
+            return window.limit - window.consumed(self.backend.get(key));
+        }
+        let t = Nanos::from(quota.replenish_interval());
+        let capacity = t * u64::from(quota.burst_size().get());
+        let now = DefaultClock::default().now().duration_since(self.start);
+        let tat = self.backend.get(key).map_or(0, |state| self.local(state));
+        let available = (now + capacity).as_u64().saturating_sub(tat);
+        (available.min(capacity.as_u64()) / t.as_u64()) as u32
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> StateStore for KeyedStore<K> {
+    type Key = K;
+
+    fn measure_and_replace<T, F, E>(&self, key: &Self::Key, f: F) -> Result<T, E>
+    where
+        F: Fn(Option<Nanos>) -> Result<(T, Nanos), E>,
+    {
+        let mut outcome = None;
+        self.backend.measure_and_replace(key, &mut |state| {
+            // The last decision is the one that was stored.
+            match f(state.map(|state| self.local(state).into())) {
+                Ok((result, new_data)) => {
+                    outcome = Some(Ok(result));
+                    Some(u64::from(new_data) + self.offset)
+                }
+                Err(e) => {
+                    outcome = Some(Err(e));
+                    None
+                }
+            }
+        });
+        outcome.expect("the store measured the state")
+    }
+}
+
+impl<K: Hash + Eq + Clone + 'static> ShrinkableKeyedStateStore<K> for KeyedStore<K> {
+    fn retain_recent(&self, drop_below: Nanos) {
+        self.backend
+            .retain(&mut |_, tat| self.local(tat) > drop_below.as_u64());
+    }
+
+    fn shrink_to_fit(&self) {
+        self.backend.shrink_to_fit();
+        if let Some(log) = &self.log {
+            log.logs.shrink_to_fit();
+        }
+    }
+
+    fn len(&self) -> usize {
+        match &self.log {
+            Some(log) => log.logs.len(),
+            None => self.backend.len(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        ShrinkableKeyedStateStore::len(self) == 0
+    }
+}
+
+// Governor's GCRA checking `increment` at `now` for a key whose state is `tat`, see
+// `GovernorStore::check_gcra`.
+fn gcra(tat: Option<u64>, now: u64, increment: u64, capacity: u64) -> Result<u64, u64> {
+    let tat = tat.unwrap_or(now).max(now);
+    let earliest = (tat + increment).saturating_sub(capacity);
+    match now < earliest {
+        true => Err(earliest),
+        false => Ok(tat + increment),
+    }
+}
+
+/// How many times the shared stores try to replace the state of a key that other instances keep
+/// changing meanwhile, before failing. This doesn't count as a failure of their backend.
+pub(crate) const MAX_ATTEMPTS: usize = 8;
+
+/// Run `f`, making blocking calls to the backend of a shared store, after letting a multi-threaded
+/// Tokio runtime move the other tasks of the current worker thread elsewhere.
+pub(crate) fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => block_in_place(f),
+        _ => f(),
+    }
+}
+
+// Same compare-and-swap loop as governor's `InMemoryState`, 0 meaning no state.
+fn measure_and_replace_one(state: &AtomicU64, f: &mut dyn FnMut(Option<u64>) -> Option<u64>) {
+    let mut prev = state.load(Ordering::Acquire);
+    while let Some(new_data) = f(NonZeroU64::new(prev).map(NonZeroU64::get)) {
+        match state.compare_exchange_weak(prev, new_data, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(next_prev) => prev = next_prev,
+        }
+    }
+}
+
+/// 64-bit FNV-1a, stable across processes, platforms and Rust releases unlike the hashers of the
+/// standard library, e.g. to assign keys consistently across the instances of a service.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    // Integers are hashed little-endian, and sizes as 64 bits, whatever the platform.
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_u64(i as i64 as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// The [`Fnv1a`] hash of `bytes`.
+#[cfg(any(feature = "memcached", feature = "ring"))]
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
@@ -1,35 +1,25 @@
-use axum::{routing::get, Router};
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use jsonrpsee::http_client::HttpBody;
+use std::convert::Infallible;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use tower_http::trace::TraceLayer;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tokio::net::TcpListener;
+use tower::util::BoxCloneService;
+use tower::{service_fn, Layer, Service, ServiceExt};
 
-use crate::{governor::GovernorConfigBuilder, GovernorLayer};
+use crate::{
+    errors::ErrorFormat,
+    governor::{GovernorConfigBuilder, HeaderNames},
+    BoxError, GovernorLayer,
+};
 
-#[tokio::main]
-async fn _main() {
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG")
-                .unwrap_or_else(|_| "example_testing=debug,tower_http=debug".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let addr = std::net::SocketAddr::from(([127, 0, 0, 1], 3000));
-
-    tracing::debug!("listening on {}", addr);
-
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-
-    axum::serve(listener, app().into_make_service())
-        .await
-        .unwrap();
-}
+type App = BoxCloneService<Request<Incoming>, Response<HttpBody>, Infallible>;
 
 /// Having a function that produces our app makes it easy to call it from tests
 /// without having to create an HTTP server.
-#[allow(dead_code)]
-fn app() -> Router {
+fn app() -> App {
     let config = Arc::new(
         GovernorConfigBuilder::default()
             .per_millisecond(90)
@@ -38,48 +28,79 @@ fn app() -> Router {
             .unwrap(),
     );
 
-    Router::new()
-        // `GET /` goes to `root`
-        .route(
-            "/",
-            get(|| async { "Hello, World!" }).post(|| async { "Hello, Post World!" }),
-        )
-        .layer(GovernorLayer { config })
-        .layer(TraceLayer::new_for_http())
+    BoxCloneService::new(GovernorLayer { config }.layer(hello()))
+}
+
+/// The inner service: `GET /` answers "Hello, World!", `POST /` answers "Hello, Post World!".
+fn hello() -> App {
+    BoxCloneService::new(service_fn(|req: Request<Incoming>| async move {
+        let body = match *req.method() {
+            Method::POST => "Hello, Post World!",
+            _ => "Hello, World!",
+        };
+        Ok::<_, Infallible>(Response::new(HttpBody::from(body)))
+    }))
+}
+
+/// Respond with the status of the path, e.g. `/401`, `200 OK` by default.
+fn echo_status() -> App {
+    BoxCloneService::new(service_fn(|req: Request<Incoming>| async move {
+        let mut res = Response::new(HttpBody::from("Hello, World!"));
+        if let Some(status) = req.uri().path()[1..]
+            .parse::<u16>()
+            .ok()
+            .and_then(|code| StatusCode::from_u16(code).ok())
+        {
+            *res.status_mut() = status;
+        }
+        Ok::<_, Infallible>(res)
+    }))
+}
+
+/// Serve `service` over HTTP/1 on a random local port, inserting the peer [`SocketAddr`]
+/// into the request extensions, and return the base url.
+async fn serve<S>(service: S) -> String
+where
+    S: Service<Request<Incoming>, Response = Response<HttpBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = listener.accept().await.unwrap();
+            let service = service.clone();
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(move |mut req: Request<Incoming>| {
+                    req.extensions_mut().insert::<SocketAddr>(peer);
+                    service.clone().oneshot(req)
+                });
+                let _ = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+
+    format!("http://{}", addr)
 }
 
 #[cfg(test)]
 mod governor_tests {
     use super::*;
-    use axum::{body, http};
     use reqwest::header::HeaderName;
     use reqwest::StatusCode;
-    use std::net::SocketAddr;
-    use tokio::net::TcpListener;
-    use tower::ServiceExt;
 
     #[tokio::test]
     async fn hello_world() {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let app = app();
-            tx.send(()).unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
-        });
-        rx.await.unwrap();
+        let url = serve(app()).await;
 
         let client = reqwest::Client::new();
 
-        let res = client.get(format!("http://{}", addr)).send().await.unwrap();
-        let res2 = client.get(format!("http://{}", addr)).send().await.unwrap();
+        let res = client.get(&url).send().await.unwrap();
+        let res2 = client.get(&url).send().await.unwrap();
 
         let body = res.text().await.unwrap();
         let body2 = res2.text().await.unwrap();
@@ -108,22 +129,7 @@ mod governor_tests {
 
     #[tokio::test]
     async fn test_server() {
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let url = format!("http://{}", addr);
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let app = app();
-            tx.send(()).unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
-        });
-        rx.await.unwrap();
+        let url = serve(app()).await;
 
         let client = reqwest::Client::new();
 
@@ -163,45 +169,19 @@ mod governor_tests {
             "0"
         );
         let body = res.text().await.unwrap();
-        assert_eq!(&body, "Too Many Requests! Wait for 0s");
+        assert_eq!(&body, "Too many requests");
     }
     #[tokio::test]
     async fn test_method_filter() {
-        use crate::governor::GovernorConfigBuilder;
-        use http::Method;
-
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let url = format!("http://{}", addr);
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let config = Arc::new(
-                GovernorConfigBuilder::default()
-                    .per_millisecond(90)
-                    .burst_size(2)
-                    .methods(vec![Method::GET])
-                    .finish()
-                    .unwrap(),
-            );
-
-            let app = Router::new()
-                // `GET /` goes to `root`
-                .route(
-                    "/",
-                    get(|| async { "Hello, World!" }).post(|| async { "Hello, Post World!" }),
-                )
-                .layer(GovernorLayer { config })
-                .layer(TraceLayer::new_for_http());
-            tx.send(()).unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
-        });
-        rx.await.unwrap();
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(90)
+                .burst_size(2)
+                .methods(vec![Method::GET])
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
 
         let client = reqwest::Client::new();
 
@@ -230,40 +210,15 @@ mod governor_tests {
 
     #[tokio::test]
     async fn test_server_use_headers() {
-        use crate::governor::GovernorConfigBuilder;
-
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let url = format!("http://{}", addr);
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let config = Arc::new(
-                GovernorConfigBuilder::default()
-                    .per_millisecond(90)
-                    .burst_size(2)
-                    .use_headers()
-                    .finish()
-                    .unwrap(),
-            );
-
-            let app = Router::new()
-                // `GET /` goes to `root`
-                .route(
-                    "/",
-                    get(|| async { "Hello, World!" }).post(|| async { "Hello, Post World!" }),
-                )
-                .layer(GovernorLayer { config })
-                .layer(TraceLayer::new_for_http());
-            tx.send(()).unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
-        });
-        rx.await.unwrap();
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(90)
+                .burst_size(2)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
 
         let client = reqwest::Client::new();
 
@@ -399,47 +354,21 @@ mod governor_tests {
             .is_none());
 
         let body = res.text().await.unwrap();
-        assert_eq!(&body, "Too Many Requests! Wait for 0s");
+        assert_eq!(&body, "Too many requests");
     }
 
     #[tokio::test]
     async fn test_method_filter_use_headers() {
-        use crate::governor::GovernorConfigBuilder;
-        use http::Method;
-
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-        let url = format!("http://{}", addr);
-
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        tokio::spawn(async move {
-            let config = Arc::new(
-                GovernorConfigBuilder::default()
-                    .per_millisecond(90)
-                    .burst_size(2)
-                    .methods(vec![Method::GET])
-                    .use_headers()
-                    .finish()
-                    .unwrap(),
-            );
-
-            let app = Router::new()
-                // `GET /` goes to `root`
-                .route(
-                    "/",
-                    get(|| async { "Hello, World!" }).post(|| async { "Hello, Post World!" }),
-                )
-                .layer(GovernorLayer { config })
-                .layer(TraceLayer::new_for_http());
-            tx.send(()).unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
-        });
-        rx.await.unwrap();
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(90)
+                .burst_size(2)
+                .methods(vec![Method::GET])
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
 
         let client = reqwest::Client::new();
 
@@ -553,32 +482,4848 @@ mod governor_tests {
                 .per_second(10)
                 .burst_size(1)
                 .error_handler(|_| {
-                    http::Response::builder()
+                    Response::builder()
                         .status(http::StatusCode::IM_A_TEAPOT)
-                        .body(axum::body::Body::from("a custom error string"))
+                        .body(HttpBody::from("a custom error string"))
                         .unwrap()
                 })
                 .finish()
                 .unwrap(),
         );
 
-        let app = Router::new()
-            .route("/", get(|| async { "Hello, World!" }))
-            .layer(GovernorLayer { config })
-            .layer(TraceLayer::new_for_http());
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
 
-        let req = || http::Request::new(body::Body::empty());
+        let client = reqwest::Client::new();
 
-        let _ = app.clone().oneshot(req()).await.unwrap();
+        let _ = client.get(&url).send().await.unwrap();
 
-        let res = app.oneshot(req()).await.unwrap();
+        let res = client.get(&url).send().await.unwrap();
 
         // second response should match the response produced by GovernorConfigBuilder::error_handler
 
-        assert_eq!(res.status(), http::StatusCode::IM_A_TEAPOT);
-        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+        assert_eq!(res.status(), StatusCode::IM_A_TEAPOT);
+        let body = res.bytes().await.unwrap();
+        assert_eq!(body.as_ref(), b"a custom error string");
+    }
+
+    #[tokio::test]
+    async fn test_operator_header_key_extractor() {
+        use crate::key_extractor::OperatorHeaderKeyExtractor;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .key_extractor(OperatorHeaderKeyExtractor::default())
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // Missing operator header
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // First request of operator A
+        let res = client
+            .get(&url)
+            .header("x-drosera-operator", "0xAbC")
+            .send()
             .await
             .unwrap();
-        assert_eq!(body.as_ref(), b"a custom error string");
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Operator A again, differently cased -> Over limit
+        let res = client
+            .get(&url)
+            .header("x-drosera-operator", "0xabc")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Operator B has its own quota
+        let res = client
+            .get(&url)
+            .header("x-drosera-operator", "0xdef")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_operator_header_signature() {
+        use crate::key_extractor::{KeyExtractor, OperatorHeaderKeyExtractor};
+        use crate::GovernorError;
+
+        let extractor = OperatorHeaderKeyExtractor::new(HeaderName::from_static("x-operator"))
+            .signature_header(HeaderName::from_static("x-operator-signature"))
+            .verify_signature(|operator, signature, _| signature == format!("signed:{operator}"));
+
+        let req = |signature: Option<&str>| {
+            let mut req = Request::builder().header("x-operator", " 0xABC ");
+            if let Some(signature) = signature {
+                req = req.header("x-operator-signature", signature);
+            }
+            req.body(()).unwrap()
+        };
+
+        // The signature covers the operator as sent, only the key is lowercased.
+        assert_eq!(
+            extractor.extract(&req(Some("signed:0xABC"))).unwrap(),
+            "0xabc"
+        );
+        assert!(matches!(
+            extractor.extract(&req(Some("signed:0xabc"))),
+            Err(GovernorError::Other { code, .. }) if code == StatusCode::UNAUTHORIZED
+        ));
+        assert!(matches!(
+            extractor.extract(&req(Some("forged"))),
+            Err(GovernorError::Other { code, .. }) if code == StatusCode::UNAUTHORIZED
+        ));
+        assert!(matches!(
+            extractor.extract(&req(None)),
+            Err(GovernorError::Other { code, .. }) if code == StatusCode::UNAUTHORIZED
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bytes_per_token() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(5)
+                .bytes_per_token(10)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // 25 bytes -> 3 tokens
+        let res = client.post(&url).body("a".repeat(25)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get(HeaderName::from_static("x-ratelimit-remaining"))
+                .unwrap(),
+            "2"
+        );
+
+        // Another 3 tokens do not fit in the remaining 2
+        let res = client.post(&url).body("a".repeat(25)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // No body -> 1 token
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers()
+                .get(HeaderName::from_static("x-ratelimit-remaining"))
+                .unwrap(),
+            "1"
+        );
+
+        // More tokens than the burst size can never be accommodated
+        let res = client.post(&url).body("a".repeat(60)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(res.headers().get("x-ratelimit-after").is_none());
+        assert_eq!(
+            res.text().await.unwrap(),
+            "Request cost of 6 exceeds the burst size of 5!"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_hit_refunds() {
+        use crate::CacheHit;
+        use http::{HeaderName, HeaderValue};
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .cache_hit_header(
+                    HeaderName::from_static("x-cache"),
+                    HeaderValue::from_static("HIT"),
+                )
+                .finish()
+                .unwrap(),
+        );
+        let inner = service_fn(|req: Request<Incoming>| async move {
+            let mut res = Response::new(HttpBody::from("Hello, World!"));
+            match req.uri().path() {
+                "/extension" => {
+                    res.extensions_mut().insert(CacheHit);
+                }
+                "/header" => {
+                    res.headers_mut()
+                        .insert("x-cache", HeaderValue::from_static("hit"));
+                }
+                _ => {}
+            }
+            Ok::<_, Infallible>(res)
+        });
+        let url = serve(GovernorLayer { config }.layer(inner)).await;
+
+        let client = reqwest::Client::new();
+
+        // Cache hits don't consume the quota
+        for path in ["/extension", "/extension", "/header", "/header"] {
+            let res = client.get(format!("{url}{path}")).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // Cache misses do
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_retry_after() {
+        use crate::governor::RetryAfter;
+
+        let client = reqwest::Client::new();
+
+        for (mode, retry_after, x_ratelimit_after) in [
+            (RetryAfter::Disabled, false, true),
+            (RetryAfter::Both, true, true),
+            (RetryAfter::Only, true, false),
+        ] {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(10)
+                    .burst_size(1)
+                    .retry_after(mode)
+                    .finish()
+                    .unwrap(),
+            );
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get("retry-after").is_none());
+
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                res.headers()
+                    .get("retry-after")
+                    .map(|hv| hv.to_str().unwrap()),
+                retry_after.then_some("9")
+            );
+            assert_eq!(
+                res.headers()
+                    .get("x-ratelimit-after")
+                    .map(|hv| hv.to_str().unwrap()),
+                x_ratelimit_after.then_some("9")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ietf_draft_headers() {
+        use crate::governor::HeaderStyle;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(2)
+                .header_style(HeaderStyle::IetfDraft)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let header = |res: &reqwest::Response, name: &str| {
+            res.headers()
+                .get(name)
+                .map(|hv| hv.to_str().unwrap().to_owned())
+        };
+
+        // First request
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "ratelimit-limit").unwrap(), "2");
+        assert_eq!(header(&res, "ratelimit-remaining").unwrap(), "1");
+        assert_eq!(header(&res, "ratelimit-reset").unwrap(), "10");
+        assert!(header(&res, "x-ratelimit-limit").is_none());
+        assert!(header(&res, "x-ratelimit-remaining").is_none());
+
+        // Second request
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "ratelimit-remaining").unwrap(), "0");
+        assert_eq!(header(&res, "ratelimit-reset").unwrap(), "20");
+
+        // Third request -> Over limit, reset is the wait time rounded up
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&res, "ratelimit-limit").unwrap(), "2");
+        assert_eq!(header(&res, "ratelimit-remaining").unwrap(), "0");
+        assert_eq!(header(&res, "ratelimit-reset").unwrap(), "10");
+        assert_eq!(header(&res, "x-ratelimit-after").unwrap(), "9");
+    }
+
+    #[tokio::test]
+    async fn test_policy_header() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(1500)
+                .burst_size(4)
+                .policy_header()
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        for _ in 0..4 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("ratelimit-policy").unwrap(), "4;w=6");
+        }
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("ratelimit-policy").unwrap(), "4;w=6");
+    }
+
+    #[tokio::test]
+    async fn test_reset_header() {
+        use crate::governor::ResetFormat;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let client = reqwest::Client::new();
+        let header = |res: &reqwest::Response| -> u64 {
+            res.headers()
+                .get("x-ratelimit-reset")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(2)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res), 10);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res), 20);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&res), 10);
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(2)
+                .reset_format(ResetFormat::EpochSeconds)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!((now + 10..=now + 11).contains(&header(&res)));
+    }
+
+    #[tokio::test]
+    async fn test_wait_time_precision() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(500)
+                .burst_size(1)
+                .after_ms_header()
+                .round_up_wait_time()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("x-ratelimit-after-ms").is_none());
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let after_ms: u64 = res
+            .headers()
+            .get("x-ratelimit-after-ms")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((400..=500).contains(&after_ms));
+        assert_eq!(res.headers().get("x-ratelimit-after").unwrap(), "1");
+        assert_eq!(res.text().await.unwrap(), "Too many requests");
+    }
+
+    #[tokio::test]
+    async fn test_json_error_format() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .round_up_wait_time()
+                .error_format(ErrorFormat::Json)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        assert_eq!(res.headers().get("x-ratelimit-after").unwrap(), "10");
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({ "error": "rate_limited", "retry_after": 10, "limit": 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_problem_json_error_format() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .round_up_wait_time()
+                .error_format(ErrorFormat::ProblemJson)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "application/problem+json"
+        );
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Too Many Requests");
+        assert_eq!(body["status"], 429);
+        assert_eq!(body["retry_after"], 10);
+        assert_eq!(body["limit"], 1);
+        assert!(body["detail"].is_string());
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_error_format() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .error_format(ErrorFormat::Json)
+                .negotiate_error_format()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for (accept, content_type) in [
+            (None, "application/json"),
+            (Some("*/*"), "application/json"),
+            (
+                Some("text/html,application/xhtml+xml,*/*;q=0.8"),
+                "text/html; charset=utf-8",
+            ),
+            (
+                Some("application/json;q=0.5, text/plain"),
+                "text/plain; charset=utf-8",
+            ),
+            (Some("application/problem+json"), "application/problem+json"),
+            (Some("text/html;q=0, image/png"), "application/json"),
+        ] {
+            let mut req = client.get(&url);
+            if let Some(accept) = accept {
+                req = req.header("accept", accept);
+            }
+            let res = req.send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(
+                res.headers().get("content-type").unwrap(),
+                content_type,
+                "{:?}",
+                accept
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_html_template() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .round_up_wait_time()
+                .negotiate_error_format()
+                .html_template("<p>Slow down! {limit} requests max, come back in {wait_time}s</p>")
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client
+            .get(&url)
+            .header("accept", "text/html")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            res.headers().get("content-type").unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(
+            res.text().await.unwrap(),
+            "<p>Slow down! 1 requests max, come back in 10s</p>"
+        );
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.text().await.unwrap(), "Too many requests");
+    }
+
+    #[tokio::test]
+    async fn test_rejection_status() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .rejection_status(http::StatusCode::SERVICE_UNAVAILABLE)
+                .negotiate_error_format()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().get("x-ratelimit-after").is_some());
+
+        let res = client
+            .get(&url)
+            .header("accept", "application/problem+json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["title"], "Service Unavailable");
+    }
+
+    #[tokio::test]
+    async fn test_header_names() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .methods(vec![Method::GET])
+                .header_names(HeaderNames {
+                    limit: HeaderName::from_static("x-rl-limit"),
+                    remaining: HeaderName::from_static("x-rl-remaining"),
+                    reset: HeaderName::from_static("x-rl-reset"),
+                    after: HeaderName::from_static("x-rl-after"),
+                    ..Default::default()
+                })
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-rl-limit").unwrap(), "1");
+        assert_eq!(res.headers().get("x-rl-remaining").unwrap(), "0");
+        assert!(res.headers().get("x-rl-reset").is_some());
+        assert!(res.headers().get("x-ratelimit-limit").is_none());
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-rl-after").is_some());
+        assert!(res.headers().get("x-ratelimit-after").is_none());
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get("x-ratelimit-whitelisted").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hide_headers() {
+        use crate::governor::RetryAfter;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .methods(vec![Method::GET])
+                .retry_after(RetryAfter::Both)
+                .after_ms_header()
+                .policy_header()
+                .hide_headers()
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let is_rate_limit_header = |name: &HeaderName| {
+            name.as_str().contains("ratelimit") || name == reqwest::header::RETRY_AFTER
+        };
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().keys().any(is_rate_limit_header));
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(!res.headers().keys().any(is_rate_limit_header));
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().keys().any(is_rate_limit_header));
+    }
+
+    #[tokio::test]
+    async fn test_expose_headers() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .methods(vec![Method::GET])
+                .expose_headers()
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let exposed = |res: &reqwest::Response| {
+            let mut names = res
+                .headers()
+                .get("access-control-expose-headers")
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .split(", ")
+                .map(str::to_owned)
+                .collect::<Vec<_>>();
+            names.sort();
+            names
+        };
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            exposed(&res),
+            [
+                "x-ratelimit-limit",
+                "x-ratelimit-remaining",
+                "x-ratelimit-reset"
+            ]
+        );
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            exposed(&res),
+            [
+                "x-ratelimit-after",
+                "x-ratelimit-limit",
+                "x-ratelimit-remaining",
+                "x-ratelimit-reset"
+            ]
+        );
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(exposed(&res), ["x-ratelimit-whitelisted"]);
+    }
+
+    #[tokio::test]
+    async fn test_error_response_preserved() {
+        use crate::key_extractor::KeyExtractor;
+        use crate::GovernorError;
+        use http::HeaderMap;
+
+        #[derive(Clone)]
+        struct RejectingKeyExtractor;
+
+        impl KeyExtractor for RejectingKeyExtractor {
+            type Key = ();
+
+            #[cfg(feature = "tracing")]
+            fn name(&self) -> &'static str {
+                "rejecting"
+            }
+
+            fn extract<T>(&self, _req: &Request<T>) -> Result<Self::Key, GovernorError> {
+                let mut headers = HeaderMap::new();
+                headers.insert("www-authenticate", "Bearer".parse().unwrap());
+                Err(GovernorError::Other {
+                    code: http::StatusCode::UNAUTHORIZED,
+                    msg: Some("Missing API key".to_string()),
+                    headers: Some(headers),
+                })
+            }
+        }
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .key_extractor(RejectingKeyExtractor)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers().get("www-authenticate").unwrap(), "Bearer");
+        assert_eq!(res.text().await.unwrap(), "Missing API key");
+
+        // Headers and body prepared by a custom error handler are kept as well.
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .error_handler(|mut error| {
+                    let mut response: Response<HttpBody> = error.as_response();
+                    response
+                        .headers_mut()
+                        .insert("x-custom", "rejected".parse().unwrap());
+                    response
+                })
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-custom").unwrap(), "rejected");
+        assert!(res.headers().get("x-ratelimit-after").is_some());
+        assert!(res.text().await.unwrap().starts_with("Too Many Requests!"));
+    }
+
+    #[tokio::test]
+    async fn test_lightweight_headers() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(3)
+                .methods(vec![Method::GET])
+                .lightweight_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        for remaining in ["2", "1", "0"] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+            assert!(res.headers().get("x-ratelimit-reset").is_some());
+        }
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get("x-ratelimit-whitelisted").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_warning_threshold() {
+        assert!(GovernorConfigBuilder::default()
+            .warning_threshold(101)
+            .finish()
+            .is_none());
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(5)
+                .warning_threshold(80)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        for warning in [None, None, None, Some("80"), Some("100")] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers()
+                    .get("x-ratelimit-warning")
+                    .map(|value| value.to_str().unwrap()),
+                warning
+            );
+            assert!(res.headers().get("x-ratelimit-remaining").is_none());
+        }
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-ratelimit-warning").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_info_extension() {
+        use crate::RateLimitInfo;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(2)
+                .finish()
+                .unwrap(),
+        );
+        let service = service_fn(|req: Request<Incoming>| async move {
+            let info = req.extensions().get::<RateLimitInfo>().unwrap();
+            Ok::<_, Infallible>(Response::new(HttpBody::from(format!(
+                "{} {} {}",
+                info.limit,
+                info.remaining,
+                info.reset.as_secs()
+            ))))
+        });
+        let url = serve(GovernorLayer { config }.layer(service)).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "2 1 10");
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "2 0 20");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_info_response_extension() {
+        use crate::RateLimitInfo;
+        use std::sync::Mutex;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .finish()
+                .unwrap(),
+        );
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let governor = GovernorLayer { config }.layer(hello());
+        let recorder = recorded.clone();
+        let service = service_fn(move |req: Request<Incoming>| {
+            let recorder = recorder.clone();
+            let mut governor = governor.clone();
+            async move {
+                let res = governor.call(req).await?;
+                let info = *res.extensions().get::<RateLimitInfo>().unwrap();
+                recorder.lock().unwrap().push((res.status(), info));
+                Ok::<_, BoxError>(res)
+            }
+        });
+        let url = serve(service).await;
+
+        let client = reqwest::Client::new();
+
+        let _ = client.get(&url).send().await.unwrap();
+        let _ = client.get(&url).send().await.unwrap();
+
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded[0].0, StatusCode::OK);
+        assert_eq!(recorded[0].1.limit, 1);
+        assert_eq!(recorded[0].1.remaining, 0);
+        assert_eq!(recorded[1].0, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(recorded[1].1.remaining, 0);
+        assert!(recorded[1].1.reset.as_secs() <= 10);
+    }
+
+    #[tokio::test]
+    async fn test_hide_whitelisted_header() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .methods(vec![Method::GET])
+                .hide_whitelisted_header()
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("x-ratelimit-limit").is_some());
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers().get("x-ratelimit-whitelisted").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_retry_after() {
+        use crate::governor::RetryAfter;
+        use std::time::Duration;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(3600)
+                .burst_size(1)
+                .retry_after(RetryAfter::Both)
+                .after_ms_header()
+                .max_retry_after(Duration::from_secs(60))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-after").unwrap(), "60");
+        assert_eq!(res.headers().get("retry-after").unwrap(), "60");
+        assert_eq!(res.headers().get("x-ratelimit-after-ms").unwrap(), "60000");
+        assert_eq!(res.text().await.unwrap(), "Too many requests");
+    }
+
+    #[tokio::test]
+    async fn test_extraction_failure_status() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .extraction_failure_status(http::StatusCode::BAD_REQUEST)
+                .negotiate_error_format()
+                .finish()
+                .unwrap(),
+        );
+        // No peer address is inserted, so the default key extractor fails.
+        let service = GovernorLayer { config }.layer(hello());
+        let service = service_fn(move |mut req: Request<Incoming>| {
+            req.extensions_mut().remove::<SocketAddr>();
+            service.clone().oneshot(req)
+        });
+        let url = serve(service).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.text().await.unwrap(), "Unable To Extract Key!");
+
+        let res = client
+            .get(&url)
+            .header("accept", "application/problem+json")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = res.json().await.unwrap();
+        assert_eq!(body["status"], 400);
+    }
+
+    #[tokio::test]
+    async fn test_rejection_body() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .round_up_wait_time()
+                .rejection_body(|ip, wait_time, quota| {
+                    HttpBody::from(format!(
+                        "{} used its {} requests, see https://example.com/limits (retry in {}s)",
+                        ip,
+                        quota.burst_size(),
+                        wait_time.as_secs_f64().ceil()
+                    ))
+                })
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-after").unwrap(), "10");
+        assert_eq!(
+            res.text().await.unwrap(),
+            "127.0.0.1 used its 1 requests, see https://example.com/limits (retry in 10s)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_localized_messages() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .round_up_wait_time()
+                .localized_message("de", "Zu viele Anfragen! Bitte {wait_time}s warten")
+                .localized_message("fr-CA", "Trop de requêtes! Attendez {wait_time}s")
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for (accept_language, message) in [
+            (None, "Too many requests"),
+            (
+                Some("de-AT, en;q=0.5"),
+                "Zu viele Anfragen! Bitte 10s warten",
+            ),
+            (
+                Some("en, fr;q=0.9, de;q=0.8"),
+                "Trop de requêtes! Attendez 10s",
+            ),
+            (Some("es, *;q=0.1"), "Too many requests"),
+        ] {
+            let mut req = client.get(&url);
+            if let Some(accept_language) = accept_language {
+                req = req.header("accept-language", accept_language);
+            }
+            let res = req.send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(res.text().await.unwrap(), message, "{:?}", accept_language);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_http_date() {
+        use crate::governor::RetryAfter;
+        use std::time::{Duration, SystemTime};
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .retry_after(RetryAfter::Both)
+                .retry_after_http_date()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let before = SystemTime::now();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after = res.headers().get("retry-after").unwrap().to_str().unwrap();
+        let retry_after = httpdate::parse_http_date(retry_after).unwrap();
+        let wait = retry_after.duration_since(before).unwrap();
+        assert!(wait > Duration::from_secs(8) && wait <= Duration::from_secs(11));
+        assert!(res.headers().get("x-ratelimit-after").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_on_allowed() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(2)
+                .cache_hit_refunds()
+                .on_allowed(|ip, info, response| {
+                    response.headers_mut().insert(
+                        "x-client",
+                        format!("{} {}", ip, info.remaining).parse().unwrap(),
+                    );
+                })
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-client").unwrap(), "127.0.0.1 1");
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-client").unwrap(), "127.0.0.1 0");
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-client").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_try_governor() {
+        use crate::{GovernorError, TryGovernorLayer};
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .finish()
+                .unwrap(),
+        );
+        let governor = TryGovernorLayer { config }.layer(hello());
+        // An outer layer turning the errors into responses.
+        let service = service_fn(move |req: Request<Incoming>| {
+            let governor = governor.clone();
+            async move {
+                match governor.oneshot(req).await {
+                    Ok(res) => Ok::<_, Infallible>(res),
+                    Err(e) => match *e.downcast::<GovernorError>().unwrap() {
+                        GovernorError::TooManyRequests { wait_time, headers } => {
+                            let mut res = Response::new(HttpBody::from(format!(
+                                "slow down for {}s",
+                                wait_time
+                            )));
+                            *res.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+                            *res.headers_mut() = headers.unwrap();
+                            Ok(res)
+                        }
+                        _ => unreachable!(),
+                    },
+                }
+            }
+        });
+        let url = serve(service).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(res.headers().get("x-ratelimit-after").is_some());
+        assert!(res.text().await.unwrap().starts_with("slow down for "));
+    }
+
+    #[tokio::test]
+    async fn test_route_governor() {
+        use crate::{RouteGovernorLayer, RouteMatcher};
+
+        let strict = GovernorConfigBuilder::default()
+            .per_second(10)
+            .burst_size(1)
+            .finish()
+            .unwrap();
+        let generous = GovernorConfigBuilder::default()
+            .per_second(10)
+            .burst_size(3)
+            .finish()
+            .unwrap();
+        let layer = RouteGovernorLayer::new()
+            .route(RouteMatcher::prefix("/login"), Arc::new(strict))
+            .route(RouteMatcher::glob("/static/*.css"), Arc::new(generous));
+        let url = serve(BoxCloneService::new(layer.layer(hello()))).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(format!("{}/login", url)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .get(format!("{}/login/otp", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..3 {
+            let res = client
+                .get(format!("{}/static/site.css", url))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = client
+            .get(format!("{}/static/main.css", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Unmatched paths aren't rate limited.
+        for path in ["/loginx", "/static/img/logo.css", "/"] {
+            let res = client.get(format!("{}{}", url, path)).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_route_matcher() {
+        use crate::RouteMatcher;
+
+        assert!(RouteMatcher::prefix("/api/").matches("/api/v1"));
+        assert!(RouteMatcher::prefix("/").matches("/anything"));
+        assert!(!RouteMatcher::prefix("/api").matches("/apis"));
+        assert!(RouteMatcher::glob("/users/*/posts").matches("/users/42/posts"));
+        assert!(!RouteMatcher::glob("/users/*/posts").matches("/users/4/2/posts"));
+        assert!(RouteMatcher::glob("/assets/**").matches("/assets/img/a.png"));
+        assert!(RouteMatcher::glob("/v?/rpc").matches("/v2/rpc"));
+        assert!(!RouteMatcher::glob("/v?/rpc").matches("/v/rpc"));
+    }
+
+    #[tokio::test]
+    async fn test_filter() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .filter(|req| {
+                    !req.uri().path().starts_with("/health")
+                        && !req.headers().contains_key("x-internal")
+                })
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let res = client.get(format!("{}/health", url)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get("x-ratelimit-whitelisted").unwrap(),
+            "true"
+        );
+
+        let res = client
+            .get(&url)
+            .header("x-internal", "1")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_exempt_path() {
+        use crate::RouteMatcher;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .exempt_path(RouteMatcher::prefix("/healthz"))
+                .exempt_path(RouteMatcher::glob("/.well-known/*"))
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for path in ["/healthz", "/.well-known/security.txt"] {
+            let res = client.get(format!("{}{}", url, path)).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get("x-ratelimit-whitelisted").is_none());
+            assert!(res.headers().get("x-ratelimit-remaining").is_none());
+        }
+
+        let res = client
+            .get(format!("{}/.well-known/a/b", url))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_skip_preflight() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .skip_preflight()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        for _ in 0..3 {
+            let res = client
+                .request(Method::OPTIONS, &url)
+                .header("origin", "https://example.com")
+                .header("access-control-request-method", "POST")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Plain OPTIONS requests are still rate limited.
+        let res = client.request(Method::OPTIONS, &url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_method_quota() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(3)
+                .method_quota(Method::POST, std::time::Duration::from_secs(10), 1)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert_eq!(res.text().await.unwrap(), "Hello, Post World!");
+
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+
+        // GET requests are counted separately with the default quota.
+        for remaining in ["2", "1", "0"] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                remaining
+            );
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_method_quota_zero() {
+        assert!(GovernorConfigBuilder::default()
+            .method_quota(Method::POST, std::time::Duration::from_secs(1), 0)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_builder() {
+        use crate::key_extractor::NamedKeyExtractor;
+        use governor::middleware::NoOpMiddleware;
+
+        let mut builder: GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> =
+            serde_json::from_str(
+                r#"{ "per_second": 10, "burst_size": 1, "methods": ["POST"], "key_extractor": "smart_ip",
+                    "exempt_keys": ["9.9.9.9"] }"#,
+            )
+            .unwrap();
+        let config = Arc::new(builder.finish().unwrap());
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client
+            .post(&url)
+            .header("x-forwarded-for", "1.2.3.4")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .post(&url)
+            .header("x-forwarded-for", "1.2.3.4")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = client
+            .post(&url)
+            .header("x-forwarded-for", "5.6.7.8")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        // Only POST is rate limited.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        // Nor are exempted keys.
+        for _ in 0..2 {
+            let res = client
+                .post(&url)
+                .header("x-forwarded-for", "9.9.9.9")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // Nor exempted networks, single addresses being accepted as well.
+        for exempt_networks in [r#"["127.0.0.0/8"]"#, r#"["10.0.0.0/8", "127.0.0.1"]"#] {
+            let mut builder: GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware> =
+                serde_json::from_str(&format!(
+                    r#"{{ "burst_size": 1, "exempt_networks": {exempt_networks} }}"#
+                ))
+                .unwrap();
+            let config = Arc::new(builder.finish().unwrap());
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+            for _ in 0..2 {
+                let res = client.get(&url).send().await.unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+            }
+        }
+
+        for invalid in [
+            r#"{ "per_second": 1, "per_millisecond": 1 }"#,
+            r#"{ "methods": ["G E T"] }"#,
+            r#"{ "key_extractor": "cookie" }"#,
+            r#"{ "burst": 1 }"#,
+            r#"{ "exempt_keys": ["alice"] }"#,
+            r#"{ "key_extractor": "global", "exempt_keys": ["alice"] }"#,
+            r#"{ "exempt_networks": ["10.0.0.0/33"] }"#,
+        ] {
+            assert!(
+                serde_json::from_str::<GovernorConfigBuilder<NamedKeyExtractor, NoOpMiddleware>>(
+                    invalid
+                )
+                .is_err()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_file() {
+        use crate::reload::{load_file, watch_file};
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("governor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("governor.toml");
+        std::fs::write(
+            &path,
+            "per_second = 10\nburst_size = 1\nexempt_paths = [{ prefix = \"/healthz\" }]\n",
+        )
+        .unwrap();
+
+        let config = watch_file(&path, Duration::from_millis(20)).unwrap();
+        let url = serve(config.layer().layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = client.get(format!("{}/healthz", url)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // An invalid file keeps the previous configuration.
+        std::fs::write(&path, "burst_size = 0\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(config.get().quota().burst_size().get(), 1);
+
+        // Throttled keys stay throttled across reloads.
+        std::fs::write(&path, "per_second = 10\nburst_size = 1\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(format!("{}/healthz", url)).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // And keep the tokens they consumed with a larger burst size.
+        std::fs::write(&path, "per_second = 10\nburst_size = 2\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(config.get().quota().burst_size().get(), 2);
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let json = dir.join("governor.json");
+        std::fs::write(&json, r#"{ "per_millisecond": 100, "burst_size": 4 }"#).unwrap();
+        assert_eq!(load_file(&json).unwrap().quota().burst_size().get(), 4);
+        std::fs::write(&json, r#"{ "burst_size": "4" }"#).unwrap();
+        assert!(load_file(&json).is_err());
+
+        // TOML values are only read as the type of their field.
+        for invalid in [
+            "burst_size = 1979-05-27",
+            "per_second = nan",
+            "burst_size = 1.5",
+        ] {
+            std::fs::write(&path, invalid).unwrap();
+            assert!(load_file(&path).is_err(), "{}", invalid);
+        }
+
+        let yaml = dir.join("governor.yaml");
+        std::fs::write(
+            &yaml,
+            "per_second: 10\nburst_size: 1\nkey_extractor: operator_header\nexempt_keys: [\"0xABC\"]\n",
+        )
+        .unwrap();
+        #[cfg(feature = "yaml")]
+        {
+            let config = Arc::new(load_file(&yaml).unwrap());
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+            for (operator, limited) in [("0xAbC", false), ("0xdef", true)] {
+                let res = client
+                    .get(&url)
+                    .header("x-drosera-operator", operator)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(res.status(), StatusCode::OK);
+                let res = client
+                    .get(&url)
+                    .header("x-drosera-operator", operator)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(res.status() == StatusCode::TOO_MANY_REQUESTS, limited);
+            }
+        }
+        #[cfg(not(feature = "yaml"))]
+        assert!(matches!(
+            load_file(&yaml),
+            Err(crate::reload::ConfigFileError::Parse(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_from_env() {
+        use crate::key_extractor::NamedKeyExtractor;
+        use std::collections::HashMap;
+        use std::ffi::OsString;
+
+        let from_vars = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(name, value)| (name.to_string(), OsString::from(value)))
+                .collect::<HashMap<_, _>>();
+            GovernorConfigBuilder::<NamedKeyExtractor, _>::from_vars(|name| vars.get(name).cloned())
+        };
+
+        let config = from_vars(&[
+            ("GOVERNOR_PERIOD_MS", "250"),
+            ("GOVERNOR_BURST", " 3"),
+            ("GOVERNOR_METHODS", "GET, POST"),
+            ("GOVERNOR_KEY_EXTRACTOR", "global"),
+        ])
+        .unwrap()
+        .finish()
+        .unwrap();
+        assert_eq!(config.quota().burst_size().get(), 3);
+        assert_eq!(
+            config.quota().replenish_interval(),
+            std::time::Duration::from_millis(250)
+        );
+
+        let config = from_vars(&[]).unwrap().finish().unwrap();
+        assert_eq!(
+            config.quota(),
+            GovernorConfigBuilder::default().finish().unwrap().quota()
+        );
+
+        let error = from_vars(&[("GOVERNOR_BURST", "many")]).unwrap_err();
+        assert_eq!(error.name, "GOVERNOR_BURST");
+        assert!(from_vars(&[("GOVERNOR_BURST", "5000000000")]).is_err());
+        assert!(from_vars(&[("GOVERNOR_METHODS", "G E T")]).is_err());
+        assert!(from_vars(&[("GOVERNOR_KEY_EXTRACTOR", "cookie")]).is_err());
+    }
+
+    #[test]
+    fn test_try_build() {
+        use crate::governor::{ConfigError, RetryAfter};
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default().try_build().is_ok());
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .burst_size(0)
+                .try_build()
+                .unwrap_err(),
+            ConfigError::ZeroBurstSize
+        );
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .period(Duration::ZERO)
+                .try_build()
+                .unwrap_err(),
+            ConfigError::ZeroPeriod
+        );
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .methods(vec![])
+                .try_build()
+                .unwrap_err(),
+            ConfigError::EmptyMethods
+        );
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .method_quota(Method::POST, Duration::ZERO, 1)
+                .try_build()
+                .unwrap_err(),
+            ConfigError::InvalidMethodQuota(Method::POST)
+        );
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .hide_headers()
+                .use_headers()
+                .try_build()
+                .unwrap_err(),
+            ConfigError::ConflictingHeaders("use_headers", "hide_headers")
+        );
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .retry_after_http_date()
+                .try_build()
+                .unwrap_err(),
+            ConfigError::ConflictingHeaders(
+                "retry_after_http_date",
+                "retry_after(RetryAfter::Disabled)"
+            )
+        );
+        assert!(GovernorConfigBuilder::default()
+            .retry_after(RetryAfter::Only)
+            .retry_after_http_date()
+            .try_build()
+            .is_ok());
+        assert_eq!(
+            GovernorConfigBuilder::default()
+                .warning_threshold(101)
+                .try_build()
+                .unwrap_err()
+                .to_string(),
+            "The warning threshold must not be above 100 percent, got 101"
+        );
+    }
+
+    #[test]
+    fn test_long_window_quotas() {
+        use std::time::Duration;
+
+        let quota = GovernorConfigBuilder::default()
+            .per_hour(60)
+            .finish()
+            .unwrap()
+            .quota();
+        assert_eq!(quota.burst_size().get(), 60);
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(60));
+
+        let quota = GovernorConfigBuilder::default()
+            .per_day(24)
+            .finish()
+            .unwrap()
+            .quota();
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(60 * 60));
+
+        let quota = GovernorConfigBuilder::default()
+            .per_week(7)
+            .finish()
+            .unwrap()
+            .quota();
+        assert_eq!(
+            quota.replenish_interval(),
+            Duration::from_secs(24 * 60 * 60)
+        );
+
+        let quota = GovernorConfigBuilder::default()
+            .quota_per(4, Duration::from_millis(100))
+            .finish()
+            .unwrap()
+            .quota();
+        assert_eq!(quota.burst_size().get(), 4);
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(25));
+
+        assert!(GovernorConfigBuilder::default()
+            .per_hour(0)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_additional_quota() {
+        use std::time::Duration;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(3)
+                .additional_quota(1, Duration::from_millis(100))
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // The additional quota is the most restrictive one.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+
+        // Rejected by the additional quota, the token of the first quota is given back.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+
+        for _ in 0..2 {
+            tokio::time::sleep(Duration::from_millis(120)).await;
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // Now the first quota is exhausted.
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+
+        assert!(GovernorConfigBuilder::default()
+            .additional_quota(0, Duration::from_secs(1))
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_quota_provider() {
+        use governor::Quota;
+        use std::net::IpAddr;
+        use std::num::NonZeroU32;
+
+        let premium =
+            Quota::per_second(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(4).unwrap());
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(1)
+                .quota_provider(move |ip: &IpAddr| match ip.is_loopback() {
+                    true => premium,
+                    false => Quota::per_second(NonZeroU32::new(1).unwrap()),
+                })
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // The loopback address gets the larger quota.
+        for remaining in (0..4).rev() {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "4");
+            assert_eq!(
+                res.headers().get("x-ratelimit-remaining").unwrap(),
+                &remaining.to_string()
+            );
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "4");
+    }
+
+    #[tokio::test]
+    async fn test_cached_quota_provider() {
+        use crate::quota_cache::CachedQuotaProvider;
+        use governor::Quota;
+        use std::net::IpAddr;
+        use std::num::NonZeroU32;
+        use std::sync::atomic::{AtomicU32, Ordering};
+        use std::time::Duration;
+
+        let default = Quota::per_second(NonZeroU32::new(1).unwrap());
+        let fetches = Arc::new(AtomicU32::new(0));
+        let provider = CachedQuotaProvider::new(default, Duration::from_millis(200), {
+            let fetches = fetches.clone();
+            move |_: &IpAddr| {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                async move { Ok(default.allow_burst(NonZeroU32::new(3).unwrap())) }
+            }
+        });
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(1)
+                .quota_provider(provider.clone())
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // The first request doesn't wait for the quota to be fetched.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.len(), 1);
+
+        // Expired quotas are fetched again.
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_plan_tiers() {
+        use crate::PlanTier;
+        use governor::Quota;
+        use std::net::IpAddr;
+        use std::num::NonZeroU32;
+
+        let per_second = |burst_size| {
+            Quota::per_second(NonZeroU32::new(1).unwrap())
+                .allow_burst(NonZeroU32::new(burst_size).unwrap())
+        };
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(1)
+                .tier("free", per_second(1))
+                .tier("pro", per_second(2))
+                .tier_of(|ip: &IpAddr| ip.is_loopback().then_some("pro"))
+                .finish()
+                .unwrap(),
+        );
+        let inner = service_fn(|req: Request<Incoming>| async move {
+            let tier = req.extensions().get::<PlanTier>().unwrap().clone();
+            Ok::<_, Infallible>(Response::new(HttpBody::from(tier.as_str().to_owned())))
+        });
+        let url = serve(GovernorLayer { config }.layer(inner)).await;
+
+        let client = reqwest::Client::new();
+
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-tier").unwrap(), "pro");
+            assert_eq!(res.text().await.unwrap(), "pro");
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers().get("x-ratelimit-tier").unwrap(), "pro");
+    }
+
+    #[tokio::test]
+    async fn test_anonymous_quota() {
+        use crate::key_extractor::{AuthOrAnonymousKeyExtractor, OperatorHeaderKeyExtractor};
+        use governor::Quota;
+        use std::num::NonZeroU32;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .key_extractor(AuthOrAnonymousKeyExtractor::new(
+                    OperatorHeaderKeyExtractor::default(),
+                ))
+                .per_second(1)
+                .burst_size(3)
+                .anonymous_quota(Quota::per_second(NonZeroU32::new(1).unwrap()))
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        // Anonymous requests get the stricter quota.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "1");
+        assert_eq!(res.headers().get("x-ratelimit-tier").unwrap(), "anonymous");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Authenticated requests are keyed by identity, with the quota of the configuration.
+        for _ in 0..3 {
+            let res = client
+                .get(&url)
+                .header("x-drosera-operator", "alice")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "3");
+            assert!(res.headers().get("x-ratelimit-tier").is_none());
+        }
+        let res = client
+            .get(&url)
+            .header("x-drosera-operator", "bob")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_update_config() {
+        use crate::reload::GovernorHandle;
+
+        let handle = GovernorHandle::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(3)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(handle.layer().layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "2");
+
+        // The token consumed before the update is still consumed.
+        handle.update_config(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(6)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "6");
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "4");
+
+        // Shrinking the burst size caps the consumed tokens.
+        handle.update_config(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(handle.config().quota().burst_size().get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        use crate::ShadowRejection;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .dry_run()
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let inner = service_fn(|req: Request<Incoming>| async move {
+            let shadowed = req.extensions().get::<ShadowRejection>().is_some();
+            Ok::<_, Infallible>(Response::new(HttpBody::from(shadowed.to_string())))
+        });
+        let url = serve(GovernorLayer { config }.layer(inner)).await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.text().await.unwrap(), "false");
+
+        // Over the quota, but passed through.
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get("x-ratelimit-after").is_none());
+            assert_eq!(res.text().await.unwrap(), "true");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enforced_percent() {
+        for (percent, status) in [(0, StatusCode::OK), (100, StatusCode::TOO_MANY_REQUESTS)] {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(10)
+                    .burst_size(1)
+                    .enforced_percent(percent)
+                    .finish()
+                    .unwrap(),
+            );
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+            let client = reqwest::Client::new();
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+
+        assert!(GovernorConfigBuilder::default()
+            .enforced_percent(101)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failure_policy() {
+        use crate::governor::FailurePolicy;
+        use crate::key_extractor::OperatorHeaderKeyExtractor;
+
+        for (policy, statuses) in [
+            (
+                FailurePolicy::FailClosed,
+                [StatusCode::INTERNAL_SERVER_ERROR; 2],
+            ),
+            (FailurePolicy::FailOpen, [StatusCode::OK; 2]),
+            (
+                FailurePolicy::FallbackKey("anonymous".to_owned()),
+                [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS],
+            ),
+        ] {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .key_extractor(OperatorHeaderKeyExtractor::default())
+                    .per_second(10)
+                    .burst_size(1)
+                    .failure_policy(policy)
+                    .finish()
+                    .unwrap(),
+            );
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+            // Requests without the operator header.
+            let client = reqwest::Client::new();
+            for status in statuses {
+                let res = client.get(&url).send().await.unwrap();
+                assert_eq!(res.status(), status);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        use crate::reload::{GovernorHandle, Maintenance};
+
+        let handle = GovernorHandle::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(handle.layer().layer(hello())).await;
+
+        let client = reqwest::Client::new();
+
+        handle.set_maintenance(Maintenance::AllowAll);
+        for _ in 0..3 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        handle.set_maintenance(Maintenance::RejectAll);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            res.text().await.unwrap(),
+            "Service unavailable for maintenance"
+        );
+
+        // Requests allowed during maintenance didn't consume the quota.
+        handle.set_maintenance(Maintenance::Off);
+        assert_eq!(handle.maintenance(), Maintenance::Off);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_exempt_key() {
+        use std::net::{IpAddr, Ipv4Addr};
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .exempt_key(IpAddr::V4(Ipv4Addr::LOCALHOST))
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let limiter = config.limiter().clone();
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        for _ in 0..3 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers().get("x-ratelimit-whitelisted").unwrap(),
+                "true"
+            );
+        }
+        // The exempt key has no rate limiting state.
+        assert_eq!(limiter.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_exempt_network() {
+        for (network, status) in [
+            ("127.0.0.0/8", StatusCode::OK),
+            ("10.0.0.0/8", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(10)
+                    .burst_size(1)
+                    .exempt_network(network.parse().unwrap())
+                    .finish()
+                    .unwrap(),
+            );
+            let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+            let client = reqwest::Client::new();
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+
+        // Forwarding headers don't exempt clients, they can be spoofed.
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .key_extractor(crate::key_extractor::SmartIpKeyExtractor)
+                .exempt_network("10.0.0.0/8".parse().unwrap())
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        for status in [StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+            let res = client
+                .get(&url)
+                .header("x-forwarded-for", "10.0.0.1")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), status);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_ban() {
+        use std::time::Duration;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .auto_ban(2, Duration::from_secs(5), Duration::from_secs(60))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        // Banned after the second rejection.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(res.headers().get("retry-after").unwrap(), "60");
+
+        assert!(GovernorConfigBuilder::default()
+            .auto_ban(0, Duration::from_secs(5), Duration::from_secs(60))
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_file() {
+        use crate::blocklist::{Blocklist, BlocklistEntries};
+        use std::net::IpAddr;
+        use std::time::Duration;
+
+        let entries = BlocklistEntries::<IpAddr>::parse("# comment\n\n10.1.2.3\n192.168.0.0/16\n");
+        assert_eq!(entries.networks.len(), 2);
+
+        let dir = std::env::temp_dir().join(format!("governor-blocklist-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("blocked.txt");
+        std::fs::write(&path, "127.0.0.0/8\n").unwrap();
+
+        let blocklist = Blocklist::<IpAddr>::watch_file(&path, Duration::from_millis(20))
+            .unwrap()
+            .status(StatusCode::UNAUTHORIZED);
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .blocklist(blocklist.clone())
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // Wait for the modification time to change.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, "10.0.0.0/8\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "blocklist-url")]
+    #[tokio::test]
+    async fn test_remote_blocklist() {
+        use crate::blocklist::{Blocklist, BlocklistEntries};
+        use std::net::IpAddr;
+        use std::sync::Mutex;
+        use std::time::Duration;
+
+        let entries = BlocklistEntries::<IpAddr>::parse("10.1.2.3,10.1.2.4\n192.168.0.0/16\n");
+        assert_eq!(entries.networks.len(), 3);
+        let entries = BlocklistEntries::<IpAddr>::parse_json(r#"["10.1.2.3", "192.168.0.0/16"]"#);
+        assert_eq!(entries.unwrap().networks.len(), 2);
+
+        let denylist = Arc::new(Mutex::new(String::from(r#"["127.0.0.1"]"#)));
+        let served = denylist.clone();
+        let abuse = serve(BoxCloneService::new(service_fn(
+            move |_req: Request<Incoming>| {
+                let body = served.lock().unwrap().clone();
+                async move { Ok::<_, Infallible>(Response::new(HttpBody::from(body))) }
+            },
+        )))
+        .await;
+
+        let blocklist = Blocklist::<IpAddr>::new(BlocklistEntries::default());
+        blocklist.refresh_from_url(abuse.parse().unwrap(), Duration::from_millis(20));
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .blocklist(blocklist.clone())
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // A failed fetch keeps the previous entries.
+        *denylist.lock().unwrap() = String::from("[not json");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        *denylist.lock().unwrap() = String::from("10.0.0.0/8");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_quota() {
+        use crate::governor::Weekday;
+        use governor::Quota;
+        use std::num::NonZeroU32;
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        let strict = Quota::per_second(NonZeroU32::new(1).unwrap());
+        let hour = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 60
+            / 60;
+
+        // A window of all day applies now.
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(10)
+                .scheduled_quota(&Weekday::ALL, 0..24, strict)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // A window of the next hour in the time zone doesn't apply yet.
+        let next = ((hour + 3) % 24) as u8;
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(10)
+                .scheduled_quota(&Weekday::ALL, next..next + 1, strict)
+                .schedule_utc_offset(2 * 60)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        assert!(GovernorConfigBuilder::default()
+            .scheduled_quota(&[], 9..17, strict)
+            .finish()
+            .is_none());
+        assert!(GovernorConfigBuilder::default()
+            .scheduled_quota(&Weekday::WORKDAYS, 9..25, strict)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_load_adaptive() {
+        use std::sync::atomic::{AtomicU8, Ordering};
+
+        let load = Arc::new(AtomicU8::new(0));
+        let signal = load.clone();
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(10)
+                .load_adaptive(
+                    move || f64::from(signal.load(Ordering::Relaxed)) / 100.0,
+                    20,
+                )
+                .use_headers()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "10");
+
+        // Full load leaves 20% of the quota.
+        load.store(100, Ordering::Relaxed);
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("x-ratelimit-limit").unwrap(), "2");
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert!(GovernorConfigBuilder::default()
+            .load_adaptive(|| 0.5, 0)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_startup_grace() {
+        use std::time::Duration;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(1)
+                .startup_grace(Duration::from_millis(200))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let client = reqwest::Client::new();
+
+        for _ in 0..3 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_governor_bypass() {
+        use crate::GovernorBypass;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(1)
+                .finish()
+                .unwrap(),
+        );
+        // Outer middleware trusting internal callers.
+        let app =
+            GovernorLayer { config }
+                .layer(hello())
+                .map_request(|mut req: Request<Incoming>| {
+                    if req.headers().contains_key("x-internal") {
+                        req.extensions_mut().insert(GovernorBypass);
+                    }
+                    req
+                });
+        let url = serve(app).await;
+        let client = reqwest::Client::new();
+
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        for _ in 0..3 {
+            let res = client
+                .get(&url)
+                .header("x-internal", "1")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_trust_loopback_and_private() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .trust_loopback_and_private()
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        for _ in 0..3 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers().get("x-ratelimit-limit").is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_store() {
+        use crate::store::{GovernorStore, MemoryStore};
+        use std::net::IpAddr;
+        use std::sync::Mutex;
+
+        // A memory store recording its scopes.
+        #[derive(Default)]
+        struct ScopedStore {
+            inner: MemoryStore<IpAddr>,
+            scopes: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl GovernorStore<IpAddr> for ScopedStore {
+            fn measure_and_replace(
+                &self,
+                key: &IpAddr,
+                f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+            ) {
+                self.inner.measure_and_replace(key, f)
+            }
+
+            fn retain(&self, keep: &mut dyn FnMut(&IpAddr, u64) -> bool) {
+                self.inner.retain(keep)
+            }
+
+            fn len(&self) -> usize {
+                self.inner.len()
+            }
+
+            fn scoped(&self, scope: &str) -> Arc<dyn GovernorStore<IpAddr>> {
+                self.scopes.lock().unwrap().push(scope.to_owned());
+                Arc::new(ScopedStore {
+                    inner: MemoryStore::default(),
+                    scopes: self.scopes.clone(),
+                })
+            }
+        }
+
+        let store = ScopedStore::default();
+        let scopes = store.scopes.clone();
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .method_quota(Method::POST, std::time::Duration::from_secs(10), 2)
+                .store(store)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(
+            GovernorLayer {
+                config: config.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        for _ in 0..2 {
+            let res = client.post(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = client.post(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert_eq!(*scopes.lock().unwrap(), ["default", "method:POST"]);
+        assert_eq!(config.limiter().len(), 1);
+    }
+
+    #[cfg(feature = "redis")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_redis_store() {
+        use crate::{redis::RedisStore, store::GovernorStore};
+        use std::collections::HashMap;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        };
+
+        // A fake Redis server handling the commands of the store.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let states = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+        let server_states = states.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let states = server_states.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 {
+                        let count: usize = line.trim()[1..].parse().unwrap();
+                        let args: Vec<String> = (0..count)
+                            .map(|_| {
+                                line.clear();
+                                reader.read_line(&mut line).unwrap();
+                                let length: usize = line.trim()[1..].parse().unwrap();
+                                let mut arg = vec![0; length + 2];
+                                reader.read_exact(&mut arg).unwrap();
+                                String::from_utf8(arg[..length].to_vec()).unwrap()
+                            })
+                            .collect();
+                        let mut states = states.lock().unwrap();
+                        let reply = match args[0].as_str() {
+                            "GET" => match states.get(&args[1]) {
+                                Some(state) => format!("${}\r\n{}\r\n", state.len(), state),
+                                None => String::from("$-1\r\n"),
+                            },
+                            // The GCRA script.
+                            "EVAL" if args[1].contains("capacity") => {
+                                let [now, increment, capacity] =
+                                    [4, 5, 6].map(|i| args[i].parse::<u64>().unwrap());
+                                let debt = states
+                                    .get(&args[3])
+                                    .map_or(0, |state| state.parse::<u64>().unwrap())
+                                    .saturating_sub(now);
+                                if debt + increment > capacity {
+                                    format!("*2\r\n:0\r\n:{}\r\n", debt + increment - capacity)
+                                } else {
+                                    let state = now + debt + increment;
+                                    states.insert(args[3].clone(), state.to_string());
+                                    format!("*2\r\n:1\r\n:{}\r\n", debt + increment)
+                                }
+                            }
+                            "EVAL" if args[3] == "test:contended" => String::from(":0\r\n"),
+                            "EVAL" => {
+                                let current = states.get(&args[3]).cloned().unwrap_or_default();
+                                if current == args[4] {
+                                    states.insert(args[3].clone(), args[5].clone());
+                                    String::from(":1\r\n")
+                                } else {
+                                    String::from(":0\r\n")
+                                }
+                            }
+                            _ => String::from("-ERR unknown command\r\n"),
+                        };
+                        reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        // Two instances sharing the quota.
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(60)
+                    .burst_size(2)
+                    .store(RedisStore::new(address.clone()).prefix("test"))
+                    .finish()
+                    .unwrap(),
+            );
+            urls.push(serve(GovernorLayer { config }.layer(hello())).await);
+        }
+
+        let client = reqwest::Client::new();
+        for url in &urls {
+            let res = client.get(url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        for url in &urls {
+            let res = client.get(url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert!(states
+            .lock()
+            .unwrap()
+            .contains_key("test:default:127.0.0.1"));
+
+        // Updates of a key other instances keep changing give up.
+        let store = RedisStore::new(address.clone()).prefix("test");
+        let mut attempts = 0;
+        let updated = GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| {
+            attempts += 1;
+            Some(1)
+        });
+        assert!(updated.is_err());
+        assert_eq!(attempts, 8);
+
+        // Which isn't a failure of the server.
+        for _ in 0..3 {
+            let updated =
+                GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert!(GovernorStore::try_measure_and_replace(&store, &"free", &mut |_| Some(1)).is_ok());
+
+        // A failing server isn't called after 3 failures in a row.
+        let failing = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let store = RedisStore::new(failing.local_addr().unwrap().to_string());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in failing.incoming() {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        for _ in 0..5 {
+            let updated = GovernorStore::try_measure_and_replace(&store, &"key", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 3);
+
+        // Unreachable servers let requests through.
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(1)
+                .store(RedisStore::new("127.0.0.1:1"))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+    }
+
+    #[cfg(feature = "memcached")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_memcached_store() {
+        use crate::{memcached::MemcachedStore, store::GovernorStore};
+        use std::collections::HashMap;
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Mutex,
+        };
+
+        // A fake memcached server handling `gets`, `cas` and `add`, keys with their value and CAS unique.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let items = Arc::new(Mutex::new(HashMap::<String, (String, u64)>::new()));
+        let server_items = items.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let items = server_items.clone();
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 {
+                        let args: Vec<String> =
+                            line.split_whitespace().map(str::to_owned).collect();
+                        let mut items = items.lock().unwrap();
+                        let reply = match args[0].as_str() {
+                            "gets" => match items.get(&args[1]) {
+                                Some((value, cas)) => format!(
+                                    "VALUE {} 0 {} {}\r\n{}\r\nEND\r\n",
+                                    args[1],
+                                    value.len(),
+                                    cas,
+                                    value
+                                ),
+                                None => String::from("END\r\n"),
+                            },
+                            command => {
+                                let mut value = String::new();
+                                reader.read_line(&mut value).unwrap();
+                                let value = value.trim_end().to_owned();
+                                let current = items.get(&args[1]).map(|(_, cas)| *cas);
+                                // Another instance keeps changing `test:contended`.
+                                let stored = args[1] != "test:contended"
+                                    && match command {
+                                        "add" => current.is_none(),
+                                        _ => current == Some(args[5].parse().unwrap()),
+                                    };
+                                if stored {
+                                    let cas = current.unwrap_or_default() + 1;
+                                    items.insert(args[1].clone(), (value, cas));
+                                    String::from("STORED\r\n")
+                                } else {
+                                    String::from("EXISTS\r\n")
+                                }
+                            }
+                        };
+                        reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+
+        // Two instances sharing the quota.
+        let mut urls = Vec::new();
+        for _ in 0..2 {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(60)
+                    .burst_size(2)
+                    .store(MemcachedStore::new([address.clone()]).prefix("test"))
+                    .finish()
+                    .unwrap(),
+            );
+            urls.push(serve(GovernorLayer { config }.layer(hello())).await);
+        }
+
+        let client = reqwest::Client::new();
+        for url in &urls {
+            let res = client.get(url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        for url in &urls {
+            let res = client.get(url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+        assert_eq!(
+            items.lock().unwrap()["test:default:127.0.0.1"].1,
+            2,
+            "added then swapped once"
+        );
+
+        // Updates of a key other instances keep changing give up.
+        let store = MemcachedStore::new([address]).prefix("test");
+        let mut attempts = 0;
+        let updated = GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| {
+            attempts += 1;
+            Some(1)
+        });
+        assert!(updated.is_err());
+        assert_eq!(attempts, 8);
+
+        // Which isn't a failure of the server.
+        for _ in 0..3 {
+            let updated =
+                GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert!(GovernorStore::try_measure_and_replace(&store, &"free", &mut |_| Some(1)).is_ok());
+
+        // A failing server isn't called after 3 failures in a row.
+        let failing = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let store = MemcachedStore::new([failing.local_addr().unwrap().to_string()]);
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in failing.incoming() {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        for _ in 0..5 {
+            let updated = GovernorStore::try_measure_and_replace(&store, &"key", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert_eq!(connections.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_sharded_store() {
+        use crate::store::{GovernorStore, ShardedStore};
+
+        let store = ShardedStore::<u32>::new(8);
+        for key in 0..100 {
+            store.measure_and_replace(&key, &mut |state| {
+                assert_eq!(state, None);
+                Some(u64::from(key) + 1)
+            });
+        }
+        assert_eq!(store.len(), 100);
+        assert_eq!(store.get(&41), Some(42));
+        store.retain(&mut |_, state| state % 2 == 0);
+        assert_eq!(store.len(), 50);
+        assert_eq!(store.get(&42), None);
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .store(ShardedStore::new(4))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_lru_store() {
+        use crate::store::{GovernorStore, LruStore};
+
+        let store = LruStore::<u32>::new(3);
+        for key in 0..3 {
+            store.measure_and_replace(&key, &mut |_| Some(1));
+        }
+        // Using 0 makes 1 the least recently used key.
+        store.measure_and_replace(&0, &mut |state| state.map(|state| state + 1));
+        store.measure_and_replace(&3, &mut |_| Some(1));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.get(&0), Some(2));
+        assert_eq!(store.get(&1), None);
+        assert_eq!(store.get(&3), Some(1));
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(10)
+                .burst_size(1)
+                .store(LruStore::new(10))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_idle_ttl() {
+        use std::time::Duration;
+
+        for (ttl, kept) in [(Duration::ZERO, 0), (Duration::from_millis(200), 1)] {
+            let config = Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_millisecond(20)
+                    .burst_size(1)
+                    .idle_ttl(ttl)
+                    .finish()
+                    .unwrap(),
+            );
+            let url = serve(
+                GovernorLayer {
+                    config: config.clone(),
+                }
+                .layer(hello()),
+            )
+            .await;
+
+            let res = reqwest::get(&url).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(config.limiter().len(), 1);
+
+            // Replenished, but not idle for the TTL yet.
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            config.retain_recent();
+            assert_eq!(config.limiter().len(), kept);
+
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            config.retain_recent();
+            assert_eq!(config.limiter().len(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_gc_interval() {
+        use crate::{reload::GovernorHandle, RouteMatcher};
+        use std::time::Duration;
+
+        let handle = GovernorHandle::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(20)
+                .burst_size(1)
+                .gc_interval(Duration::from_millis(50))
+                .exempt_path(RouteMatcher::prefix("/healthz"))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(handle.layer().layer(hello())).await;
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(handle.config().limiter().len(), 1);
+
+        // Any request triggers the cleanup once the interval elapsed.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let res = reqwest::get(format!("{}/healthz", url)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(handle.config().limiter().len(), 0);
+
+        let res = reqwest::get(&url).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(handle.config().limiter().len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        handle.gc_now();
+        assert_eq!(handle.config().limiter().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_gc_threshold() {
+        use crate::key_extractor::SmartIpKeyExtractor;
+        use std::time::Duration;
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_millisecond(200)
+                .burst_size(1)
+                .key_extractor(SmartIpKeyExtractor)
+                .gc_threshold(3)
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(
+            GovernorLayer {
+                config: config.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |ip: &'static str| client.get(&url).header("x-forwarded-for", ip).send();
+
+        send("1.2.3.1").await.unwrap();
+        send("1.2.3.2").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        send("1.2.3.3").await.unwrap();
+        assert_eq!(config.limiter().len(), 3);
+
+        // Reaching the threshold drops the replenished keys.
+        send("1.2.3.4").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(config.limiter().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_save_state() {
+        let dir = std::env::temp_dir().join(format!("governor-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        let build = || {
+            Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(60)
+                    .burst_size(2)
+                    .use_headers()
+                    .finish()
+                    .unwrap(),
+            )
+        };
+
+        // A missing state leaves the quotas full.
+        let config = build();
+        config.restore_state(&path).unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: config.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+        config.save_state(&path).unwrap();
+
+        // After a restart, the key keeps the token it consumed.
+        let config = build();
+        config.restore_state(&path).unwrap();
+        let url = serve(GovernorLayer { config }.layer(hello())).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "0");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_export_state() {
+        use crate::{reload::GovernorHandle, store::Snapshot};
+
+        let build = || {
+            GovernorHandle::new(
+                GovernorConfigBuilder::default()
+                    .per_second(60)
+                    .burst_size(3)
+                    .use_headers()
+                    .finish()
+                    .unwrap(),
+            )
+        };
+        let primary = build();
+        let url = serve(primary.layer().layer(hello())).await;
+        let client = reqwest::Client::new();
+        client.get(&url).send().await.unwrap();
+
+        let snapshot = primary.export_state();
+        let keys = &snapshot.limiters["default"];
+        assert_eq!(keys.len(), 1);
+        assert_eq!(
+            keys[0].key,
+            "127.0.0.1".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(keys[0].remaining, 2);
+
+        // A standby picks up where the primary left.
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let standby = build();
+        standby.import_state(&serde_json::from_str::<Snapshot<_>>(&json).unwrap());
+        let url = serve(standby.layer().layer(hello())).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.headers().get("x-ratelimit-remaining").unwrap(), "1");
+    }
+
+    #[tokio::test]
+    async fn test_track_offenders() {
+        use crate::key_extractor::SmartIpKeyExtractor;
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .track_offenders(0, Duration::from_secs(60))
+            .finish()
+            .is_none());
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(1)
+                .key_extractor(SmartIpKeyExtractor)
+                .track_offenders(1, Duration::from_secs(60))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(
+            GovernorLayer {
+                config: config.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        for ip in ["1.2.3.4", "1.2.3.4", "1.2.3.4", "5.6.7.8", "5.6.7.8"] {
+            client
+                .get(&url)
+                .header("x-forwarded-for", ip)
+                .send()
+                .await
+                .unwrap();
+        }
+
+        let offenders = config.top_offenders();
+        assert_eq!(offenders.len(), 1);
+        assert_eq!(
+            offenders[0].key,
+            "1.2.3.4".parse::<std::net::IpAddr>().unwrap()
+        );
+        assert_eq!(offenders[0].rejections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_cap() {
+        use crate::key_extractor::SmartIpKeyExtractor;
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .key_cap(0, 1, Duration::from_secs(60))
+            .finish()
+            .is_none());
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(1)
+                .key_extractor(SmartIpKeyExtractor)
+                .key_cap(2, 1, Duration::from_secs(60))
+                .finish()
+                .unwrap(),
+        );
+        let url = serve(
+            GovernorLayer {
+                config: config.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |ip: &'static str| client.get(&url).header("x-forwarded-for", ip).send();
+
+        assert_eq!(send("1.2.3.1").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send("1.2.3.2").await.unwrap().status(), StatusCode::OK);
+
+        // New keys share the overflow quota.
+        assert_eq!(send("1.2.3.3").await.unwrap().status(), StatusCode::OK);
+        assert_eq!(
+            send("1.2.3.4").await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(config.limiter().len(), 2);
+
+        // Tracked keys keep their own quota.
+        assert_eq!(
+            send("1.2.3.1").await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_named_layers() {
+        use crate::{
+            key_extractor::{GlobalKeyExtractor, PeerIpKeyExtractor},
+            registry,
+        };
+        use governor::middleware::NoOpMiddleware;
+
+        let make = || {
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(2)
+                .finish()
+                .unwrap()
+        };
+        let first = serve(GovernorLayer::named("test_named_layers", make).layer(hello())).await;
+        let second = serve(GovernorLayer::named("test_named_layers", make).layer(hello())).await;
+
+        // Both listeners share the quota.
+        let client = reqwest::Client::new();
+        assert_eq!(
+            client.get(&first).send().await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            client.get(&second).send().await.unwrap().status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            client.get(&first).send().await.unwrap().status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        assert!(registry::get::<GlobalKeyExtractor, NoOpMiddleware>("test_named_layers").is_none());
+        assert!(registry::unregister("test_named_layers"));
+        assert!(registry::get::<PeerIpKeyExtractor, NoOpMiddleware>("test_named_layers").is_none());
+    }
+
+    #[cfg(feature = "gossip")]
+    #[tokio::test]
+    async fn test_gossip_store() {
+        use crate::gossip::GossipStore;
+        use std::{net::IpAddr, time::Duration};
+
+        let first = GossipStore::<IpAddr>::bind("127.0.0.1:0", Duration::from_millis(20)).unwrap();
+        let second = GossipStore::<IpAddr>::bind("127.0.0.1:0", Duration::from_millis(20)).unwrap();
+        first.add_peer(second.local_addr().unwrap()).unwrap();
+        second.add_peer(first.local_addr().unwrap()).unwrap();
+
+        let serve_with = |store| {
+            let config = GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(2)
+                .store(store)
+                .finish()
+                .unwrap();
+            serve(
+                GovernorLayer {
+                    config: Arc::new(config),
+                }
+                .layer(hello()),
+            )
+        };
+        let first = serve_with(first).await;
+        let second = serve_with(second).await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&first).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The second instance hears of the token consumed by the first one.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&second).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&second).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[cfg(feature = "ring")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_ring_store() {
+        use crate::{ring::RingStore, store::GovernorStore};
+        use std::{
+            io::{BufRead, BufReader, Write},
+            sync::atomic::{AtomicUsize, Ordering},
+        };
+
+        let members: Vec<String> = (0..2)
+            .map(|_| {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                listener.local_addr().unwrap().to_string()
+            })
+            .collect();
+        let mut urls = Vec::new();
+        for member in &members {
+            let config = GovernorConfigBuilder::default()
+                .per_second(1)
+                .burst_size(2)
+                .store(RingStore::bind(member, members.clone()).unwrap())
+                .finish()
+                .unwrap();
+            urls.push(
+                serve(
+                    GovernorLayer {
+                        config: Arc::new(config),
+                    }
+                    .layer(hello()),
+                )
+                .await,
+            );
+        }
+
+        // Whichever instance owns the key, both enforce its quota.
+        let client = reqwest::Client::new();
+        let res = client.get(&urls[0]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&urls[1]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&urls[0]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = client.get(&urls[1]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The address of the instance must be one of the members.
+        let err = RingStore::bind("127.0.0.1:0", members.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // Only the members are served, up to 256 connections at once.
+        let get = |mut stream: std::net::TcpStream| {
+            stream.write_all(b"GET governor:key\r\n").unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            let read = reader.read_line(&mut line).unwrap_or(0);
+            (read > 0, reader.into_inner())
+        };
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind("127.0.0.2:0".parse().unwrap()).unwrap();
+        let stream = socket
+            .connect(members[0].parse().unwrap())
+            .await
+            .unwrap()
+            .into_std()
+            .unwrap();
+        stream.set_nonblocking(false).unwrap();
+        assert!(!get(stream).0);
+        // The other instance keeps at most one connection in its pool.
+        let connections: Vec<_> = (0..257)
+            .map(|_| get(std::net::TcpStream::connect(&members[0]).unwrap()))
+            .collect();
+        let served = connections.iter().filter(|(served, _)| *served).count();
+        assert!((255..=256).contains(&served));
+        assert!(!connections[256].0);
+        drop(connections);
+
+        // A store stops serving once shut down.
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let store = RingStore::bind(&address, [address.clone()]).unwrap();
+        assert!(get(std::net::TcpStream::connect(&address).unwrap()).0);
+        GovernorStore::<u32>::shutdown(&store);
+        let mut refused = false;
+        for _ in 0..100 {
+            if std::net::TcpStream::connect(&address).is_err() {
+                refused = true;
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert!(refused);
+
+        // A member whose keys keep being changed by other instances.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let busy = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                std::thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap() > 0 {
+                        let reply = match line.starts_with("GET") {
+                            true => "NONE\r\n",
+                            false => "EXISTS\r\n",
+                        };
+                        reader.get_mut().write_all(reply.as_bytes()).unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+        let this = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let store = RingStore::bind(&this, [this.clone(), busy]).unwrap();
+        // The first key owned by the busy member gives up.
+        let (updated, attempts) = (0..)
+            .map(|key| {
+                let mut attempts = 0;
+                let updated = GovernorStore::try_measure_and_replace(&store, &key, &mut |_| {
+                    attempts += 1;
+                    Some(1)
+                });
+                (updated, attempts)
+            })
+            .find(|(_, attempts)| *attempts > 1)
+            .unwrap();
+        assert!(updated.is_err());
+        assert_eq!(attempts, 8);
+
+        // Which isn't a failure of the member.
+        let key = (0..)
+            .find(|key| {
+                let mut attempts = 0;
+                let _ = GovernorStore::try_measure_and_replace(&store, key, &mut |_| {
+                    attempts += 1;
+                    Some(1)
+                });
+                attempts > 1
+            })
+            .unwrap();
+        for _ in 0..2 {
+            let updated = GovernorStore::try_measure_and_replace(&store, &key, &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert!(GovernorStore::try_measure_and_replace(&store, &key, &mut |_| None).is_ok());
+
+        // A failing member isn't called after 3 failures in a row.
+        let failing = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let failing_address = failing.local_addr().unwrap().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        std::thread::spawn(move || {
+            for stream in failing.incoming() {
+                accepted.fetch_add(1, Ordering::Relaxed);
+                drop(stream);
+            }
+        });
+        let this = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let store = RingStore::bind(&this, [this.clone(), failing_address]).unwrap();
+        let failures = (0..)
+            .filter(|key| {
+                GovernorStore::try_measure_and_replace(&store, key, &mut |_| Some(1)).is_err()
+            })
+            .take(5)
+            .count();
+        assert_eq!(failures, 5);
+        assert_eq!(connections.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn test_kv_store() {
+        use crate::{
+            kv::{ConditionalKv, KvStore},
+            store::GovernorStore,
+        };
+        use std::{
+            collections::HashMap,
+            sync::{
+                atomic::{AtomicBool, AtomicUsize, Ordering},
+                Mutex,
+            },
+            time::SystemTime,
+        };
+
+        #[derive(Default)]
+        struct Table {
+            items: Mutex<HashMap<String, u64>>,
+            down: AtomicBool,
+            calls: AtomicUsize,
+        }
+
+        impl ConditionalKv for Arc<Table> {
+            type Error = &'static str;
+
+            fn get(&self, key: &str) -> Result<Option<u64>, Self::Error> {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                if self.down.load(Ordering::Relaxed) {
+                    return Err("down");
+                }
+                Ok(self.items.lock().unwrap().get(key).copied())
+            }
+
+            fn put_if(
+                &self,
+                key: &str,
+                expected: Option<u64>,
+                state: u64,
+                _expires_at: SystemTime,
+            ) -> Result<bool, Self::Error> {
+                let mut items = self.items.lock().unwrap();
+                // Another instance keeps changing `test:contended`.
+                if items.get(key).copied() != expected || key == "test:contended" {
+                    return Ok(false);
+                }
+                items.insert(key.to_owned(), state);
+                Ok(true)
+            }
+        }
+
+        let table = Arc::new(Table::default());
+        let config = GovernorConfigBuilder::default()
+            .per_second(1)
+            .burst_size(1)
+            .store(KvStore::new(table.clone()).prefix("test"))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(table
+            .items
+            .lock()
+            .unwrap()
+            .contains_key("test:default:127.0.0.1"));
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Updates of a key other instances keep changing give up.
+        let store = KvStore::new(table.clone()).prefix("test");
+        let mut attempts = 0;
+        let updated = GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| {
+            attempts += 1;
+            Some(1)
+        });
+        assert!(updated.is_err());
+        assert_eq!(attempts, 8);
+
+        // Which isn't a failure of the service.
+        for _ in 0..3 {
+            let updated =
+                GovernorStore::try_measure_and_replace(&store, &"contended", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert!(GovernorStore::try_measure_and_replace(&store, &"free", &mut |_| Some(1)).is_ok());
+
+        // Failures let the requests through.
+        table.down.store(true, Ordering::Relaxed);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // And the service isn't called after 3 of them in a row.
+        let calls = table.calls.load(Ordering::Relaxed);
+        for _ in 0..5 {
+            let updated = GovernorStore::try_measure_and_replace(&store, &"key", &mut |_| Some(1));
+            assert!(updated.is_err());
+        }
+        assert_eq!(table.calls.load(Ordering::Relaxed), calls + 3);
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker_store() {
+        use crate::{
+            store::{CircuitBreakerStore, GovernorStore, MemoryStore},
+            BoxError,
+        };
+        use std::{
+            net::IpAddr,
+            sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+            time::Duration,
+        };
+
+        #[derive(Default)]
+        struct Backend {
+            store: MemoryStore<IpAddr>,
+            down: AtomicBool,
+            calls: AtomicUsize,
+        }
+
+        impl GovernorStore<IpAddr> for Arc<Backend> {
+            fn measure_and_replace(
+                &self,
+                key: &IpAddr,
+                f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+            ) {
+                self.store.measure_and_replace(key, f)
+            }
+
+            fn try_measure_and_replace(
+                &self,
+                key: &IpAddr,
+                f: &mut dyn FnMut(Option<u64>) -> Option<u64>,
+            ) -> Result<(), BoxError> {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                if self.down.load(Ordering::Relaxed) {
+                    return Err("down".into());
+                }
+                self.store.measure_and_replace(key, f);
+                Ok(())
+            }
+
+            fn retain(&self, keep: &mut dyn FnMut(&IpAddr, u64) -> bool) {
+                self.store.retain(keep)
+            }
+
+            fn len(&self) -> usize {
+                self.store.len()
+            }
+
+            fn scoped(&self, _scope: &str) -> Arc<dyn GovernorStore<IpAddr>> {
+                Arc::new(self.clone())
+            }
+        }
+
+        let backend = Arc::new(Backend::default());
+        backend.down.store(true, Ordering::Relaxed);
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(2)
+            .store(CircuitBreakerStore::new(
+                backend.clone(),
+                2,
+                Duration::from_millis(500),
+            ))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        // The backend fails twice, then the circuit opens and the keys are checked in memory.
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 2);
+
+        // Once the cooldown elapsed, the backend is called again.
+        backend.down.store(false, Ordering::Relaxed);
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(backend.calls.load(Ordering::Relaxed) > 2);
+        assert_eq!(backend.store.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_limiter_mode() {
+        use crate::{governor::LimiterMode, store::MemoryStore};
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(4)
+            .limiter_mode(LimiterMode::PerWorker(2))
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        // A single thread handles the requests, with half the burst size.
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-ratelimit-limit"], "2");
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert!(GovernorConfigBuilder::default()
+            .limiter_mode(LimiterMode::PerWorker(0))
+            .finish()
+            .is_none());
+        assert!(GovernorConfigBuilder::default()
+            .limiter_mode(LimiterMode::PerWorker(2))
+            .store(MemoryStore::default())
+            .finish()
+            .is_none());
+    }
+
+    #[test]
+    fn test_per_worker_migration() {
+        use crate::store::{KeyedStore, WorkerStore};
+        use governor::{nanos::Nanos, Quota};
+        use std::sync::{Barrier, Mutex};
+        use std::{num::NonZeroU32, time::Duration};
+
+        let quota = |burst_size| {
+            Quota::with_period(Duration::from_secs(60))
+                .unwrap()
+                .allow_burst(NonZeroU32::new(burst_size).unwrap())
+        };
+        let (from, to) = (quota(2), quota(4));
+        let store = KeyedStore::new(Arc::new(WorkerStore::new(64)));
+        let migrated = Mutex::new(None);
+        let barrier = Barrier::new(3);
+
+        // Each thread consumes two tokens in its own worker, and still has them consumed there
+        // once migrated from another thread.
+        std::thread::scope(|scope| {
+            for key in ["a", "b"] {
+                let (store, migrated, barrier) = (&store, &migrated, &barrier);
+                scope.spawn(move || {
+                    store.charge(&key, Nanos::from(Duration::from_secs(120)));
+                    barrier.wait();
+                    barrier.wait();
+                    let migrated: KeyedStore<&str> = migrated.lock().unwrap().clone().unwrap();
+                    assert_eq!(migrated.remaining_burst_capacity(&key, &to), 2);
+                });
+            }
+            barrier.wait();
+            *migrated.lock().unwrap() = Some(store.migrate(&from, &to));
+            barrier.wait();
+        });
+    }
+
+    #[cfg(feature = "gossip")]
+    #[tokio::test]
+    async fn test_shutdown() {
+        use crate::gossip::GossipStore;
+        use std::{net::IpAddr, time::Duration};
+
+        // The states would only be exchanged in an hour.
+        let interval = Duration::from_secs(60 * 60);
+        let first = GossipStore::<IpAddr>::bind("127.0.0.1:0", interval).unwrap();
+        let second = GossipStore::<IpAddr>::bind("127.0.0.1:0", interval).unwrap();
+        first.add_peer(second.local_addr().unwrap()).unwrap();
+        second.add_peer(first.local_addr().unwrap()).unwrap();
+
+        let config_with = |store| {
+            Arc::new(
+                GovernorConfigBuilder::default()
+                    .per_second(1)
+                    .burst_size(2)
+                    .store(store)
+                    .finish()
+                    .unwrap(),
+            )
+        };
+        let (first, second) = (config_with(first), config_with(second));
+        let first_url = serve(
+            GovernorLayer {
+                config: first.clone(),
+            }
+            .layer(hello()),
+        )
+        .await;
+        let second_url = serve(GovernorLayer { config: second }.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&first_url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Shutting the first instance down sends its state right away.
+        first.shutdown();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let res = client.get(&second_url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.get(&second_url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Requests are still checked afterwards.
+        let res = client.get(&first_url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_global_governor() {
+        use crate::GlobalGovernorLayer;
+        use governor::Quota;
+        use std::num::NonZeroU32;
+
+        let quota =
+            Quota::per_minute(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(2).unwrap());
+        let url = serve(GlobalGovernorLayer::new(quota).layer(hello())).await;
+
+        // Every client shares the quota.
+        let client = reqwest::Client::new();
+        for forwarded in ["1.1.1.1", "2.2.2.2"] {
+            let res = client
+                .get(&url)
+                .header("x-forwarded-for", forwarded)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = client
+            .get(&url)
+            .header("x-forwarded-for", "3.3.3.3")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            res.headers()["retry-after"],
+            res.headers()["x-ratelimit-after"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_global_quota() {
+        use crate::key_extractor::SmartIpKeyExtractor;
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .global_quota(0, Duration::from_secs(60))
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(3)
+            .key_extractor(SmartIpKeyExtractor)
+            .global_quota(4, Duration::from_secs(60))
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |ip: &'static str| client.get(&url).header("x-forwarded-for", ip).send();
+
+        // The first client is limited by its own quota.
+        for _ in 0..3 {
+            let res = send("1.2.3.1").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-ratelimit-limit"], "3");
+        }
+        let res = send("1.2.3.1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The second one by the global quota.
+        let res = send("1.2.3.2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-limit"], "4");
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        let res = send("1.2.3.2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["x-ratelimit-limit"], "4");
+    }
+
+    #[tokio::test]
+    async fn test_levels() {
+        use crate::key_extractor::{OperatorHeaderKeyExtractor, SmartIpKeyExtractor};
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .level(
+                "tenant",
+                0,
+                Duration::from_secs(60),
+                OperatorHeaderKeyExtractor::new(HeaderName::from_static("x-tenant-id")),
+            )
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(3)
+            .key_extractor(SmartIpKeyExtractor)
+            .level(
+                "tenant",
+                4,
+                Duration::from_secs(60),
+                OperatorHeaderKeyExtractor::new(HeaderName::from_static("x-tenant-id")),
+            )
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |ip: &'static str, tenant: &'static str| {
+            client
+                .get(&url)
+                .header("x-forwarded-for", ip)
+                .header("x-tenant-id", tenant)
+                .send()
+        };
+
+        // The first client is limited by its own quota.
+        for _ in 0..3 {
+            let res = send("1.2.3.1", "a").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("1.2.3.1", "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-ratelimit-level").is_none());
+
+        // The second one by the quota of its tenant.
+        let res = send("1.2.3.2", "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-limit"], "4");
+        let res = send("1.2.3.2", "a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["x-ratelimit-level"], "tenant");
+
+        // Other tenants and requests without a tenant have their own quota.
+        let res = send("1.2.3.2", "b").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client
+            .get(&url)
+            .header("x-forwarded-for", "1.2.3.3")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_ip_quota() {
+        use crate::key_extractor::OperatorHeaderKeyExtractor;
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .ip_quota(10, Duration::ZERO)
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .key_extractor(OperatorHeaderKeyExtractor::new(HeaderName::from_static(
+                "x-api-key",
+            )))
+            .per_second(60)
+            .burst_size(3)
+            .ip_quota(5, Duration::from_secs(60))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |api_key: &'static str| client.get(&url).header("x-api-key", api_key).send();
+
+        // The first API key is limited by its own quota.
+        for _ in 0..3 {
+            let res = send("a").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("a").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-ratelimit-level").is_none());
+
+        // The second one by the quota of the IP address they share.
+        for _ in 0..2 {
+            let res = send("b").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("b").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["x-ratelimit-level"], "ip");
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .max_in_flight(0)
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .max_in_flight(1)
+            .finish()
+            .unwrap();
+        let slow = service_fn(|_: Request<Incoming>| async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            Ok::<_, Infallible>(Response::new(HttpBody::from("Hello, World!")))
+        });
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(slow),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let first = tokio::spawn(client.get(&url).send());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The first request is still in flight.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().get("x-ratelimit-after").is_none());
+
+        // Its slot is released with its response.
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::OK);
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_response_bytes_quota() {
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .response_bytes_quota(0, Duration::from_secs(60))
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .response_bytes_quota(20, Duration::from_secs(60))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        // Each body of 13 bytes is charged once sent, the second one going over the quota.
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.text().await.unwrap(), "Hello, World!");
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Checking that a byte is left doesn't consume it.
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .response_bytes_quota(14, Duration::from_secs(60))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+        for status in [
+            StatusCode::OK,
+            StatusCode::OK,
+            StatusCode::TOO_MANY_REQUESTS,
+        ] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), status);
+            res.bytes().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_bytes_quota() {
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .request_bytes_quota(100, Duration::ZERO)
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .request_bytes_quota(100, Duration::from_secs(60))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.post(&url).body(vec![0; 60]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.post(&url).body(vec![0; 60]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = client.post(&url).body(vec![0; 200]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Requests without a body aren't charged.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = client.post(&url).body(vec![0; 30]).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_cost_extractor() {
+        use std::num::NonZeroU32;
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(4)
+            .cost_extractor(|req: &Request<Incoming>| {
+                let cost = match req.uri().path() {
+                    "/expensive" => 3,
+                    "/huge" => 5,
+                    _ => 1,
+                };
+                NonZeroU32::new(cost).unwrap()
+            })
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(format!("{url}/expensive")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "1");
+        let res = client.get(format!("{url}/expensive")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = client.get(format!("{url}/huge")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = client.get(format!("{url}/cheap")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn test_header_cost() {
+        use crate::cost::HeaderCost;
+        use std::num::NonZeroU32;
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .cost_extractor(
+                HeaderCost::default()
+                    .default_cost(NonZeroU32::new(2).unwrap())
+                    .max(NonZeroU32::new(5).unwrap()),
+            )
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |cost: Option<&'static str>| {
+            let req = client.get(&url);
+            match cost {
+                Some(cost) => req.header("x-request-cost", cost),
+                None => req,
+            }
+            .send()
+        };
+
+        // The default cost applies without a valid cost, and costs are capped.
+        let res = send(None).await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "8");
+        let res = send(Some("0")).await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "6");
+        let res = send(Some("1")).await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "5");
+        let res = send(Some("1000")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+    }
+
+    #[cfg(feature = "graphql")]
+    #[tokio::test]
+    async fn test_graphql_cost() {
+        use crate::graphql::GraphQlCost;
+        use std::num::NonZeroU32;
+
+        let cost = GraphQlCost::new(3, NonZeroU32::new(50).unwrap());
+        let query_cost = |query| cost.query_cost(query).get();
+        assert_eq!(query_cost("{ me }"), 1);
+        assert_eq!(
+            query_cost(
+                r#"query Q($id: ID!) {
+                    # a comment { with braces }
+                    user(id: $id, filter: { name: "a { b" }) {
+                        first: name
+                        ... on Admin { roles }
+                        ...Details @include(if: true)
+                    }
+                }"#
+            ),
+            3
+        );
+        assert_eq!(query_cost("{ a { b { c { d } } } }"), 50);
+        // Malformed queries.
+        assert_eq!(query_cost("}{ a }"), 1);
+        assert_eq!(query_cost("{ a }}} { b { c } }"), 3);
+        assert_eq!(query_cost(")}("), 50);
+        // A stray closing parenthesis doesn't hide the following fields.
+        assert_eq!(query_cost("{ a) b c }"), 3);
+        assert_eq!(query_cost("{ a(x: 1)) b(y: 2) { c } }"), 3);
+        // Nor does an unclosed argument list.
+        assert_eq!(query_cost("{ a(x: 1 b c }"), 50);
+        assert_eq!(query_cost("{ a(x: 1 "), 50);
+
+        assert_eq!(cost.body_cost(br#"{"query": "{ a b }"}"#).get(), 2);
+        assert_eq!(
+            cost.body_cost(br#"[{"query": "{ a b }"}, {"query": "{ c }"}]"#)
+                .get(),
+            3
+        );
+        assert_eq!(cost.body_cost(b"not json").get(), 1);
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .cost_extractor(cost)
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let res = client
+            .get(format!(
+                "{url}/graphql?query=%7B+user+%7B+name+email+%7D+%7D"
+            ))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "7");
+        let res = client
+            .get(format!("{url}/graphql?query=%7Ba%7Bb%7Bc%7Bd%7D%7D%7D%7D"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        let res = client.post(format!("{url}/graphql")).send().await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "6");
+    }
+
+    #[tokio::test]
+    async fn test_count_responses() {
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(2)
+            .count_responses(|status| status == StatusCode::UNAUTHORIZED)
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(echo_status()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        // Other responses don't count.
+        for _ in 0..4 {
+            let res = client.get(format!("{url}/200")).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        for _ in 0..2 {
+            let res = client.get(format!("{url}/401")).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = client.get(format!("{url}/200")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_server_error_refunds() {
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(2)
+            .server_error_refunds()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(echo_status()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        // Server errors don't consume the quota, client errors do.
+        for _ in 0..4 {
+            let res = client.get(format!("{url}/503")).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        for _ in 0..2 {
+            let res = client.get(format!("{url}/404")).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        let res = client.get(format!("{url}/503")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_penalty() {
+        assert!(GovernorConfigBuilder::default()
+            .auth_failure_penalty(0)
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(1)
+            .burst_size(4)
+            .auth_failure_penalty(3)
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(echo_status()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+
+        let res = client.get(format!("{url}/200")).send().await.unwrap();
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "3");
+        // The failed authentication takes 2 more tokens once known.
+        let res = client.get(format!("{url}/401")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "2");
+        let res = client.get(format!("{url}/200")).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_sliding_window_log() {
+        use crate::{governor::Algorithm, store::MemoryStore};
+        use std::time::Duration;
+
+        // 2 requests over any 600ms, where the GCRA would give a token back every 300ms.
+        let config = GovernorConfigBuilder::default()
+            .quota_per(2, Duration::from_millis(600))
+            .algorithm(Algorithm::SlidingWindowLog)
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "1");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The requests are still in the window.
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Both left it.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        assert!(GovernorConfigBuilder::default()
+            .algorithm(Algorithm::SlidingWindowLog)
+            .store(MemoryStore::default())
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_window() {
+        use crate::{governor::Algorithm, store::MemoryStore};
+        use std::time::Duration;
+
+        // 2 requests in each window of 600ms, counted in the store.
+        let config = GovernorConfigBuilder::default()
+            .quota_per(2, Duration::from_millis(600))
+            .algorithm(Algorithm::FixedWindow)
+            .store(MemoryStore::default())
+            .use_headers()
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "1");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Every token is back in the next window.
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        for remaining in ["1", "0"] {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()["x-ratelimit-remaining"], remaining);
+        }
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_max_delay() {
+        use std::time::{Duration, Instant};
+
+        let config = GovernorConfigBuilder::default()
+            .per_millisecond(300)
+            .burst_size(1)
+            .max_delay(Duration::from_millis(500))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Waits for the next token instead of being rejected.
+        let start = Instant::now();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+
+        // The second one would wait after the first, more than the maximum delay.
+        let (first, second) = tokio::join!(client.get(&url).send(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.get(&url).send().await
+        });
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert!(GovernorConfigBuilder::default()
+            .max_delay(Duration::ZERO)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_delay_calls_after_the_wait() {
+        use std::{
+            sync::Mutex,
+            time::{Duration, Instant},
+        };
+
+        let config = GovernorConfigBuilder::default()
+            .per_millisecond(300)
+            .burst_size(1)
+            .max_delay(Duration::from_millis(500))
+            .finish()
+            .unwrap();
+        // Records when the inner service is called.
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let inner_calls = calls.clone();
+        let inner = BoxCloneService::new(service_fn(move |_: Request<Incoming>| {
+            inner_calls.lock().unwrap().push(Instant::now());
+            async { Ok::<_, Infallible>(Response::new(HttpBody::from("Hello, World!"))) }
+        }));
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(inner),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let start = Instant::now();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[1].duration_since(start) >= Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn test_max_queued() {
+        use std::time::Duration;
+
+        let config = GovernorConfigBuilder::default()
+            .per_millisecond(300)
+            .burst_size(1)
+            .max_delay(Duration::from_secs(2))
+            .max_queued(1)
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // The first one waits, the queue is full for the second one.
+        let (first, second) = tokio::join!(client.get(&url).send(), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.get(&url).send().await
+        });
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The queue is empty again.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        assert!(GovernorConfigBuilder::default()
+            .max_queued(1)
+            .finish()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_global_backpressure() {
+        use std::time::{Duration, Instant};
+
+        assert!(GovernorConfigBuilder::default()
+            .global_backpressure()
+            .finish()
+            .is_none());
+
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(10)
+                .global_quota(2, Duration::from_millis(600))
+                .global_backpressure()
+                .finish()
+                .unwrap(),
+        );
+        let layer = GovernorLayer {
+            config: config.clone(),
+        };
+        let url = serve(layer.layer(hello())).await;
+
+        let client = reqwest::Client::new();
+        for _ in 0..2 {
+            let res = client.get(&url).send().await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        // The service isn't ready until the global quota replenishes a request.
+        let mut governor = layer.layer(hello());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(100), governor.ready())
+                .await
+                .is_err()
+        );
+        tokio::time::timeout(Duration::from_millis(400), governor.ready())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Requests wait for it instead of being rejected.
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let start = Instant::now();
+        let res = client.get(&url).send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn test_global_reserve() {
+        use crate::key_extractor::SmartIpKeyExtractor;
+        use std::{net::IpAddr, time::Duration};
+
+        assert!(GovernorConfigBuilder::default()
+            .global_reserve(20)
+            .finish()
+            .is_none());
+        assert!(GovernorConfigBuilder::default()
+            .global_quota(10, Duration::from_secs(60))
+            .global_reserve(100)
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .key_extractor(SmartIpKeyExtractor)
+            .global_quota(5, Duration::from_secs(60))
+            .global_reserve(40)
+            .high_priority(|ip: &IpAddr| *ip == IpAddr::from([1, 2, 3, 9]))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send = |ip: &'static str| client.get(&url).header("x-forwarded-for", ip).send();
+
+        // Other clients can't take the last 2 requests of the global quota.
+        for _ in 0..3 {
+            let res = send("1.2.3.1").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("1.2.3.2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // The high priority one can.
+        for _ in 0..2 {
+            let res = send("1.2.3.9").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("1.2.3.9").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget() {
+        use std::time::Duration;
+
+        assert!(GovernorConfigBuilder::default()
+            .retry_budget(101, 1, Duration::from_secs(60))
+            .finish()
+            .is_none());
+
+        let config = GovernorConfigBuilder::default()
+            .per_second(60)
+            .burst_size(10)
+            .retry_budget(50, 1, Duration::from_secs(60))
+            .finish()
+            .unwrap();
+        let url = serve(
+            GovernorLayer {
+                config: Arc::new(config),
+            }
+            .layer(hello()),
+        )
+        .await;
+
+        let client = reqwest::Client::new();
+        let send =
+            |attempt: &'static str| client.get(&url).header("x-retry-attempt", attempt).send();
+
+        // Only the minimum of retries is allowed at first.
+        let res = send("1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send("2").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "60");
+
+        // Then a retry for every two other requests.
+        for _ in 0..2 {
+            let res = send("0").await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        let res = send("1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send("1").await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_fnv1a_is_stable() {
+        use crate::store::Fnv1a;
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63_dc4c_8601_ec8c);
+
+        // Integers hash the same on every platform.
+        let hash = |value: &dyn Fn(&mut Fnv1a)| {
+            let mut hasher = Fnv1a::default();
+            value(&mut hasher);
+            hasher.finish()
+        };
+        assert_eq!(hash(&|h| 1u32.hash(h)), hash(&|h| h.write(&[1, 0, 0, 0])));
+        assert_eq!(hash(&|h| 1usize.hash(h)), hash(&|h| 1u64.hash(h)));
     }
 }